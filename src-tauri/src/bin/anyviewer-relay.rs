@@ -0,0 +1,44 @@
+//! Self-hosted AnyViewer relay server.
+
+use anyviewer_lib::network::relay_server::{RelayServer, RelayServerConfig};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "anyviewer-relay", version, about = "Relay server for AnyViewer connections")]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    bind: String,
+
+    /// Maximum number of connected clients
+    #[arg(long, default_value_t = 1000)]
+    max_clients: usize,
+
+    /// Per-client bandwidth cap in KB/s, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    max_bandwidth_kbps: u64,
+
+    /// Seconds of silence before a client is dropped
+    #[arg(long, default_value_t = 90)]
+    heartbeat_timeout: u64,
+
+    /// Seconds between stats log lines, 0 to disable
+    #[arg(long, default_value_t = 60)]
+    stats_interval: u64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = Args::parse();
+    let config = RelayServerConfig {
+        bind_address: args.bind,
+        max_clients: args.max_clients,
+        max_bandwidth_per_client: args.max_bandwidth_kbps * 1024,
+        heartbeat_timeout_seconds: args.heartbeat_timeout,
+        stats_interval_seconds: args.stats_interval,
+    };
+
+    RelayServer::new(config).start().await
+}
@@ -0,0 +1,359 @@
+//! The host's mouse pointer, sent to viewers apart from the frames.
+//!
+//! Screen captures leave the pointer out. Viewers that negotiate the cursor
+//! channel get each distinct shape once, cached by ID, and the position as
+//! often as it moves, so they can draw the pointer at their own rate however
+//! slowly frames arrive. For other viewers the pointer is drawn into the frames.
+
+use anyhow::Result;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::streaming::StreamGeometry;
+
+/// How often the pointer is sampled for viewers drawing it themselves
+pub const CURSOR_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Largest shape accepted from a host; real pointers are far smaller
+pub const MAX_CURSOR_SIZE: u32 = 256;
+
+/// ID of the arrow used where the pointer's real shape can't be read
+pub const DEFAULT_CURSOR_ID: u64 = 0;
+
+/// A pointer image with its hotspot, the pixel that sits on the pointer position
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CursorShape {
+    pub id: u64,
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: u32,
+    pub hot_y: u32,
+    /// Unpremultiplied RGBA, row by row
+    #[serde(with = "base64_bytes")]
+    pub rgba: Vec<u8>,
+}
+
+impl CursorShape {
+    pub fn to_image(&self) -> Result<RgbaImage> {
+        if self.width > MAX_CURSOR_SIZE || self.height > MAX_CURSOR_SIZE {
+            return Err(anyhow::anyhow!("Cursor of {}x{} is too large", self.width, self.height));
+        }
+
+        RgbaImage::from_raw(self.width, self.height, self.rgba.clone())
+            .ok_or_else(|| anyhow::anyhow!("Cursor data doesn't match its {}x{} size", self.width, self.height))
+    }
+}
+
+/// Where the pointer is, in pixels of the frames the viewer receives
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorPosition {
+    pub x: i32,
+    pub y: i32,
+    pub shape_id: u64,
+    /// False while the pointer is outside the streamed monitor(s)
+    pub visible: bool,
+}
+
+/// The pointer as sampled on the host, in desktop coordinates
+#[derive(Debug, Clone)]
+pub struct CursorSample {
+    pub x: i32,
+    pub y: i32,
+    pub shape: CursorShape,
+}
+
+pub trait CursorSource: Send {
+    fn sample(&mut self) -> Result<CursorSample>;
+}
+
+/// The pointer's real shape where the platform exposes it, otherwise its position with an arrow
+pub fn open_cursor_source() -> Result<Box<dyn CursorSource>> {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    match x11::XFixesCursor::connect() {
+        Ok(source) => return Ok(Box::new(source)),
+        Err(e) => log::debug!("Cursor shapes unavailable, falling back to an arrow: {}", e),
+    }
+
+    let mut source = PositionOnly;
+    source.sample()?;
+    Ok(Box::new(source))
+}
+
+/// Position through the input backend, drawn as the default arrow
+struct PositionOnly;
+
+impl CursorSource for PositionOnly {
+    fn sample(&mut self) -> Result<CursorSample> {
+        use enigo::{Enigo, Mouse, Settings};
+
+        // Enigo isn't `Send` on every platform, so it can't be kept across samples
+        let enigo = Enigo::new(&Settings::default())
+            .map_err(|e| anyhow::anyhow!("Could not open the input backend: {}", e))?;
+        let (x, y) = enigo.location()
+            .map_err(|e| anyhow::anyhow!("Could not read the pointer position: {}", e))?;
+
+        Ok(CursorSample { x, y, shape: default_arrow() })
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod x11 {
+    use anyhow::Result;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::ConnectionExt as _;
+    use x11rb::rust_connection::RustConnection;
+
+    use super::{CursorSample, CursorShape, CursorSource};
+
+    /// The X server's current pointer through the XFixes extension
+    pub struct XFixesCursor {
+        connection: RustConnection,
+        /// The last shape, reused while the server reports the same cursor
+        shape: Option<CursorShape>,
+    }
+
+    impl XFixesCursor {
+        pub fn connect() -> Result<Self> {
+            let (connection, _) = x11rb::connect(None)?;
+            connection.xfixes_query_version(4, 0)?.reply()?;
+            connection.flush()?;
+
+            Ok(Self { connection, shape: None })
+        }
+    }
+
+    impl CursorSource for XFixesCursor {
+        fn sample(&mut self) -> Result<CursorSample> {
+            let reply = self.connection.xfixes_get_cursor_image()?.reply()?;
+            let id = reply.cursor_serial as u64;
+
+            let shape = match self.shape.take() {
+                Some(shape) if shape.id == id => shape,
+                _ => CursorShape {
+                    id,
+                    width: reply.width as u32,
+                    height: reply.height as u32,
+                    hot_x: reply.xhot as u32,
+                    hot_y: reply.yhot as u32,
+                    rgba: reply.cursor_image.iter().flat_map(|&argb| unpremultiply(argb)).collect(),
+                },
+            };
+            self.shape = Some(shape.clone());
+
+            Ok(CursorSample { x: reply.x as i32, y: reply.y as i32, shape })
+        }
+    }
+
+    /// XFixes hands out premultiplied ARGB
+    fn unpremultiply(argb: u32) -> [u8; 4] {
+        let [alpha, red, green, blue] = argb.to_be_bytes();
+        if alpha == 0 {
+            return [0, 0, 0, 0];
+        }
+
+        let channel = |value: u8| ((value as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255) as u8;
+        [channel(red), channel(green), channel(blue), alpha]
+    }
+}
+
+/// White arrow with a black outline, hotspot at the tip
+pub fn default_arrow() -> CursorShape {
+    const WIDTH: u32 = 12;
+    const HEIGHT: u32 = 19;
+
+    let inside = |x: i32, y: i32| -> bool {
+        // The head is a wedge widening by two pixels every three rows, the tail a short stem below it
+        (y < 16 && x >= 0 && x <= y * 2 / 3) || ((13..HEIGHT as i32).contains(&y) && (4..=6).contains(&(x - (y - 13) / 2)))
+    };
+
+    let mut image = RgbaImage::new(WIDTH, HEIGHT);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (x, y) = (x as i32, y as i32);
+        if !inside(x, y) {
+            continue;
+        }
+
+        let edge = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|(dx, dy)| !inside(x + dx, y + dy));
+        *pixel = if edge { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 255, 255, 255]) };
+    }
+
+    CursorShape {
+        id: DEFAULT_CURSOR_ID,
+        width: WIDTH,
+        height: HEIGHT,
+        hot_x: 0,
+        hot_y: 0,
+        rgba: image.into_raw(),
+    }
+}
+
+/// Host side of the cursor channel for one viewer
+#[derive(Default)]
+pub struct CursorTracker {
+    /// Shapes the viewer already has
+    sent_shapes: HashSet<u64>,
+    last_position: Option<CursorPosition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CursorUpdate {
+    Shape(CursorShape),
+    Position(CursorPosition),
+}
+
+impl CursorTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Messages that bring the viewer up to date with `sample`; none if nothing changed
+    pub fn update(&mut self, sample: &CursorSample, geometry: &StreamGeometry) -> Vec<CursorUpdate> {
+        let mut updates = Vec::new();
+
+        if self.sent_shapes.insert(sample.shape.id) {
+            updates.push(CursorUpdate::Shape(sample.shape.clone()));
+        }
+
+        let (x, y) = geometry.to_frame(sample.x, sample.y);
+        let position = CursorPosition {
+            x,
+            y,
+            shape_id: sample.shape.id,
+            visible: geometry.contains(sample.x, sample.y),
+        };
+
+        // Both sides already agree on a pointer that stays out of view
+        let unchanged = self.last_position.is_some_and(|last| last == position || (!last.visible && !position.visible && last.shape_id == position.shape_id));
+        if !unchanged {
+            self.last_position = Some(position);
+            updates.push(CursorUpdate::Position(position));
+        }
+
+        updates
+    }
+}
+
+/// Viewer side of the cursor channel: the shapes received and where the pointer is
+#[derive(Debug, Default)]
+pub struct CursorCache {
+    shapes: HashMap<u64, CursorShape>,
+    position: Option<CursorPosition>,
+}
+
+impl CursorCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert_shape(&mut self, shape: CursorShape) {
+        self.shapes.insert(shape.id, shape);
+    }
+
+    pub fn set_position(&mut self, position: CursorPosition) {
+        self.position = Some(position);
+    }
+
+    /// The pointer to draw and where, if it is on screen and its shape has arrived
+    pub fn current(&self) -> Option<(&CursorShape, CursorPosition)> {
+        let position = self.position.filter(|position| position.visible)?;
+        let shape = self.shapes.get(&position.shape_id)?;
+        Some((shape, position))
+    }
+
+    /// Draw the pointer over a received frame
+    pub fn draw(&self, frame: &mut RgbaImage) -> Result<()> {
+        if let Some((shape, position)) = self.current() {
+            composite(frame, shape, position.x, position.y)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Blend `shape` onto `image` with its hotspot at (`x`, `y`)
+pub fn composite(image: &mut RgbaImage, shape: &CursorShape, x: i32, y: i32) -> Result<()> {
+    let cursor = shape.to_image()?;
+    let (left, top) = (x - shape.hot_x as i32, y - shape.hot_y as i32);
+
+    for (cx, cy, pixel) in cursor.enumerate_pixels() {
+        let (px, py) = (left + cx as i32, top + cy as i32);
+        if px < 0 || py < 0 || px >= image.width() as i32 || py >= image.height() as i32 {
+            continue;
+        }
+
+        let alpha = pixel[3] as u32;
+        let target = image.get_pixel_mut(px as u32, py as u32);
+        for channel in 0..3 {
+            target[channel] = ((pixel[channel] as u32 * alpha + target[channel] as u32 * (255 - alpha)) / 255) as u8;
+        }
+    }
+
+    Ok(())
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::screen_capture::CaptureRegion;
+
+    fn sample(x: i32, y: i32, shape: &CursorShape) -> CursorSample {
+        CursorSample { x, y, shape: shape.clone() }
+    }
+
+    #[test]
+    fn test_shapes_sent_once_and_positions_follow_scale() {
+        let geometry = StreamGeometry {
+            frame_width: 960,
+            frame_height: 540,
+            region: CaptureRegion { x: 1920, y: 0, width: 1920, height: 1080 },
+        };
+        let arrow = default_arrow();
+        let beam = CursorShape { id: 7, width: 1, height: 2, hot_x: 0, hot_y: 1, rgba: vec![255; 8] };
+        let mut tracker = CursorTracker::new();
+
+        let first = tracker.update(&sample(2020, 200, &arrow), &geometry);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0], CursorUpdate::Shape(arrow.clone()));
+        assert_eq!(first[1], CursorUpdate::Position(CursorPosition { x: 50, y: 100, shape_id: 0, visible: true }));
+
+        assert!(tracker.update(&sample(2020, 200, &arrow), &geometry).is_empty());
+
+        // Switching back to a shape the viewer has only moves the pointer
+        assert_eq!(tracker.update(&sample(2020, 200, &beam), &geometry).len(), 2);
+        assert_eq!(
+            tracker.update(&sample(2030, 210, &arrow), &geometry),
+            vec![CursorUpdate::Position(CursorPosition { x: 55, y: 105, shape_id: 0, visible: true })]
+        );
+
+        // Moving around another monitor is reported once
+        assert_eq!(tracker.update(&sample(100, 100, &arrow), &geometry).len(), 1);
+        assert!(tracker.update(&sample(300, 300, &arrow), &geometry).is_empty());
+
+        let mut viewer = CursorCache::new();
+        let json = serde_json::to_string(&beam).unwrap();
+        viewer.insert_shape(serde_json::from_str(&json).unwrap());
+        viewer.set_position(CursorPosition { x: 1, y: 1, shape_id: 7, visible: true });
+
+        let mut frame = RgbaImage::from_pixel(4, 4, image::Rgba([0, 0, 0, 255]));
+        viewer.draw(&mut frame).unwrap();
+        assert_eq!(frame.get_pixel(1, 0), &image::Rgba([255, 255, 255, 255]));
+        assert_eq!(frame.get_pixel(1, 1), &image::Rgba([255, 255, 255, 255]));
+        assert_eq!(frame.get_pixel(1, 2), &image::Rgba([0, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,156 @@
+use image::{imageops, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use super::screen_capture::CaptureRegion;
+use super::ScreenInfo;
+
+/// Which part of the desktop is streamed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MonitorSelection {
+    Single(usize),
+    /// Every monitor stitched into one frame, laid out as on the host
+    All,
+}
+
+impl Default for MonitorSelection {
+    fn default() -> Self {
+        MonitorSelection::Single(0)
+    }
+}
+
+impl std::str::FromStr for MonitorSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(MonitorSelection::All),
+            index => index.parse()
+                .map(MonitorSelection::Single)
+                .map_err(|_| format!("Expected a monitor index or \"all\", got {}", index)),
+        }
+    }
+}
+
+/// The host's monitors and the one being streamed, as sent to viewers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenLayout {
+    pub screens: Vec<ScreenInfo>,
+    pub selected: MonitorSelection,
+}
+
+impl ScreenLayout {
+    /// Desktop area shown in the streamed frame
+    pub fn bounds(&self) -> CaptureRegion {
+        match self.selected {
+            MonitorSelection::Single(index) => match self.screens.get(index).or(self.screens.first()) {
+                Some(screen) => CaptureRegion {
+                    x: screen.x,
+                    y: screen.y,
+                    width: screen.width,
+                    height: screen.height,
+                },
+                None => CaptureRegion { x: 0, y: 0, width: 0, height: 0 },
+            },
+            MonitorSelection::All => virtual_desktop(&self.screens),
+        }
+    }
+
+    /// Map a point in the streamed frame to desktop coordinates for input injection
+    pub fn to_desktop(&self, x: i32, y: i32) -> (i32, i32) {
+        let bounds = self.bounds();
+        (bounds.x + x, bounds.y + y)
+    }
+}
+
+/// Smallest rectangle covering every monitor
+pub fn virtual_desktop(screens: &[ScreenInfo]) -> CaptureRegion {
+    if screens.is_empty() {
+        return CaptureRegion { x: 0, y: 0, width: 0, height: 0 };
+    }
+
+    let left = screens.iter().map(|s| s.x).min().unwrap_or(0);
+    let top = screens.iter().map(|s| s.y).min().unwrap_or(0);
+    let right = screens.iter().map(|s| s.x + s.width as i32).max().unwrap_or(0);
+    let bottom = screens.iter().map(|s| s.y + s.height as i32).max().unwrap_or(0);
+
+    CaptureRegion {
+        x: left,
+        y: top,
+        width: (right - left) as u32,
+        height: (bottom - top) as u32,
+    }
+}
+
+/// Compose per-monitor captures into one virtual desktop image; gaps between monitors stay black
+pub fn stitch(captures: &[(ScreenInfo, RgbaImage)]) -> RgbaImage {
+    let screens: Vec<ScreenInfo> = captures.iter().map(|(screen, _)| screen.clone()).collect();
+    let desktop = virtual_desktop(&screens);
+    let mut canvas = RgbaImage::from_pixel(desktop.width, desktop.height, image::Rgba([0, 0, 0, 255]));
+
+    for (screen, capture) in captures {
+        // HiDPI captures come back in physical pixels; input and layout use logical ones
+        let resized;
+        let capture = if capture.dimensions() != (screen.width, screen.height) {
+            resized = imageops::resize(capture, screen.width, screen.height, imageops::FilterType::Triangle);
+            &resized
+        } else {
+            capture
+        };
+
+        imageops::replace(
+            &mut canvas,
+            capture,
+            (screen.x - desktop.x) as i64,
+            (screen.y - desktop.y) as i64,
+        );
+    }
+
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(index: usize, x: i32, y: i32, width: u32, height: u32) -> ScreenInfo {
+        ScreenInfo {
+            index,
+            x,
+            y,
+            width,
+            height,
+            scale_factor: 1.0,
+            is_primary: index == 0,
+        }
+    }
+
+    #[test]
+    fn test_stitch_and_map_input() {
+        // Secondary monitor to the left of the primary and lower
+        let primary = screen(0, 0, 0, 4, 2);
+        let secondary = screen(1, -2, 1, 2, 2);
+        let captures = vec![
+            (primary.clone(), RgbaImage::from_pixel(4, 2, image::Rgba([255, 0, 0, 255]))),
+            (secondary.clone(), RgbaImage::from_pixel(2, 2, image::Rgba([0, 255, 0, 255]))),
+        ];
+
+        let stitched = stitch(&captures);
+        assert_eq!(stitched.dimensions(), (6, 3));
+        assert_eq!(stitched.get_pixel(2, 0)[0], 255);
+        assert_eq!(stitched.get_pixel(0, 2)[1], 255);
+        assert_eq!(stitched.get_pixel(0, 0).0, [0, 0, 0, 255]);
+
+        let mut layout = ScreenLayout {
+            screens: vec![primary, secondary],
+            selected: MonitorSelection::All,
+        };
+        assert_eq!(layout.to_desktop(0, 0), (-2, 0));
+
+        layout.selected = MonitorSelection::Single(1);
+        assert_eq!(layout.to_desktop(1, 1), (-1, 2));
+
+        assert_eq!("all".parse::<MonitorSelection>(), Ok(MonitorSelection::All));
+        assert_eq!("1".parse::<MonitorSelection>(), Ok(MonitorSelection::Single(1)));
+    }
+}
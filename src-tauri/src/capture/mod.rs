@@ -1,17 +1,21 @@
 pub mod screen_capture;
+pub mod layout;
+pub mod cursor;
 
 use anyhow::Result;
+use image::RgbaImage;
 use log::{debug, error, info, warn};
 use screenshots::Screen;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+pub use layout::{MonitorSelection, ScreenLayout};
 
 #[derive(Debug, Clone)]
 pub struct CaptureConfig {
     pub fps: u32,
     pub quality: u8,
-    pub monitor_index: usize,
+    pub monitor: MonitorSelection,
     pub capture_cursor: bool,
 }
 
@@ -20,7 +24,7 @@ impl Default for CaptureConfig {
         Self {
             fps: 30,
             quality: 80,
-            monitor_index: 0,
+            monitor: MonitorSelection::default(),
             capture_cursor: true,
         }
     }
@@ -54,17 +58,18 @@ impl ScreenCaptureManager {
     
     pub async fn capture_primary_screen(&self) -> Result<Vec<u8>> {
         let config = self.config.read().await;
-        let screen = &self.screens[config.monitor_index.min(self.screens.len() - 1)];
+        let monitor_index = self.single_monitor_index(config.monitor);
+        let screen = &self.screens[monitor_index];
         
         debug!("Capturing screen {} ({}x{})", 
-               config.monitor_index, 
+               monitor_index, 
                screen.display_info.width, 
                screen.display_info.height);
         
         // Try to capture actual screen, fall back to test image if it fails
         match screen.capture() {
             Ok(screenshot) => {
-                debug!("Successfully captured screenshot from screen {}", config.monitor_index);
+                debug!("Successfully captured screenshot from screen {}", monitor_index);
                 
                 // Try to get the screenshot as bytes/buffer
                 // Let's try different approaches to get the image data
@@ -206,20 +211,77 @@ impl ScreenCaptureManager {
         Ok(results)
     }
     
+    /// Capture the selected monitor, or all of them stitched together
+    pub async fn capture_selected(&self) -> Result<RgbaImage> {
+        let selection = self.config.read().await.monitor;
+        
+        match selection {
+            MonitorSelection::Single(_) => {
+                Self::capture_screen_image(&self.screens[self.single_monitor_index(selection)])
+            }
+            MonitorSelection::All => {
+                let mut captures = Vec::with_capacity(self.screens.len());
+                for (info, screen) in self.get_screen_info().await.into_iter().zip(&self.screens) {
+                    captures.push((info, Self::capture_screen_image(screen)?));
+                }
+                Ok(layout::stitch(&captures))
+            }
+        }
+    }
+    
+    fn capture_screen_image(screen: &Screen) -> Result<RgbaImage> {
+        let capture = screen.capture()?;
+        Ok(image::load_from_memory(capture.buffer())?.to_rgba8())
+    }
+    
+    fn single_monitor_index(&self, selection: MonitorSelection) -> usize {
+        match selection {
+            MonitorSelection::Single(index) => index.min(self.screens.len() - 1),
+            MonitorSelection::All => self.screens.iter()
+                .position(|screen| screen.display_info.is_primary)
+                .unwrap_or(0),
+        }
+    }
+    
+    pub async fn select_monitor(&self, selection: MonitorSelection) -> Result<()> {
+        if let MonitorSelection::Single(index) = selection {
+            if index >= self.screens.len() {
+                return Err(anyhow::anyhow!("No monitor {} (host has {})", index, self.screens.len()));
+            }
+        }
+        
+        self.config.write().await.monitor = selection;
+        info!("Capturing {:?}", selection);
+        Ok(())
+    }
+    
     pub async fn get_screen_info(&self) -> Vec<ScreenInfo> {
         self.screens
             .iter()
             .enumerate()
             .map(|(i, screen)| ScreenInfo {
                 index: i,
+                x: screen.display_info.x,
+                y: screen.display_info.y,
                 width: screen.display_info.width as u32,
                 height: screen.display_info.height as u32,
                 scale_factor: screen.display_info.scale_factor as f64,
-                is_primary: i == 0, // Assume first screen is primary
+                is_primary: screen.display_info.is_primary,
             })
             .collect()
     }
     
+    pub async fn get_config(&self) -> CaptureConfig {
+        self.config.read().await.clone()
+    }
+    
+    pub async fn get_layout(&self) -> ScreenLayout {
+        ScreenLayout {
+            screens: self.get_screen_info().await,
+            selected: self.config.read().await.monitor,
+        }
+    }
+    
     pub async fn update_config(&self, new_config: CaptureConfig) -> Result<()> {
         let mut config = self.config.write().await;
         *config = new_config;
@@ -239,6 +301,7 @@ impl ScreenCaptureManager {
         
         let config = self.config.clone();
         let screens = self.screens.clone();
+        let primary_index = self.single_monitor_index(MonitorSelection::All);
         let is_capturing_clone = self.is_capturing.clone();
         
         tokio::spawn(async move {
@@ -247,7 +310,10 @@ impl ScreenCaptureManager {
             while *is_capturing_clone.read().await {
                 let config_read = config.read().await;
                 let fps = config_read.fps;
-                let monitor_index = config_read.monitor_index.min(screens.len() - 1);
+                let monitor_index = match config_read.monitor {
+                    MonitorSelection::Single(index) => index.min(screens.len() - 1),
+                    MonitorSelection::All => primary_index,
+                };
                 drop(config_read);
                 
                 let frame_duration = std::time::Duration::from_millis(1000 / fps as u64);
@@ -295,9 +361,12 @@ impl ScreenCaptureManager {
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ScreenInfo {
     pub index: usize,
+    /// Position on the virtual desktop
+    pub x: i32,
+    pub y: i32,
     pub width: u32,
     pub height: u32,
     pub scale_factor: f64,
@@ -3,6 +3,8 @@ use image::RgbaImage;
 use log::{debug, warn};
 use std::time::{Duration, Instant};
 
+use crate::codec::damage::DamageTracker;
+
 #[derive(Debug, Clone)]
 pub struct CaptureRegion {
     pub x: i32,
@@ -20,7 +22,7 @@ pub struct CaptureStats {
 }
 
 pub struct AdvancedScreenCapture {
-    last_frame: Option<RgbaImage>,
+    damage: DamageTracker,
     capture_stats: CaptureStats,
     last_capture_time: Instant,
 }
@@ -28,7 +30,7 @@ pub struct AdvancedScreenCapture {
 impl AdvancedScreenCapture {
     pub fn new() -> Self {
         Self {
-            last_frame: None,
+            damage: DamageTracker::new(),
             capture_stats: CaptureStats {
                 frames_captured: 0,
                 bytes_captured: 0,
@@ -84,77 +86,26 @@ impl AdvancedScreenCapture {
     }
     
     /// Detect changes between current and previous frame
-    pub fn detect_changes(&mut self, current_frame: &RgbaImage, threshold: u8) -> Vec<CaptureRegion> {
-        let mut changed_regions = Vec::new();
+    pub fn detect_changes(&mut self, current_frame: &RgbaImage) -> Vec<CaptureRegion> {
+        let had_reference = self.damage.has_reference();
+        let damage = self.damage.update(current_frame);
         
-        if let Some(ref last_frame) = self.last_frame {
-            if last_frame.dimensions() != current_frame.dimensions() {
-                warn!("Frame dimensions changed, capturing full screen");
-                let (width, height) = current_frame.dimensions();
-                changed_regions.push(CaptureRegion {
-                    x: 0,
-                    y: 0,
-                    width,
-                    height,
-                });
-            } else {
-                // Simple change detection by comparing pixels
-                // In a real implementation, you'd use more sophisticated algorithms
-                let (width, height) = current_frame.dimensions();
-                let block_size = 64u32; // 64x64 pixel blocks
-                
-                for y in (0..height).step_by(block_size as usize) {
-                    for x in (0..width).step_by(block_size as usize) {
-                        let block_width = block_size.min(width - x);
-                        let block_height = block_size.min(height - y);
-                        
-                        if self.block_changed(last_frame, current_frame, x, y, block_width, block_height, threshold) {
-                            changed_regions.push(CaptureRegion {
-                                x: x as i32,
-                                y: y as i32,
-                                width: block_width,
-                                height: block_height,
-                            });
-                        }
-                    }
-                }
-            }
+        // The first frame has nothing to be compared with
+        if damage.full && !had_reference {
+            return Vec::new();
         }
-        
-        // Store current frame for next comparison
-        self.last_frame = Some(current_frame.clone());
-        
-        changed_regions
-    }
-    
-    fn block_changed(&self, old_frame: &RgbaImage, new_frame: &RgbaImage, 
-                    x: u32, y: u32, width: u32, height: u32, threshold: u8) -> bool {
-        let mut diff_pixels = 0;
-        let total_pixels = width * height;
-        
-        for dy in 0..height {
-            for dx in 0..width {
-                let px = x + dx;
-                let py = y + dy;
-                
-                if px < old_frame.width() && py < old_frame.height() {
-                    let old_pixel = old_frame.get_pixel(px, py);
-                    let new_pixel = new_frame.get_pixel(px, py);
-                    
-                    // Simple RGB difference
-                    let diff = ((old_pixel[0] as i16 - new_pixel[0] as i16).abs() +
-                               (old_pixel[1] as i16 - new_pixel[1] as i16).abs() +
-                               (old_pixel[2] as i16 - new_pixel[2] as i16).abs()) as u8;
-                    
-                    if diff > threshold {
-                        diff_pixels += 1;
-                    }
-                }
-            }
+        if damage.full {
+            warn!("Frame dimensions changed, capturing full screen");
         }
         
-        // Consider block changed if more than 5% of pixels changed
-        (diff_pixels * 100 / total_pixels) > 5
+        damage.regions().into_iter()
+            .map(|region| CaptureRegion {
+                x: region.x as i32,
+                y: region.y as i32,
+                width: region.width,
+                height: region.height,
+            })
+            .collect()
     }
     
     fn update_stats(&mut self, bytes_captured: usize, start_time: Instant) {
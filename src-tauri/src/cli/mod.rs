@@ -0,0 +1,34 @@
+//! Command line entry points; without any of them the desktop app starts.
+
+use clap::{Parser, Subcommand};
+
+pub mod script;
+pub mod viewer;
+
+#[derive(Parser, Debug)]
+#[command(name = "anyviewer", version, about = "Remote desktop access")]
+pub struct Cli {
+    /// Host without a window, answering requests from the approval policy
+    #[arg(long)]
+    pub headless: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Connect to a host, save frames and replay scripted input
+    View(viewer::ViewArgs),
+}
+
+impl Cli {
+    /// Parse the command line, or `None` to start the desktop app
+    pub fn from_env() -> Option<Self> {
+        // Leave unknown arguments alone, the OS and Tauri pass their own when launching the app
+        match std::env::args().nth(1).as_deref() {
+            Some("view" | "--headless" | "-h" | "--help" | "-V" | "--version") => Some(Self::parse()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Input scripts replayed by `anyviewer view --script`.
+//!
+//! One step per line. A `#` at the start of a line, or after whitespace, starts a
+//! comment; `type` sends the rest of its line as written, `#` included:
+//!
+//! ```text
+//! move 640 400
+//! click 640 400 left
+//! release 640 400 left
+//! scroll down 3
+//! key Return ctrl+shift
+//! type hello world
+//! type issue #42
+//! wait 500   # let the page settle
+//! monitor all
+//! screenshot after-login.png
+//! ```
+
+use anyhow::Result;
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+
+use crate::capture::MonitorSelection;
+use crate::network::protocol::{InputEvent, InputEventType, KeyModifier, MouseButton};
+
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    Input(InputEvent),
+    /// Pause for this many milliseconds
+    Wait(u64),
+    /// Save the next frame to this path
+    Screenshot(PathBuf),
+    /// Stream another monitor; later coordinates are relative to it
+    Monitor(MonitorSelection),
+}
+
+pub fn load_script(path: &Path) -> Result<Vec<ScriptStep>> {
+    parse_script(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse_script(source: &str) -> Result<Vec<ScriptStep>> {
+    source.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = strip_comment(line);
+            if line.is_empty() {
+                None
+            } else {
+                Some(parse_step(line).map_err(|e| anyhow::anyhow!("Line {}: {}", index + 1, e)))
+            }
+        })
+        .collect()
+}
+
+fn strip_comment(line: &str) -> &str {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return "";
+    }
+    if line.split_whitespace().next() == Some("type") {
+        return line;
+    }
+
+    line.char_indices()
+        .find(|&(index, c)| c == '#' && line[..index].ends_with(char::is_whitespace))
+        .map_or(line, |(index, _)| line[..index].trim_end())
+}
+
+fn parse_step(line: &str) -> Result<ScriptStep> {
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args: Vec<&str> = rest.split_whitespace().collect();
+
+    let step = match command {
+        "move" => ScriptStep::Input(mouse_event(InputEventType::MouseMove, &args, false)?),
+        "click" => ScriptStep::Input(mouse_event(InputEventType::MouseClick, &args, true)?),
+        "release" => ScriptStep::Input(mouse_event(InputEventType::MouseRelease, &args, true)?),
+        "scroll" => {
+            let direction = match args.first() {
+                Some(&"up") => "up",
+                Some(&"down") => "down",
+                _ => return Err(anyhow::anyhow!("scroll needs a direction, up or down")),
+            };
+            let amount = args.get(1).map(|a| a.parse::<u32>()).transpose()?.unwrap_or(1);
+            ScriptStep::Input(key_event(InputEventType::MouseScroll, format!("{}{}", direction, amount), None))
+        }
+        "key" => {
+            let key = args.first().ok_or_else(|| anyhow::anyhow!("key needs a key name"))?;
+            let modifiers = args.get(1).map(|m| parse_modifiers(m)).transpose()?;
+            ScriptStep::Input(key_event(InputEventType::KeyPress, key.to_string(), modifiers))
+        }
+        "type" => ScriptStep::Input(key_event(InputEventType::KeyType, rest.to_string(), None)),
+        "wait" => ScriptStep::Wait(
+            args.first().ok_or_else(|| anyhow::anyhow!("wait needs milliseconds"))?.parse()?,
+        ),
+        "screenshot" => ScriptStep::Screenshot(PathBuf::from(
+            args.first().ok_or_else(|| anyhow::anyhow!("screenshot needs a file name"))?,
+        )),
+        "monitor" => ScriptStep::Monitor(
+            args.first().ok_or_else(|| anyhow::anyhow!("monitor needs an index or all"))?
+                .parse()
+                .map_err(|e: String| anyhow::anyhow!(e))?,
+        ),
+        other => return Err(anyhow::anyhow!("Unknown command: {}", other)),
+    };
+
+    Ok(step)
+}
+
+fn mouse_event(event_type: InputEventType, args: &[&str], with_button: bool) -> Result<InputEvent> {
+    let (x, y) = match args {
+        [x, y, ..] => (x.parse()?, y.parse()?),
+        _ => return Err(anyhow::anyhow!("expected x and y coordinates")),
+    };
+
+    let button = if with_button {
+        Some(match args.get(2).copied().unwrap_or("left") {
+            "left" => MouseButton::Left,
+            "right" => MouseButton::Right,
+            "middle" => MouseButton::Middle,
+            other => return Err(anyhow::anyhow!("Unknown mouse button: {}", other)),
+        })
+    } else {
+        None
+    };
+
+    Ok(InputEvent {
+        event_type,
+        x: Some(x),
+        y: Some(y),
+        button,
+        key: None,
+        modifiers: None,
+        timestamp: Utc::now(),
+    })
+}
+
+fn key_event(event_type: InputEventType, key: String, modifiers: Option<Vec<KeyModifier>>) -> InputEvent {
+    InputEvent {
+        event_type,
+        x: None,
+        y: None,
+        button: None,
+        key: Some(key),
+        modifiers,
+        timestamp: Utc::now(),
+    }
+}
+
+fn parse_modifiers(modifiers: &str) -> Result<Vec<KeyModifier>> {
+    modifiers.split('+')
+        .map(|modifier| match modifier {
+            "ctrl" => Ok(KeyModifier::Ctrl),
+            "alt" => Ok(KeyModifier::Alt),
+            "shift" => Ok(KeyModifier::Shift),
+            "meta" => Ok(KeyModifier::Meta),
+            "super" => Ok(KeyModifier::Super),
+            other => Err(anyhow::anyhow!("Unknown modifier: {}", other)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let steps = parse_script(
+            "# log in\nclick 10 20 right\nkey Return ctrl+shift\ntype hello world\n\nwait 250\nmonitor all\nscreenshot out.png\n",
+        ).unwrap();
+        assert_eq!(steps.len(), 6);
+
+        match &steps[0] {
+            ScriptStep::Input(event) => {
+                assert!(matches!(event.event_type, InputEventType::MouseClick));
+                assert_eq!((event.x, event.y), (Some(10), Some(20)));
+                assert!(matches!(event.button, Some(MouseButton::Right)));
+            }
+            other => panic!("Unexpected step: {:?}", other),
+        }
+        match &steps[2] {
+            ScriptStep::Input(event) => assert_eq!(event.key.as_deref(), Some("hello world")),
+            other => panic!("Unexpected step: {:?}", other),
+        }
+        assert!(matches!(steps[3], ScriptStep::Wait(250)));
+        assert!(matches!(steps[4], ScriptStep::Monitor(MonitorSelection::All)));
+
+        let error = parse_script("move 10\n").unwrap_err();
+        assert!(error.to_string().starts_with("Line 1"));
+
+        // Only a `#` that starts a line or follows whitespace is a comment, and `type` keeps its text
+        let steps = parse_script("  # indented\ntype issue #42\nwait 250 # settle\nkey a#b\n").unwrap();
+        assert_eq!(steps.len(), 3);
+        match &steps[0] {
+            ScriptStep::Input(event) => assert_eq!(event.key.as_deref(), Some("issue #42")),
+            other => panic!("Unexpected step: {:?}", other),
+        }
+        assert!(matches!(steps[1], ScriptStep::Wait(250)));
+        match &steps[2] {
+            ScriptStep::Input(event) => assert_eq!(event.key.as_deref(), Some("a#b")),
+            other => panic!("Unexpected step: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,409 @@
+//! `anyviewer view`: drive a remote session from the command line.
+
+use anyhow::Result;
+use clap::Args;
+use image::{DynamicImage, RgbaImage};
+use log::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::script::{load_script, ScriptStep};
+use crate::capture::cursor::CursorCache;
+use crate::capture::{MonitorSelection, ScreenLayout};
+use crate::network::client::{ClientConfig, ClientEvent, RemoteDesktopClient};
+use crate::streaming::Viewport;
+use crate::network::discovery::{DiscoveredDevice, NetworkDiscovery};
+use crate::utils::file_transfer::{FileTransferManager, TransferEvent};
+
+const DEFAULT_HOST_PORT: u16 = 7878;
+
+#[derive(Args, Debug)]
+pub struct ViewArgs {
+    /// Host address (IP or IP:port), ws:// URL, or the device name or ID announced on the LAN
+    pub target: String,
+
+    /// Session PIN shown on the host. Arguments show up in `ps`, so prefer ANYVIEWER_PIN
+    #[arg(long, env = "ANYVIEWER_PIN", hide_env_values = true)]
+    pub pin: Option<String>,
+
+    #[arg(long)]
+    pub username: Option<String>,
+
+    /// Unattended access password; prefer ANYVIEWER_PASSWORD or --password-stdin
+    #[arg(long, env = "ANYVIEWER_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// Read the password from the first line of standard input
+    #[arg(long)]
+    pub password_stdin: bool,
+
+    /// Signed access token; prefer ANYVIEWER_TOKEN
+    #[arg(long, env = "ANYVIEWER_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Save one frame to this file
+    #[arg(long)]
+    pub screenshot: Option<PathBuf>,
+
+    /// Save a sequence of frames into this directory
+    #[arg(long)]
+    pub frames: Option<PathBuf>,
+
+    /// Number of frames to save with --frames
+    #[arg(long, default_value_t = 10)]
+    pub count: u32,
+
+    /// Milliseconds between frames with --frames
+    #[arg(long, default_value_t = 1000)]
+    pub interval_ms: u64,
+
+    /// Monitor to stream: an index from --list-monitors, or all for the whole desktop
+    #[arg(long)]
+    pub monitor: Option<MonitorSelection>,
+
+    /// Have the host shrink frames to fit this window, e.g. 1280x720
+    #[arg(long)]
+    pub viewport: Option<Viewport>,
+
+    /// Print the host's monitors
+    #[arg(long)]
+    pub list_monitors: bool,
+
+    /// Replay input from this script file before capturing
+    #[arg(long)]
+    pub script: Option<PathBuf>,
+
+    /// Send this file, or this directory and everything in it, to the host's download directory
+    #[arg(long)]
+    pub send_file: Option<PathBuf>,
+
+    /// Seconds to wait for the host, its answers and each frame
+    #[arg(long, default_value_t = 15)]
+    pub timeout: u64,
+}
+
+pub async fn run(args: ViewArgs) -> Result<()> {
+    // Parse the script up front so a typo fails before touching the host
+    let script = match args.script {
+        Some(ref path) => load_script(path)?,
+        None => Vec::new(),
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    let password = match args.password_stdin {
+        true => Some(read_password_stdin()?),
+        false => args.password.clone(),
+    };
+
+    let server_url = resolve_target(&args.target, timeout).await?;
+    info!("Connecting to {}", server_url);
+
+    let mut client = RemoteDesktopClient::new(ClientConfig {
+        server_url,
+        auth_token: args.token.clone(),
+        username: args.username.clone(),
+        password,
+        session_pin: args.pin.clone(),
+        auto_reconnect: false,
+        ..ClientConfig::default()
+    });
+    let (file_transfers, mut transfer_events) = FileTransferManager::new();
+    client.set_file_transfer_manager(Arc::new(file_transfers));
+    let mut events = client.connect().await?;
+    wait_for_authentication(&mut events, timeout).await?;
+    info!("Authenticated with {}", args.target);
+
+    let result = run_session(&client, &mut events, &mut transfer_events, &args, &script, timeout).await;
+    client.disconnect().await?;
+    result
+}
+
+async fn run_session(
+    client: &RemoteDesktopClient,
+    events: &mut mpsc::UnboundedReceiver<ClientEvent>,
+    transfer_events: &mut mpsc::UnboundedReceiver<TransferEvent>,
+    args: &ViewArgs,
+    script: &[ScriptStep],
+    timeout: Duration,
+) -> Result<()> {
+    // The host sends its pointer apart from the frames; it is drawn into every picture saved
+    let mut cursor = CursorCache::new();
+
+    if args.list_monitors {
+        client.request_screen_info().await?;
+        for screen in next_layout(events, timeout).await?.screens {
+            println!(
+                "{}: {}x{} at ({}, {}){}",
+                screen.index,
+                screen.width,
+                screen.height,
+                screen.x,
+                screen.y,
+                if screen.is_primary { " primary" } else { "" }
+            );
+        }
+    }
+
+    if let Some(selection) = args.monitor {
+        select_monitor(client, events, selection, timeout).await?;
+    }
+
+    if args.viewport.is_some() {
+        client.set_viewport(args.viewport).await?;
+    }
+
+    for step in script {
+        match step {
+            ScriptStep::Input(event) => client.send_input_event(event.clone()).await?,
+            ScriptStep::Wait(millis) => tokio::time::sleep(Duration::from_millis(*millis)).await,
+            ScriptStep::Screenshot(path) => save_frame(client, events, &mut cursor, path, timeout).await?,
+            ScriptStep::Monitor(selection) => select_monitor(client, events, *selection, timeout).await?,
+        }
+    }
+
+    if let Some(ref path) = args.send_file {
+        send_file(client, transfer_events, path, timeout).await?;
+    }
+
+    if let Some(ref path) = args.screenshot {
+        save_frame(client, events, &mut cursor, path, timeout).await?;
+    }
+
+    if let Some(ref directory) = args.frames {
+        std::fs::create_dir_all(directory)?;
+
+        for index in 1..=args.count {
+            let frame = next_frame(client, events, &mut cursor, timeout).await?;
+            let path = directory.join(format!("frame_{:05}.png", index));
+            write_image(&frame, &path)?;
+            info!("Saved {}", path.display());
+
+            if index < args.count {
+                tokio::time::sleep(Duration::from_millis(args.interval_ms)).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The first line of standard input, for `--password-stdin`
+fn read_password_stdin() -> Result<String> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(anyhow::anyhow!("--password-stdin given but standard input had no password"));
+    }
+    Ok(password.to_string())
+}
+
+/// Turn the target argument into a server URL, looking it up on the LAN if it isn't an address
+async fn resolve_target(target: &str, timeout: Duration) -> Result<String> {
+    if target.starts_with("ws://") || target.starts_with("wss://") {
+        return Ok(target.to_string());
+    }
+
+    if let Ok(ip) = target.parse::<std::net::IpAddr>() {
+        return Ok(format!("ws://{}", std::net::SocketAddr::new(ip, DEFAULT_HOST_PORT)));
+    }
+
+    if target.parse::<std::net::SocketAddr>().is_ok() {
+        return Ok(format!("ws://{}", target));
+    }
+
+    // Connection IDs are only reachable through the relay, which this viewer doesn't use
+    let digits = target.replace([' ', '-'], "");
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow::anyhow!(
+            "{} looks like a connection ID, which needs the relay; give the host's address or LAN device name instead",
+            target
+        ));
+    }
+
+    let device = discover_device(target, timeout).await?;
+    Ok(format!("ws://{}:{}", device.info.ip_address, device.info.server_port))
+}
+
+async fn discover_device(target: &str, timeout: Duration) -> Result<DiscoveredDevice> {
+    info!("Looking for {} on the local network", target);
+
+    let (device_updates_tx, _device_updates_rx) = mpsc::unbounded_channel();
+    let discovery = NetworkDiscovery::new("anyviewer-cli".to_string(), 0, device_updates_tx);
+    discovery.start().await?;
+
+    let found = tokio::time::timeout(timeout, async {
+        loop {
+            let device = discovery.get_discovered_devices().await.into_iter().find(|device| {
+                device.info.device_id == target || device.info.device_name.eq_ignore_ascii_case(target)
+            });
+            if let Some(device) = device {
+                return device;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }).await;
+
+    discovery.stop().await?;
+    found.map_err(|_| anyhow::anyhow!("No host called {} answered on the local network", target))
+}
+
+async fn wait_for_authentication(events: &mut mpsc::UnboundedReceiver<ClientEvent>, timeout: Duration) -> Result<()> {
+    tokio::time::timeout(timeout, async {
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::AuthenticationSuccess => return Ok(()),
+                ClientEvent::EncryptionEstablished { fingerprint, first_use } => {
+                    if first_use {
+                        warn!("First connection to this host, pinned its identity {}", fingerprint);
+                    } else {
+                        info!("Host identity verified: {}", fingerprint);
+                    }
+                }
+                ClientEvent::HostKeyMismatch { expected, presented } => {
+                    return Err(anyhow::anyhow!(
+                        "Host identity changed (expected {}, got {}); forget the host to accept the new key",
+                        expected, presented
+                    ));
+                }
+                ClientEvent::AuthenticationFailed(reason) => {
+                    return Err(anyhow::anyhow!("Authentication failed: {}", reason));
+                }
+                ClientEvent::NegotiationFailed(reason) => {
+                    return Err(anyhow::anyhow!("Host refused the session: {}", reason));
+                }
+                ClientEvent::Disconnected => return Err(anyhow::anyhow!("Host closed the connection")),
+                ClientEvent::Error(error) => warn!("{}", error),
+                _ => {}
+            }
+        }
+        Err(anyhow::anyhow!("Connection closed"))
+    }).await.map_err(|_| anyhow::anyhow!("Timed out waiting for the host to accept"))?
+}
+
+async fn select_monitor(
+    client: &RemoteDesktopClient,
+    events: &mut mpsc::UnboundedReceiver<ClientEvent>,
+    selection: MonitorSelection,
+    timeout: Duration,
+) -> Result<()> {
+    client.select_monitor(selection).await?;
+    let layout = next_layout(events, timeout).await?;
+
+    let bounds = layout.bounds();
+    info!("Streaming {:?}, {}x{}", layout.selected, bounds.width, bounds.height);
+    Ok(())
+}
+
+async fn next_layout(events: &mut mpsc::UnboundedReceiver<ClientEvent>, timeout: Duration) -> Result<ScreenLayout> {
+    tokio::time::timeout(timeout, async {
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::ScreenLayoutReceived(layout) => return Ok(layout),
+                ClientEvent::Error(error) => return Err(anyhow::anyhow!("Monitor request failed: {}", error)),
+                ClientEvent::Disconnected => return Err(anyhow::anyhow!("Host closed the connection")),
+                _ => {}
+            }
+        }
+        Err(anyhow::anyhow!("Connection closed"))
+    }).await.map_err(|_| anyhow::anyhow!("Timed out waiting for the monitor layout"))?
+}
+
+/// Request a frame and return the whole decoded picture, with the host's pointer drawn in
+async fn next_frame(
+    client: &RemoteDesktopClient,
+    events: &mut mpsc::UnboundedReceiver<ClientEvent>,
+    cursor: &mut CursorCache,
+    timeout: Duration,
+) -> Result<RgbaImage> {
+    tokio::time::timeout(timeout, async {
+        client.request_screen_frame().await?;
+        
+        while let Some(event) = events.recv().await {
+            match event {
+                ClientEvent::FrameDecoded(frame) => {
+                    let mut image = frame.image;
+                    if let Err(e) = cursor.draw(&mut image) {
+                        warn!("Could not draw the host's pointer: {}", e);
+                    }
+                    return Ok(image);
+                }
+                ClientEvent::CursorShapeReceived(shape) => cursor.insert_shape(shape),
+                ClientEvent::CursorMoved(position) => cursor.set_position(position),
+                ClientEvent::FrameDropped(sequence_number) => {
+                    // The client has asked for a keyframe if the stream broke; the next frame will be one
+                    warn!("Frame #{} could not be applied, requesting another", sequence_number);
+                    client.request_screen_frame().await?;
+                }
+                ClientEvent::Disconnected => return Err(anyhow::anyhow!("Host closed the connection")),
+                _ => {}
+            }
+        }
+        Err(anyhow::anyhow!("Connection closed"))
+    }).await.map_err(|_| anyhow::anyhow!("Timed out waiting for a frame"))?
+}
+
+async fn save_frame(
+    client: &RemoteDesktopClient,
+    events: &mut mpsc::UnboundedReceiver<ClientEvent>,
+    cursor: &mut CursorCache,
+    path: &Path,
+    timeout: Duration,
+) -> Result<()> {
+    let frame = next_frame(client, events, cursor, timeout).await?;
+    write_image(&frame, path)?;
+    info!("Saved {}", path.display());
+    Ok(())
+}
+
+/// Send a file or directory and wait until the host has it; the timeout applies between progress updates
+async fn send_file(
+    client: &RemoteDesktopClient,
+    transfer_events: &mut mpsc::UnboundedReceiver<TransferEvent>,
+    path: &Path,
+    timeout: Duration,
+) -> Result<()> {
+    let transfer_id = if path.is_dir() {
+        client.send_directory(path).await?
+    } else {
+        client.send_file(path).await?
+    };
+
+    loop {
+        let event = tokio::time::timeout(timeout, transfer_events.recv()).await
+            .map_err(|_| anyhow::anyhow!("Timed out sending {}", path.display()))?
+            .ok_or_else(|| anyhow::anyhow!("File transfer stopped"))?;
+
+        match event {
+            TransferEvent::ProgressUpdate(progress) if progress.transfer_id == transfer_id => {
+                debug!("Sent {}/{} bytes", progress.bytes_transferred, progress.total_bytes);
+            }
+            TransferEvent::TransferCompleted(id) if id == transfer_id => {
+                info!("Sent {}", path.display());
+                return Ok(());
+            }
+            TransferEvent::TransferFailed(id, e) if id == transfer_id => {
+                return Err(anyhow::anyhow!("Sending {} failed: {}", path.display(), e));
+            }
+            TransferEvent::TransferCancelled(id) if id == transfer_id => {
+                return Err(anyhow::anyhow!("Sending {} was cancelled", path.display()));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Save in the format the file extension asks for, PNG when it names none
+fn write_image(image: &RgbaImage, path: &Path) -> Result<()> {
+    let format = image::ImageFormat::from_path(path).unwrap_or(image::ImageFormat::Png);
+    
+    // JPEG has no alpha channel
+    match format {
+        image::ImageFormat::Jpeg => DynamicImage::ImageRgba8(image.clone()).to_rgb8().save_with_format(path, format)?,
+        _ => image.save_with_format(path, format)?,
+    }
+    
+    Ok(())
+}
@@ -0,0 +1,450 @@
+//! Damage tracking by tile hashes.
+//!
+//! Instead of a copy of the previous frame, the tracker keeps an xxHash of
+//! every tile-wide segment of each of its rows: a few bytes per tile row, and
+//! one pass over the new pixels to compare. A tile is damaged when any of its
+//! segments hashes differently. When content moved, as it does when scrolling
+//! or dragging a window, damaged tiles found at one common offset in the new
+//! frame are reported as moved, so a viewer can copy them rather than receive
+//! them.
+
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::delta::{merge_regions, DiffRegion};
+
+/// Side of the square tiles frames are compared in
+pub const TILE_SIZE: u32 = 64;
+
+/// Damaged rows searched for moved content, spread over the damage
+const MOTION_SAMPLE_ROWS: usize = 32;
+
+/// Only every this many rows of the old damage are looked up; rows are sampled
+/// in runs this long, so whatever the offset, one of each run lines up
+const MOTION_ROW_STEP: u32 = 4;
+
+/// Matches that must agree on an offset before content counts as moved
+const MIN_MOTION_VOTES: usize = 4;
+
+/// Segment hashes are well mixed already, so maps keyed by them don't hash them again
+#[derive(Default)]
+struct SegmentHasher(u64);
+
+impl Hasher for SegmentHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
+type SegmentMap<V> = HashMap<u64, V, BuildHasherDefault<SegmentHasher>>;
+
+/// Rectangle whose content was at (`source_x`, `source_y`) in the previous frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MovedRegion {
+    pub region: DiffRegion,
+    pub source_x: u32,
+    pub source_y: u32,
+}
+
+/// What changed since the previous frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Damage {
+    /// Nothing to compare against: the first frame, or the size changed
+    pub full: bool,
+    /// Tiles with new content, merged into rectangles
+    pub dirty: Vec<DiffRegion>,
+    /// Tiles holding content from elsewhere in the previous frame, merged into rectangles
+    pub moved: Vec<MovedRegion>,
+}
+
+impl Damage {
+    fn full(width: u32, height: u32) -> Self {
+        Self {
+            full: true,
+            dirty: vec![DiffRegion { x: 0, y: 0, width, height }],
+            moved: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirty.is_empty() && self.moved.is_empty()
+    }
+
+    /// Every changed rectangle, for consumers that can't copy moved content
+    pub fn regions(&self) -> Vec<DiffRegion> {
+        self.dirty.iter()
+            .copied()
+            .chain(self.moved.iter().map(|moved| moved.region))
+            .collect()
+    }
+}
+
+pub struct DamageTracker {
+    tile_size: u32,
+    width: u32,
+    height: u32,
+    /// Tiles across a row
+    columns: usize,
+    /// Hash of each tile-wide segment of each row of the previous frame, row by row
+    segments: Vec<u64>,
+}
+
+impl DamageTracker {
+    pub fn new() -> Self {
+        Self::with_tile_size(TILE_SIZE)
+    }
+
+    pub fn with_tile_size(tile_size: u32) -> Self {
+        Self {
+            tile_size: tile_size.max(1),
+            width: 0,
+            height: 0,
+            columns: 0,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Whether there is a previous frame to compare against
+    pub fn has_reference(&self) -> bool {
+        !self.segments.is_empty()
+    }
+
+    /// Forget the previous frame, so the next one is damaged as a whole
+    pub fn reset(&mut self) {
+        self.segments.clear();
+    }
+
+    /// Compare `frame` with the previous one and remember it in its place
+    pub fn update(&mut self, frame: &RgbaImage) -> Damage {
+        let (width, height) = frame.dimensions();
+        let comparable = self.has_reference() && (width, height) == (self.width, self.height);
+
+        let previous = std::mem::replace(&mut self.segments, hash_segments(frame, self.tile_size));
+        self.width = width;
+        self.height = height;
+        self.columns = width.div_ceil(self.tile_size) as usize;
+
+        if !comparable {
+            return Damage::full(width, height);
+        }
+
+        self.compare(frame, &previous)
+    }
+
+    fn compare(&self, frame: &RgbaImage, previous: &[u64]) -> Damage {
+        let mut damaged = Vec::new();
+
+        for y in (0..self.height).step_by(self.tile_size as usize) {
+            for column in 0..self.columns {
+                let x = column as u32 * self.tile_size;
+                let tile = DiffRegion {
+                    x,
+                    y,
+                    width: self.tile_size.min(self.width - x),
+                    height: self.tile_size.min(self.height - y),
+                };
+
+                let changed = (tile.y..tile.y + tile.height)
+                    .any(|row| self.segments[self.index(row, column)] != previous[self.index(row, column)]);
+                if changed {
+                    damaged.push((column, tile));
+                }
+            }
+        }
+
+        let tiles = |tiles: Vec<(usize, DiffRegion)>| tiles.into_iter().map(|(_, tile)| tile).collect::<Vec<_>>();
+
+        let (dx, dy) = match self.find_motion(frame, previous, &damaged) {
+            Some(offset) => offset,
+            None => {
+                return Damage {
+                    full: false,
+                    dirty: merge_regions(&tiles(damaged)),
+                    moved: Vec::new(),
+                };
+            }
+        };
+
+        // Parts of the damaged tiles whose old content now sits (dx, dy) away
+        let sources: Vec<(usize, DiffRegion)> = damaged.iter()
+            .filter_map(|&(column, tile)| {
+                self.moved_part(frame, previous, column, &tile, dx, dy).map(|part| (column, part))
+            })
+            .collect();
+        let moved_parts: HashMap<(usize, u32), DiffRegion> = sources.iter()
+            .map(|&(column, part)| ((column, part.y - part.y % self.tile_size), part))
+            .collect();
+
+        let dirty = damaged.into_iter()
+            .filter(|(_, tile)| !self.covered(tile, dx, dy, &moved_parts))
+            .collect();
+
+        let moved = merge_regions(&tiles(sources)).into_iter()
+            .map(|source| MovedRegion {
+                region: DiffRegion {
+                    x: (source.x as i64 + dx) as u32,
+                    y: (source.y as i64 + dy) as u32,
+                    ..source
+                },
+                source_x: source.x,
+                source_y: source.y,
+            })
+            .collect();
+
+        Damage {
+            full: false,
+            dirty: merge_regions(&tiles(dirty)),
+            moved,
+        }
+    }
+
+    /// Most common offset damaged content moved by, if enough of it agrees
+    ///
+    /// Tile-wide windows of a sample of the damaged rows of `frame` are hashed
+    /// at every position and looked up among the segments the damaged tiles held
+    /// before, each match voting for the offset between the two.
+    fn find_motion(&self, frame: &RgbaImage, previous: &[u64], damaged: &[(usize, DiffRegion)]) -> Option<(i64, i64)> {
+        // Repeated content, like blank lines, can't tell where it came from
+        let mut origins: SegmentMap<Option<(u32, u32)>> = SegmentMap::default();
+        for &(column, tile) in damaged.iter().filter(|(_, tile)| tile.width == self.tile_size) {
+            for row in (tile.y..tile.y + tile.height).filter(|row| row % MOTION_ROW_STEP == 0) {
+                origins.entry(previous[self.index(row, column)])
+                    .and_modify(|origin| *origin = None)
+                    .or_insert(Some((tile.x, row)));
+            }
+        }
+
+        // Damaged tiles side by side on a row of tiles
+        let mut runs: Vec<DiffRegion> = Vec::new();
+        for &(_, tile) in damaged {
+            match runs.last_mut() {
+                Some(run) if run.y == tile.y && run.x + run.width == tile.x => run.width += tile.width,
+                _ => runs.push(tile),
+            }
+        }
+
+        let rows: Vec<(u32, &DiffRegion)> = runs.iter()
+            .filter(|run| run.width >= self.tile_size)
+            .flat_map(|run| (run.y..run.y + run.height).map(move |row| (row, run)))
+            .collect();
+        let runs = MOTION_SAMPLE_ROWS / MOTION_ROW_STEP as usize;
+        let sampled: Vec<(u32, &DiffRegion)> = if rows.len() <= MOTION_SAMPLE_ROWS {
+            rows
+        } else {
+            (0..runs)
+                .flat_map(|run| rows[run * rows.len() / runs..].iter().take(MOTION_ROW_STEP as usize).copied())
+                .collect()
+        };
+
+        let mut votes: HashMap<(i64, i64), usize> = HashMap::new();
+        for &(row, run) in &sampled {
+            for x in run.x..=run.x + run.width - self.tile_size {
+                if let Some(Some((old_x, old_y))) = origins.get(&hash_at(frame, x, row, self.tile_size)) {
+                    let offset = (x as i64 - *old_x as i64, row as i64 - *old_y as i64);
+                    if offset != (0, 0) {
+                        *votes.entry(offset).or_default() += 1;
+                    }
+                }
+            }
+        }
+
+        votes.into_iter()
+            .filter(|&(_, count)| count >= MIN_MOTION_VOTES)
+            .max_by_key(|&((dx, dy), count)| (count, Reverse(dx.abs() + dy.abs()), dx, dy))
+            .map(|(offset, _)| offset)
+    }
+
+    /// Rows of an old `tile` that are found (`dx`, `dy`) away in `frame`, if all of them are
+    fn moved_part(&self, frame: &RgbaImage, previous: &[u64], column: usize, tile: &DiffRegion, dx: i64, dy: i64) -> Option<DiffRegion> {
+        let x = tile.x as i64 + dx;
+        if x < 0 || x + tile.width as i64 > self.width as i64 {
+            return None;
+        }
+
+        // Rows pushed past the top or bottom edge are left out
+        let top = (tile.y as i64).max(-dy);
+        let bottom = ((tile.y + tile.height) as i64).min(self.height as i64 - dy);
+        if top >= bottom {
+            return None;
+        }
+
+        let found = (top..bottom).all(|row| {
+            previous[self.index(row as u32, column)] == hash_at(frame, x as u32, (row + dy) as u32, tile.width)
+        });
+
+        found.then(|| DiffRegion {
+            x: tile.x,
+            y: top as u32,
+            width: tile.width,
+            height: (bottom - top) as u32,
+        })
+    }
+
+    /// Whether copying the moved parts repaints all of `tile`
+    fn covered(&self, tile: &DiffRegion, dx: i64, dy: i64, moved_parts: &HashMap<(usize, u32), DiffRegion>) -> bool {
+        let (x, y) = (tile.x as i64 - dx, tile.y as i64 - dy);
+        let (width, height) = (tile.width as i64, tile.height as i64);
+        if x < 0 || y < 0 || x + width > self.width as i64 || y + height > self.height as i64 {
+            return false;
+        }
+
+        let size = self.tile_size as i64;
+        (y / size..=(y + height - 1) / size).all(|tile_row| {
+            (x / size..=(x + width - 1) / size).all(|column| {
+                let part = match moved_parts.get(&(column as usize, (tile_row * size) as u32)) {
+                    Some(part) => part,
+                    None => return false,
+                };
+
+                let top = y.max(tile_row * size);
+                let bottom = (y + height).min((tile_row + 1) * size);
+                top >= part.y as i64 && bottom <= (part.y + part.height) as i64
+            })
+        })
+    }
+
+    fn index(&self, row: u32, column: usize) -> usize {
+        row as usize * self.columns + column
+    }
+}
+
+impl Default for DamageTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn hash_segments(frame: &RgbaImage, tile_size: u32) -> Vec<u64> {
+    let stride = frame.width() as usize * 4;
+    if stride == 0 {
+        return Vec::new();
+    }
+
+    frame.as_raw()
+        .chunks_exact(stride)
+        .flat_map(|row| row.chunks(tile_size as usize * 4).map(xxh3_64))
+        .collect()
+}
+
+fn hash_at(frame: &RgbaImage, x: u32, y: u32, width: u32) -> u64 {
+    let start = (y as usize * frame.width() as usize + x as usize) * 4;
+    xxh3_64(&frame.as_raw()[start..start + width as usize * 4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::delta::crop;
+    use image::imageops;
+
+    #[test]
+    fn test_dirty_tiles() {
+        let mut tracker = DamageTracker::new();
+        let mut frame = RgbaImage::from_pixel(200, 100, image::Rgba([30, 30, 30, 255]));
+
+        let first = tracker.update(&frame);
+        assert!(first.full);
+        assert_eq!(first.dirty, vec![DiffRegion { x: 0, y: 0, width: 200, height: 100 }]);
+        assert!(tracker.update(&frame).is_empty());
+
+        // One pixel damages its tile, clipped at the frame's edge
+        frame.put_pixel(150, 70, image::Rgba([250, 250, 250, 255]));
+        let damage = tracker.update(&frame);
+        assert!(!damage.full && damage.moved.is_empty());
+        assert_eq!(damage.dirty, vec![DiffRegion { x: 128, y: 64, width: 64, height: 36 }]);
+
+        tracker.reset();
+        assert!(tracker.update(&frame).full);
+        assert!(tracker.update(&RgbaImage::new(100, 100)).full);
+    }
+
+    #[test]
+    fn test_scroll_becomes_moved_region() {
+        // Every row distinct, like lines of text
+        let old = RgbaImage::from_fn(64, 128, |x, y| image::Rgba([y as u8, (y * 7) as u8, x as u8, 255]));
+
+        // Scrolled 20 rows down the page, with new lines appearing at the bottom
+        let new = RgbaImage::from_fn(64, 128, |x, y| match y {
+            0..=107 => *old.get_pixel(x, y + 20),
+            _ => image::Rgba([200, y as u8, x as u8, 255]),
+        });
+
+        let mut tracker = DamageTracker::with_tile_size(16);
+        tracker.update(&old);
+        let damage = tracker.update(&new);
+
+        assert_eq!(damage.moved, vec![MovedRegion {
+            region: DiffRegion { x: 0, y: 0, width: 64, height: 108 },
+            source_x: 0,
+            source_y: 20,
+        }]);
+        assert_eq!(damage.dirty, vec![DiffRegion { x: 0, y: 96, width: 64, height: 32 }]);
+
+        assert_eq!(rebuild(&old, &new, &damage), new);
+    }
+
+    #[test]
+    fn test_window_move_becomes_moved_region() {
+        let background = image::Rgba([40, 40, 40, 255]);
+        let window = |x: u32, y: u32| image::Rgba([(x * 5) as u8, (y * 5) as u8, 200, 255]);
+        let draw = |left: u32, top: u32| RgbaImage::from_fn(160, 96, |x, y| {
+            match (x.checked_sub(left), y.checked_sub(top)) {
+                (Some(x), Some(y)) if x < 48 && y < 40 => window(x, y),
+                _ => background,
+            }
+        });
+
+        // Dragged 37 pixels right and 20 down
+        let (old, new) = (draw(10, 10), draw(47, 30));
+        let mut tracker = DamageTracker::with_tile_size(16);
+        tracker.update(&old);
+        let damage = tracker.update(&new);
+
+        assert!(!damage.moved.is_empty());
+        assert!(damage.moved.iter().all(|moved| {
+            (moved.region.x - moved.source_x, moved.region.y - moved.source_y) == (37, 20)
+        }));
+
+        // The window's inside is copied; its edges, which don't line up with tiles, and what it uncovered are sent
+        let sent = |x: u32, y: u32| damage.dirty.iter()
+            .any(|region| (region.x..region.x + region.width).contains(&x) && (region.y..region.y + region.height).contains(&y));
+        assert!(!sent(70, 40) && !sent(90, 60));
+        assert!(sent(20, 20));
+
+        let dirty: u64 = damage.dirty.iter().map(DiffRegion::area).sum();
+        let damaged: u64 = (0..96).step_by(16)
+            .flat_map(|y| (0..160).step_by(16).map(move |x| DiffRegion { x, y, width: 16, height: 16 }))
+            .filter(|tile| crop(&old, tile) != crop(&new, tile))
+            .map(|tile| tile.area())
+            .sum();
+        assert!(dirty < damaged);
+        assert_eq!(rebuild(&old, &new, &damage), new);
+    }
+
+    /// Copy moved content within the old frame, then patch the dirty tiles from the new one
+    fn rebuild(old: &RgbaImage, new: &RgbaImage, damage: &Damage) -> RgbaImage {
+        let mut viewer = old.clone();
+        for moved in &damage.moved {
+            let source = DiffRegion { x: moved.source_x, y: moved.source_y, ..moved.region };
+            imageops::replace(&mut viewer, &crop(old, &source), moved.region.x as i64, moved.region.y as i64);
+        }
+        for region in &damage.dirty {
+            imageops::replace(&mut viewer, &crop(new, region), region.x as i64, region.y as i64);
+        }
+        viewer
+    }
+}
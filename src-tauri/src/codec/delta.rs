@@ -0,0 +1,183 @@
+//! Region-only delta frames.
+//!
+//! A delta carries just the rectangles that changed since the reference frame,
+//! each encoded on its own, behind a small header:
+//!
+//! ```text
+//! bincode(DeltaHeader) | region 0 bytes | region 1 bytes | ...
+//! ```
+//!
+//! The header also lists content that moved, e.g. by scrolling or dragging a
+//! window, as rectangles the viewer copies within its reference before any
+//! region is patched in. Regions are in the format of the stream they belong
+//! to; keyframes are plain pictures and replace the reference outright. What
+//! changed or moved is worked out by `damage::DamageTracker`.
+
+use anyhow::Result;
+use image::{imageops, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+use super::damage::MovedRegion;
+
+/// Side of the tiles delta frames are cut into, small enough that a caret doesn't resend a large tile
+pub const BLOCK_SIZE: u32 = 16;
+
+/// Rectangle of a frame that changed since the reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DiffRegion {
+    pub fn area(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+
+    fn fits(&self, width: u32, height: u32) -> bool {
+        self.x as u64 + self.width as u64 <= width as u64 && self.y as u64 + self.height as u64 <= height as u64
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeltaHeader {
+    /// Size of the reference the regions are patched into
+    width: u32,
+    height: u32,
+    /// Copied from where they were in the reference, before the regions are patched in
+    copies: Vec<MovedRegion>,
+    /// Each region with the length of its encoded data
+    regions: Vec<(DiffRegion, u32)>,
+}
+
+/// Merge changed blocks, given in row-major order, into larger rectangles
+///
+/// Blocks touching on the same row become one run, then runs spanning the same
+/// columns on consecutive rows are stacked.
+pub fn merge_regions(blocks: &[DiffRegion]) -> Vec<DiffRegion> {
+    let mut runs: Vec<DiffRegion> = Vec::new();
+
+    for block in blocks {
+        match runs.last_mut() {
+            Some(run) if run.y == block.y && run.height == block.height && run.x + run.width == block.x => {
+                run.width += block.width;
+            }
+            _ => runs.push(*block),
+        }
+    }
+
+    let mut merged: Vec<DiffRegion> = Vec::new();
+
+    for run in runs {
+        let above = merged.iter_mut().find(|rect| {
+            rect.x == run.x && rect.width == run.width && rect.y + rect.height == run.y
+        });
+
+        match above {
+            Some(rect) => rect.height += run.height,
+            None => merged.push(run),
+        }
+    }
+
+    merged
+}
+
+pub fn crop(image: &RgbaImage, region: &DiffRegion) -> RgbaImage {
+    imageops::crop_imm(image, region.x, region.y, region.width, region.height).to_image()
+}
+
+/// Serialize moved content and already encoded regions into a delta payload
+pub fn write_delta(width: u32, height: u32, copies: &[MovedRegion], regions: &[(DiffRegion, Vec<u8>)]) -> Result<Vec<u8>> {
+    let header = DeltaHeader {
+        width,
+        height,
+        copies: copies.to_vec(),
+        regions: regions.iter().map(|(region, data)| (*region, data.len() as u32)).collect(),
+    };
+
+    let mut payload = bincode::serialize(&header)?;
+    for (_, data) in regions {
+        payload.extend_from_slice(data);
+    }
+
+    Ok(payload)
+}
+
+/// Apply a delta payload's copies to `reference`, then patch it with each region decoded by `decode_region`
+pub fn apply_delta<F>(reference: &mut RgbaImage, data: &[u8], mut decode_region: F) -> Result<()>
+where
+    F: FnMut(&[u8], &DiffRegion) -> Result<RgbaImage>,
+{
+    let mut cursor = Cursor::new(data);
+    let header: DeltaHeader = bincode::deserialize_from(&mut cursor)?;
+
+    if (header.width, header.height) != reference.dimensions() {
+        return Err(anyhow::anyhow!(
+            "Delta frame is for a {}x{} picture, the reference is {}x{}",
+            header.width, header.height, reference.width(), reference.height()
+        ));
+    }
+
+    // Every copy reads the reference as it was, even where another copy lands
+    let copied = header.copies.iter()
+        .map(|copy| {
+            let source = DiffRegion { x: copy.source_x, y: copy.source_y, ..copy.region };
+            if !source.fits(header.width, header.height) || !copy.region.fits(header.width, header.height) {
+                return Err(anyhow::anyhow!("Delta copy {:?} lies outside the frame", copy));
+            }
+            Ok((copy.region, crop(reference, &source)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (region, pixels) in copied {
+        imageops::replace(reference, &pixels, region.x as i64, region.y as i64);
+    }
+
+    let mut offset = cursor.position() as usize;
+
+    for (region, length) in header.regions {
+        let end = offset + length as usize;
+        let bytes = data.get(offset..end)
+            .ok_or_else(|| anyhow::anyhow!("Delta frame is truncated"))?;
+        offset = end;
+
+        if !region.fits(header.width, header.height) {
+            return Err(anyhow::anyhow!("Delta region {:?} lies outside the frame", region));
+        }
+
+        let patch = decode_region(bytes, &region)?;
+        if patch.dimensions() != (region.width, region.height) {
+            return Err(anyhow::anyhow!("Delta region {:?} decoded to {:?}", region, patch.dimensions()));
+        }
+
+        imageops::replace(reference, &patch, region.x as i64, region.y as i64);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(x: u32, y: u32) -> DiffRegion {
+        DiffRegion { x, y, width: 16, height: 16 }
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        // A 3x2 block area plus a lone block further right
+        let blocks = [block(0, 0), block(16, 0), block(32, 0), block(96, 0), block(0, 16), block(16, 16), block(32, 16)];
+
+        assert_eq!(
+            merge_regions(&blocks),
+            vec![
+                DiffRegion { x: 0, y: 0, width: 48, height: 32 },
+                DiffRegion { x: 96, y: 0, width: 16, height: 16 },
+            ]
+        );
+    }
+}
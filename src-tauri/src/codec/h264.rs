@@ -0,0 +1,319 @@
+//! Software H.264 through FFmpeg (libx264 when available), built with the `ffmpeg` feature.
+//!
+//! Frames are inter-coded: after a keyframe only changes are sent, so a decoder
+//! has to see every frame since the last keyframe. Keyframes are emitted every
+//! `keyframe_interval` frames and on demand via `request_keyframe`.
+
+use anyhow::Result;
+use ffmpeg_next as ffmpeg;
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling;
+use ffmpeg::{codec, decoder, encoder, frame, picture, Dictionary, Packet};
+use image::RgbaImage;
+use log::{debug, info};
+
+use super::{EncodedFrame, EncoderSettings, FrameDecoder, FrameEncoder, FrameFormat};
+
+pub struct H264Encoder {
+    settings: EncoderSettings,
+    /// Opened on the first frame and reopened whenever the frame size changes
+    stream: Option<Stream>,
+    force_keyframe: bool,
+}
+
+// SAFETY: the ffmpeg-next wrappers are only `!Send` because they hold raw pointers. Each
+// AVCodecContext and SwsContext here is owned by this value alone, and FFmpeg keeps no
+// per-thread state for them (libx264's worker threads belong to the context, not the caller),
+// so they can move to another thread. The type isn't `Sync` and every call goes through
+// `&mut self`, so they are never used from two threads at once.
+unsafe impl Send for H264Encoder {}
+
+impl H264Encoder {
+    pub fn new(settings: &EncoderSettings) -> Result<Self> {
+        ffmpeg::init()?;
+
+        Ok(Self {
+            settings: settings.clone(),
+            stream: None,
+            force_keyframe: false,
+        })
+    }
+}
+
+impl FrameEncoder for H264Encoder {
+    fn format(&self) -> FrameFormat {
+        FrameFormat::H264
+    }
+
+    fn encode(&mut self, image: &RgbaImage) -> Result<EncodedFrame> {
+        // A monitor switch changes the size, and the new stream starts with a keyframe
+        if self.stream.as_ref().map_or(true, |stream| stream.source_dimensions() != image.dimensions()) {
+            self.stream = Some(Stream::open(image.width(), image.height(), &self.settings)?);
+        }
+
+        let stream = self.stream.as_mut().unwrap();
+        let (data, is_keyframe) = stream.encode(image, std::mem::take(&mut self.force_keyframe))?;
+
+        Ok(EncodedFrame {
+            format: FrameFormat::H264,
+            width: stream.encoder.width(),
+            height: stream.encoder.height(),
+            data,
+            is_keyframe,
+            changed_regions: None,
+        })
+    }
+
+    fn request_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+}
+
+/// One encoder session at a fixed frame size
+struct Stream {
+    encoder: encoder::video::Encoder,
+    scaler: scaling::Context,
+    source_width: u32,
+    source_height: u32,
+    frame_index: i64,
+}
+
+impl Stream {
+    fn open(width: u32, height: u32, settings: &EncoderSettings) -> Result<Self> {
+        let codec = encoder::find_by_name("libx264")
+            .or_else(|| encoder::find(codec::Id::H264))
+            .ok_or_else(|| anyhow::anyhow!("This FFmpeg build has no H.264 encoder"))?;
+
+        // 4:2:0 chroma needs even dimensions; the odd row or column is scaled away
+        let (encoded_width, encoded_height) = (even(width), even(height));
+
+        let mut video = codec::context::Context::new().encoder().video()?;
+        video.set_width(encoded_width);
+        video.set_height(encoded_height);
+        video.set_format(Pixel::YUV420P);
+        video.set_time_base((1, settings.fps as i32));
+        video.set_frame_rate(Some((settings.fps as i32, 1)));
+        video.set_gop(settings.keyframe_interval);
+        // B-frames would hold frames back waiting for later ones
+        video.set_max_b_frames(0);
+        video.set_bit_rate(settings.bitrate_bps);
+
+        let mut options = Dictionary::new();
+        options.set("preset", "ultrafast");
+        options.set("tune", "zerolatency");
+        // Keyframes forced on request must be IDR so a fresh decoder can start on them
+        options.set("forced-idr", "1");
+
+        let encoder = video.open_as_with(codec, options)?;
+        let scaler = scaling::Context::get(
+            Pixel::RGBA,
+            width,
+            height,
+            Pixel::YUV420P,
+            encoded_width,
+            encoded_height,
+            scaling::Flags::BILINEAR,
+        )?;
+
+        info!(
+            "H.264 encoder ready: {}x{} at {} fps, {} kbps, keyframe every {} frames",
+            encoded_width, encoded_height, settings.fps, settings.bitrate_bps / 1000, settings.keyframe_interval
+        );
+
+        Ok(Self {
+            encoder,
+            scaler,
+            source_width: width,
+            source_height: height,
+            frame_index: 0,
+        })
+    }
+
+    /// Size of the images this stream accepts
+    fn source_dimensions(&self) -> (u32, u32) {
+        (self.source_width, self.source_height)
+    }
+
+    fn encode(&mut self, image: &RgbaImage, force_keyframe: bool) -> Result<(Vec<u8>, bool)> {
+        let mut rgba = frame::Video::new(Pixel::RGBA, self.source_width, self.source_height);
+        let stride = rgba.stride(0);
+        let row_bytes = self.source_width as usize * 4;
+        for (y, row) in image.as_raw().chunks_exact(row_bytes).enumerate() {
+            rgba.data_mut(0)[y * stride..y * stride + row_bytes].copy_from_slice(row);
+        }
+
+        let mut yuv = frame::Video::empty();
+        self.scaler.run(&rgba, &mut yuv)?;
+        yuv.set_pts(Some(self.frame_index));
+        self.frame_index += 1;
+
+        if force_keyframe {
+            debug!("Forcing keyframe at frame {}", self.frame_index);
+            yuv.set_kind(picture::Type::I);
+        }
+
+        self.encoder.send_frame(&yuv)?;
+
+        let mut data = Vec::new();
+        let mut is_keyframe = false;
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(bytes) = packet.data() {
+                data.extend_from_slice(bytes);
+            }
+            is_keyframe |= packet.is_key();
+        }
+
+        if data.is_empty() {
+            return Err(anyhow::anyhow!("H.264 encoder held the frame back"));
+        }
+
+        Ok((data, is_keyframe))
+    }
+}
+
+pub struct H264Decoder {
+    decoder: decoder::Video,
+    scaler: Option<scaling::Context>,
+}
+
+// SAFETY: as for `H264Encoder`; the decoder and scaler contexts are owned here alone and only
+// used through `&mut self`.
+unsafe impl Send for H264Decoder {}
+
+impl H264Decoder {
+    pub fn new() -> Result<Self> {
+        ffmpeg::init()?;
+
+        let codec = decoder::find(codec::Id::H264)
+            .ok_or_else(|| anyhow::anyhow!("This FFmpeg build has no H.264 decoder"))?;
+        let decoder = codec::context::Context::new().decoder().open_as(codec)?.video()?;
+
+        Ok(Self { decoder, scaler: None })
+    }
+
+    fn to_rgba(&mut self, picture: &frame::Video) -> Result<RgbaImage> {
+        let (width, height) = (picture.width(), picture.height());
+
+        let stale = self.scaler.as_ref().map_or(true, |scaler| {
+            let input = scaler.input();
+            (input.format, input.width, input.height) != (picture.format(), width, height)
+        });
+        if stale {
+            self.scaler = Some(scaling::Context::get(
+                picture.format(),
+                width,
+                height,
+                Pixel::RGBA,
+                width,
+                height,
+                scaling::Flags::BILINEAR,
+            )?);
+        }
+
+        let mut rgba = frame::Video::empty();
+        if let Some(ref mut scaler) = self.scaler {
+            scaler.run(picture, &mut rgba)?;
+        }
+
+        let stride = rgba.stride(0);
+        let row_bytes = width as usize * 4;
+        let mut pixels = Vec::with_capacity(row_bytes * height as usize);
+        for y in 0..height as usize {
+            pixels.extend_from_slice(&rgba.data(0)[y * stride..y * stride + row_bytes]);
+        }
+
+        RgbaImage::from_raw(width, height, pixels)
+            .ok_or_else(|| anyhow::anyhow!("H.264 decoder returned a truncated picture"))
+    }
+}
+
+impl FrameDecoder for H264Decoder {
+    fn format(&self) -> FrameFormat {
+        FrameFormat::H264
+    }
+
+    /// Fails until the decoder has seen a keyframe
+    fn decode(&mut self, data: &[u8], _is_keyframe: bool) -> Result<RgbaImage> {
+        self.decoder.send_packet(&Packet::copy(data))?;
+
+        let mut picture = frame::Video::empty();
+        let mut image = None;
+        while self.decoder.receive_frame(&mut picture).is_ok() {
+            image = Some(self.to_rgba(&picture)?);
+        }
+
+        image.ok_or_else(|| anyhow::anyhow!("H.264 stream needs a keyframe before it can be decoded"))
+    }
+}
+
+fn even(dimension: u32) -> u32 {
+    (dimension & !1).max(2)
+}
+
+#[cfg(all(test, feature = "ffmpeg"))]
+mod tests {
+    use super::*;
+
+    fn settings() -> EncoderSettings {
+        EncoderSettings {
+            keyframe_interval: 1000,
+            ..EncoderSettings::default()
+        }
+    }
+
+    fn gradient(width: u32, height: u32, shift: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([((x + shift) * 2) as u8, (y * 4) as u8, 128, 255])
+        })
+    }
+
+    #[test]
+    fn test_odd_dimensions_round_trip() {
+        let mut encoder = H264Encoder::new(&settings()).unwrap();
+        let mut decoder = H264Decoder::new().unwrap();
+
+        // The odd row and column are scaled away
+        let image = gradient(101, 57, 0);
+        let frame = encoder.encode(&image).unwrap();
+        assert!(frame.is_keyframe);
+        assert_eq!((frame.width, frame.height), (100, 56));
+
+        let decoded = decoder.decode(&frame.data, frame.is_keyframe).unwrap();
+        assert_eq!(decoded.dimensions(), (100, 56));
+
+        // Lossy, but close to the source away from the scaled edge
+        let pixels: Vec<(u32, u32)> = (8..48).flat_map(|y| (8..92).map(move |x| (x, y))).collect();
+        let difference = pixels.iter()
+            .map(|&(x, y)| {
+                let (a, b) = (decoded.get_pixel(x, y), image.get_pixel(x, y));
+                (0..3).map(|c| (a[c] as i32 - b[c] as i32).unsigned_abs()).sum::<u32>()
+            })
+            .sum::<u32>() / pixels.len() as u32;
+        assert!(difference < 24, "{}", difference);
+
+        assert_eq!(even(1), 2);
+        assert_eq!(even(101), 100);
+    }
+
+    #[test]
+    fn test_requested_keyframe_is_idr() {
+        let mut encoder = H264Encoder::new(&settings()).unwrap();
+
+        assert!(encoder.encode(&gradient(64, 48, 0)).unwrap().is_keyframe);
+        let inter = encoder.encode(&gradient(64, 48, 1)).unwrap();
+        assert!(!inter.is_keyframe);
+
+        // A decoder that never saw the stream can't start on an inter frame
+        assert!(H264Decoder::new().unwrap().decode(&inter.data, false).is_err());
+
+        encoder.request_keyframe();
+        let forced = encoder.encode(&gradient(64, 48, 2)).unwrap();
+        assert!(forced.is_keyframe);
+        assert!(!encoder.encode(&gradient(64, 48, 3)).unwrap().is_keyframe);
+
+        // But it can start on the forced one
+        let decoded = H264Decoder::new().unwrap().decode(&forced.data, true).unwrap();
+        assert_eq!(decoded.dimensions(), (64, 48));
+    }
+}
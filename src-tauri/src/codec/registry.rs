@@ -0,0 +1,182 @@
+//! Encoders and decoders by format, shared across the application.
+
+use anyhow::Result;
+use std::sync::{OnceLock, PoisonError, RwLock};
+
+#[cfg(feature = "ffmpeg")]
+use super::h264::{H264Decoder, H264Encoder};
+use super::still::{self, StillDecoder, StillEncoder};
+use super::{EncoderSettings, FrameDecoder, FrameEncoder, FrameFormat};
+
+type EncoderFactory = Box<dyn Fn(&EncoderSettings) -> Result<Box<dyn FrameEncoder>> + Send + Sync>;
+type DecoderFactory = Box<dyn Fn() -> Result<Box<dyn FrameDecoder>> + Send + Sync>;
+
+/// Encoder and decoder factories, most preferred format first
+pub struct CodecRegistry {
+    encoders: Vec<(FrameFormat, EncoderFactory)>,
+    decoders: Vec<(FrameFormat, DecoderFactory)>,
+}
+
+impl CodecRegistry {
+    /// An empty registry; `builtin` has the codecs this build ships with
+    pub fn new() -> Self {
+        Self {
+            encoders: Vec::new(),
+            decoders: Vec::new(),
+        }
+    }
+
+    /// H.264 first when built with the `ffmpeg` feature, then JPEG, PNG, WebP and raw frames
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+
+        #[cfg(feature = "ffmpeg")]
+        {
+            registry.register_encoder(FrameFormat::H264, |settings| Ok(Box::new(H264Encoder::new(settings)?)));
+            registry.register_decoder(FrameFormat::H264, || Ok(Box::new(H264Decoder::new()?)));
+        }
+
+        for format in still::FORMATS {
+            registry.register_encoder(format, move |settings| Ok(Box::new(StillEncoder::new(format, settings)?)));
+            registry.register_decoder(format, move || Ok(Box::new(StillDecoder::new(format)?)));
+        }
+
+        registry
+    }
+
+    /// Add an encoder at the end of the preference order, or replace the one registered for `format`
+    pub fn register_encoder<F>(&mut self, format: FrameFormat, factory: F)
+    where
+        F: Fn(&EncoderSettings) -> Result<Box<dyn FrameEncoder>> + Send + Sync + 'static,
+    {
+        let factory: EncoderFactory = Box::new(factory);
+        match self.encoders.iter_mut().find(|(registered, _)| *registered == format) {
+            Some(entry) => entry.1 = factory,
+            None => self.encoders.push((format, factory)),
+        }
+    }
+
+    /// Add a decoder, or replace the one registered for `format`
+    pub fn register_decoder<F>(&mut self, format: FrameFormat, factory: F)
+    where
+        F: Fn() -> Result<Box<dyn FrameDecoder>> + Send + Sync + 'static,
+    {
+        let factory: DecoderFactory = Box::new(factory);
+        match self.decoders.iter_mut().find(|(registered, _)| *registered == format) {
+            Some(entry) => entry.1 = factory,
+            None => self.decoders.push((format, factory)),
+        }
+    }
+
+    pub fn encoder(&self, format: FrameFormat, settings: &EncoderSettings) -> Result<Box<dyn FrameEncoder>> {
+        let (_, factory) = self.encoders.iter()
+            .find(|(registered, _)| *registered == format)
+            .ok_or_else(|| anyhow::anyhow!("No encoder registered for {}", format))?;
+
+        factory(settings)
+    }
+
+    pub fn decoder(&self, format: FrameFormat) -> Result<Box<dyn FrameDecoder>> {
+        let (_, factory) = self.decoders.iter()
+            .find(|(registered, _)| *registered == format)
+            .ok_or_else(|| anyhow::anyhow!("No decoder registered for {}", format))?;
+
+        factory()
+    }
+
+    /// Formats that can be both sent and received, most preferred first
+    pub fn formats(&self) -> Vec<FrameFormat> {
+        self.encoders.iter()
+            .map(|(format, _)| *format)
+            .filter(|format| self.decoders.iter().any(|(registered, _)| registered == format))
+            .collect()
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+static REGISTRY: OnceLock<RwLock<CodecRegistry>> = OnceLock::new();
+
+/// The registry streaming, the network host, the viewer and the benchmarks all draw from
+///
+/// Third-party codecs are registered through `registry().write()` before any session starts.
+pub fn registry() -> &'static RwLock<CodecRegistry> {
+    REGISTRY.get_or_init(|| RwLock::new(CodecRegistry::builtin()))
+}
+
+pub fn create_encoder(format: FrameFormat, settings: &EncoderSettings) -> Result<Box<dyn FrameEncoder>> {
+    registry().read().unwrap_or_else(PoisonError::into_inner).encoder(format, settings)
+}
+
+pub fn create_decoder(format: FrameFormat) -> Result<Box<dyn FrameDecoder>> {
+    registry().read().unwrap_or_else(PoisonError::into_inner).decoder(format)
+}
+
+/// See `CodecRegistry::formats`
+pub fn available_formats() -> Vec<FrameFormat> {
+    registry().read().unwrap_or_else(PoisonError::into_inner).formats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::EncodedFrame;
+    use image::RgbaImage;
+
+    /// Sends every frame as the grey level of its first pixel
+    struct GreyEncoder;
+
+    impl FrameEncoder for GreyEncoder {
+        fn format(&self) -> FrameFormat {
+            FrameFormat::Vp8
+        }
+
+        fn encode(&mut self, image: &RgbaImage) -> Result<EncodedFrame> {
+            Ok(EncodedFrame {
+                format: FrameFormat::Vp8,
+                width: image.width(),
+                height: image.height(),
+                data: vec![image.get_pixel(0, 0)[0], image.width() as u8, image.height() as u8],
+                is_keyframe: true,
+                changed_regions: None,
+            })
+        }
+
+        fn request_keyframe(&mut self) {}
+    }
+
+    struct GreyDecoder;
+
+    impl FrameDecoder for GreyDecoder {
+        fn format(&self) -> FrameFormat {
+            FrameFormat::Vp8
+        }
+
+        fn decode(&mut self, data: &[u8], _is_keyframe: bool) -> Result<RgbaImage> {
+            let level = data[0];
+            Ok(RgbaImage::from_pixel(data[1] as u32, data[2] as u32, image::Rgba([level, level, level, 255])))
+        }
+    }
+
+    #[test]
+    fn test_third_party_codec() {
+        let mut registry = CodecRegistry::builtin();
+        assert!(registry.encoder(FrameFormat::Vp8, &EncoderSettings::default()).is_err());
+
+        // Only formats that can also be decoded are offered to peers
+        registry.register_encoder(FrameFormat::Vp8, |_| Ok(Box::new(GreyEncoder)));
+        assert!(!registry.formats().contains(&FrameFormat::Vp8));
+
+        registry.register_decoder(FrameFormat::Vp8, || Ok(Box::new(GreyDecoder)));
+        assert_eq!(registry.formats().last(), Some(&FrameFormat::Vp8));
+
+        let frame = RgbaImage::from_pixel(4, 2, image::Rgba([9, 9, 9, 255]));
+        let encoded = registry.encoder(FrameFormat::Vp8, &EncoderSettings::default()).unwrap().encode(&frame).unwrap();
+        let decoded = registry.decoder(encoded.format).unwrap().decode(&encoded.data, encoded.is_keyframe).unwrap();
+        assert_eq!(decoded, frame);
+    }
+}
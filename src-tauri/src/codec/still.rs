@@ -0,0 +1,305 @@
+//! JPEG, PNG, WebP and raw RGBA frames.
+//!
+//! Keyframes are whole pictures. With `EncoderSettings::delta` on, the frames in
+//! between only carry the regions that changed, as a `delta` payload whose
+//! regions use the same format; what changed is found by comparing tile
+//! hashes, so no copy of the previous frame is kept. Raw keyframes start with their width and
+//! height as little-endian `u32`s, since the pixels alone don't say.
+
+use anyhow::Result;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use log::debug;
+use std::io::Cursor;
+
+use super::damage::DamageTracker;
+use super::delta::{self, DiffRegion, BLOCK_SIZE};
+use super::{webp, EncodedFrame, EncoderSettings, FrameDecoder, FrameEncoder, FrameFormat};
+
+/// Formats handled here, in the order they are preferred
+pub const FORMATS: [FrameFormat; 4] = [FrameFormat::Jpeg, FrameFormat::Png, FrameFormat::WebP, FrameFormat::Raw];
+
+/// Share of the picture past which a keyframe costs about as much as the patches
+const MAX_DELTA_RATIO: f64 = 0.3;
+
+pub struct StillEncoder {
+    format: FrameFormat,
+    quality: u8,
+    /// Hashes of what the viewer has; only kept for deltas
+    damage: Option<DamageTracker>,
+}
+
+impl StillEncoder {
+    pub fn new(format: FrameFormat, settings: &EncoderSettings) -> Result<Self> {
+        if !FORMATS.contains(&format) {
+            return Err(anyhow::anyhow!("{} is not a still image format", format));
+        }
+
+        Ok(Self {
+            format,
+            quality: settings.quality,
+            damage: settings.delta.then(|| DamageTracker::with_tile_size(BLOCK_SIZE)),
+        })
+    }
+
+    fn encode_keyframe(&mut self, image: &RgbaImage) -> Result<EncodedFrame> {
+        let (width, height) = image.dimensions();
+
+        let mut data = Vec::new();
+        if self.format == FrameFormat::Raw {
+            data.extend_from_slice(&width.to_le_bytes());
+            data.extend_from_slice(&height.to_le_bytes());
+        }
+        data.extend(encode_picture(self.format, image, self.quality)?);
+
+        Ok(EncodedFrame {
+            format: self.format,
+            width,
+            height,
+            data,
+            is_keyframe: true,
+            changed_regions: None,
+        })
+    }
+
+    fn encode_frame(&mut self, image: &RgbaImage) -> Result<EncodedFrame> {
+        // A monitor switch changes the size, so the next frame goes out whole
+        let damage = match self.damage {
+            Some(ref mut tracker) => tracker.update(image),
+            None => return self.encode_keyframe(image),
+        };
+        if damage.full {
+            return self.encode_keyframe(image);
+        }
+
+        // Moved content costs a few bytes to copy, only what's new has to be sent
+        let (width, height) = image.dimensions();
+        let changed_area: u64 = damage.dirty.iter().map(DiffRegion::area).sum();
+        let change_ratio = changed_area as f64 / (width as u64 * height as u64) as f64;
+        if change_ratio > MAX_DELTA_RATIO {
+            debug!("High change ratio ({:.2}), encoding full frame", change_ratio);
+            return self.encode_keyframe(image);
+        }
+
+        // An empty delta still tells the viewer the picture is unchanged
+        let encoded_regions = damage.dirty.iter()
+            .map(|region| Ok((*region, encode_picture(self.format, &delta::crop(image, region), self.quality)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let data = delta::write_delta(width, height, &damage.moved, &encoded_regions)?;
+
+        debug!("Encoded {} delta with {} regions ({:.1}% of the picture) and {} copies, {} bytes",
+               self.format, damage.dirty.len(), change_ratio * 100.0, damage.moved.len(), data.len());
+
+        Ok(EncodedFrame {
+            format: self.format,
+            width,
+            height,
+            data,
+            is_keyframe: false,
+            changed_regions: Some(damage.regions()),
+        })
+    }
+}
+
+impl FrameEncoder for StillEncoder {
+    fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    fn encode(&mut self, image: &RgbaImage) -> Result<EncodedFrame> {
+        let encoded = self.encode_frame(image);
+
+        // The tracker already holds this frame, which the viewer never gets
+        if encoded.is_err() {
+            self.request_keyframe();
+        }
+
+        encoded
+    }
+
+    fn request_keyframe(&mut self) {
+        if let Some(ref mut tracker) = self.damage {
+            tracker.reset();
+        }
+    }
+
+    fn set_quality(&mut self, quality: u8) {
+        self.quality = quality;
+    }
+}
+
+/// Viewer side of `StillEncoder`, keeping the reference picture deltas patch
+pub struct StillDecoder {
+    format: FrameFormat,
+    reference: Option<RgbaImage>,
+}
+
+impl StillDecoder {
+    pub fn new(format: FrameFormat) -> Result<Self> {
+        if !FORMATS.contains(&format) {
+            return Err(anyhow::anyhow!("{} is not a still image format", format));
+        }
+
+        Ok(Self { format, reference: None })
+    }
+
+    fn decode_keyframe(&self, data: &[u8]) -> Result<RgbaImage> {
+        if self.format != FrameFormat::Raw {
+            return decode_picture(self.format, data, None);
+        }
+
+        let size = |offset: usize| -> Result<u32> {
+            let bytes = data.get(offset..offset + 4)
+                .ok_or_else(|| anyhow::anyhow!("Raw keyframe is missing its size"))?;
+            Ok(u32::from_le_bytes(bytes.try_into()?))
+        };
+
+        decode_picture(self.format, &data[8.min(data.len())..], Some((size(0)?, size(4)?)))
+    }
+}
+
+impl FrameDecoder for StillDecoder {
+    fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    fn decode(&mut self, data: &[u8], is_keyframe: bool) -> Result<RgbaImage> {
+        if is_keyframe {
+            let picture = self.decode_keyframe(data)?;
+            self.reference = Some(picture.clone());
+            return Ok(picture);
+        }
+
+        let format = self.format;
+        let reference = self.reference.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Delta frame received before any keyframe"))?;
+
+        let patched = delta::apply_delta(reference, data, |bytes, region| {
+            decode_picture(format, bytes, Some((region.width, region.height)))
+        });
+
+        match patched {
+            Ok(()) => Ok(reference.clone()),
+            Err(e) => {
+                // A delta that doesn't fit means frames went missing; wait for the next keyframe
+                self.reference = None;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn encode_picture(format: FrameFormat, image: &RgbaImage, quality: u8) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+
+    match format {
+        FrameFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb_image = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+            let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+            encoder.encode(rgb_image.as_raw(), rgb_image.width(), rgb_image.height(), image::ColorType::Rgb8)?;
+        }
+        FrameFormat::Png => image.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)?,
+        // Quality 100 switches to lossless
+        FrameFormat::WebP => buffer = webp::encode(image, quality)?,
+        FrameFormat::Raw => buffer.extend_from_slice(image.as_raw()),
+        other => return Err(anyhow::anyhow!("{} is not a still image format", other)),
+    }
+
+    Ok(buffer)
+}
+
+/// `raw_size` gives the dimensions of raw pixels, which carry none of their own
+fn decode_picture(format: FrameFormat, data: &[u8], raw_size: Option<(u32, u32)>) -> Result<RgbaImage> {
+    match format {
+        FrameFormat::Jpeg => Ok(image::load_from_memory_with_format(data, ImageFormat::Jpeg)?.to_rgba8()),
+        FrameFormat::Png => Ok(image::load_from_memory_with_format(data, ImageFormat::Png)?.to_rgba8()),
+        FrameFormat::WebP => webp::decode(data),
+        FrameFormat::Raw => {
+            let (width, height) = raw_size
+                .ok_or_else(|| anyhow::anyhow!("Raw frame without dimensions"))?;
+            RgbaImage::from_raw(width, height, data.to_vec())
+                .ok_or_else(|| anyhow::anyhow!("Raw frame has the wrong size for {}x{}", width, height))
+        }
+        other => Err(anyhow::anyhow!("{} is not a still image format", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::imageops;
+
+    #[test]
+    fn test_still_delta_round_trip() {
+        let settings = EncoderSettings { delta: true, ..Default::default() };
+        let mut encoder = StillEncoder::new(FrameFormat::Png, &settings).unwrap();
+        let mut decoder = StillDecoder::new(FrameFormat::Png).unwrap();
+
+        let mut frame = RgbaImage::from_pixel(128, 64, image::Rgba([30, 30, 30, 255]));
+        let keyframe = encoder.encode(&frame).unwrap();
+        assert!(keyframe.is_keyframe);
+        decoder.decode(&keyframe.data, true).unwrap();
+
+        // A caret-sized change only sends the block around it
+        for y in 20..30 {
+            frame.put_pixel(40, y, image::Rgba([250, 250, 250, 255]));
+            frame.put_pixel(41, y, image::Rgba([250, 250, 250, 255]));
+        }
+        let delta = encoder.encode(&frame).unwrap();
+        assert!(!delta.is_keyframe);
+        assert_eq!(delta.changed_regions, Some(vec![DiffRegion { x: 32, y: 16, width: 16, height: 16 }]));
+        assert!(delta.data.len() < keyframe.data.len());
+        assert_eq!(decoder.decode(&delta.data, false).unwrap(), frame);
+
+        let unchanged = encoder.encode(&frame).unwrap();
+        assert_eq!(unchanged.changed_regions, Some(vec![]));
+        assert_eq!(decoder.decode(&unchanged.data, false).unwrap(), frame);
+
+        // Repainting everything is cheaper as a keyframe
+        let repainted = RgbaImage::from_pixel(128, 64, image::Rgba([200, 10, 10, 255]));
+        assert!(encoder.encode(&repainted).unwrap().is_keyframe);
+    }
+
+    #[test]
+    fn test_scroll_is_copied() {
+        let settings = EncoderSettings { delta: true, ..Default::default() };
+        let mut encoder = StillEncoder::new(FrameFormat::Png, &settings).unwrap();
+        let mut decoder = StillDecoder::new(FrameFormat::Png).unwrap();
+
+        // A page of distinct lines, scrolled down by three of them
+        let line = |x: u32, y: u32| image::Rgba([(y * 3) as u8, (x ^ y) as u8, (y / 2) as u8, 255]);
+        let page = RgbaImage::from_fn(128, 96, line);
+        let scrolled = RgbaImage::from_fn(128, 96, |x, y| line(x, y + 12));
+
+        let keyframe = encoder.encode(&page).unwrap();
+        decoder.decode(&keyframe.data, true).unwrap();
+
+        let delta = encoder.encode(&scrolled).unwrap();
+        assert!(!delta.is_keyframe);
+        assert!(delta.data.len() * 4 < keyframe.data.len());
+        assert_eq!(decoder.decode(&delta.data, false).unwrap(), scrolled);
+    }
+
+    #[test]
+    fn test_delta_patches_reference() {
+        let mut reference = RgbaImage::from_pixel(64, 32, image::Rgba([0, 0, 0, 255]));
+        let mut encoder = StillEncoder::new(FrameFormat::Raw, &EncoderSettings::default()).unwrap();
+        let mut decoder = StillDecoder::new(FrameFormat::Raw).unwrap();
+
+        let keyframe = encoder.encode(&reference).unwrap();
+        assert!(decoder.decode(b"not a delta", false).is_err());
+        assert_eq!(decoder.decode(&keyframe.data, true).unwrap(), reference);
+
+        let region = DiffRegion { x: 16, y: 8, width: 8, height: 4 };
+        let patch = RgbaImage::from_pixel(8, 4, image::Rgba([255, 0, 0, 255]));
+        let delta = delta::write_delta(64, 32, &[], &[(region, patch.as_raw().clone())]).unwrap();
+
+        imageops::replace(&mut reference, &patch, 16, 8);
+        assert_eq!(decoder.decode(&delta, false).unwrap(), reference);
+
+        // A delta for another frame size means the stream was lost
+        let wrong_size = delta::write_delta(32, 32, &[], &[]).unwrap();
+        assert!(decoder.decode(&wrong_size, false).is_err());
+        assert!(decoder.decode(&delta, false).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! WebP through the bundled libwebp, shared by the codec and the streaming compressor.
+
+use anyhow::Result;
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+/// Quality at which frames are encoded losslessly instead
+pub const LOSSLESS_QUALITY: u8 = 100;
+
+/// Lossy below `LOSSLESS_QUALITY`, lossless at it; alpha is dropped since screens are opaque
+pub fn encode(image: &RgbaImage, quality: u8) -> Result<Vec<u8>> {
+    if image.width() == 0 || image.height() == 0 {
+        return Err(anyhow::anyhow!("Cannot encode an empty image as WebP"));
+    }
+
+    let rgb = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+    let encoder = webp::Encoder::from_rgb(rgb.as_raw(), rgb.width(), rgb.height());
+
+    let encoded = if quality >= LOSSLESS_QUALITY {
+        encoder.encode_lossless()
+    } else {
+        encoder.encode(quality.max(1) as f32)
+    };
+
+    Ok(encoded.to_vec())
+}
+
+pub fn decode(data: &[u8]) -> Result<RgbaImage> {
+    let decoded = webp::Decoder::new(data)
+        .decode()
+        .ok_or_else(|| anyhow::anyhow!("Invalid or animated WebP data"))?;
+
+    let (width, height) = (decoded.width(), decoded.height());
+    let image = if decoded.is_alpha() {
+        RgbaImage::from_raw(width, height, decoded.to_vec()).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(width, height, decoded.to_vec()).map(DynamicImage::ImageRgb8)
+    };
+
+    image
+        .map(|image| image.to_rgba8())
+        .ok_or_else(|| anyhow::anyhow!("WebP decoder returned a truncated image"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webp_round_trip() {
+        let image = RgbaImage::from_fn(64, 32, |x, y| {
+            if (x / 4 + y / 4) % 2 == 0 {
+                image::Rgba([20, 20, 20, 255])
+            } else {
+                image::Rgba([240, 240, 240, 255])
+            }
+        });
+
+        let lossless = encode(&image, LOSSLESS_QUALITY).unwrap();
+        assert_eq!(decode(&lossless).unwrap(), image);
+
+        let lossy = encode(&image, 50).unwrap();
+        let decoded = decode(&lossy).unwrap();
+        assert_eq!(decoded.dimensions(), image.dimensions());
+        assert!(decode(b"not webp").is_err());
+    }
+}
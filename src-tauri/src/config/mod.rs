@@ -4,6 +4,9 @@ use log::info;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+use crate::codec::{self, FrameFormat};
+use crate::permissions::ApprovalPolicy;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
@@ -13,6 +16,8 @@ pub struct AppConfig {
     pub codec: CodecConfig,
     pub network: NetworkConfig,
     pub ui: UiConfig,
+    #[serde(default)]
+    pub headless: HeadlessConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -49,7 +54,7 @@ pub struct CaptureConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodecConfig {
-    pub format: String,
+    pub format: FrameFormat,
     pub quality: u8,
     pub enable_hardware_acceleration: bool,
 }
@@ -70,6 +75,16 @@ pub struct UiConfig {
     pub start_minimized: bool,
 }
 
+/// Settings for `anyviewer --headless`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeadlessConfig {
+    /// Name announced to the LAN; the hostname if unset
+    pub device_name: Option<String>,
+    pub relay_server_url: Option<String>,
+    pub approval: ApprovalPolicy,
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
@@ -98,7 +113,7 @@ impl Default for AppConfig {
                 monitor_index: 0,
             },
             codec: CodecConfig {
-                format: "jpeg".to_string(),
+                format: FrameFormat::Jpeg,
                 quality: 80,
                 enable_hardware_acceleration: true,
             },
@@ -114,6 +129,7 @@ impl Default for AppConfig {
                 minimize_to_tray: true,
                 start_minimized: false,
             },
+            headless: HeadlessConfig::default(),
         }
     }
 }
@@ -216,9 +232,8 @@ impl AppConfig {
             return Err(anyhow::anyhow!("Codec quality must be between 1 and 100"));
         }
         
-        let valid_formats = ["jpeg", "png", "webp"];
-        if !valid_formats.contains(&self.codec.format.as_str()) {
-            return Err(anyhow::anyhow!("Invalid codec format: {}", self.codec.format));
+        if !codec::available_formats().contains(&self.codec.format) {
+            return Err(anyhow::anyhow!("Codec format {} is not supported by this build", self.codec.format));
         }
         
         // Validate security config
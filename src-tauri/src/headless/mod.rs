@@ -0,0 +1,351 @@
+//! Host mode without the Tauri UI, for machines nobody sits at.
+//!
+//! Requests are answered by the approval policy in the config. Anything the
+//! policy leaves to a person is denied, since nobody is there to answer.
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+
+use crate::config::AppConfig;
+use crate::network::connection_manager::{ConnectionConfig, ConnectionEvent, ConnectionManager};
+use crate::network::{IncomingConnectionRequest, NetworkConfig, NetworkManager};
+use crate::permissions::{
+    ApprovalMode, DeviceInfo, Permission, PermissionConfig, PermissionEvent, PermissionManager, PermissionResponse,
+};
+use crate::security::SecurityManager;
+use crate::streaming::{StreamingConfig, StreamingEvent, StreamingManager};
+use crate::utils::file_transfer::{FileTransferManager, TransferEvent};
+
+pub struct HeadlessHost {
+    config: AppConfig,
+    network_manager: NetworkManager,
+    connection_manager: Arc<ConnectionManager>,
+    permission_manager: Arc<PermissionManager>,
+    streaming_manager: StreamingManager,
+    /// Permission request ID -> LAN connection request ID still waiting for an answer
+    lan_requests: HashMap<String, String>,
+    /// Viewers that asked through the relay; LAN viewers get their frames from the host server
+    relay_peers: Arc<RwLock<HashSet<String>>>,
+    streaming_initialized: bool,
+}
+
+impl HeadlessHost {
+    pub fn new(config: AppConfig) -> Self {
+        Self {
+            config,
+            network_manager: NetworkManager::new(),
+            connection_manager: Arc::new(ConnectionManager::new()),
+            permission_manager: Arc::new(PermissionManager::new()),
+            streaming_manager: StreamingManager::new(),
+            lan_requests: HashMap::new(),
+            relay_peers: Arc::new(RwLock::new(HashSet::new())),
+            streaming_initialized: false,
+        }
+    }
+
+    /// Start hosting and answer requests until SIGINT/SIGTERM
+    pub async fn run(mut self) -> Result<()> {
+        info!("Starting AnyViewer headless host");
+
+        let policy = self.config.headless.approval.clone();
+        if policy.mode == ApprovalMode::Manual {
+            warn!("Approval mode is manual; with no one to answer, every request will be denied");
+        }
+
+        let security = Arc::new(SecurityManager::new()?);
+        info!("Host fingerprint: {}", security.host_fingerprint());
+
+        // Permissions first so no request can arrive before the policy is in place
+        self.permission_manager.update_config(PermissionConfig {
+            max_concurrent_connections: self.config.server.max_connections,
+            auto_approval: policy,
+            ..PermissionConfig::default()
+        }).await?;
+        let mut permission_events = self.permission_manager.initialize().await?;
+
+        let streaming_config = StreamingConfig {
+            target_fps: self.config.capture.fps,
+            quality: self.config.capture.quality,
+            ..StreamingConfig::default()
+        };
+        self.streaming_manager.update_config(streaming_config.clone()).await?;
+
+        // Direct LAN hosting
+        self.network_manager.update_config(NetworkConfig {
+            server_port: self.config.server.port,
+            max_connections: self.config.server.max_connections,
+            enable_encryption: self.config.security.enable_encryption,
+            relay_server_url: self.config.headless.relay_server_url.clone(),
+        }).await?;
+        self.network_manager.set_security_manager(security.clone());
+        self.network_manager.set_streaming_config(streaming_config).await;
+
+        // Viewers' file offers go through the same policy as their other permissions
+        let (file_transfers, mut transfer_events) = FileTransferManager::new();
+        let file_transfers = Arc::new(file_transfers);
+        self.network_manager.set_permission_manager(self.permission_manager.clone());
+        self.network_manager.set_file_transfer_manager(file_transfers.clone());
+        let (mut lan_requests, _lan_responses) = self.network_manager.initialize_connection_requests().await?;
+        self.network_manager.start_host_server().await?;
+
+        let _discovered_devices = if self.config.server.enable_discovery {
+            let device_name = self.config.headless.device_name.clone()
+                .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().to_string());
+            Some(self.network_manager.start_discovery(device_name).await?)
+        } else {
+            None
+        };
+
+        // P2P/relay hosting under the connection ID
+        let mut connection_config = ConnectionConfig::default();
+        if let Some(ref relay_server_url) = self.config.headless.relay_server_url {
+            connection_config.relay_config.server_url = relay_server_url.clone();
+        }
+        self.connection_manager.update_config(connection_config).await?;
+        self.connection_manager.set_permission_manager(self.permission_manager.clone()).await;
+        self.connection_manager.set_security_manager(security).await;
+        self.connection_manager.set_file_transfer_manager(file_transfers).await;
+        let mut connection_events = self.connection_manager.initialize().await?;
+
+        match self.connection_manager.start_hosting().await {
+            Ok(connection_id) => info!("Hosting as {}", connection_id),
+            Err(e) => error!("Hosting by connection ID failed, LAN connections only: {}", e),
+        }
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                Some(request) = lan_requests.recv() => {
+                    self.handle_lan_request(request).await;
+                }
+                Some(event) = connection_events.recv() => {
+                    self.handle_connection_event(event).await;
+                }
+                Some(event) = permission_events.recv() => {
+                    self.handle_permission_event(event).await;
+                }
+                Some(event) = transfer_events.recv() => {
+                    log_transfer_event(event);
+                }
+                _ = &mut shutdown => {
+                    info!("Shutting down headless host");
+                    break;
+                }
+            }
+        }
+
+        if let Err(e) = self.streaming_manager.stop_streaming().await {
+            warn!("Failed to stop streaming: {}", e);
+        }
+        self.network_manager.stop_discovery().await?;
+        self.connection_manager.disconnect().await?;
+
+        Ok(())
+    }
+
+    async fn handle_lan_request(&mut self, request: IncomingConnectionRequest) {
+        info!("Connection request from {} ({})", request.requester_name, request.requester_ip);
+
+        let permissions: Vec<Permission> = request.requested_permissions.iter()
+            .filter_map(|name| Permission::from_name(name))
+            .collect();
+        let device_info = DeviceInfo {
+            name: request.requester_name.clone(),
+            os: "unknown".to_string(),
+            version: "unknown".to_string(),
+            ip_address: Some(request.requester_ip.clone()),
+        };
+
+        // The policy answers inside request_permission; the answer is handled with the permission events
+        match self.permission_manager.request_permission(request.requester_device_id, device_info, permissions).await {
+            Ok(permission_request_id) => {
+                self.lan_requests.insert(permission_request_id, request.request_id);
+            }
+            Err(e) => {
+                warn!("Refusing connection request {}: {}", request.request_id, e);
+                self.answer_lan_request(request.request_id, &PermissionResponse::Denied { reason: e.to_string() }).await;
+            }
+        }
+    }
+
+    async fn handle_connection_event(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::ConnectionRequest { from_id, device_name, .. } => {
+                info!("Relay connection request from {}", device_name);
+
+                let device_info = DeviceInfo {
+                    name: device_name,
+                    os: "unknown".to_string(),
+                    version: "unknown".to_string(),
+                    ip_address: None,
+                };
+                let permissions = self.config.headless.approval.permissions.clone();
+                self.relay_peers.write().await.insert(from_id.clone());
+
+                if let Err(e) = self.permission_manager.request_permission(from_id, device_info, permissions).await {
+                    warn!("Refusing relay connection request: {}", e);
+                }
+            }
+            ConnectionEvent::StatusChanged(status) => info!("Connection status: {:?}", status),
+            ConnectionEvent::Error(e) => error!("Connection error: {}", e),
+            ConnectionEvent::DataReceived { .. } => {}
+        }
+    }
+
+    async fn handle_permission_event(&mut self, event: PermissionEvent) {
+        match event {
+            PermissionEvent::RequestReceived(request) => {
+                // Left open by the policy, and nobody is here to decide
+                let response = PermissionResponse::Denied {
+                    reason: "No one is available to approve the request".to_string(),
+                };
+                if let Err(e) = self.permission_manager.respond_to_request(request.id, response).await {
+                    warn!("Failed to deny permission request: {}", e);
+                }
+            }
+            PermissionEvent::RequestResponded(permission_request_id, response) => {
+                if let Some(lan_request_id) = self.lan_requests.remove(&permission_request_id) {
+                    self.answer_lan_request(lan_request_id, &response).await;
+                }
+
+                if let PermissionResponse::Granted { permissions, .. } = response {
+                    if permissions.contains(&Permission::ScreenView) {
+                        if let Err(e) = self.ensure_streaming().await {
+                            error!("Failed to start streaming: {}", e);
+                        }
+                    }
+                }
+            }
+            PermissionEvent::PermissionRevoked(..) | PermissionEvent::PermissionExpired(_) => {
+                if self.permission_manager.get_active_grants().await.is_empty() {
+                    info!("No sessions left, stopping streaming");
+                    if let Err(e) = self.streaming_manager.stop_streaming().await {
+                        warn!("Failed to stop streaming: {}", e);
+                    }
+                }
+            }
+            PermissionEvent::SecurityAlert(message) => warn!("Security alert: {}", message),
+        }
+    }
+
+    /// Capture starts with the first viewer, so a display that comes up after boot is still found
+    async fn ensure_streaming(&mut self) -> Result<()> {
+        if !self.streaming_initialized {
+            let streaming_events = self.streaming_manager.initialize().await?;
+            tokio::spawn(forward_frames(
+                streaming_events,
+                self.connection_manager.clone(),
+                self.permission_manager.clone(),
+                self.relay_peers.clone(),
+            ));
+            self.streaming_initialized = true;
+        }
+
+        if !self.streaming_manager.is_streaming().await {
+            self.streaming_manager.start_streaming().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn answer_lan_request(&self, request_id: String, response: &PermissionResponse) {
+        let result = match response {
+            PermissionResponse::Granted { permissions, duration_minutes } => {
+                self.network_manager.respond_to_connection_request(
+                    request_id,
+                    true,
+                    permissions.iter().map(|p| p.name().to_string()).collect(),
+                    *duration_minutes,
+                    None,
+                ).await
+            }
+            PermissionResponse::Denied { reason } => {
+                self.network_manager.respond_to_connection_request(request_id, false, vec![], None, Some(reason.clone())).await
+            }
+            PermissionResponse::Expired => {
+                self.network_manager.respond_to_connection_request(request_id, false, vec![], None, Some("Request expired".to_string())).await
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to answer connection request: {}", e);
+        }
+    }
+}
+
+/// Send each captured frame to the relay viewers currently allowed to see the screen
+async fn forward_frames(
+    mut streaming_events: mpsc::UnboundedReceiver<StreamingEvent>,
+    connection_manager: Arc<ConnectionManager>,
+    permission_manager: Arc<PermissionManager>,
+    relay_peers: Arc<RwLock<HashSet<String>>>,
+) {
+    while let Some(event) = streaming_events.recv().await {
+        let frame = match event {
+            StreamingEvent::FrameReady(frame) => frame,
+            StreamingEvent::Error(e) => {
+                warn!("Streaming error: {}", e);
+                continue;
+            }
+            _ => continue,
+        };
+
+        let peers: Vec<String> = relay_peers.read().await.iter().cloned().collect();
+        for peer_id in peers {
+            if !permission_manager.check_permission(&peer_id, &Permission::ScreenView).await {
+                continue;
+            }
+            if let Err(e) = connection_manager.send_screen_frame_to(&peer_id, frame.clone()).await {
+                debug!("Failed to send frame to {}: {}", peer_id, e);
+            }
+        }
+    }
+}
+
+/// Progress is left at debug so a large transfer doesn't flood the log
+fn log_transfer_event(event: TransferEvent) {
+    match event {
+        TransferEvent::TransferStarted(transfer_id, file_name) => info!("File transfer {} started: {}", transfer_id, file_name),
+        TransferEvent::ProgressUpdate(progress) => {
+            debug!("File transfer {}: {}/{} bytes", progress.transfer_id, progress.bytes_transferred, progress.total_bytes);
+        }
+        TransferEvent::TransferCompleted(transfer_id) => info!("File transfer {} completed", transfer_id),
+        TransferEvent::TransferFailed(transfer_id, e) => warn!("File transfer {} failed: {}", transfer_id, e),
+        TransferEvent::TransferCancelled(transfer_id) => info!("File transfer {} cancelled", transfer_id),
+        TransferEvent::TransferPaused(transfer_id) => info!("File transfer {} paused", transfer_id),
+        TransferEvent::TransferResumed(transfer_id) => info!("File transfer {} resumed", transfer_id),
+        TransferEvent::DirectoryProgress(progress) => {
+            debug!("Directory transfer {}: {}/{} files, {}/{} bytes", progress.directory_id,
+                   progress.files_done, progress.file_count, progress.bytes_transferred, progress.total_bytes);
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
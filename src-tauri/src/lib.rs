@@ -0,0 +1,15 @@
+//! Core of AnyViewer, shared by the desktop app and the relay server binary.
+
+pub mod capture;
+pub mod network;
+pub mod codec;
+pub mod input;
+pub mod security;
+pub mod config;
+pub mod utils;
+pub mod streaming;
+pub mod permissions;
+pub mod metrics;
+pub mod testing;
+pub mod headless;
+pub mod cli;
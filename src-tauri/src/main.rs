@@ -5,23 +5,16 @@ use log::{info, error, debug, warn};
 use tauri::{CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu, SystemTrayMenuItem};
 
 // Import modules
-mod capture;
-mod network;
-mod codec;
-mod input;
-mod security;
-mod config;
-mod utils;
-mod streaming;
-mod permissions;
-mod metrics;
-mod testing;
-
-use capture::ScreenCaptureManager;
+use anyviewer_lib::{
+    capture, cli, codec, config, headless, input, metrics, network, permissions, security, streaming, testing, utils,
+};
+
+use capture::{ScreenCaptureManager, ScreenLayout};
 use network::{NetworkManager, ConnectionRequest as NetworkConnectionRequest, ConnectionResponse, DiscoveredDevice, IncomingConnectionRequest};
 use network::connection_manager::{ConnectionManager, ConnectionConfig, ConnectionStatus, ConnectionType};
 use input::InputManager;
-use security::SecurityManager;
+use security::{ClientCredentials, SecurityManager};
+use security::known_hosts::{KnownHost, KnownHostsStore};
 use config::AppConfig;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -35,10 +28,65 @@ async fn get_global_network_manager() -> Arc<Mutex<NetworkManager>> {
     }).await.clone()
 }
 
+// Global security manager, shared by the host server and the UI
+static GLOBAL_SECURITY_MANAGER: tokio::sync::OnceCell<Arc<SecurityManager>> = tokio::sync::OnceCell::const_new();
+
+async fn get_global_security_manager() -> Result<Arc<SecurityManager>, String> {
+    GLOBAL_SECURITY_MANAGER.get_or_try_init(|| async {
+        SecurityManager::new().map(Arc::new).map_err(|e| e.to_string())
+    }).await.cloned()
+}
+
+// Global permission manager, so grants made in the UI apply to live sessions
+static GLOBAL_PERMISSION_MANAGER: tokio::sync::OnceCell<Arc<PermissionManager>> = tokio::sync::OnceCell::const_new();
+
+async fn get_global_permission_manager() -> Arc<PermissionManager> {
+    GLOBAL_PERMISSION_MANAGER.get_or_init(|| async {
+        Arc::new(PermissionManager::new())
+    }).await.clone()
+}
+
+// Global file transfer manager, set up with the app so its events reach the UI
+static GLOBAL_FILE_TRANSFER_MANAGER: tokio::sync::OnceCell<Arc<FileTransferManager>> = tokio::sync::OnceCell::const_new();
+
+fn get_global_file_transfer_manager() -> Result<Arc<FileTransferManager>, String> {
+    GLOBAL_FILE_TRANSFER_MANAGER.get().cloned().ok_or_else(|| "File transfer not initialized".to_string())
+}
+
+// Global connection manager, so a connection made by ID outlives the command that made it
+static GLOBAL_CONNECTION_MANAGER: tokio::sync::OnceCell<Arc<ConnectionManager>> = tokio::sync::OnceCell::const_new();
+static CONNECTION_MANAGER_INITIALIZED: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+
+async fn get_global_connection_manager() -> Result<Arc<ConnectionManager>, String> {
+    GLOBAL_CONNECTION_MANAGER.get_or_try_init(|| async {
+        let connection_manager = ConnectionManager::new();
+        connection_manager.set_permission_manager(get_global_permission_manager().await).await;
+        connection_manager.set_security_manager(get_global_security_manager().await?).await;
+        connection_manager.set_file_transfer_manager(get_global_file_transfer_manager()?).await;
+        Ok::<_, String>(Arc::new(connection_manager))
+    }).await.cloned()
+}
+
+/// The global connection manager, started on first use with the config set so far
+async fn get_initialized_connection_manager() -> Result<Arc<ConnectionManager>, String> {
+    let connection_manager = get_global_connection_manager().await?;
+    CONNECTION_MANAGER_INITIALIZED.get_or_try_init(|| async {
+        let mut events = connection_manager.initialize().await.map_err(|e| e.to_string())?;
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                debug!("Connection event: {:?}", event);
+            }
+        });
+        Ok::<_, String>(())
+    }).await?;
+    Ok(connection_manager)
+}
+
 use streaming::{StreamingManager, StreamingConfig, StreamingStats};
 use permissions::{PermissionManager, PermissionConfig, Permission, PermissionResponse, DeviceInfo as PermissionDeviceInfo};
 use metrics::{MetricsCollector, ConnectionMetrics, SystemMetrics, QualityMetrics, AlertThresholds};
 use testing::{PerformanceTester, PerformanceTestConfig, PerformanceTestResult};
+use utils::file_transfer::{DirectoryProgress, FileTransferManager, FileTransferProgress};
 
 // Tauri commands
 #[tauri::command]
@@ -52,7 +100,11 @@ async fn start_host_session() -> Result<String, String> {
     let session_id = uuid::Uuid::new_v4().to_string();
     
     // Start network server
-    let network_manager = NetworkManager::new();
+    let network_manager = get_global_network_manager().await;
+    let mut network_manager = network_manager.lock().await;
+    network_manager.set_security_manager(get_global_security_manager().await?);
+    network_manager.set_permission_manager(get_global_permission_manager().await);
+    network_manager.set_file_transfer_manager(get_global_file_transfer_manager()?);
     network_manager.start_host_server().await.map_err(|e| e.to_string())?;
     
     info!("Host session started with ID: {}", session_id);
@@ -82,6 +134,12 @@ async fn capture_screen() -> Result<Vec<u8>, String> {
     Ok(screen_data)
 }
 
+#[tauri::command]
+async fn get_monitors() -> Result<ScreenLayout, String> {
+    let capture_manager = ScreenCaptureManager::new().map_err(|e| e.to_string())?;
+    Ok(capture_manager.get_layout().await)
+}
+
 #[tauri::command]
 async fn send_input_event(x: i32, y: i32, event_type: String, data: String) -> Result<(), String> {
     debug!("Sending input event: {} at ({}, {})", event_type, x, y);
@@ -120,12 +178,51 @@ async fn generate_session_id() -> Result<String, String> {
 async fn initialize_security() -> Result<String, String> {
     info!("Initializing security subsystem");
     
-    let security_manager = SecurityManager::new().map_err(|e| e.to_string())?;
+    let security_manager = get_global_security_manager().await?;
     let public_key = security_manager.get_public_key().map_err(|e| e.to_string())?;
     
     Ok(public_key)
 }
 
+#[tauri::command]
+async fn generate_session_pin() -> Result<String, String> {
+    let security_manager = get_global_security_manager().await?;
+    let pin = security_manager.generate_session_pin();
+    
+    info!("Generated new session PIN");
+    Ok(pin)
+}
+
+#[tauri::command]
+async fn set_unattended_password(username: String, password: String) -> Result<(), String> {
+    let security_manager = get_global_security_manager().await?;
+    security_manager.set_unattended_password(&username, &password).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn remove_unattended_password(username: String) -> Result<bool, String> {
+    let security_manager = get_global_security_manager().await?;
+    security_manager.remove_unattended_password(&username).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_host_fingerprint() -> Result<String, String> {
+    let security_manager = get_global_security_manager().await?;
+    Ok(security_manager.host_fingerprint())
+}
+
+#[tauri::command]
+async fn list_known_hosts() -> Result<Vec<KnownHost>, String> {
+    let known_hosts = KnownHostsStore::load_default().map_err(|e| e.to_string())?;
+    Ok(known_hosts.list())
+}
+
+#[tauri::command]
+async fn forget_known_host(host_id: String) -> Result<bool, String> {
+    let known_hosts = KnownHostsStore::load_default().map_err(|e| e.to_string())?;
+    known_hosts.forget(&host_id).map_err(|e| e.to_string())
+}
+
 // Connection request commands
 #[tauri::command]
 async fn initialize_connection_requests() -> Result<(), String> {
@@ -417,8 +514,7 @@ async fn send_connection_request_to_device(
 async fn initialize_connection_manager() -> Result<String, String> {
     info!("Initializing connection manager");
     
-    let connection_manager = ConnectionManager::new();
-    let _event_receiver = connection_manager.initialize().await.map_err(|e| e.to_string())?;
+    let connection_manager = get_initialized_connection_manager().await?;
     
     // Get the generated connection ID
     let connection_id = connection_manager.get_connection_id().await
@@ -432,9 +528,7 @@ async fn initialize_connection_manager() -> Result<String, String> {
 async fn start_hosting_with_fallback() -> Result<String, String> {
     info!("Starting hosting with P2P/Relay fallback");
     
-    let connection_manager = ConnectionManager::new();
-    let _event_receiver = connection_manager.initialize().await.map_err(|e| e.to_string())?;
-    
+    let connection_manager = get_initialized_connection_manager().await?;
     let connection_id = connection_manager.start_hosting().await.map_err(|e| e.to_string())?;
     
     info!("Hosting started with connection ID: {}", connection_id);
@@ -442,13 +536,11 @@ async fn start_hosting_with_fallback() -> Result<String, String> {
 }
 
 #[tauri::command]
-async fn connect_to_host_with_fallback(target_id: String) -> Result<(), String> {
+async fn connect_to_host_with_fallback(target_id: String, credentials: Option<ClientCredentials>) -> Result<(), String> {
     info!("Connecting to host with P2P/Relay fallback: {}", target_id);
     
-    let connection_manager = ConnectionManager::new();
-    let _event_receiver = connection_manager.initialize().await.map_err(|e| e.to_string())?;
-    
-    connection_manager.connect_to_host(target_id.clone()).await.map_err(|e| e.to_string())?;
+    let connection_manager = get_initialized_connection_manager().await?;
+    connection_manager.connect_to_host(target_id.clone(), credentials).await.map_err(|e| e.to_string())?;
     
     info!("Successfully connected to host: {}", target_id);
     Ok(())
@@ -456,7 +548,7 @@ async fn connect_to_host_with_fallback(target_id: String) -> Result<(), String>
 
 #[tauri::command]
 async fn get_connection_status() -> Result<String, String> {
-    let connection_manager = ConnectionManager::new();
+    let connection_manager = get_global_connection_manager().await?;
     let status = connection_manager.get_connection_status().await;
     
     let status_str = match status {
@@ -474,7 +566,7 @@ async fn get_connection_status() -> Result<String, String> {
 
 #[tauri::command]
 async fn get_available_peers() -> Result<Vec<String>, String> {
-    let connection_manager = ConnectionManager::new();
+    let connection_manager = get_global_connection_manager().await?;
     let peers = connection_manager.get_available_peers().await;
     
     Ok(peers)
@@ -484,7 +576,7 @@ async fn get_available_peers() -> Result<Vec<String>, String> {
 async fn disconnect_connection() -> Result<(), String> {
     info!("Disconnecting current connection");
     
-    let connection_manager = ConnectionManager::new();
+    let connection_manager = get_global_connection_manager().await?;
     connection_manager.disconnect().await.map_err(|e| e.to_string())?;
     
     Ok(())
@@ -499,7 +591,7 @@ async fn update_connection_config(
 ) -> Result<(), String> {
     info!("Updating connection configuration");
     
-    let connection_manager = ConnectionManager::new();
+    let connection_manager = get_global_connection_manager().await?;
     
     let mut relay_config = network::relay_client::RelayConfig::default();
     relay_config.server_url = relay_server_url;
@@ -579,17 +671,12 @@ async fn update_streaming_config(
     adaptive_quality: bool,
     max_bandwidth_mbps: f32,
     enable_delta_compression: bool,
+    scale: Option<f32>,
+    max_dimension: Option<u32>,
 ) -> Result<(), String> {
     info!("Updating streaming configuration");
     
-    let compression_type = match compression_type.as_str() {
-        "jpeg" => streaming::CompressionType::JPEG,
-        "webp" => streaming::CompressionType::WebP,
-        "h264" => streaming::CompressionType::H264,
-        "vp8" => streaming::CompressionType::VP8,
-        "av1" => streaming::CompressionType::AV1,
-        _ => streaming::CompressionType::JPEG,
-    };
+    let compression_type = compression_type.parse::<codec::FrameFormat>().map_err(|e| e.to_string())?;
     
     let new_config = StreamingConfig {
         target_fps,
@@ -599,8 +686,14 @@ async fn update_streaming_config(
         max_bandwidth_mbps,
         enable_delta_compression,
         buffer_size: 3,
+        keyframe_interval: streaming::DEFAULT_KEYFRAME_INTERVAL,
+        scale: scale.unwrap_or(1.0),
+        max_dimension,
     };
     
+    // LAN viewers each get their own stream from the host server
+    get_global_network_manager().await.lock().await.set_streaming_config(new_config.clone()).await;
+    
     let streaming_manager = StreamingManager::new();
     streaming_manager.update_config(new_config).await.map_err(|e| e.to_string())?;
     
@@ -620,7 +713,7 @@ async fn get_latest_frame() -> Result<Option<Vec<u8>>, String> {
 async fn initialize_permissions() -> Result<(), String> {
     info!("Initializing permission manager");
     
-    let permission_manager = PermissionManager::new();
+    let permission_manager = get_global_permission_manager().await;
     let _event_receiver = permission_manager.initialize().await.map_err(|e| e.to_string())?;
     
     info!("Permission manager initialized");
@@ -657,8 +750,7 @@ async fn request_permission(
         })
         .collect();
     
-    let permission_manager = PermissionManager::new();
-    let _event_receiver = permission_manager.initialize().await.map_err(|e| e.to_string())?;
+    let permission_manager = get_global_permission_manager().await;
     
     let request_id = permission_manager
         .request_permission(connection_id, device_info, requested_permissions)
@@ -701,7 +793,7 @@ async fn respond_to_permission_request(
         }
     };
     
-    let permission_manager = PermissionManager::new();
+    let permission_manager = get_global_permission_manager().await;
     permission_manager
         .respond_to_request(request_id, response)
         .await
@@ -722,7 +814,7 @@ async fn check_permission(connection_id: String, permission: String) -> Result<b
         _ => return Ok(false),
     };
     
-    let permission_manager = PermissionManager::new();
+    let permission_manager = get_global_permission_manager().await;
     let has_permission = permission_manager
         .check_permission(&connection_id, &permission_enum)
         .await;
@@ -751,7 +843,7 @@ async fn revoke_permissions(
             .collect()
     });
     
-    let permission_manager = PermissionManager::new();
+    let permission_manager = get_global_permission_manager().await;
     permission_manager
         .revoke_permissions(&connection_id, revoke_permissions)
         .await
@@ -762,7 +854,7 @@ async fn revoke_permissions(
 
 #[tauri::command]
 async fn get_active_permissions() -> Result<Vec<serde_json::Value>, String> {
-    let permission_manager = PermissionManager::new();
+    let permission_manager = get_global_permission_manager().await;
     let grants = permission_manager.get_active_grants().await;
     
     let grants_json: Vec<serde_json::Value> = grants.iter()
@@ -774,7 +866,7 @@ async fn get_active_permissions() -> Result<Vec<serde_json::Value>, String> {
 
 #[tauri::command]
 async fn get_pending_permission_requests() -> Result<Vec<serde_json::Value>, String> {
-    let permission_manager = PermissionManager::new();
+    let permission_manager = get_global_permission_manager().await;
     let requests = permission_manager.get_pending_requests().await;
     
     let requests_json: Vec<serde_json::Value> = requests.iter()
@@ -797,6 +889,8 @@ async fn update_permission_config(
 ) -> Result<(), String> {
     info!("Updating permission configuration");
     
+    // Settings this command doesn't take, such as the approval policy, are kept
+    let permission_manager = get_global_permission_manager().await;
     let new_config = PermissionConfig {
         require_permission_for_screen_view: require_screen_view,
         require_permission_for_input_control: require_input_control,
@@ -806,9 +900,9 @@ async fn update_permission_config(
         enable_whitelist,
         whitelisted_devices,
         default_session_duration_minutes: default_session_minutes,
+        ..permission_manager.get_config().await
     };
     
-    let permission_manager = PermissionManager::new();
     permission_manager
         .update_config(new_config)
         .await
@@ -817,6 +911,110 @@ async fn update_permission_config(
     Ok(())
 }
 
+// File transfer commands
+#[tauri::command]
+async fn send_file_to_client(client_id: String, path: String) -> Result<String, String> {
+    info!("Sending {} to client {}", path, client_id);
+    
+    let network_manager = get_global_network_manager().await;
+    let network_manager = network_manager.lock().await;
+    network_manager
+        .send_file(&client_id, std::path::Path::new(&path))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn send_directory_to_client(client_id: String, path: String) -> Result<String, String> {
+    info!("Sending directory {} to client {}", path, client_id);
+    
+    let network_manager = get_global_network_manager().await;
+    let network_manager = network_manager.lock().await;
+    network_manager
+        .send_directory(&client_id, std::path::Path::new(&path))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn send_file_to_peer(path: String) -> Result<String, String> {
+    info!("Sending {} to the connected peer", path);
+    
+    let connection_manager = get_global_connection_manager().await?;
+    connection_manager
+        .send_file(std::path::Path::new(&path))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn send_directory_to_peer(path: String) -> Result<String, String> {
+    info!("Sending directory {} to the connected peer", path);
+    
+    let connection_manager = get_global_connection_manager().await?;
+    connection_manager
+        .send_directory(std::path::Path::new(&path))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_file_transfers() -> Result<Vec<FileTransferProgress>, String> {
+    let file_transfers = get_global_file_transfer_manager()?;
+    Ok(file_transfers.get_active_transfers().await)
+}
+
+#[tauri::command]
+async fn get_directory_transfer(directory_id: String) -> Result<Option<DirectoryProgress>, String> {
+    let file_transfers = get_global_file_transfer_manager()?;
+    Ok(file_transfers.get_directory_progress(&directory_id).await)
+}
+
+#[tauri::command]
+async fn set_file_transfer_speed_limit(max_speed_bps: Option<u64>) -> Result<(), String> {
+    info!("Setting file transfer speed limit: {:?} B/s", max_speed_bps);
+    
+    let file_transfers = get_global_file_transfer_manager()?;
+    file_transfers.set_speed_limit(max_speed_bps).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn cancel_file_transfer(transfer_id: String) -> Result<(), String> {
+    info!("Cancelling file transfer: {}", transfer_id);
+    
+    let network_manager = get_global_network_manager().await;
+    let network_manager = network_manager.lock().await;
+    network_manager
+        .cancel_file_transfer(&transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn pause_file_transfer(transfer_id: String) -> Result<(), String> {
+    info!("Pausing file transfer: {}", transfer_id);
+    
+    let network_manager = get_global_network_manager().await;
+    let network_manager = network_manager.lock().await;
+    network_manager
+        .pause_file_transfer(&transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn resume_file_transfer(transfer_id: String) -> Result<(), String> {
+    info!("Resuming file transfer: {}", transfer_id);
+    
+    let network_manager = get_global_network_manager().await;
+    let network_manager = network_manager.lock().await;
+    network_manager
+        .resume_file_transfer(&transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 // Metrics and monitoring commands
 #[tauri::command]
 async fn initialize_metrics() -> Result<(), String> {
@@ -967,8 +1165,8 @@ async fn run_performance_tests(
         test_duration_seconds,
         target_fps,
         test_compression_types: vec![
-            streaming::CompressionType::JPEG,
-            streaming::CompressionType::WebP,
+            codec::FrameFormat::Jpeg,
+            codec::FrameFormat::WebP,
         ],
         test_quality_levels: vec![50, 75, 90],
         connection_timeout_seconds: 10,
@@ -989,7 +1187,7 @@ async fn run_p2p_vs_relay_test(test_duration_seconds: u64) -> Result<Vec<Perform
     let test_config = PerformanceTestConfig {
         test_duration_seconds,
         target_fps: 30,
-        test_compression_types: vec![streaming::CompressionType::JPEG],
+        test_compression_types: vec![codec::FrameFormat::Jpeg],
         test_quality_levels: vec![75],
         connection_timeout_seconds: 10,
         benchmark_iterations: 1,
@@ -1034,17 +1232,17 @@ async fn benchmark_compression_algorithms() -> Result<Vec<PerformanceTestResult>
         test_duration_seconds: 15,
         target_fps: 30,
         test_compression_types: vec![
-            streaming::CompressionType::JPEG,
-            streaming::CompressionType::WebP,
-            streaming::CompressionType::H264,
+            codec::FrameFormat::Jpeg,
+            codec::FrameFormat::WebP,
+            codec::FrameFormat::H264,
         ],
         test_quality_levels: vec![75],
         connection_timeout_seconds: 10,
         benchmark_iterations: 3,
     };
     
+    // Encodes frames directly, so no streaming session is needed
     let mut tester = PerformanceTester::new(test_config);
-    tester.streaming_manager.initialize().await.map_err(|e| e.to_string())?;
     
     let results = tester.test_compression_algorithms().await.map_err(|e| e.to_string())?;
     
@@ -1059,7 +1257,7 @@ async fn test_quality_levels() -> Result<Vec<PerformanceTestResult>, String> {
     let test_config = PerformanceTestConfig {
         test_duration_seconds: 10,
         target_fps: 30,
-        test_compression_types: vec![streaming::CompressionType::JPEG],
+        test_compression_types: vec![codec::FrameFormat::Jpeg],
         test_quality_levels: vec![25, 50, 75, 90, 95],
         connection_timeout_seconds: 10,
         benchmark_iterations: 1,
@@ -1133,11 +1331,24 @@ async fn main() {
     info!("Starting AnyViewer application");
     
     // Load configuration
-    let _config = AppConfig::load().unwrap_or_else(|e| {
+    let config = AppConfig::load().unwrap_or_else(|e| {
         error!("Failed to load config: {}", e);
         AppConfig::default()
     });
     
+    if let Some(cli) = cli::Cli::from_env() {
+        let result = match cli.command {
+            Some(cli::Command::View(args)) => cli::viewer::run(args).await,
+            None if cli.headless => headless::HeadlessHost::new(config).run().await,
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
     let system_tray = create_system_tray();
     
     tauri::Builder::default()
@@ -1147,10 +1358,17 @@ async fn main() {
             start_host_session,
             connect_to_session,
             capture_screen,
+            get_monitors,
             send_input_event,
             get_system_info,
             generate_session_id,
             initialize_security,
+            generate_session_pin,
+            set_unattended_password,
+            remove_unattended_password,
+            get_host_fingerprint,
+            list_known_hosts,
+            forget_known_host,
             initialize_connection_requests,
             create_connection_request,
             respond_to_connection_request,
@@ -1188,6 +1406,16 @@ async fn main() {
             get_active_permissions,
             get_pending_permission_requests,
             update_permission_config,
+            send_file_to_client,
+            send_directory_to_client,
+            send_file_to_peer,
+            send_directory_to_peer,
+            get_file_transfers,
+            get_directory_transfer,
+            set_file_transfer_speed_limit,
+            cancel_file_transfer,
+            pause_file_transfer,
+            resume_file_transfer,
             initialize_metrics,
             get_connection_metrics,
             get_all_connection_metrics,
@@ -1204,7 +1432,19 @@ async fn main() {
             benchmark_compression_algorithms,
             test_quality_levels
         ])
-        .setup(|_app| {
+        .setup(|app| {
+            // Forward transfer progress to the UI as "file-transfer" events
+            let (file_transfers, mut transfer_events) = FileTransferManager::new();
+            let _ = GLOBAL_FILE_TRANSFER_MANAGER.set(Arc::new(file_transfers));
+            let app_handle = app.handle();
+            tauri::async_runtime::spawn(async move {
+                while let Some(event) = transfer_events.recv().await {
+                    if let Err(e) = app_handle.emit_all("file-transfer", event) {
+                        warn!("Failed to emit file transfer event: {}", e);
+                    }
+                }
+            });
+            
             info!("AnyViewer application setup complete");
             Ok(())
         })
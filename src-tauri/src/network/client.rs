@@ -2,6 +2,8 @@ use anyhow::Result;
 use futures_util::{SinkExt, StreamExt, stream::SplitSink};
 use log::{info, error, debug, warn};
 use serde_json;
+use std::collections::HashSet;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, RwLock};
@@ -9,7 +11,18 @@ use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, Web
 use url::Url;
 use uuid::Uuid;
 
-use super::protocol::{ProtocolMessage, MessageType, ScreenFrame, InputEvent};
+use super::protocol::{
+    Capabilities, CursorPosition, CursorShape, ErrorMessage, HelloAck, InputEvent, KeyframeRequest, MessageType, ProtocolMessage,
+    ScreenFrame, Viewport,
+    ERROR_ENCRYPTION_REQUIRED, ERROR_HANDSHAKE_REQUIRED, ERROR_NO_COMMON_CAPABILITIES, ERROR_PROTOCOL_VERSION_MISMATCH,
+};
+use super::reconstructor::{DecodedFrame, FrameReconstructor, Reconstructed};
+use super::wire::{is_encrypted, WireFormat, WireMessage};
+use crate::capture::{MonitorSelection, ScreenLayout};
+use crate::security::identity::fingerprint;
+use crate::security::known_hosts::{HostKeyStatus, KnownHostsStore};
+use crate::security::secure_channel::{ChannelOpener, ChannelSealer, KeyExchange, KeyExchangeReply};
+use crate::utils::file_transfer::{FileTransferManager, TransferMessage};
 
 type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
@@ -17,8 +30,16 @@ type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 pub struct ClientConfig {
     pub server_url: String,
     pub auth_token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub session_pin: Option<String>,
     pub auto_reconnect: bool,
     pub heartbeat_interval: u64, // seconds
+    pub wire_format: WireFormat,
+    pub capabilities: Capabilities,
+    pub enable_encryption: bool,
+    /// Key for the known-hosts store; the connection ID when known, else the server URL
+    pub host_id: Option<String>,
 }
 
 impl Default for ClientConfig {
@@ -26,8 +47,15 @@ impl Default for ClientConfig {
         Self {
             server_url: "ws://127.0.0.1:7878".to_string(),
             auth_token: None,
+            username: None,
+            password: None,
+            session_pin: None,
             auto_reconnect: true,
             heartbeat_interval: 30,
+            wire_format: WireFormat::Binary,
+            capabilities: Capabilities::local(),
+            enable_encryption: true,
+            host_id: None,
         }
     }
 }
@@ -36,9 +64,23 @@ impl Default for ClientConfig {
 pub enum ClientEvent {
     Connected,
     Disconnected,
+    CapabilitiesNegotiated(Capabilities),
+    NegotiationFailed(String),
+    EncryptionEstablished { fingerprint: String, first_use: bool },
+    HostKeyMismatch { expected: String, presented: String },
     AuthenticationSuccess,
     AuthenticationFailed(String),
-    ScreenFrameReceived(Vec<u8>),
+    ScreenFrameReceived(ScreenFrame),
+    /// The whole picture once a received frame has been applied
+    FrameDecoded(DecodedFrame),
+    /// A frame that couldn't be applied; a keyframe has been requested if needed
+    FrameDropped(u64),
+    /// The host's monitors and which one is streamed; input coordinates are relative to it
+    ScreenLayoutReceived(ScreenLayout),
+    /// A pointer image to keep by ID; the host sends each shape once
+    CursorShapeReceived(CursorShape),
+    /// Where to draw the host's pointer, in pixels of the received frames
+    CursorMoved(CursorPosition),
     InputEventSent,
     Error(String),
 }
@@ -47,8 +89,11 @@ pub struct RemoteDesktopClient {
     config: Arc<RwLock<ClientConfig>>,
     ws_stream: Option<WebSocket>,
     event_tx: Option<mpsc::UnboundedSender<ClientEvent>>,
+    outbox: Option<Outbox>,
     is_connected: Arc<RwLock<bool>>,
     is_authenticated: Arc<RwLock<bool>>,
+    negotiated: Arc<RwLock<Option<Capabilities>>>,
+    file_transfers: Option<Arc<FileTransferManager>>,
 }
 
 impl RemoteDesktopClient {
@@ -57,16 +102,28 @@ impl RemoteDesktopClient {
             config: Arc::new(RwLock::new(config)),
             ws_stream: None,
             event_tx: None,
+            outbox: None,
             is_connected: Arc::new(RwLock::new(false)),
             is_authenticated: Arc::new(RwLock::new(false)),
+            negotiated: Arc::new(RwLock::new(None)),
+            file_transfers: None,
         }
     }
     
+    /// Enable file transfer with the host; without it the host's offers are declined
+    pub fn set_file_transfer_manager(&mut self, file_transfers: Arc<FileTransferManager>) {
+        self.file_transfers = Some(file_transfers);
+    }
+    
     pub async fn connect(&mut self) -> Result<mpsc::UnboundedReceiver<ClientEvent>> {
         let config = self.config.read().await;
         let url = Url::parse(&config.server_url)?;
         drop(config);
         
+        // Without the pinned keys a changed host would pass as a new one
+        let known_hosts = Arc::new(KnownHostsStore::load_default()
+            .map_err(|e| anyhow::anyhow!("Could not load known hosts: {}", e))?);
+        
         info!("Connecting to remote desktop server: {}", url);
         
         let (ws_stream, _) = connect_async(url).await?;
@@ -75,6 +132,19 @@ impl RemoteDesktopClient {
         let (event_tx, event_rx) = mpsc::unbounded_channel::<ClientEvent>();
         self.event_tx = Some(event_tx.clone());
         
+        // Outgoing messages are funnelled through a single writer task
+        let (write_tx, write_rx) = mpsc::unbounded_channel::<Message>();
+        let (chunk_tx, chunk_rx) = mpsc::unbounded_channel::<(TransferMessage, WireFormat)>();
+        let outbox = Outbox::new(write_tx, chunk_tx);
+        self.outbox = Some(outbox.clone());
+        
+        let key_exchange = if self.config.read().await.enable_encryption {
+            Some(KeyExchange::new())
+        } else {
+            None
+        };
+        let key_exchange_init = key_exchange.as_ref().map(|exchange| exchange.init_message());
+        
         // Set connected status
         *self.is_connected.write().await = true;
         
@@ -85,54 +155,91 @@ impl RemoteDesktopClient {
         if let Some(ws_stream) = self.ws_stream.take() {
             let is_connected = self.is_connected.clone();
             let is_authenticated = self.is_authenticated.clone();
+            let negotiated = self.negotiated.clone();
             let config = self.config.clone();
+            let file_transfers = self.file_transfers.clone();
             
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_messages(
+                let mut transfers = HashSet::new();
+                let result = Self::handle_messages(
                     ws_stream,
+                    outbox,
+                    Outgoing { messages: write_rx, chunks: chunk_rx },
+                    key_exchange,
+                    known_hosts,
                     event_tx,
                     is_connected,
                     is_authenticated,
+                    negotiated,
                     config,
-                ).await {
+                    file_transfers.clone(),
+                    &mut transfers,
+                ).await;
+                
+                // Transfers wait, paused, for the file to be offered again
+                if let Some(file_transfers) = file_transfers {
+                    file_transfers.pause_transfers(transfers).await;
+                }
+                
+                if let Err(e) = result {
                     error!("Message handling error: {}", e);
                 }
             });
         }
         
-        // Start authentication
-        self.authenticate().await?;
+        // Authentication follows once the server acknowledges our hello
+        // and, when encrypting, once the key exchange completes
+        self.send_hello().await?;
+        
+        if let Some(init) = key_exchange_init {
+            debug!("Sending key exchange");
+            self.send_wire_message(WireMessage::Control(ProtocolMessage::key_exchange_init(init))).await?;
+        }
         
         Ok(event_rx)
     }
     
     async fn handle_messages(
-        mut ws_stream: WebSocket,
+        ws_stream: WebSocket,
+        outbox: Outbox,
+        outgoing: Outgoing,
+        key_exchange: Option<KeyExchange>,
+        known_hosts: Arc<KnownHostsStore>,
         event_tx: mpsc::UnboundedSender<ClientEvent>,
         is_connected: Arc<RwLock<bool>>,
         is_authenticated: Arc<RwLock<bool>>,
+        negotiated: Arc<RwLock<Option<Capabilities>>>,
         config: Arc<RwLock<ClientConfig>>,
+        file_transfers: Option<Arc<FileTransferManager>>,
+        transfers: &mut HashSet<String>,
     ) -> Result<()> {
         // Split the WebSocket stream for concurrent read/write
         let (mut ws_sink, mut ws_stream_read) = ws_stream.split();
         
-        // Create channel for sending messages from reader to writer
-        let (write_tx, mut write_rx) = mpsc::unbounded_channel::<Message>();
-        
         // Start heartbeat and message writer with the sink
-        let heartbeat_tx = event_tx.clone();
+        let heartbeat_outbox = outbox.clone();
         let heartbeat_config = config.clone();
+        let throttle = file_transfers.clone();
+        let Outgoing { messages: mut write_rx, chunks: mut chunk_rx } = outgoing;
         tokio::spawn(async move {
-            let mut heartbeat_interval = tokio::time::interval(tokio::time::Duration::from_secs(30)); // Default interval
+            // Skip the immediate first tick, it would race the key exchange and go out unsealed
+            let period = tokio::time::Duration::from_secs(30); // Default interval
+            let mut heartbeat_interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            
+            // A file chunk waits for everything else, then for its share of the speed limit
+            let mut next_chunk: Option<(TransferMessage, WireFormat, tokio::time::Instant)> = None;
             
             loop {
+                let next_chunk_at = next_chunk.as_ref().map(|(_, _, at)| *at);
+                
                 tokio::select! {
+                    biased;
                     // Handle heartbeat
                     _ = heartbeat_interval.tick() => {
                         // Update interval if config changed
-                        let new_interval = {
+                        let (new_interval, wire_format) = {
                             let config = heartbeat_config.read().await;
-                            config.heartbeat_interval
+                            (config.heartbeat_interval, config.wire_format)
                         };
                         
                         let heartbeat_msg = ProtocolMessage {
@@ -142,12 +249,8 @@ impl RemoteDesktopClient {
                             timestamp: chrono::Utc::now(),
                         };
                         
-                        if let Ok(msg_text) = serde_json::to_string(&heartbeat_msg) {
-                            if ws_sink.send(Message::Text(msg_text)).await.is_err() {
-                                let _ = heartbeat_tx.send(ClientEvent::Error("Heartbeat failed".to_string()));
-                                break;
-                            }
-                        }
+                        // Queued rather than written directly so it is sealed in order
+                        let _ = heartbeat_outbox.send(WireMessage::Control(heartbeat_msg), wire_format);
                     },
                     // Handle messages from reader
                     Some(msg) = write_rx.recv() => {
@@ -155,33 +258,133 @@ impl RemoteDesktopClient {
                             break;
                         }
                     }
+                    Some((chunk, format)) = chunk_rx.recv(), if next_chunk.is_none() => {
+                        let delay = throttle.as_ref()
+                            .map(|file_transfers| file_transfers.reserve_bandwidth(&chunk))
+                            .unwrap_or_default();
+                        next_chunk = Some((chunk, format, tokio::time::Instant::now() + delay));
+                    }
+                    _ = tokio::time::sleep_until(next_chunk_at.unwrap_or_else(tokio::time::Instant::now)), if next_chunk_at.is_some() => {
+                        let (chunk, format, _) = next_chunk.take().unwrap();
+                        let messages = match heartbeat_outbox.seal_chunk(chunk.into(), format, &mut write_rx) {
+                            Ok(messages) => messages,
+                            Err(e) => {
+                                warn!("Dropping file chunk that could not be sealed: {}", e);
+                                continue;
+                            }
+                        };
+                        
+                        let mut closed = false;
+                        for msg in messages {
+                            closed = closed || ws_sink.send(msg).await.is_err();
+                        }
+                        if closed {
+                            break;
+                        }
+                    }
                     else => break,
                 }
             }
         });
         
+        let mut channel = ChannelState {
+            encrypt: key_exchange.is_some(),
+            pending: key_exchange,
+            opener: None,
+            known_hosts,
+        };
+        let mut reconstructor = FrameReconstructor::new();
+        
         while let Some(msg) = ws_stream_read.next().await {
-            match msg? {
-                Message::Text(text) => {
-                    debug!("Received text message: {}", text);
+            let msg = msg?;
+            
+            match msg {
+                Message::Text(_) | Message::Binary(_) => {
+                    let decoded = match channel.opener {
+                        Some(ref mut opener) => match msg {
+                            // A frame that fails to open means tampering or a desync
+                            Message::Binary(ref data) if is_encrypted(&msg) => Ok(Some(
+                                WireMessage::open(data, opener)
+                                    .map_err(|e| anyhow::anyhow!("Could not open frame from server: {}", e))?,
+                            )),
+                            _ => {
+                                warn!("Dropping plaintext message on encrypted session");
+                                continue;
+                            }
+                        },
+                        None => {
+                            let decoded = WireMessage::from_ws_message(&msg);
+                            let is_data = matches!(
+                                decoded,
+                                Ok(Some(WireMessage::ScreenFrame(_))) | Ok(Some(WireMessage::InputEvent(_))) | Ok(Some(WireMessage::FileChunk(_)))
+                            );
+                            if channel.encrypt && is_data {
+                                warn!("Dropping plaintext data before the session channel is ready");
+                                continue;
+                            }
+                            decoded
+                        }
+                    };
                     
-                    if let Ok(protocol_msg) = serde_json::from_str::<ProtocolMessage>(&text) {
-                        Self::handle_protocol_message(
-                            protocol_msg,
-                            &event_tx,
-                            &is_authenticated,
-                        ).await?;
-                    } else {
-                        warn!("Invalid protocol message: {}", text);
+                    match decoded {
+                        Ok(Some(WireMessage::ScreenFrame(frame))) => {
+                            debug!("Received screen frame #{} ({} bytes)", frame.sequence_number, frame.data.len());
+                            let sequence_number = frame.sequence_number;
+                            let wire_format = config.read().await.wire_format;
+                            
+                            // Acknowledged on arrival so the round trip measures the link, not decoding
+                            if let Err(e) = outbox.send(WireMessage::Control(ProtocolMessage::frame_ack(sequence_number)), wire_format) {
+                                warn!("Could not acknowledge frame #{}: {}", sequence_number, e);
+                            }
+                            
+                            let outcome = reconstructor.push(&frame);
+                            let _ = event_tx.send(ClientEvent::ScreenFrameReceived(frame));
+                            
+                            match outcome {
+                                Reconstructed::Frame(decoded) => {
+                                    let _ = event_tx.send(ClientEvent::FrameDecoded(decoded));
+                                }
+                                Reconstructed::Dropped { keyframe } => {
+                                    if let Some(request) = keyframe {
+                                        debug!("Requesting keyframe ({:?})", request.reason);
+                                        if let Err(e) = outbox.send(WireMessage::Control(ProtocolMessage::keyframe_request(request)), wire_format) {
+                                            warn!("Could not request a keyframe: {}", e);
+                                        }
+                                    }
+                                    let _ = event_tx.send(ClientEvent::FrameDropped(sequence_number));
+                                }
+                            }
+                        }
+                        Ok(Some(WireMessage::Control(protocol_msg))) if protocol_msg.message_type.is_file_transfer() => {
+                            let transfer = protocol_msg.to_file_transfer()?;
+                            Self::handle_file_transfer(transfer, &file_transfers, transfers, &outbox, &is_authenticated, &config).await?;
+                        }
+                        Ok(Some(WireMessage::Control(protocol_msg))) => {
+                            Self::handle_protocol_message(
+                                protocol_msg,
+                                &event_tx,
+                                &outbox,
+                                &mut channel,
+                                &is_authenticated,
+                                &negotiated,
+                                &config,
+                            ).await?;
+                        }
+                        Ok(Some(WireMessage::InputEvent(_))) => {
+                            debug!("Ignoring input event sent by server");
+                        }
+                        Ok(Some(WireMessage::FileChunk(chunk))) => {
+                            Self::handle_file_transfer(TransferMessage::Chunk(chunk), &file_transfers, transfers, &outbox, &is_authenticated, &config).await?;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("Invalid protocol message: {}", e);
+                        }
                     }
                 }
-                Message::Binary(data) => {
-                    debug!("Received binary message ({} bytes)", data.len());
-                    let _ = event_tx.send(ClientEvent::ScreenFrameReceived(data));
-                }
                 Message::Ping(payload) => {
                     debug!("Received ping");
-                    let _ = write_tx.send(Message::Pong(payload));
+                    let _ = outbox.send_raw(Message::Pong(payload));
                 }
                 Message::Pong(_) => {
                     debug!("Received pong");
@@ -202,14 +405,85 @@ impl RemoteDesktopClient {
     async fn handle_protocol_message(
         message: ProtocolMessage,
         event_tx: &mpsc::UnboundedSender<ClientEvent>,
+        outbox: &Outbox,
+        channel: &mut ChannelState,
         is_authenticated: &Arc<RwLock<bool>>,
+        negotiated: &Arc<RwLock<Option<Capabilities>>>,
+        config: &Arc<RwLock<ClientConfig>>,
     ) -> Result<()> {
         match message.message_type {
+            MessageType::HelloAck => {
+                let hello_ack = serde_json::from_value::<HelloAck>(message.data)?;
+                info!("Server accepted hello (protocol {})", hello_ack.protocol_version);
+                
+                *negotiated.write().await = Some(hello_ack.capabilities.clone());
+                let _ = event_tx.send(ClientEvent::CapabilitiesNegotiated(hello_ack.capabilities));
+                
+                if !channel.encrypt {
+                    let config = config.read().await;
+                    outbox.send(WireMessage::Control(Self::auth_request_message(&config)), config.wire_format)?;
+                }
+            }
+            MessageType::KeyExchangeReply => {
+                let reply = serde_json::from_value::<KeyExchangeReply>(message.data)?;
+                let exchange = channel.pending.take()
+                    .ok_or_else(|| anyhow::anyhow!("Unexpected key exchange reply"))?;
+                
+                // A bad signature means someone is sitting between us and the host
+                let (sealer, opener) = exchange.complete(&reply)?.into_split();
+                
+                let config = config.read().await;
+                let host_id = config.host_id.clone().unwrap_or_else(|| config.server_url.clone());
+                let host_fingerprint = fingerprint(&reply.identity_key);
+                
+                let status = channel.known_hosts.verify(&host_id, &reply.identity_key)?;
+                
+                if let HostKeyStatus::Mismatch { expected, presented } = status {
+                    error!("Identity of {} changed from {} to {}, aborting", host_id, expected, presented);
+                    let _ = event_tx.send(ClientEvent::HostKeyMismatch { expected, presented });
+                    let _ = outbox.send_raw(Message::Close(None));
+                    return Err(anyhow::anyhow!("Host identity mismatch for {}", host_id));
+                }
+                
+                outbox.set_sealer(sealer);
+                channel.opener = Some(opener);
+                
+                info!("Encrypted channel established with host {}", host_fingerprint);
+                let _ = event_tx.send(ClientEvent::EncryptionEstablished {
+                    fingerprint: host_fingerprint,
+                    first_use: status == HostKeyStatus::FirstUse,
+                });
+                
+                outbox.send(WireMessage::Control(Self::auth_request_message(&config)), config.wire_format)?;
+            }
+            MessageType::Error => {
+                let error_msg = serde_json::from_value::<ErrorMessage>(message.data)?;
+                error!("Server error {}: {}", error_msg.code, error_msg.message);
+                
+                match error_msg.code {
+                    ERROR_PROTOCOL_VERSION_MISMATCH
+                    | ERROR_NO_COMMON_CAPABILITIES
+                    | ERROR_HANDSHAKE_REQUIRED
+                    | ERROR_ENCRYPTION_REQUIRED => {
+                        let _ = event_tx.send(ClientEvent::NegotiationFailed(error_msg.message));
+                    }
+                    _ => {
+                        let _ = event_tx.send(ClientEvent::Error(error_msg.message));
+                    }
+                }
+            }
             MessageType::AuthResponse => {
                 debug!("Received auth response");
                 
                 if let Ok(success) = message.data.get("success").and_then(|v| v.as_bool()).ok_or("Missing success field") {
                     if success {
+                        // Keep the issued token so reconnects don't need the PIN again
+                        if let Some(token) = message.data.get("session_token").and_then(|v| v.as_str()) {
+                            let mut config = config.write().await;
+                            config.auth_token = Some(token.to_string());
+                            config.session_pin = None;
+                        }
+                        
                         *is_authenticated.write().await = true;
                         let _ = event_tx.send(ClientEvent::AuthenticationSuccess);
                         info!("Authentication successful");
@@ -222,12 +496,19 @@ impl RemoteDesktopClient {
                     }
                 }
             }
-            MessageType::ScreenFrame => {
-                debug!("Received screen frame message");
-                
-                if let Ok(frame) = serde_json::from_value::<ScreenFrame>(message.data) {
-                    let _ = event_tx.send(ClientEvent::ScreenFrameReceived(frame.data));
-                }
+            MessageType::ScreenInfo => {
+                let layout = serde_json::from_value::<ScreenLayout>(message.data)?;
+                debug!("Host has {} monitor(s), streaming {:?}", layout.screens.len(), layout.selected);
+                let _ = event_tx.send(ClientEvent::ScreenLayoutReceived(layout));
+            }
+            MessageType::CursorShape => {
+                let shape = serde_json::from_value::<CursorShape>(message.data)?;
+                debug!("Received cursor shape {} ({}x{})", shape.id, shape.width, shape.height);
+                let _ = event_tx.send(ClientEvent::CursorShapeReceived(shape));
+            }
+            MessageType::CursorPosition => {
+                let position = serde_json::from_value::<CursorPosition>(message.data)?;
+                let _ = event_tx.send(ClientEvent::CursorMoved(position));
             }
             MessageType::Heartbeat => {
                 debug!("Received heartbeat response");
@@ -240,6 +521,51 @@ impl RemoteDesktopClient {
         Ok(())
     }
     
+    /// Take a transfer step with the host, declining its offers if file transfer isn't enabled here
+    async fn handle_file_transfer(
+        transfer: TransferMessage,
+        file_transfers: &Option<Arc<FileTransferManager>>,
+        transfers: &mut HashSet<String>,
+        outbox: &Outbox,
+        is_authenticated: &Arc<RwLock<bool>>,
+        config: &Arc<RwLock<ClientConfig>>,
+    ) -> Result<()> {
+        if !*is_authenticated.read().await {
+            warn!("Ignoring file transfer before authentication");
+            return Ok(());
+        }
+        
+        let wire_format = config.read().await.wire_format;
+        let file_transfers = match file_transfers {
+            Some(file_transfers) => file_transfers,
+            None => {
+                if transfer.offered_name().is_some() {
+                    let response = TransferMessage::decline(transfer.transfer_id(), "File transfer is not enabled on this viewer");
+                    outbox.send(response.into(), wire_format)?;
+                }
+                return Ok(());
+            }
+        };
+        
+        let transfer_id = transfer.transfer_id().to_string();
+        transfers.insert(transfer_id.clone());
+        match file_transfers.handle_message(transfer).await {
+            Ok(replies) => {
+                // Including the next file of a directory, which is paused with the rest if the session drops
+                for reply in replies {
+                    transfers.insert(reply.transfer_id().to_string());
+                    match reply {
+                        TransferMessage::Chunk(_) => outbox.send_chunk(reply, wire_format)?,
+                        _ => outbox.send(reply.into(), wire_format)?,
+                    }
+                }
+            }
+            Err(e) => warn!("File transfer {} failed: {}", transfer_id, e),
+        }
+        
+        Ok(())
+    }
+    
     async fn heartbeat_loop_with_sink(
         mut ws_sink: SplitSink<WebSocket, Message>,
         event_tx: mpsc::UnboundedSender<ClientEvent>,
@@ -298,24 +624,35 @@ impl RemoteDesktopClient {
         }
     }
     
+    async fn send_wire_message(&self, message: WireMessage) -> Result<()> {
+        let outbox = self.outbox.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        
+        let wire_format = self.config.read().await.wire_format;
+        outbox.send(message, wire_format)
+    }
+    
+    async fn send_hello(&self) -> Result<()> {
+        let capabilities = self.config.read().await.capabilities.clone();
+        
+        debug!("Sending hello");
+        self.send_wire_message(WireMessage::Control(ProtocolMessage::hello(capabilities))).await
+    }
+    
+    fn auth_request_message(config: &ClientConfig) -> ProtocolMessage {
+        ProtocolMessage::auth_request(
+            config.username.clone(),
+            config.password.clone(),
+            config.auth_token.clone(),
+            config.session_pin.clone(),
+        )
+    }
+    
     pub async fn authenticate(&self) -> Result<()> {
-        if let Some(ref event_tx) = self.event_tx {
-            let auth_msg = ProtocolMessage {
-                id: Uuid::new_v4().to_string(),
-                message_type: MessageType::AuthRequest,
-                data: serde_json::json!({
-                    "token": self.config.read().await.auth_token
-                }),
-                timestamp: chrono::Utc::now(),
-            };
-            
-            let msg_text = serde_json::to_string(&auth_msg)?;
-            
-            // In a real implementation, send this through the WebSocket
-            debug!("Sending authentication request");
-        }
+        let auth_msg = Self::auth_request_message(&*self.config.read().await);
         
-        Ok(())
+        debug!("Sending authentication request");
+        self.send_wire_message(WireMessage::Control(auth_msg)).await
     }
     
     pub async fn request_screen_frame(&self) -> Result<()> {
@@ -330,10 +667,8 @@ impl RemoteDesktopClient {
             timestamp: chrono::Utc::now(),
         };
         
-        let msg_text = serde_json::to_string(&request_msg)?;
-        
-        // In a real implementation, send this through the WebSocket
         debug!("Requesting screen frame");
+        self.send_wire_message(WireMessage::Control(request_msg)).await?;
         
         if let Some(ref event_tx) = self.event_tx {
             let _ = event_tx.send(ClientEvent::InputEventSent);
@@ -342,22 +677,55 @@ impl RemoteDesktopClient {
         Ok(())
     }
     
-    pub async fn send_input_event(&self, input_event: InputEvent) -> Result<()> {
+    /// Ask for a keyframe; lost or corrupt frames already trigger one automatically
+    pub async fn request_keyframe(&self) -> Result<()> {
         if !*self.is_authenticated.read().await {
             return Err(anyhow::anyhow!("Not authenticated"));
         }
         
-        let input_msg = ProtocolMessage {
-            id: Uuid::new_v4().to_string(),
-            message_type: MessageType::InputEvent,
-            data: serde_json::to_value(input_event)?,
-            timestamp: chrono::Utc::now(),
-        };
+        debug!("Requesting keyframe");
+        self.send_wire_message(WireMessage::Control(ProtocolMessage::keyframe_request(KeyframeRequest::default()))).await
+    }
+    
+    /// Ask for the host's monitors; answered with `ScreenLayoutReceived`
+    pub async fn request_screen_info(&self) -> Result<()> {
+        if !*self.is_authenticated.read().await {
+            return Err(anyhow::anyhow!("Not authenticated"));
+        }
+        
+        let request_msg = ProtocolMessage::new(MessageType::ScreenInfo, serde_json::json!({}));
+        self.send_wire_message(WireMessage::Control(request_msg)).await
+    }
+    
+    /// Have the host fit frames into this window, or stream at full size with `None`
+    ///
+    /// Input positions stay in the pixels of the frames received; the host maps them back.
+    pub async fn set_viewport(&self, viewport: Option<Viewport>) -> Result<()> {
+        if !*self.is_authenticated.read().await {
+            return Err(anyhow::anyhow!("Not authenticated"));
+        }
+        
+        debug!("Setting viewport {:?}", viewport);
+        self.send_wire_message(WireMessage::Control(ProtocolMessage::viewport(viewport))).await
+    }
+    
+    /// Switch the streamed monitor; answered with `ScreenLayoutReceived`
+    pub async fn select_monitor(&self, selection: MonitorSelection) -> Result<()> {
+        if !*self.is_authenticated.read().await {
+            return Err(anyhow::anyhow!("Not authenticated"));
+        }
         
-        let msg_text = serde_json::to_string(&input_msg)?;
+        debug!("Selecting monitor {:?}", selection);
+        self.send_wire_message(WireMessage::Control(ProtocolMessage::select_monitor(selection))).await
+    }
+    
+    pub async fn send_input_event(&self, input_event: InputEvent) -> Result<()> {
+        if !*self.is_authenticated.read().await {
+            return Err(anyhow::anyhow!("Not authenticated"));
+        }
         
-        // In a real implementation, send this through the WebSocket
         debug!("Sending input event");
+        self.send_wire_message(WireMessage::InputEvent(input_event)).await?;
         
         if let Some(ref event_tx) = self.event_tx {
             let _ = event_tx.send(ClientEvent::InputEventSent);
@@ -366,14 +734,89 @@ impl RemoteDesktopClient {
         Ok(())
     }
     
+    /// Offer a file to the host, returning the transfer ID; progress arrives as `TransferEvent`s
+    pub async fn send_file(&self, path: &Path) -> Result<String> {
+        let file_transfers = self.file_transfers_with_host().await?;
+        
+        let request = file_transfers.send_file(path).await?;
+        let transfer_id = request.transfer_id.clone();
+        
+        debug!("Offering {} to host", request.file_name);
+        self.send_wire_message(TransferMessage::Offer(request).into()).await?;
+        
+        Ok(transfer_id)
+    }
+    
+    /// Offer a directory to the host, returning its transfer ID; its files follow one at a time
+    pub async fn send_directory(&self, path: &Path) -> Result<String> {
+        let file_transfers = self.file_transfers_with_host().await?;
+        
+        let request = file_transfers.send_directory(path).await?;
+        let directory_id = request.directory_id.clone();
+        
+        debug!("Offering directory {} to host", request.name);
+        self.send_wire_message(TransferMessage::DirectoryOffer(request).into()).await?;
+        
+        Ok(directory_id)
+    }
+    
+    /// The transfer manager, once the host has agreed to file transfer
+    async fn file_transfers_with_host(&self) -> Result<&Arc<FileTransferManager>> {
+        if !*self.is_authenticated.read().await {
+            return Err(anyhow::anyhow!("Not authenticated"));
+        }
+        
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let negotiated = self.negotiated.read().await.as_ref()
+            .map(|capabilities| capabilities.file_transfer)
+            .unwrap_or(false);
+        if !negotiated {
+            return Err(anyhow::anyhow!("Host does not support file transfer"));
+        }
+        
+        Ok(file_transfers)
+    }
+    
+    /// Stop a transfer in either direction and tell the host
+    pub async fn cancel_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        file_transfers.cancel_transfer(transfer_id).await?;
+        self.send_wire_message(TransferMessage::cancel(transfer_id, "Cancelled by the viewer").into()).await
+    }
+    
+    pub async fn pause_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let pause = file_transfers.pause_transfer(transfer_id).await?;
+        self.send_wire_message(pause.into()).await
+    }
+    
+    pub async fn resume_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let resume = file_transfers.resume_transfer(transfer_id).await?;
+        self.send_wire_message(resume.into()).await
+    }
+    
     pub async fn disconnect(&mut self) -> Result<()> {
         *self.is_connected.write().await = false;
         *self.is_authenticated.write().await = false;
+        *self.negotiated.write().await = None;
         
         if let Some(ref mut ws_stream) = self.ws_stream {
             ws_stream.close(None).await?;
         }
         
+        if let Some(outbox) = self.outbox.take() {
+            let _ = outbox.send_raw(Message::Close(None));
+        }
+        
         info!("Disconnected from remote desktop server");
         Ok(())
     }
@@ -385,4 +828,84 @@ impl RemoteDesktopClient {
     pub async fn is_authenticated(&self) -> bool {
         *self.is_authenticated.read().await
     }
-}
\ No newline at end of file
+    
+    /// Capabilities agreed with the server, once the hello exchange is done
+    pub async fn negotiated_capabilities(&self) -> Option<Capabilities> {
+        self.negotiated.read().await.clone()
+    }
+}
+/// Queue for the writer task that seals messages once the session channel is up
+#[derive(Clone)]
+struct Outbox {
+    write_tx: mpsc::UnboundedSender<Message>,
+    /// File chunks, sealed by the writer when their turn comes rather than when queued
+    chunk_tx: mpsc::UnboundedSender<(TransferMessage, WireFormat)>,
+    sealer: Arc<std::sync::Mutex<Option<ChannelSealer>>>,
+}
+
+/// What the writer task sends: messages ready for the wire, and file chunks to fit in around them
+struct Outgoing {
+    messages: mpsc::UnboundedReceiver<Message>,
+    chunks: mpsc::UnboundedReceiver<(TransferMessage, WireFormat)>,
+}
+
+impl Outbox {
+    fn new(write_tx: mpsc::UnboundedSender<Message>, chunk_tx: mpsc::UnboundedSender<(TransferMessage, WireFormat)>) -> Self {
+        Self {
+            write_tx,
+            chunk_tx,
+            sealer: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+    
+    /// Queue a file chunk behind everything else
+    fn send_chunk(&self, chunk: TransferMessage, format: WireFormat) -> Result<()> {
+        self.chunk_tx.send((chunk, format))
+            .map_err(|_| anyhow::anyhow!("Connection writer closed"))
+    }
+    
+    /// Seal a chunk as it goes out. Messages already queued were sealed before it, so they are
+    /// taken off the queue to go first and counters still reach the wire in order.
+    fn seal_chunk(&self, chunk: WireMessage, format: WireFormat, queued: &mut mpsc::UnboundedReceiver<Message>) -> Result<Vec<Message>> {
+        let mut sealer = self.sealer.lock().unwrap();
+        let sealed = match sealer.as_mut() {
+            Some(sealer) => chunk.seal(sealer)?,
+            None => chunk.to_ws_message(format)?,
+        };
+        
+        let mut messages = Vec::new();
+        while let Ok(message) = queued.try_recv() {
+            messages.push(message);
+        }
+        messages.push(sealed);
+        Ok(messages)
+    }
+    
+    fn send(&self, message: WireMessage, format: WireFormat) -> Result<()> {
+        // Hold the lock until queued so sealed counters reach the wire in order
+        let mut sealer = self.sealer.lock().unwrap();
+        let ws_message = match sealer.as_mut() {
+            Some(sealer) => message.seal(sealer)?,
+            None => message.to_ws_message(format)?,
+        };
+        
+        self.send_raw(ws_message)
+    }
+    
+    fn send_raw(&self, message: Message) -> Result<()> {
+        self.write_tx.send(message)
+            .map_err(|_| anyhow::anyhow!("Connection writer closed"))
+    }
+    
+    fn set_sealer(&self, sealer: ChannelSealer) {
+        *self.sealer.lock().unwrap() = Some(sealer);
+    }
+}
+
+/// Reader-side half of the session channel
+struct ChannelState {
+    encrypt: bool,
+    pending: Option<KeyExchange>,
+    opener: Option<ChannelOpener>,
+    known_hosts: Arc<KnownHostsStore>,
+}
@@ -1,13 +1,23 @@
 use anyhow::Result;
 use log::{info, error, debug, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::{RwLock, mpsc};
 
-use crate::network::p2p::{P2PManager, P2PConnectionStatus};
-use crate::network::relay_client::{RelayClient, RelayConfig, RelayClientEvent};
+use crate::network::p2p::{P2PManager, P2PEvent};
+use crate::network::protocol::InputEvent;
+use crate::network::relay_client::{RelayClient, RelayConfig, RelayClientEvent, RelayMessageType, RelayPacket};
+use crate::network::wire::WireMessage;
+use crate::permissions::{Permission, PermissionManager};
+use crate::security::{ClientCredentials, SecurityManager};
+use crate::utils::file_transfer::{FileTransferManager, TransferMessage};
 use crate::utils::id_generator::{IdGenerator, ConnectionId};
 
+/// Transfer ID -> the peer it is with
+type TransferOwners = Arc<RwLock<HashMap<String, String>>>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionConfig {
     pub p2p_enabled: bool,
@@ -67,6 +77,12 @@ pub struct ConnectionManager {
     current_connection_id: Arc<RwLock<Option<ConnectionId>>>,
     connection_status: Arc<RwLock<ConnectionStatus>>,
     event_sender: Arc<RwLock<Option<mpsc::UnboundedSender<ConnectionEvent>>>>,
+    peer_id: Arc<RwLock<Option<String>>>,
+    file_transfers: Arc<RwLock<Option<Arc<FileTransferManager>>>>,
+    permissions: Arc<RwLock<Option<Arc<PermissionManager>>>>,
+    security: Arc<RwLock<Option<Arc<SecurityManager>>>>,
+    /// Messages for a transfer are only taken from the peer it was offered to or by
+    transfer_owners: TransferOwners,
 }
 
 impl ConnectionManager {
@@ -79,9 +95,29 @@ impl ConnectionManager {
             current_connection_id: Arc::new(RwLock::new(None)),
             connection_status: Arc::new(RwLock::new(ConnectionStatus::Disconnected)),
             event_sender: Arc::new(RwLock::new(None)),
+            peer_id: Arc::new(RwLock::new(None)),
+            file_transfers: Arc::new(RwLock::new(None)),
+            permissions: Arc::new(RwLock::new(None)),
+            security: Arc::new(RwLock::new(None)),
+            transfer_owners: Arc::new(RwLock::new(HashMap::new())),
         }
     }
     
+    /// Enable file transfer with peers
+    pub async fn set_file_transfer_manager(&self, file_transfers: Arc<FileTransferManager>) {
+        *self.file_transfers.write().await = Some(file_transfers);
+    }
+    
+    /// Gate incoming file offers on `Permission::FileTransfer`
+    pub async fn set_permission_manager(&self, permissions: Arc<PermissionManager>) {
+        *self.permissions.write().await = Some(permissions);
+    }
+    
+    /// Check relay viewers' credentials before their requests reach the permission manager
+    pub async fn set_security_manager(&self, security: Arc<SecurityManager>) {
+        *self.security.write().await = Some(security);
+    }
+    
     pub async fn initialize(&self) -> Result<mpsc::UnboundedReceiver<ConnectionEvent>> {
         info!("Initializing connection manager");
         
@@ -104,9 +140,38 @@ impl ConnectionManager {
         // Initialize P2P manager if enabled
         let config = self.config.read().await;
         if config.p2p_enabled {
-            let mut p2p_manager = P2PManager::new();
+            let p2p_manager = P2PManager::new()?;
             p2p_manager.start_discovery().await?;
             
+            let mut p2p_events = p2p_manager.register_event_listener("connection-manager".to_string()).await;
+            let p2p_manager_ref = self.p2p_manager.clone();
+            let file_transfers = self.file_transfers.clone();
+            let permissions = self.permissions.clone();
+            let transfer_owners = self.transfer_owners.clone();
+            tokio::spawn(async move {
+                while let Some(event) = p2p_events.recv().await {
+                    if let P2PEvent::MessageReceived(peer_id, message) = event {
+                        let replies = match message.file_transfer() {
+                            Some(Ok(transfer)) => Self::handle_file_transfer(&peer_id, transfer, &file_transfers, &permissions, &transfer_owners).await,
+                            Some(Err(e)) => {
+                                warn!("Malformed file transfer message from {}: {}", peer_id, e);
+                                continue;
+                            }
+                            None => continue,
+                        };
+                        
+                        if let Some(p2p_manager) = p2p_manager_ref.read().await.as_ref() {
+                            for reply in replies {
+                                let send_at = Self::transfer_send_at(&reply, &file_transfers).await;
+                                if let Err(e) = p2p_manager.send_transfer_to_peer(&peer_id, reply.into(), send_at).await {
+                                    warn!("Failed to send file transfer reply to {}: {}", peer_id, e);
+                                }
+                            }
+                        }
+                    }
+                }
+            });
+            
             let mut p2p_manager_lock = self.p2p_manager.write().await;
             *p2p_manager_lock = Some(p2p_manager);
             
@@ -115,7 +180,10 @@ impl ConnectionManager {
         
         // Initialize relay client if enabled
         if config.relay_enabled {
-            let relay_client = RelayClient::new(config.relay_config.clone());
+            let mut relay_client = RelayClient::new(config.relay_config.clone())?;
+            if let Some(security) = self.security.read().await.clone() {
+                relay_client.set_security_manager(security);
+            }
             
             let mut relay_client_lock = self.relay_client.write().await;
             *relay_client_lock = Some(relay_client);
@@ -172,6 +240,10 @@ impl ConnectionManager {
                 // Handle relay events in background
                 let event_sender = self.event_sender.clone();
                 let connection_status = self.connection_status.clone();
+                let relay_client_ref = self.relay_client.clone();
+                let file_transfers = self.file_transfers.clone();
+                let permissions = self.permissions.clone();
+                let transfer_owners = self.transfer_owners.clone();
                 tokio::spawn(async move {
                     while let Some(event) = relay_events.recv().await {
                         match event {
@@ -194,14 +266,34 @@ impl ConnectionManager {
                             RelayClientEvent::ConnectionRequest(request) => {
                                 info!("Received connection request via relay: {}", request.client_info.name);
                                 
+                                let from_id = match request.source_id {
+                                    Some(from_id) => from_id,
+                                    None => {
+                                        warn!("Dropping relay connection request without a source ID");
+                                        continue;
+                                    }
+                                };
+                                
                                 if let Some(sender) = event_sender.read().await.as_ref() {
                                     let _ = sender.send(ConnectionEvent::ConnectionRequest {
-                                        from_id: request.target_connection_id,
+                                        from_id,
                                         device_name: request.client_info.name,
                                         requires_permission: true,
                                     });
                                 }
                             }
+                            RelayClientEvent::PacketReceived(packet) if matches!(packet.message_type, RelayMessageType::FileTransfer) => {
+                                Self::handle_relay_file_transfer(packet, &relay_client_ref, &file_transfers, &permissions, &transfer_owners).await;
+                            }
+                            RelayClientEvent::PacketReceived(packet) => {
+                                if let Some(sender) = event_sender.read().await.as_ref() {
+                                    let _ = sender.send(ConnectionEvent::DataReceived {
+                                        from_id: packet.source_id.unwrap_or_default(),
+                                        data_type: format!("{:?}", packet.message_type),
+                                        data: packet.payload,
+                                    });
+                                }
+                            }
                             RelayClientEvent::Error(error) => {
                                 error!("Relay client error: {}", error);
                                 
@@ -228,7 +320,8 @@ impl ConnectionManager {
         Ok(connection_id.formatted_id)
     }
     
-    pub async fn connect_to_host(&self, target_connection_id: String) -> Result<()> {
+    /// Connect to a host by its connection ID. A relay connection needs `credentials`.
+    pub async fn connect_to_host(&self, target_connection_id: String, credentials: Option<ClientCredentials>) -> Result<()> {
         info!("Attempting to connect to host: {}", target_connection_id);
         
         // Update status
@@ -240,31 +333,13 @@ impl ConnectionManager {
         // Try P2P first if enabled
         if config.p2p_enabled {
             if let Some(p2p_manager) = self.p2p_manager.read().await.as_ref() {
-                // Create ConnectionId from string
-                let connection_id = ConnectionId {
-                    id: target_connection_id.clone(),
-                    numeric_id: target_connection_id.replace(" ", "").parse::<u32>().unwrap_or(0),
-                    formatted_id: target_connection_id.clone(),
-                };
-                match p2p_manager.connect_to_peer(connection_id).await {
-                    Ok(status) => {
-                        match status {
-                            P2PConnectionStatus::Connected => {
-                                info!("P2P connection established");
-                                self.update_status(ConnectionStatus::Connected(ConnectionType::P2P)).await;
-                                connection_established = true;
-                            }
-                            P2PConnectionStatus::Failed => {
-                                warn!("P2P connection failed");
-                                
-                                if !config.auto_fallback_to_relay {
-                                    return Err(anyhow::anyhow!("P2P connection failed"));
-                                }
-                            }
-                            _ => {
-                                debug!("P2P connection in progress");
-                            }
-                        }
+                match p2p_manager.connect_to_host(&target_connection_id, None).await {
+                    Ok(p2p_connection_id) => {
+                        // The P2P connection's own ID is what its messages arrive under
+                        info!("P2P connection established");
+                        *self.peer_id.write().await = Some(p2p_connection_id);
+                        self.update_status(ConnectionStatus::Connected(ConnectionType::P2P)).await;
+                        connection_established = true;
                     }
                     Err(e) => {
                         warn!("P2P connection error: {}", e);
@@ -280,9 +355,31 @@ impl ConnectionManager {
         // Fallback to relay if P2P failed and relay is enabled
         if !connection_established && config.relay_enabled {
             if let Some(relay_client) = self.relay_client.write().await.as_mut() {
+                let credentials = credentials
+                    .ok_or_else(|| anyhow::anyhow!("Connecting through the relay needs a PIN, password or token"))?;
+                
                 // Connect to relay server if not already connected
                 if !relay_client.is_connected().await {
-                    let _relay_events = relay_client.connect().await?;
+                    let mut relay_events = relay_client.connect().await?;
+                    
+                    // Answer the host's side of file transfers in background
+                    let relay_client_ref = self.relay_client.clone();
+                    let file_transfers = self.file_transfers.clone();
+                    let permissions = self.permissions.clone();
+                    let transfer_owners = self.transfer_owners.clone();
+                    tokio::spawn(async move {
+                        while let Some(event) = relay_events.recv().await {
+                            match event {
+                                RelayClientEvent::PacketReceived(packet) if matches!(packet.message_type, RelayMessageType::FileTransfer) => {
+                                    Self::handle_relay_file_transfer(packet, &relay_client_ref, &file_transfers, &permissions, &transfer_owners).await;
+                                }
+                                RelayClientEvent::Error(error) => {
+                                    error!("Relay client error: {}", error);
+                                }
+                                _ => {}
+                            }
+                        }
+                    });
                     
                     let connection_id = {
                         let current_id = self.current_connection_id.read().await;
@@ -293,7 +390,8 @@ impl ConnectionManager {
                 }
                 
                 // Request connection to target
-                relay_client.connect_to_peer(target_connection_id).await?;
+                relay_client.connect_to_peer(target_connection_id.clone(), credentials).await?;
+                *self.peer_id.write().await = Some(target_connection_id);
                 
                 info!("Relay connection request sent");
                 self.update_status(ConnectionStatus::Connected(ConnectionType::Relay)).await;
@@ -327,28 +425,29 @@ impl ConnectionManager {
             }
         }
         
+        *self.peer_id.write().await = None;
+        self.transfer_owners.write().await.clear();
+        
         // Update status
         self.update_status(ConnectionStatus::Disconnected).await;
         
         Ok(())
     }
     
+    /// Send a frame to the connected relay peer
     pub async fn send_screen_frame(&self, frame_data: Vec<u8>) -> Result<()> {
         let status = self.connection_status.read().await;
         
         match status.clone() {
             ConnectionStatus::Connected(ConnectionType::P2P) => {
-                if let Some(p2p_manager) = self.p2p_manager.read().await.as_ref() {
-                    // P2P screen frame sending would be implemented here
-                    debug!("Sending screen frame via P2P ({} bytes)", frame_data.len());
-                }
+                // A P2P peer is sent its frames by the host server it connected to
+                return Err(anyhow::anyhow!("Screen frames can't be sent over a P2P connection"));
             }
             ConnectionStatus::Connected(ConnectionType::Relay) => {
-                if let Some(relay_client) = self.relay_client.read().await.as_ref() {
-                    // Get target ID from current connection context
-                    // This would be tracked in a real implementation
-                    let target_id = "target_connection_id".to_string(); // Placeholder
-                    relay_client.send_screen_frame(target_id, frame_data).await?;
+                let peer_id = self.connected_peer().await?;
+                match self.relay_client.read().await.as_ref() {
+                    Some(relay_client) => relay_client.send_screen_frame(peer_id, frame_data).await?,
+                    None => return Err(anyhow::anyhow!("Relay client not initialized")),
                 }
             }
             _ => {
@@ -359,21 +458,39 @@ impl ConnectionManager {
         Ok(())
     }
     
-    pub async fn send_input_event(&self, input_data: serde_json::Value) -> Result<()> {
+    /// The peer `connect_to_host` reached
+    async fn connected_peer(&self) -> Result<String> {
+        self.peer_id.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("No peer to send to"))
+    }
+    
+    /// Send a frame to one relay peer, such as each viewer a host has granted screen view
+    pub async fn send_screen_frame_to(&self, peer_id: &str, frame_data: Vec<u8>) -> Result<()> {
+        match self.relay_client.read().await.as_ref() {
+            Some(relay_client) => relay_client.send_screen_frame(peer_id.to_string(), frame_data).await,
+            None => Err(anyhow::anyhow!("Relay client not initialized")),
+        }
+    }
+    
+    pub async fn send_input_event(&self, input_event: InputEvent) -> Result<()> {
         let status = self.connection_status.read().await;
         
         match status.clone() {
             ConnectionStatus::Connected(ConnectionType::P2P) => {
-                if let Some(p2p_manager) = self.p2p_manager.read().await.as_ref() {
-                    // P2P input event sending would be implemented here
-                    debug!("Sending input event via P2P");
+                let peer_id = self.connected_peer().await?;
+                match self.p2p_manager.read().await.as_ref() {
+                    Some(p2p_manager) => {
+                        debug!("Sending input event via P2P");
+                        p2p_manager.send_to_peer(&peer_id, &WireMessage::InputEvent(input_event)).await?;
+                    }
+                    None => return Err(anyhow::anyhow!("P2P manager not initialized")),
                 }
             }
             ConnectionStatus::Connected(ConnectionType::Relay) => {
-                if let Some(relay_client) = self.relay_client.read().await.as_ref() {
-                    // Get target ID from current connection context
-                    let target_id = "target_connection_id".to_string(); // Placeholder
-                    relay_client.send_input_event(target_id, input_data).await?;
+                let peer_id = self.connected_peer().await?;
+                match self.relay_client.read().await.as_ref() {
+                    Some(relay_client) => relay_client.send_input_event(peer_id, input_event).await?,
+                    None => return Err(anyhow::anyhow!("Relay client not initialized")),
                 }
             }
             _ => {
@@ -384,6 +501,171 @@ impl ConnectionManager {
         Ok(())
     }
     
+    /// Offer a file to the connected peer, returning the transfer ID
+    pub async fn send_file(&self, path: &Path) -> Result<String> {
+        let file_transfers = self.file_transfers.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let request = file_transfers.send_file(path).await?;
+        self.send_offer(&file_transfers, TransferMessage::Offer(request)).await
+    }
+    
+    /// Offer a directory to the connected peer, returning the directory's transfer ID
+    pub async fn send_directory(&self, path: &Path) -> Result<String> {
+        let file_transfers = self.file_transfers.read().await.clone()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let request = file_transfers.send_directory(path).await?;
+        self.send_offer(&file_transfers, TransferMessage::DirectoryOffer(request)).await
+    }
+    
+    /// Send an offer over the current connection, cancelling its transfer if it can't go
+    async fn send_offer(&self, file_transfers: &FileTransferManager, offer: TransferMessage) -> Result<String> {
+        let status = self.connection_status.read().await.clone();
+        let peer_id = self.peer_id.read().await.clone();
+        
+        let transfer_id = offer.transfer_id().to_string();
+        let message = WireMessage::from(offer);
+        
+        let sent = match status {
+            ConnectionStatus::Connected(ConnectionType::P2P) => {
+                match (self.p2p_manager.read().await.as_ref(), peer_id.as_deref()) {
+                    (Some(p2p_manager), Some(peer_id)) => {
+                        p2p_manager.send_transfer_to_peer(peer_id, message, tokio::time::Instant::now()).await
+                    }
+                    (None, _) => Err(anyhow::anyhow!("P2P manager not initialized")),
+                    (_, None) => Err(anyhow::anyhow!("No P2P peer to send to")),
+                }
+            }
+            ConnectionStatus::Connected(ConnectionType::Relay) => {
+                match (self.relay_client.read().await.as_ref(), peer_id.clone()) {
+                    (Some(relay_client), Some(peer_id)) => {
+                        relay_client.send_file_transfer(peer_id, message.encode()?, tokio::time::Instant::now()).await
+                    }
+                    (None, _) => Err(anyhow::anyhow!("Relay client not initialized")),
+                    (_, None) => Err(anyhow::anyhow!("No relay peer to send to")),
+                }
+            }
+            _ => Err(anyhow::anyhow!("No active connection to send file")),
+        };
+        
+        if let Err(e) = sent {
+            let _ = file_transfers.cancel_transfer(&transfer_id).await;
+            return Err(e);
+        }
+        
+        if let Some(peer_id) = peer_id {
+            self.transfer_owners.write().await.insert(transfer_id.clone(), peer_id);
+        }
+        
+        Ok(transfer_id)
+    }
+    
+    /// Run a peer's file transfer message through the manager, returning the replies
+    async fn handle_file_transfer(
+        peer_id: &str,
+        transfer: TransferMessage,
+        file_transfers: &Arc<RwLock<Option<Arc<FileTransferManager>>>>,
+        permissions: &Arc<RwLock<Option<Arc<PermissionManager>>>>,
+        transfer_owners: &TransferOwners,
+    ) -> Vec<TransferMessage> {
+        let file_transfers = file_transfers.read().await.clone();
+        let transfer_id = transfer.transfer_id().to_string();
+        let owner = transfer_owners.read().await.get(&transfer_id).cloned();
+        
+        if let Some(name) = transfer.offered_name() {
+            if owner.is_some_and(|owner| owner != peer_id) {
+                warn!("Ignoring offer of {} from {}, whose transfer ID is already in use", name, peer_id);
+                return Vec::new();
+            }
+            
+            let declined = match (&file_transfers, permissions.read().await.as_ref()) {
+                (None, _) => Some("File transfer is not enabled"),
+                (Some(_), None) => Some("File transfer permission not granted"),
+                (Some(_), Some(permissions)) if !permissions.is_allowed(peer_id, &Permission::FileTransfer).await => {
+                    Some("File transfer permission not granted")
+                }
+                _ => None,
+            };
+            
+            if let Some(reason) = declined {
+                info!("Declining file offer {} from {}: {}", name, peer_id, reason);
+                return vec![TransferMessage::decline(&transfer_id, reason)];
+            }
+            transfer_owners.write().await.insert(transfer_id.clone(), peer_id.to_string());
+        } else if owner.as_deref() != Some(peer_id) {
+            warn!("Ignoring file transfer {} that {} is not part of", transfer_id, peer_id);
+            return Vec::new();
+        }
+        
+        let file_transfers = match file_transfers {
+            Some(file_transfers) => file_transfers,
+            None => return Vec::new(),
+        };
+        
+        let replies = file_transfers.handle_message(transfer).await.unwrap_or_else(|e| {
+            warn!("File transfer {} with {} failed: {}", transfer_id, peer_id, e);
+            Vec::new()
+        });
+        
+        // Replies may offer the next file of a directory, which is then with this peer too
+        let mut transfer_owners = transfer_owners.write().await;
+        for reply in replies.iter().filter(|reply| reply.offered_name().is_some()) {
+            transfer_owners.insert(reply.transfer_id().to_string(), peer_id.to_string());
+        }
+        replies
+    }
+    
+    async fn handle_relay_file_transfer(
+        packet: RelayPacket,
+        relay_client: &Arc<RwLock<Option<RelayClient>>>,
+        file_transfers: &Arc<RwLock<Option<Arc<FileTransferManager>>>>,
+        permissions: &Arc<RwLock<Option<Arc<PermissionManager>>>>,
+        transfer_owners: &TransferOwners,
+    ) {
+        let peer_id = match packet.source_id {
+            Some(peer_id) => peer_id,
+            None => {
+                warn!("Dropping file transfer relay packet without a source ID");
+                return;
+            }
+        };
+        
+        let transfer = match WireMessage::decode(&packet.payload).map(|message| message.file_transfer()) {
+            Ok(Some(Ok(transfer))) => transfer,
+            Ok(None) => {
+                warn!("Dropping non file transfer payload from {}", peer_id);
+                return;
+            }
+            Ok(Some(Err(e))) | Err(e) => {
+                warn!("Malformed file transfer packet from {}: {}", peer_id, e);
+                return;
+            }
+        };
+        
+        let replies = Self::handle_file_transfer(&peer_id, transfer, file_transfers, permissions, transfer_owners).await;
+        if let Some(relay_client) = relay_client.read().await.as_ref() {
+            for reply in replies {
+                let send_at = Self::transfer_send_at(&reply, file_transfers).await;
+                let sent = match WireMessage::from(reply).encode() {
+                    Ok(payload) => relay_client.send_file_transfer(peer_id.clone(), payload, send_at).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    warn!("Failed to send file transfer reply to {}: {}", peer_id, e);
+                }
+            }
+        }
+    }
+    
+    /// When a reply may go; a file chunk waits for its share of the transfer speed limit
+    async fn transfer_send_at(reply: &TransferMessage, file_transfers: &Arc<RwLock<Option<Arc<FileTransferManager>>>>) -> tokio::time::Instant {
+        let delay = file_transfers.read().await.as_ref()
+            .map(|file_transfers| file_transfers.reserve_bandwidth(reply))
+            .unwrap_or_default();
+        tokio::time::Instant::now() + delay
+    }
+    
     pub async fn get_connection_status(&self) -> ConnectionStatus {
         self.connection_status.read().await.clone()
     }
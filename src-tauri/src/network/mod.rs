@@ -3,18 +3,28 @@ pub mod client;
 pub mod protocol;
 pub mod p2p;
 pub mod relay_client;
+pub mod relay_server;
 pub mod connection_manager;
 pub mod discovery;
 pub mod connection_requests;
+pub mod wire;
+pub mod negotiation;
+pub mod reconstructor;
 
 use anyhow::Result;
 use log::{info, error, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::{RwLock, mpsc};
 use uuid::Uuid;
 
+use crate::permissions::PermissionManager;
+use crate::security::SecurityManager;
+use crate::streaming::StreamingConfig;
+use crate::utils::file_transfer::FileTransferManager;
+
 pub use discovery::*;
 pub use connection_requests::{IncomingConnectionRequest, ConnectionRequestResponse, ConnectionRequestManager};
 
@@ -66,6 +76,10 @@ pub struct NetworkManager {
     discovery: Option<Arc<NetworkDiscovery>>,
     device_updates_rx: Option<mpsc::UnboundedReceiver<Vec<DiscoveredDevice>>>,
     connection_requests: Option<Arc<ConnectionRequestManager>>,
+    security: Option<Arc<SecurityManager>>,
+    permissions: Option<Arc<PermissionManager>>,
+    file_transfers: Option<Arc<FileTransferManager>>,
+    streaming_config: StreamingConfig,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -85,17 +99,54 @@ impl NetworkManager {
             discovery: None,
             device_updates_rx: None,
             connection_requests: None,
+            security: None,
+            permissions: None,
+            file_transfers: None,
+            streaming_config: StreamingConfig::default(),
+        }
+    }
+    
+    /// Share a security manager so PINs and passwords set on the host apply to the server
+    pub fn set_security_manager(&mut self, security: Arc<SecurityManager>) {
+        self.security = Some(security);
+    }
+    
+    /// Gate viewers' file offers on the host's permission grants
+    pub fn set_permission_manager(&mut self, permissions: Arc<PermissionManager>) {
+        self.permissions = Some(permissions);
+    }
+    
+    /// Enable file transfer with viewers of the host server
+    pub fn set_file_transfer_manager(&mut self, file_transfers: Arc<FileTransferManager>) {
+        self.file_transfers = Some(file_transfers);
+    }
+    
+    /// Quality, frame rate and bandwidth budget of viewers' streams; applies to viewers that connect afterwards
+    pub async fn set_streaming_config(&mut self, config: StreamingConfig) {
+        if let Some(ref server) = self.server {
+            server.set_streaming_config(config.clone()).await;
         }
+        self.streaming_config = config;
     }
     
-    pub async fn start_host_server(&self) -> Result<String> {
+    pub async fn start_host_server(&mut self) -> Result<String> {
         let config = self.config.read().await;
         let port = config.server_port;
         drop(config);
         
         info!("Starting host server on port {}", port);
         
-        let server = RemoteDesktopServer::new(port).await?;
+        let mut server = match self.security {
+            Some(ref security) => RemoteDesktopServer::with_security(port, security.clone()),
+            None => RemoteDesktopServer::new(port).await?,
+        };
+        if let Some(ref permissions) = self.permissions {
+            server.set_permission_manager(permissions.clone());
+        }
+        if let Some(ref file_transfers) = self.file_transfers {
+            server.set_file_transfer_manager(file_transfers.clone());
+        }
+        server.set_streaming_config(self.streaming_config.clone()).await;
         let session_id = Uuid::new_v4().to_string();
         
         // Store session info
@@ -111,6 +162,7 @@ impl NetworkManager {
         // Start server in background
         let active_sessions = self.active_sessions.clone();
         let server_arc = Arc::new(server);
+        self.server = Some(server_arc.clone());
         
         tokio::spawn(async move {
             if let Err(e) = server_arc.start().await {
@@ -122,6 +174,43 @@ impl NetworkManager {
         Ok(session_id)
     }
     
+    /// Offer a file to a viewer of the host server, returning the transfer ID
+    pub async fn send_file(&self, client_id: &str, path: &Path) -> Result<String> {
+        match self.server {
+            Some(ref server) => server.send_file(client_id, path).await,
+            None => Err(anyhow::anyhow!("Host server not started")),
+        }
+    }
+    
+    /// Offer a directory to a viewer of the host server, returning its transfer ID
+    pub async fn send_directory(&self, client_id: &str, path: &Path) -> Result<String> {
+        match self.server {
+            Some(ref server) => server.send_directory(client_id, path).await,
+            None => Err(anyhow::anyhow!("Host server not started")),
+        }
+    }
+    
+    pub async fn cancel_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        match self.server {
+            Some(ref server) => server.cancel_file_transfer(transfer_id).await,
+            None => Err(anyhow::anyhow!("Host server not started")),
+        }
+    }
+    
+    pub async fn pause_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        match self.server {
+            Some(ref server) => server.pause_file_transfer(transfer_id).await,
+            None => Err(anyhow::anyhow!("Host server not started")),
+        }
+    }
+    
+    pub async fn resume_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        match self.server {
+            Some(ref server) => server.resume_file_transfer(transfer_id).await,
+            None => Err(anyhow::anyhow!("Host server not started")),
+        }
+    }
+    
     pub async fn connect_to_host(&self, request: ConnectionRequest) -> Result<ConnectionResponse> {
         info!("Attempting to connect to session: {}", request.session_id);
         
@@ -0,0 +1,178 @@
+//! Protocol version and capability negotiation.
+//!
+//! The viewer opens every connection with a `Hello` advertising what it can
+//! handle. The host intersects that with its own capabilities and answers
+//! with a `HelloAck`, or refuses with an `ErrorMessage` when the versions are
+//! incompatible or nothing usable is left.
+
+use super::protocol::{
+    Capabilities, ErrorMessage, FrameFormat, Hello, InputFeature, ERROR_NO_COMMON_CAPABILITIES,
+    ERROR_PROTOCOL_VERSION_MISMATCH, PROTOCOL_VERSION,
+};
+use crate::codec;
+
+pub const MAX_SUPPORTED_WIDTH: u32 = 3840;
+pub const MAX_SUPPORTED_HEIGHT: u32 = 2160;
+
+impl Capabilities {
+    /// Capabilities of this build, with the frame formats from the codec registry
+    pub fn local() -> Self {
+        Self {
+            image_formats: codec::available_formats(),
+            max_width: MAX_SUPPORTED_WIDTH,
+            max_height: MAX_SUPPORTED_HEIGHT,
+            input_features: vec![
+                InputFeature::Mouse,
+                InputFeature::Scroll,
+                InputFeature::Keyboard,
+                InputFeature::TextInput,
+            ],
+            file_transfer: true,
+            clipboard: false,
+            cursor: true,
+        }
+    }
+
+    /// Settle on what both sides support, keeping our order of preference
+    pub fn negotiate(&self, remote: &Capabilities) -> Result<Capabilities, ErrorMessage> {
+        let image_formats: Vec<FrameFormat> = self.image_formats.iter()
+            .filter(|format| remote.image_formats.contains(format))
+            .copied()
+            .collect();
+
+        if image_formats.is_empty() {
+            return Err(no_common_capabilities("image format", &self.image_formats, &remote.image_formats));
+        }
+
+        let input_features = self.input_features.iter()
+            .filter(|feature| remote.input_features.contains(feature))
+            .copied()
+            .collect();
+
+        Ok(Capabilities {
+            image_formats,
+            max_width: self.max_width.min(remote.max_width),
+            max_height: self.max_height.min(remote.max_height),
+            input_features,
+            file_transfer: self.file_transfer && remote.file_transfer,
+            clipboard: self.clipboard && remote.clipboard,
+            cursor: self.cursor && remote.cursor,
+        })
+    }
+
+    /// Flat feature list for `ClientInfo`/`AuthResponse`
+    pub fn feature_names(&self) -> Vec<String> {
+        let mut names = vec!["screen_capture".to_string()];
+
+        if !self.input_features.is_empty() {
+            names.push("input_forwarding".to_string());
+        }
+        if self.file_transfer {
+            names.push("file_transfer".to_string());
+        }
+        if self.clipboard {
+            names.push("clipboard".to_string());
+        }
+        if self.cursor {
+            names.push("cursor".to_string());
+        }
+
+        names
+    }
+}
+
+/// Versions are compatible when their major components match
+pub fn is_version_compatible(local: &str, remote: &str) -> bool {
+    let major = |version: &str| version.split('.').next().and_then(|part| part.parse::<u32>().ok());
+
+    match (major(local), major(remote)) {
+        (Some(local_major), Some(remote_major)) => local_major == remote_major,
+        _ => false,
+    }
+}
+
+/// Host side of the handshake
+pub fn negotiate_hello(local: &Capabilities, hello: &Hello) -> Result<Capabilities, ErrorMessage> {
+    if !is_version_compatible(PROTOCOL_VERSION, &hello.protocol_version) {
+        return Err(ErrorMessage {
+            code: ERROR_PROTOCOL_VERSION_MISMATCH,
+            message: format!(
+                "Protocol version {} is not compatible with host version {}",
+                hello.protocol_version, PROTOCOL_VERSION
+            ),
+            details: Some(serde_json::json!({
+                "host_version": PROTOCOL_VERSION,
+                "client_version": hello.protocol_version,
+                "client_app_version": hello.client_info.version,
+            })),
+        });
+    }
+
+    local.negotiate(&hello.capabilities)
+}
+
+fn no_common_capabilities<T: std::fmt::Debug>(what: &str, local: &[T], remote: &[T]) -> ErrorMessage {
+    ErrorMessage {
+        code: ERROR_NO_COMMON_CAPABILITIES,
+        message: format!("No common {} (host: {:?}, client: {:?})", what, local, remote),
+        details: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::protocol::ClientInfo;
+
+    fn hello(version: &str, capabilities: Capabilities) -> Hello {
+        Hello {
+            protocol_version: version.to_string(),
+            client_info: ClientInfo {
+                name: "test".to_string(),
+                version: "0.0.0".to_string(),
+                platform: "test".to_string(),
+                capabilities: vec![],
+            },
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn test_version_compatibility() {
+        assert!(is_version_compatible("1.1.0", "1.0.0"));
+        assert!(!is_version_compatible("1.1.0", "2.0.0"));
+        assert!(!is_version_compatible("1.1.0", "garbage"));
+    }
+
+    #[test]
+    fn test_negotiate_intersection() {
+        let local = Capabilities::local();
+        let mut remote = Capabilities::local();
+        remote.image_formats = vec![FrameFormat::Png, FrameFormat::Jpeg];
+        remote.max_width = 1920;
+        remote.max_height = 1080;
+        remote.input_features = vec![InputFeature::Mouse];
+        remote.file_transfer = false;
+
+        let negotiated = negotiate_hello(&local, &hello(PROTOCOL_VERSION, remote)).unwrap();
+
+        assert_eq!(negotiated.image_formats[0], FrameFormat::Jpeg);
+        assert!(negotiated.image_formats.contains(&FrameFormat::Png));
+        assert_eq!((negotiated.max_width, negotiated.max_height), (1920, 1080));
+        assert_eq!(negotiated.input_features, vec![InputFeature::Mouse]);
+        assert!(!negotiated.file_transfer);
+    }
+
+    #[test]
+    fn test_negotiate_refusals() {
+        let local = Capabilities::local();
+
+        let error = negotiate_hello(&local, &hello("2.0.0", Capabilities::local())).unwrap_err();
+        assert_eq!(error.code, ERROR_PROTOCOL_VERSION_MISMATCH);
+
+        let mut remote = Capabilities::local();
+        remote.image_formats = vec![FrameFormat::H265];
+        let error = negotiate_hello(&local, &hello(PROTOCOL_VERSION, remote)).unwrap_err();
+        assert_eq!(error.code, ERROR_NO_COMMON_CAPABILITIES);
+    }
+}
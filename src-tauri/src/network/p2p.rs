@@ -7,16 +7,80 @@ use std::net::{SocketAddr, UdpSocket};
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, RwLock};
-use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+use tokio_tungstenite::{accept_async, client_async, tungstenite::Message, WebSocketStream};
 use uuid::Uuid;
 
+use crate::security::identity::{fingerprint, HostIdentity};
+use crate::security::known_hosts::{HostKeyStatus, KnownHostsStore};
+use crate::security::secure_channel::{
+    ChannelOpener, ChannelSealer, KeyExchange, KeyExchangeInit, KeyExchangeReply, SecureChannel,
+};
 use crate::utils::id_generator::{IdGenerator, ConnectionId};
-use super::protocol::{ProtocolMessage, MessageType};
+use super::protocol::{MessageType, ProtocolMessage};
+use super::wire::{is_encrypted, WireFormat, WireMessage};
+
+type PeerSenders = Arc<RwLock<HashMap<String, PeerSender>>>;
+
+const KEY_EXCHANGE_TIMEOUT_SECONDS: u64 = 10;
+
+/// Outgoing queues for one peer; sealing happens under the sealer lock so counters stay ordered
+struct PeerSender {
+    tx: mpsc::UnboundedSender<Message>,
+    /// File transfer messages, sent in order whenever `tx` has nothing waiting
+    transfers: mpsc::UnboundedSender<QueuedTransfer>,
+    sealer: Arc<std::sync::Mutex<ChannelSealer>>,
+}
+
+/// A file transfer message, held back until `send_at` for the transfer speed limit
+struct QueuedTransfer {
+    message: WireMessage,
+    send_at: tokio::time::Instant,
+}
+
+/// What a peer's writer task sends: sealed messages first, file transfer messages around them
+struct PeerQueues {
+    messages: mpsc::UnboundedReceiver<Message>,
+    transfers: mpsc::UnboundedReceiver<QueuedTransfer>,
+    sealer: Arc<std::sync::Mutex<ChannelSealer>>,
+}
+
+impl PeerSender {
+    fn send(&self, message: &WireMessage) -> Result<()> {
+        let mut sealer = self.sealer.lock().unwrap();
+        let sealed = message.seal(&mut sealer)?;
+        self.tx.send(sealed)
+            .map_err(|_| anyhow::anyhow!("P2P connection is closed"))
+    }
+    
+    fn queue_transfer(&self, message: WireMessage, send_at: tokio::time::Instant) -> Result<()> {
+        self.transfers.send(QueuedTransfer { message, send_at })
+            .map_err(|_| anyhow::anyhow!("P2P connection is closed"))
+    }
+}
+
+impl PeerQueues {
+    /// Seal a transfer message as it goes out. Messages already queued were sealed before it,
+    /// so they are taken off the queue to go first and counters still reach the peer in order.
+    fn seal_transfer(&mut self, message: &WireMessage) -> Result<Vec<Message>> {
+        let mut sealer = self.sealer.lock().unwrap();
+        let sealed = message.seal(&mut sealer)?;
+        
+        let mut messages = Vec::new();
+        while let Ok(message) = self.messages.try_recv() {
+            messages.push(message);
+        }
+        messages.push(sealed);
+        Ok(messages)
+    }
+}
 
 pub struct P2PManager {
     id_generator: Arc<IdGenerator>,
     active_connections: Arc<RwLock<HashMap<String, P2PConnection>>>,
     connection_listeners: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<P2PEvent>>>>,
+    peer_senders: PeerSenders,
+    identity: Arc<HostIdentity>,
+    known_hosts: Arc<KnownHostsStore>,
     is_host: Arc<RwLock<bool>>,
     current_connection_id: Arc<RwLock<Option<ConnectionId>>>, 
 }
@@ -34,11 +98,13 @@ pub struct P2PConnection {
 pub enum P2PEvent {
     ConnectionEstablished(String, SocketAddr),
     ConnectionLost(String),
-    MessageReceived(String, ProtocolMessage),
+    MessageReceived(String, WireMessage),
     AuthenticationSuccess(String),
     AuthenticationFailed(String, String),
     HostStarted(ConnectionId),
     HostStopped,
+    HostVerified { connection_id: String, fingerprint: String, first_use: bool },
+    HostKeyMismatch { connection_id: String, expected: String, presented: String },
 }
 
 #[derive(Debug, Clone)]
@@ -50,14 +116,18 @@ pub enum P2PConnectionStatus {
 }
 
 impl P2PManager {
-    pub fn new() -> Self {
-        Self {
+    /// Fails if the host identity or the known hosts can't be loaded, rather than run without them
+    pub fn new() -> Result<Self> {
+        Ok(Self {
             id_generator: Arc::new(IdGenerator::new()),
             active_connections: Arc::new(RwLock::new(HashMap::new())),
             connection_listeners: Arc::new(RwLock::new(HashMap::new())),
+            peer_senders: Arc::new(RwLock::new(HashMap::new())),
+            identity: Arc::new(HostIdentity::load_default()?),
+            known_hosts: Arc::new(KnownHostsStore::load_default()?),
             is_host: Arc::new(RwLock::new(false)),
             current_connection_id: Arc::new(RwLock::new(None)),
-        }
+        })
     }
 
     /// Start hosting with P2P capability - generates 8-digit ID
@@ -83,7 +153,10 @@ impl P2PManager {
         // Clone necessary data for the spawn
         let active_connections = self.active_connections.clone();
         let connection_listeners = self.connection_listeners.clone();
+        let peer_senders = self.peer_senders.clone();
         let id_generator = self.id_generator.clone();
+        let identity = self.identity.clone();
+        let known_hosts = self.known_hosts.clone();
         let connection_id_clone = connection_id.clone();
         
         // Spawn connection acceptor
@@ -93,7 +166,10 @@ impl P2PManager {
                 
                 let active_connections = active_connections.clone();
                 let connection_listeners = connection_listeners.clone();
+                let peer_senders = peer_senders.clone();
                 let id_generator = id_generator.clone();
+                let identity = identity.clone();
+                let known_hosts = known_hosts.clone();
                 let connection_id = connection_id_clone.clone();
                 
                 tokio::spawn(async move {
@@ -102,8 +178,12 @@ impl P2PManager {
                         addr, 
                         active_connections, 
                         connection_listeners,
+                        peer_senders,
                         id_generator,
+                        identity,
+                        known_hosts,
                         connection_id,
+                        Uuid::new_v4().to_string(),
                         true // is_host
                     ).await {
                         error!("P2P connection error: {}", e);
@@ -143,7 +223,10 @@ impl P2PManager {
         
         let active_connections = self.active_connections.clone();
         let connection_listeners = self.connection_listeners.clone();
+        let peer_senders = self.peer_senders.clone();
         let id_generator = self.id_generator.clone();
+        let identity = self.identity.clone();
+        let known_hosts = self.known_hosts.clone();
         
         // Parse the connection ID
         let numeric_id = self.id_generator.parse_connection_id(formatted_id)?;
@@ -153,16 +236,21 @@ impl P2PManager {
             formatted_id: formatted_id.to_string(),
         };
         
-        // Handle connection
+        // Handle connection, under the ID returned so the caller can address this peer
         let connection_uuid = Uuid::new_v4().to_string();
+        let peer_connection_id = connection_uuid.clone();
         tokio::spawn(async move {
             if let Err(e) = Self::handle_p2p_connection(
                 stream,
                 peer_addr,
                 active_connections,
                 connection_listeners,
+                peer_senders,
                 id_generator,
+                identity,
+                known_hosts,
                 connection_id,
+                peer_connection_id,
                 false // is_host
             ).await {
                 error!("P2P client connection error: {}", e);
@@ -230,12 +318,54 @@ impl P2PManager {
         addr: SocketAddr,
         active_connections: Arc<RwLock<HashMap<String, P2PConnection>>>,
         connection_listeners: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<P2PEvent>>>>,
+        peer_senders: PeerSenders,
         id_generator: Arc<IdGenerator>,
+        identity: Arc<HostIdentity>,
+        known_hosts: Arc<KnownHostsStore>,
         connection_id: ConnectionId,
+        connection_uuid: String,
         is_host: bool,
     ) -> Result<()> {
-        let ws_stream = accept_async(stream).await?;
-        let connection_uuid = Uuid::new_v4().to_string();
+        let mut ws_stream = if is_host {
+            accept_async(stream).await?
+        } else {
+            client_async(format!("ws://{}", addr), stream).await?.0
+        };
+        
+        // Nothing is exchanged in the clear beyond the key exchange itself
+        let (channel, host_key) = tokio::time::timeout(
+            std::time::Duration::from_secs(KEY_EXCHANGE_TIMEOUT_SECONDS),
+            Self::establish_channel(&mut ws_stream, &identity, is_host),
+        ).await
+            .map_err(|_| anyhow::anyhow!("Key exchange with {} timed out", addr))??;
+        let (sealer, opener) = channel.into_split();
+        
+        // Viewer side: the host must present the key we pinned for its connection ID
+        if let Some(host_key) = host_key {
+            let status = known_hosts.verify(&connection_id.formatted_id, &host_key)?;
+            
+            let event = match status {
+                HostKeyStatus::Mismatch { expected, presented } => {
+                    error!("Host {} presented a different identity, aborting", connection_id.formatted_id);
+                    Self::notify_listeners(&connection_listeners, P2PEvent::HostKeyMismatch {
+                        connection_id: connection_id.formatted_id.clone(),
+                        expected,
+                        presented,
+                    }).await;
+                    
+                    let _ = ws_stream.close(None).await;
+                    return Err(anyhow::anyhow!("Host identity mismatch for {}", connection_id.formatted_id));
+                }
+                status => P2PEvent::HostVerified {
+                    connection_id: connection_id.formatted_id.clone(),
+                    fingerprint: fingerprint(&host_key),
+                    first_use: status == HostKeyStatus::FirstUse,
+                },
+            };
+            Self::notify_listeners(&connection_listeners, event).await;
+        }
+        
+        debug!("Encrypted P2P channel established with {}", addr);
         
         // Register connection
         let connection_info = P2PConnection {
@@ -248,6 +378,15 @@ impl P2PManager {
         
         active_connections.write().await.insert(connection_uuid.clone(), connection_info);
         
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel::<Message>();
+        let (transfers_tx, transfers_rx) = mpsc::unbounded_channel::<QueuedTransfer>();
+        let sealer = Arc::new(std::sync::Mutex::new(sealer));
+        peer_senders.write().await.insert(connection_uuid.clone(), PeerSender {
+            tx: outgoing_tx.clone(),
+            transfers: transfers_tx,
+            sealer: sealer.clone(),
+        });
+        
         // Notify connection established
         let listeners = connection_listeners.read().await;
         for sender in listeners.values() {
@@ -258,6 +397,9 @@ impl P2PManager {
         // Handle WebSocket messages
         let result = Self::handle_websocket_messages(
             ws_stream,
+            outgoing_tx,
+            PeerQueues { messages: outgoing_rx, transfers: transfers_rx, sealer },
+            opener,
             connection_uuid.clone(),
             active_connections.clone(),
             connection_listeners.clone(),
@@ -266,6 +408,7 @@ impl P2PManager {
         
         // Cleanup on disconnect
         active_connections.write().await.remove(&connection_uuid);
+        peer_senders.write().await.remove(&connection_uuid);
         
         let listeners = connection_listeners.read().await;
         for sender in listeners.values() {
@@ -275,44 +418,131 @@ impl P2PManager {
         result
     }
     
+    /// Run the key exchange; the host answers with its identity, the viewer initiates
+    /// and gets back the host's identity key
+    async fn establish_channel(
+        ws_stream: &mut WebSocketStream<TcpStream>,
+        identity: &HostIdentity,
+        is_host: bool,
+    ) -> Result<(SecureChannel, Option<Vec<u8>>)> {
+        let exchange = KeyExchange::new();
+        
+        if is_host {
+            let data = Self::next_handshake_message(ws_stream, MessageType::KeyExchangeInit).await?;
+            let init = serde_json::from_value::<KeyExchangeInit>(data)?;
+            let (reply, channel) = exchange.respond(&init, identity)?;
+            
+            let reply = WireMessage::Control(ProtocolMessage::key_exchange_reply(reply));
+            ws_stream.send(reply.to_ws_message(WireFormat::Binary)?).await?;
+            Ok((channel, None))
+        } else {
+            let init = WireMessage::Control(ProtocolMessage::key_exchange_init(exchange.init_message()));
+            ws_stream.send(init.to_ws_message(WireFormat::Binary)?).await?;
+            
+            let data = Self::next_handshake_message(ws_stream, MessageType::KeyExchangeReply).await?;
+            let reply = serde_json::from_value::<KeyExchangeReply>(data)?;
+            Ok((exchange.complete(&reply)?, Some(reply.identity_key)))
+        }
+    }
+    
+    async fn next_handshake_message(
+        ws_stream: &mut WebSocketStream<TcpStream>,
+        expected: MessageType,
+    ) -> Result<serde_json::Value> {
+        while let Some(msg) = ws_stream.next().await {
+            match WireMessage::from_ws_message(&msg?)? {
+                Some(WireMessage::Control(message)) if message.message_type == expected => return Ok(message.data),
+                Some(other) => {
+                    return Err(anyhow::anyhow!("Expected {:?} during key exchange, got {:?}", expected, other.kind()));
+                }
+                None => continue,
+            }
+        }
+        
+        Err(anyhow::anyhow!("Connection closed during key exchange"))
+    }
+    
     /// Handle WebSocket message exchange
     async fn handle_websocket_messages(
-        mut ws_stream: WebSocketStream<TcpStream>,
+        ws_stream: WebSocketStream<TcpStream>,
+        outgoing_tx: mpsc::UnboundedSender<Message>,
+        mut queues: PeerQueues,
+        mut opener: ChannelOpener,
         connection_id: String,
         active_connections: Arc<RwLock<HashMap<String, P2PConnection>>>,
         connection_listeners: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<P2PEvent>>>>,
         is_host: bool,
     ) -> Result<()> {
+        let (mut ws_sink, mut ws_stream) = ws_stream.split();
+        
+        // Writer task drains the per-peer outgoing queue; file transfer messages wait until it is empty
+        tokio::spawn(async move {
+            let mut next_transfer: Option<QueuedTransfer> = None;
+            
+            loop {
+                let send_at = next_transfer.as_ref().map(|transfer| transfer.send_at);
+                
+                let messages = tokio::select! {
+                    biased;
+                    Some(msg) = queues.messages.recv() => vec![msg],
+                    Some(transfer) = queues.transfers.recv(), if next_transfer.is_none() => {
+                        next_transfer = Some(transfer);
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(send_at.unwrap_or_else(tokio::time::Instant::now)), if send_at.is_some() => {
+                        let transfer = next_transfer.take().unwrap();
+                        match queues.seal_transfer(&transfer.message) {
+                            Ok(messages) => messages,
+                            Err(e) => {
+                                warn!("Dropping file transfer message that could not be sealed: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    else => break,
+                };
+                
+                for msg in messages {
+                    if ws_sink.send(msg).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        
         while let Some(msg) = ws_stream.next().await {
-            match msg? {
-                Message::Text(text) => {
-                    debug!("Received P2P message: {}", text);
+            let msg = msg?;
+            
+            match msg {
+                Message::Binary(ref data) if is_encrypted(&msg) => {
+                    // A frame that fails to open means tampering or a desync; drop the peer
+                    let wire_msg = WireMessage::open(data, &mut opener)
+                        .map_err(|e| anyhow::anyhow!("Could not open P2P frame from {}: {}", connection_id, e))?;
                     
-                    if let Ok(protocol_msg) = serde_json::from_str::<ProtocolMessage>(&text) {
-                        // Update last ping time
+                    // Update last ping time
+                    if let WireMessage::Control(ref protocol_msg) = wire_msg {
                         if protocol_msg.message_type == MessageType::Heartbeat {
                             let mut connections = active_connections.write().await;
                             if let Some(conn) = connections.get_mut(&connection_id) {
                                 conn.last_ping = Some(chrono::Utc::now());
                             }
                         }
-                        
-                        // Notify listeners
-                        let listeners = connection_listeners.read().await;
-                        for sender in listeners.values() {
-                            let _ = sender.send(P2PEvent::MessageReceived(
-                                connection_id.clone(), 
-                                protocol_msg.clone()
-                            ));
-                        }
+                    }
+                    
+                    // Notify listeners
+                    let listeners = connection_listeners.read().await;
+                    for sender in listeners.values() {
+                        let _ = sender.send(P2PEvent::MessageReceived(
+                            connection_id.clone(), 
+                            wire_msg.clone()
+                        ));
                     }
                 }
-                Message::Binary(data) => {
-                    debug!("Received P2P binary data: {} bytes", data.len());
-                    // Handle binary data (screen frames, file transfers, etc.)
+                Message::Text(_) | Message::Binary(_) => {
+                    warn!("Dropping plaintext P2P message from {}", connection_id);
                 }
                 Message::Ping(payload) => {
-                    ws_stream.send(Message::Pong(payload)).await?;
+                    let _ = outgoing_tx.send(Message::Pong(payload));
                 }
                 Message::Pong(_) => {
                     // Update ping time
@@ -332,6 +562,36 @@ impl P2PManager {
         Ok(())
     }
     
+    /// Send a message to a single peer
+    pub async fn send_to_peer(&self, connection_id: &str, message: &WireMessage) -> Result<()> {
+        let peers = self.peer_senders.read().await;
+        let sender = peers.get(connection_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown P2P connection: {}", connection_id))?;
+        
+        sender.send(message)
+    }
+    
+    /// Queue a file transfer message for a peer, in order with the others, behind everything
+    /// else and not before `send_at`
+    pub async fn send_transfer_to_peer(&self, connection_id: &str, message: WireMessage, send_at: tokio::time::Instant) -> Result<()> {
+        let peers = self.peer_senders.read().await;
+        let sender = peers.get(connection_id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown P2P connection: {}", connection_id))?;
+        
+        sender.queue_transfer(message, send_at)
+    }
+    
+    /// Send a message to every connected peer
+    pub async fn broadcast(&self, message: &WireMessage) -> Result<()> {
+        for (connection_id, sender) in self.peer_senders.read().await.iter() {
+            if sender.send(message).is_err() {
+                debug!("Skipping closed P2P connection {}", connection_id);
+            }
+        }
+        
+        Ok(())
+    }
+    
     /// Stop hosting
     pub async fn stop_host(&self) -> Result<()> {
         info!("Stopping P2P host");
@@ -345,6 +605,7 @@ impl P2PManager {
         
         // Close all connections
         self.active_connections.write().await.clear();
+        self.close_peer_connections().await;
         
         // Notify listeners
         self.notify_event(P2PEvent::HostStopped).await;
@@ -366,12 +627,24 @@ impl P2PManager {
     
     /// Notify all event listeners
     async fn notify_event(&self, event: P2PEvent) {
-        let listeners = self.connection_listeners.read().await;
+        Self::notify_listeners(&self.connection_listeners, event).await;
+    }
+    
+    async fn notify_listeners(
+        connection_listeners: &Arc<RwLock<HashMap<String, mpsc::UnboundedSender<P2PEvent>>>>,
+        event: P2PEvent,
+    ) {
+        let listeners = connection_listeners.read().await;
         for sender in listeners.values() {
             let _ = sender.send(event.clone());
         }
     }
     
+    /// Fingerprint of this machine's identity, for the host to display
+    pub fn host_fingerprint(&self) -> String {
+        self.identity.fingerprint()
+    }
+    
     /// Get active connections
     pub async fn get_active_connections(&self) -> Vec<P2PConnection> {
         self.active_connections.read().await.values().cloned().collect()
@@ -416,9 +689,16 @@ impl P2PManager {
         
         // Clear active connections
         self.active_connections.write().await.clear();
+        self.close_peer_connections().await;
         
         Ok(())
     }
+    
+    async fn close_peer_connections(&self) {
+        for (_, sender) in self.peer_senders.write().await.drain() {
+            let _ = sender.tx.send(Message::Close(None));
+        }
+    }
 
     /// Get list of discovered peers
     pub async fn get_discovered_peers(&self) -> Result<Vec<ConnectionId>> {
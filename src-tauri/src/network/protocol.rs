@@ -1,6 +1,15 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 
+use crate::capture::{MonitorSelection, ScreenLayout};
+use crate::codec::delta::DiffRegion;
+use crate::security::secure_channel::{KeyExchangeInit, KeyExchangeReply};
+use crate::utils::file_transfer::TransferMessage;
+
+pub use crate::capture::cursor::{CursorPosition, CursorShape};
+pub use crate::codec::FrameFormat;
+pub use crate::streaming::Viewport;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProtocolMessage {
     pub id: String,
@@ -12,6 +21,12 @@ pub struct ProtocolMessage {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageType {
+    // Session setup
+    Hello,
+    HelloAck,
+    KeyExchangeInit,
+    KeyExchangeReply,
+    
     // Authentication
     AuthRequest,
     AuthResponse,
@@ -19,7 +34,20 @@ pub enum MessageType {
     // Screen capture
     ScreenFrameRequest,
     ScreenFrame,
+    /// Empty from the viewer to ask for the layout; the host answers with a `ScreenLayout`
     ScreenInfo,
+    /// Viewer picks the streamed monitor; the host answers with `ScreenInfo`
+    SelectMonitor,
+    /// Viewer's window size, which the host shrinks frames to fit; `null` streams at full size
+    Viewport,
+    /// Viewer lost its reference for an inter-coded stream; the next frame is a keyframe
+    KeyframeRequest,
+    /// Viewer received a frame; the host times the round trip to pace the stream
+    FrameAck,
+    /// Host's pointer image, sent once per shape; viewers keep it by ID
+    CursorShape,
+    /// Host's pointer moved or changed shape
+    CursorPosition,
     
     // Input events
     InputEvent,
@@ -30,21 +58,52 @@ pub enum MessageType {
     ConnectionStatus,
     Error,
     
-    // File transfer (future feature)
+    // File transfer, in either direction once authenticated
+    /// Offer of a file; answered with `FileTransferResponse`
     FileTransferRequest,
+    /// Offer of a directory's manifest, also answered with `FileTransferResponse`; its files follow as `FileTransferRequest`s
+    DirectoryTransferRequest,
+    FileTransferResponse,
+    /// A chunk in JSON sessions; binary sessions send chunks as `FileChunk` frames
     FileTransferData,
+    /// Receiver wrote a chunk, so the sender may send another
+    FileTransferAck,
+    /// Receiver has the whole file and checked it, or gave up
     FileTransferComplete,
+    FileTransferCancel,
+    FileTransferPause,
+    /// Continue a paused transfer; the receiver lists the chunks it still needs
+    FileTransferResume,
+}
+
+impl MessageType {
+    pub fn is_file_transfer(&self) -> bool {
+        matches!(
+            self,
+            MessageType::FileTransferRequest
+                | MessageType::DirectoryTransferRequest
+                | MessageType::FileTransferResponse
+                | MessageType::FileTransferData
+                | MessageType::FileTransferAck
+                | MessageType::FileTransferComplete
+                | MessageType::FileTransferCancel
+                | MessageType::FileTransferPause
+                | MessageType::FileTransferResume
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScreenFrame {
     pub width: u32,
     pub height: u32,
-    pub format: ImageFormat,
+    pub format: FrameFormat,
+    /// Output of the format's `FrameEncoder`: for still formats a whole image on keyframes, a `codec::delta` payload otherwise
     pub data: Vec<u8>,
     pub timestamp: DateTime<Utc>,
     pub sequence_number: u64,
     pub is_keyframe: bool,
+    /// Areas a still delta frame repaints
     pub changed_regions: Option<Vec<Region>>,
 }
 
@@ -56,14 +115,43 @@ pub struct Region {
     pub height: u32,
 }
 
+impl From<DiffRegion> for Region {
+    fn from(region: DiffRegion) -> Self {
+        Self {
+            x: region.x as i32,
+            y: region.y as i32,
+            width: region.width,
+            height: region.height,
+        }
+    }
+}
+
+/// Viewer asking for a keyframe because it can no longer build on the frames it has
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyframeRequest {
+    /// Last frame the viewer applied, if any
+    #[serde(default)]
+    pub last_sequence: Option<u64>,
+    #[serde(default)]
+    pub reason: KeyframeReason,
+}
+
+/// Sent by the viewer as soon as a frame arrives, before decoding it
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum ImageFormat {
-    Jpeg,
-    Png,
-    Raw,
-    H264,
-    H265,
+pub struct FrameAck {
+    pub sequence_number: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyframeReason {
+    /// Asked for by the application, e.g. after a viewer restart
+    #[default]
+    Requested,
+    /// Frames are missing or arrived out of order
+    FrameGap,
+    /// A frame could not be decoded against the viewer's reference
+    DecodeFailed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -114,6 +202,8 @@ pub struct AuthRequest {
     pub username: Option<String>,
     pub password: Option<String>,
     pub token: Option<String>,
+    #[serde(default)]
+    pub pin: Option<String>,
     pub client_info: ClientInfo,
 }
 
@@ -133,6 +223,44 @@ pub struct ClientInfo {
     pub capabilities: Vec<String>,
 }
 
+/// First message on a connection, sent by the viewer before authentication
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: String,
+    pub client_info: ClientInfo,
+    pub capabilities: Capabilities,
+}
+
+/// Host reply to `Hello` carrying the capabilities both sides agreed on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub protocol_version: String,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Capabilities {
+    /// Frame formats in order of preference
+    pub image_formats: Vec<FrameFormat>,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub input_features: Vec<InputFeature>,
+    pub file_transfer: bool,
+    pub clipboard: bool,
+    /// Viewer draws the host's pointer from cursor messages instead of it being drawn into frames
+    #[serde(default)]
+    pub cursor: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFeature {
+    Mouse,
+    Scroll,
+    Keyboard,
+    TextInput,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionStatus {
     pub connected: bool,
@@ -161,7 +289,7 @@ pub struct ErrorMessage {
 }
 
 // Protocol constants
-pub const PROTOCOL_VERSION: &str = "1.0.0";
+pub const PROTOCOL_VERSION: &str = "1.1.0";
 pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024; // 10MB
 pub const HEARTBEAT_INTERVAL_SECONDS: u64 = 30;
 pub const CONNECTION_TIMEOUT_SECONDS: u64 = 60;
@@ -169,7 +297,11 @@ pub const CONNECTION_TIMEOUT_SECONDS: u64 = 60;
 // Error codes
 pub const ERROR_AUTHENTICATION_FAILED: u32 = 1001;
 pub const ERROR_UNAUTHORIZED: u32 = 1002;
+pub const ERROR_ENCRYPTION_REQUIRED: u32 = 1003;
 pub const ERROR_INVALID_MESSAGE: u32 = 2001;
+pub const ERROR_PROTOCOL_VERSION_MISMATCH: u32 = 2002;
+pub const ERROR_NO_COMMON_CAPABILITIES: u32 = 2003;
+pub const ERROR_HANDSHAKE_REQUIRED: u32 = 2004;
 pub const ERROR_SCREEN_CAPTURE_FAILED: u32 = 3001;
 pub const ERROR_INPUT_INJECTION_FAILED: u32 = 3002;
 pub const ERROR_NETWORK_ERROR: u32 = 4001;
@@ -185,11 +317,17 @@ impl ProtocolMessage {
         }
     }
     
-    pub fn auth_request(username: Option<String>, password: Option<String>, token: Option<String>) -> Self {
+    pub fn auth_request(
+        username: Option<String>,
+        password: Option<String>,
+        token: Option<String>,
+        pin: Option<String>,
+    ) -> Self {
         let auth_request = AuthRequest {
             username,
             password,
             token,
+            pin,
             client_info: ClientInfo {
                 name: "AnyViewer".to_string(),
                 version: env!("CARGO_PKG_VERSION").to_string(),
@@ -205,29 +343,123 @@ impl ProtocolMessage {
         Self::new(MessageType::AuthRequest, serde_json::to_value(auth_request).unwrap())
     }
     
-    pub fn auth_response(success: bool, error: Option<String>, session_token: Option<String>) -> Self {
+    pub fn auth_response(
+        success: bool,
+        error: Option<String>,
+        session_token: Option<String>,
+        server_capabilities: Vec<String>,
+    ) -> Self {
         let auth_response = AuthResponse {
             success,
             error,
             session_token,
-            server_capabilities: vec![
-                "screen_capture".to_string(),
-                "input_forwarding".to_string(),
-                "file_transfer".to_string(),
-            ],
+            server_capabilities,
         };
         
         Self::new(MessageType::AuthResponse, serde_json::to_value(auth_response).unwrap())
     }
     
+    pub fn hello(capabilities: Capabilities) -> Self {
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION.to_string(),
+            client_info: ClientInfo {
+                name: "AnyViewer".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                platform: std::env::consts::OS.to_string(),
+                capabilities: capabilities.feature_names(),
+            },
+            capabilities,
+        };
+        
+        Self::new(MessageType::Hello, serde_json::to_value(hello).unwrap())
+    }
+    
+    pub fn hello_ack(capabilities: Capabilities) -> Self {
+        let hello_ack = HelloAck {
+            protocol_version: PROTOCOL_VERSION.to_string(),
+            capabilities,
+        };
+        
+        Self::new(MessageType::HelloAck, serde_json::to_value(hello_ack).unwrap())
+    }
+    
+    pub fn key_exchange_init(init: KeyExchangeInit) -> Self {
+        Self::new(MessageType::KeyExchangeInit, serde_json::to_value(init).unwrap())
+    }
+    
+    pub fn key_exchange_reply(reply: KeyExchangeReply) -> Self {
+        Self::new(MessageType::KeyExchangeReply, serde_json::to_value(reply).unwrap())
+    }
+    
     pub fn screen_frame(frame: ScreenFrame) -> Self {
         Self::new(MessageType::ScreenFrame, serde_json::to_value(frame).unwrap())
     }
     
+    pub fn screen_info(layout: &ScreenLayout) -> Self {
+        Self::new(MessageType::ScreenInfo, serde_json::to_value(layout).unwrap())
+    }
+    
+    pub fn select_monitor(selection: MonitorSelection) -> Self {
+        Self::new(MessageType::SelectMonitor, serde_json::to_value(selection).unwrap())
+    }
+    
+    pub fn viewport(viewport: Option<Viewport>) -> Self {
+        Self::new(MessageType::Viewport, serde_json::to_value(viewport).unwrap())
+    }
+    
+    pub fn keyframe_request(request: KeyframeRequest) -> Self {
+        Self::new(MessageType::KeyframeRequest, serde_json::to_value(request).unwrap())
+    }
+    
+    pub fn frame_ack(sequence_number: u64) -> Self {
+        Self::new(MessageType::FrameAck, serde_json::to_value(FrameAck { sequence_number }).unwrap())
+    }
+    
+    pub fn cursor_shape(shape: &CursorShape) -> Self {
+        Self::new(MessageType::CursorShape, serde_json::to_value(shape).unwrap())
+    }
+    
+    pub fn cursor_position(position: CursorPosition) -> Self {
+        Self::new(MessageType::CursorPosition, serde_json::to_value(position).unwrap())
+    }
+    
     pub fn input_event(event: InputEvent) -> Self {
         Self::new(MessageType::InputEvent, serde_json::to_value(event).unwrap())
     }
     
+    pub fn file_transfer(message: &TransferMessage) -> Self {
+        let (message_type, data) = match message {
+            TransferMessage::Offer(request) => (MessageType::FileTransferRequest, serde_json::to_value(request)),
+            TransferMessage::DirectoryOffer(request) => (MessageType::DirectoryTransferRequest, serde_json::to_value(request)),
+            TransferMessage::Response(response) => (MessageType::FileTransferResponse, serde_json::to_value(response)),
+            TransferMessage::Chunk(chunk) => (MessageType::FileTransferData, serde_json::to_value(chunk)),
+            TransferMessage::Ack(ack) => (MessageType::FileTransferAck, serde_json::to_value(ack)),
+            TransferMessage::Complete(complete) => (MessageType::FileTransferComplete, serde_json::to_value(complete)),
+            TransferMessage::Cancel(cancel) => (MessageType::FileTransferCancel, serde_json::to_value(cancel)),
+            TransferMessage::Pause(pause) => (MessageType::FileTransferPause, serde_json::to_value(pause)),
+            TransferMessage::Resume(resume) => (MessageType::FileTransferResume, serde_json::to_value(resume)),
+        };
+        
+        Self::new(message_type, data.unwrap())
+    }
+    
+    /// Parse a file transfer message; fails for other message types
+    pub fn to_file_transfer(&self) -> anyhow::Result<TransferMessage> {
+        let data = self.data.clone();
+        Ok(match self.message_type {
+            MessageType::FileTransferRequest => TransferMessage::Offer(serde_json::from_value(data)?),
+            MessageType::DirectoryTransferRequest => TransferMessage::DirectoryOffer(serde_json::from_value(data)?),
+            MessageType::FileTransferResponse => TransferMessage::Response(serde_json::from_value(data)?),
+            MessageType::FileTransferData => TransferMessage::Chunk(serde_json::from_value(data)?),
+            MessageType::FileTransferAck => TransferMessage::Ack(serde_json::from_value(data)?),
+            MessageType::FileTransferComplete => TransferMessage::Complete(serde_json::from_value(data)?),
+            MessageType::FileTransferCancel => TransferMessage::Cancel(serde_json::from_value(data)?),
+            MessageType::FileTransferPause => TransferMessage::Pause(serde_json::from_value(data)?),
+            MessageType::FileTransferResume => TransferMessage::Resume(serde_json::from_value(data)?),
+            ref other => return Err(anyhow::anyhow!("Not a file transfer message: {:?}", other)),
+        })
+    }
+    
     pub fn error(code: u32, message: String, details: Option<serde_json::Value>) -> Self {
         let error_msg = ErrorMessage {
             code,
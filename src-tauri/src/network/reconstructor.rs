@@ -0,0 +1,238 @@
+//! Viewer-side reassembly of the frame stream.
+//!
+//! Delta and inter-coded frames only make sense on top of every frame before
+//! them. The reconstructor applies frames strictly in sequence; once one is
+//! missing or fails to decode, it stops drawing on a reference it can no
+//! longer trust, drops frames until the next keyframe and asks the host for
+//! one, instead of leaving garbage on screen.
+
+use anyhow::Result;
+use image::RgbaImage;
+use log::{debug, warn};
+use std::collections::hash_map::{Entry, HashMap};
+use std::time::{Duration, Instant};
+
+use super::protocol::{FrameFormat, KeyframeReason, KeyframeRequest, ScreenFrame};
+use crate::codec::{self, FrameDecoder};
+
+/// How long to wait for a requested keyframe before asking again, in case the request was lost
+pub const KEYFRAME_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The whole picture after applying one frame
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    pub sequence_number: u64,
+    pub is_keyframe: bool,
+    pub image: RgbaImage,
+}
+
+#[derive(Debug)]
+pub enum Reconstructed {
+    Frame(DecodedFrame),
+    /// The frame can't be shown; `keyframe` is set when the host should be asked for one
+    Dropped { keyframe: Option<KeyframeRequest> },
+}
+
+#[derive(Default)]
+pub struct FrameReconstructor {
+    /// One per format seen, holding the reference later frames build on
+    decoders: HashMap<FrameFormat, Box<dyn FrameDecoder>>,
+    last_sequence: Option<u64>,
+    /// When a keyframe was requested, until one arrives
+    keyframe_requested: Option<Instant>,
+}
+
+impl FrameReconstructor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the next frame received from the host
+    pub fn push(&mut self, frame: &ScreenFrame) -> Reconstructed {
+        // Duplicates and frames overtaken by newer ones would roll the picture back
+        if self.last_sequence.is_some_and(|last| frame.sequence_number <= last) {
+            debug!("Dropping stale frame #{}", frame.sequence_number);
+            return Reconstructed::Dropped { keyframe: None };
+        }
+
+        if !frame.is_keyframe {
+            if self.keyframe_requested.is_some() {
+                return self.wait_for_keyframe(frame.sequence_number);
+            }
+
+            let expected = self.last_sequence.map_or(0, |last| last + 1);
+            if self.last_sequence.is_none() || frame.sequence_number != expected {
+                warn!("Frame #{} arrived, expected #{}; waiting for a keyframe", frame.sequence_number, expected);
+                return self.lose_reference(KeyframeReason::FrameGap);
+            }
+        }
+
+        match self.decode(frame) {
+            Ok(image) => {
+                self.last_sequence = Some(frame.sequence_number);
+                self.keyframe_requested = None;
+
+                Reconstructed::Frame(DecodedFrame {
+                    sequence_number: frame.sequence_number,
+                    is_keyframe: frame.is_keyframe,
+                    image,
+                })
+            }
+            Err(e) => {
+                warn!("Could not decode frame #{}: {}; waiting for a keyframe", frame.sequence_number, e);
+                self.lose_reference(KeyframeReason::DecodeFailed)
+            }
+        }
+    }
+
+    /// Sequence number of the last frame applied
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    /// Whether frames are being dropped until a keyframe arrives
+    pub fn is_waiting_for_keyframe(&self) -> bool {
+        self.keyframe_requested.is_some()
+    }
+
+    fn decode(&mut self, frame: &ScreenFrame) -> Result<RgbaImage> {
+        let decoder = match self.decoders.entry(frame.format) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(codec::create_decoder(frame.format)?),
+        };
+
+        decoder.decode(&frame.data, frame.is_keyframe)
+    }
+
+    fn lose_reference(&mut self, reason: KeyframeReason) -> Reconstructed {
+        // Whatever the decoders hold no longer matches the host
+        self.decoders.clear();
+        self.keyframe_requested = Some(Instant::now());
+
+        Reconstructed::Dropped {
+            keyframe: Some(KeyframeRequest {
+                last_sequence: self.last_sequence,
+                reason,
+            }),
+        }
+    }
+
+    fn wait_for_keyframe(&mut self, sequence_number: u64) -> Reconstructed {
+        debug!("Dropping frame #{} while waiting for a keyframe", sequence_number);
+
+        let retry = self.keyframe_requested
+            .is_some_and(|requested| requested.elapsed() >= KEYFRAME_RETRY_INTERVAL);
+        if !retry {
+            return Reconstructed::Dropped { keyframe: None };
+        }
+
+        self.keyframe_requested = Some(Instant::now());
+        Reconstructed::Dropped {
+            keyframe: Some(KeyframeRequest {
+                last_sequence: self.last_sequence,
+                reason: KeyframeReason::FrameGap,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{EncoderSettings, FrameEncoder};
+    use crate::codec::still::StillEncoder;
+
+    struct Host {
+        encoder: StillEncoder,
+        sequence: u64,
+        picture: RgbaImage,
+    }
+
+    impl Host {
+        fn new() -> Self {
+            Self {
+                encoder: StillEncoder::new(FrameFormat::Png, &EncoderSettings::default()).unwrap(),
+                sequence: 0,
+                picture: RgbaImage::from_pixel(64, 32, image::Rgba([20, 20, 20, 255])),
+            }
+        }
+
+        /// Draw a small change and encode the next frame
+        fn frame(&mut self) -> ScreenFrame {
+            self.sequence += 1;
+            let x = (self.sequence as u32 * 3) % 64;
+            self.picture.put_pixel(x, 5, image::Rgba([250, 250, 250, 255]));
+
+            let encoded = self.encoder.encode(&self.picture).unwrap();
+            ScreenFrame {
+                width: encoded.width,
+                height: encoded.height,
+                format: encoded.format,
+                data: encoded.data,
+                timestamp: chrono::Utc::now(),
+                sequence_number: self.sequence,
+                is_keyframe: encoded.is_keyframe,
+                changed_regions: None,
+            }
+        }
+    }
+
+    fn image(outcome: Reconstructed) -> RgbaImage {
+        match outcome {
+            Reconstructed::Frame(frame) => frame.image,
+            Reconstructed::Dropped { .. } => panic!("frame was dropped"),
+        }
+    }
+
+    fn requested(outcome: Reconstructed) -> Option<KeyframeReason> {
+        match outcome {
+            Reconstructed::Dropped { keyframe } => keyframe.map(|request| request.reason),
+            Reconstructed::Frame(_) => panic!("frame was applied"),
+        }
+    }
+
+    #[test]
+    fn test_recovers_from_lost_frame() {
+        let mut host = Host::new();
+        let mut viewer = FrameReconstructor::new();
+
+        let keyframe = host.frame();
+        assert!(keyframe.is_keyframe);
+        image(viewer.push(&keyframe));
+        assert_eq!(image(viewer.push(&host.frame())), host.picture);
+
+        // Frame 3 is lost on the way; frame 4 would patch a picture the viewer doesn't have
+        host.frame();
+        assert_eq!(requested(viewer.push(&host.frame())), Some(KeyframeReason::FrameGap));
+        assert!(viewer.is_waiting_for_keyframe());
+        assert_eq!(requested(viewer.push(&host.frame())), None);
+
+        // Resending an applied frame changes nothing
+        assert_eq!(requested(viewer.push(&keyframe)), None);
+
+        host.encoder.request_keyframe();
+        assert_eq!(image(viewer.push(&host.frame())), host.picture);
+        assert_eq!(image(viewer.push(&host.frame())), host.picture);
+        assert_eq!(viewer.last_sequence(), Some(host.sequence));
+    }
+
+    #[test]
+    fn test_corrupt_frame_requests_keyframe() {
+        let mut host = Host::new();
+        let mut viewer = FrameReconstructor::new();
+
+        // Joining mid-stream is a gap too
+        host.frame();
+        assert_eq!(requested(viewer.push(&host.frame())), Some(KeyframeReason::FrameGap));
+
+        host.encoder.request_keyframe();
+        image(viewer.push(&host.frame()));
+
+        let mut corrupt = host.frame();
+        corrupt.data.truncate(corrupt.data.len() / 2);
+        assert_eq!(requested(viewer.push(&corrupt)), Some(KeyframeReason::DecodeFailed));
+
+        host.encoder.request_keyframe();
+        assert_eq!(image(viewer.push(&host.frame())), host.picture);
+    }
+}
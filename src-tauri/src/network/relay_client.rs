@@ -2,12 +2,24 @@ use anyhow::Result;
 use base64::{Engine as _, engine::general_purpose};
 use log::{info, error, debug, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::{RwLock, mpsc};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
 use futures_util::{SinkExt, StreamExt};
 use url::Url;
 
+use super::protocol::InputEvent;
+use super::wire::{self, FrameKind, WireFormat};
+use crate::security::identity::{fingerprint, HostIdentity};
+use crate::security::known_hosts::{HostKeyStatus, KnownHostsStore};
+use crate::security::secure_channel::{
+    ChannelOpener, ChannelSealer, KeyExchange, KeyExchangeInit, KeyExchangeReply,
+};
+use crate::security::{ClientCredentials, SecurityManager};
+
+type PeerSessions = Arc<RwLock<HashMap<String, PeerSession>>>;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayConfig {
     pub server_url: String,
@@ -15,6 +27,15 @@ pub struct RelayConfig {
     pub auto_fallback: bool, // Automatically fallback to relay when P2P fails
     pub connection_timeout_seconds: u64,
     pub heartbeat_interval_seconds: u64,
+    #[serde(default)]
+    pub wire_format: WireFormat,
+    /// Refuse to send or accept peer data outside an end-to-end session
+    #[serde(default = "default_require_encryption")]
+    pub require_encryption: bool,
+}
+
+fn default_require_encryption() -> bool {
+    true
 }
 
 impl Default for RelayConfig {
@@ -25,6 +46,8 @@ impl Default for RelayConfig {
             auto_fallback: true,
             connection_timeout_seconds: 30,
             heartbeat_interval_seconds: 30,
+            wire_format: WireFormat::Binary,
+            require_encryption: true,
         }
     }
 }
@@ -38,6 +61,29 @@ pub struct RelayMessage {
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
+/// Binary counterpart of `RelayMessage` for bulk data. The relay only reads
+/// the routing fields; the payload is forwarded untouched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayPacket {
+    pub message_type: RelayMessageType,
+    pub source_id: Option<String>,
+    pub target_id: String,
+    pub payload: Vec<u8>,
+}
+
+impl RelayPacket {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        wire::encode_frame(FrameKind::Relay, &bincode::serialize(self)?)
+    }
+    
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        match wire::decode_frame(data)? {
+            (FrameKind::Relay, payload) => Ok(bincode::deserialize(payload)?),
+            (kind, _) => Err(anyhow::anyhow!("Expected relay frame, got {:?}", kind)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum RelayMessageType {
@@ -48,10 +94,18 @@ pub enum RelayMessageType {
     ConnectResponse,
     Disconnect,
     
+    // End-to-end session setup, forwarded untouched by the relay
+    KeyExchangeInit,
+    KeyExchangeReply,
+    /// Viewer credentials, only ever sent sealed
+    Authenticate,
+    
     // Data forwarding
     ScreenFrame,
     InputEvent,
     FileTransfer,
+    /// Encrypted peer data; the real type is inside the ciphertext
+    Sealed,
     
     // Control messages
     Heartbeat,
@@ -76,6 +130,9 @@ pub struct DeviceInfo {
 pub struct ConnectRequest {
     pub target_connection_id: String,
     pub client_info:DeviceInfo,
+    /// The requester's connection ID, as stamped by the relay rather than sent by the requester
+    #[serde(skip)]
+    pub source_id: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -83,37 +140,90 @@ pub enum RelayClientEvent {
     Connected,
     Disconnected,
     MessageReceived(RelayMessage),
+    PacketReceived(RelayPacket),
     RegistrationSuccess(String), // connection_id
     RegistrationFailed(String),  // error message
     ConnectionRequest(ConnectRequest),
+    PeerSecured { peer_id: String, fingerprint: Option<String>, first_use: bool },
+    HostKeyMismatch { peer_id: String, expected: String, presented: String },
     Error(String),
 }
 
+/// End-to-end session with one peer
+enum PeerSession {
+    /// We sent a key exchange and are waiting for the reply; the credentials go once it's sealed
+    Pending { exchange: KeyExchange, credentials: Option<ClientCredentials> },
+    /// `authenticated` is set for a host once its identity checks out, and for a viewer once
+    /// its credentials do. Nothing but the handshake is taken from a peer until then.
+    Established { sealer: ChannelSealer, opener: ChannelOpener, authenticated: bool },
+}
+
+/// Plaintext of a `Sealed` packet
+#[derive(Serialize, Deserialize)]
+struct SealedPayload {
+    message_type: RelayMessageType,
+    payload: Vec<u8>,
+}
+
+/// What a viewer sends sealed before the host asks anyone for permission
+#[derive(Serialize, Deserialize)]
+struct PeerAuthentication {
+    client_info: DeviceInfo,
+    credentials: ClientCredentials,
+}
+
+/// An encoded file transfer message, held back until `send_at` for the transfer speed limit
+struct QueuedTransfer {
+    target_id: String,
+    source_id: Option<String>,
+    payload: Vec<u8>,
+    send_at: tokio::time::Instant,
+}
+
 pub struct RelayClient {
     config: RelayConfig,
     connection_id: Option<String>, // Our 8-digit ID
     device_info: DeviceInfo,
     event_sender: Option<mpsc::UnboundedSender<RelayClientEvent>>,
+    outgoing_sender: Option<mpsc::UnboundedSender<Message>>,
+    /// File transfer messages, sent in order whenever the outgoing queue has nothing waiting
+    transfer_sender: Option<mpsc::UnboundedSender<QueuedTransfer>>,
+    sessions: PeerSessions,
+    identity: Arc<HostIdentity>,
+    known_hosts: Arc<KnownHostsStore>,
+    security: Option<Arc<SecurityManager>>,
     is_connected: Arc<RwLock<bool>>,
     is_registered: Arc<RwLock<bool>>,
 }
 
 impl RelayClient {
-    pub fn new(config: RelayConfig) -> Self {
+    /// Fails if the host identity or the known hosts can't be loaded, rather than run without them
+    pub fn new(config: RelayConfig) -> Result<Self> {
         let device_info = DeviceInfo {
             name: get_device_name(),
             os: get_os_name(),
             version: env!("CARGO_PKG_VERSION").to_string(),
         };
         
-        Self {
+        Ok(Self {
             config,
             connection_id: None,
             device_info,
             event_sender: None,
+            outgoing_sender: None,
+            transfer_sender: None,
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            identity: Arc::new(HostIdentity::load_default()?),
+            known_hosts: Arc::new(KnownHostsStore::load_default()?),
+            security: None,
             is_connected: Arc::new(RwLock::new(false)),
             is_registered: Arc::new(RwLock::new(false)),
-        }
+        })
+    }
+    
+    /// Check viewers' credentials when hosting; without it every relay viewer is refused
+    pub fn set_security_manager(&mut self, security: Arc<SecurityManager>) {
+        self.security = Some(security);
     }
     
     pub async fn connect(&mut self) -> Result<mpsc::UnboundedReceiver<RelayClientEvent>> {
@@ -149,13 +259,44 @@ impl RelayClient {
         }
         
         // Handle outgoing messages
-        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<RelayMessage>();
+        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+        self.outgoing_sender = Some(outgoing_tx.clone());
+        let (transfer_tx, mut transfer_rx) = mpsc::unbounded_channel::<QueuedTransfer>();
+        self.transfer_sender = Some(transfer_tx);
         let event_tx_clone = event_tx.clone();
         let is_connected_clone = is_connected.clone();
+        let sessions = self.sessions.clone();
+        let encrypted = self.config.require_encryption;
+        let wire_format = self.config.wire_format;
         tokio::spawn(async move {
-            while let Some(message) = outgoing_rx.recv().await {
-                if let Ok(text) = serde_json::to_string(&message) {
-                    if let Err(e) = ws_sender.send(Message::Text(text)).await {
+            // File transfer messages go in order, and only when nothing else is waiting
+            let mut next_transfer: Option<QueuedTransfer> = None;
+            
+            loop {
+                let send_at = next_transfer.as_ref().map(|transfer| transfer.send_at);
+                
+                let messages = tokio::select! {
+                    biased;
+                    Some(message) = outgoing_rx.recv() => vec![message],
+                    Some(transfer) = transfer_rx.recv(), if next_transfer.is_none() => {
+                        next_transfer = Some(transfer);
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(send_at.unwrap_or_else(tokio::time::Instant::now)), if send_at.is_some() => {
+                        let transfer = next_transfer.take().unwrap();
+                        match take_transfer(transfer, &sessions, encrypted, wire_format, &mut outgoing_rx).await {
+                            Ok(messages) => messages,
+                            Err(e) => {
+                                warn!("Dropping file transfer message: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    else => break,
+                };
+                
+                for message in messages {
+                    if let Err(e) = ws_sender.send(message).await {
                         error!("Failed to send message to relay server: {}", e);
                         
                         // Update connection status
@@ -168,10 +309,33 @@ impl RelayClient {
                         if let Err(e) = event_tx_clone.send(RelayClientEvent::Disconnected) {
                             error!("Failed to send disconnected event: {}", e);
                         }
-                        break;
+                        return;
                     }
-                } else {
-                    error!("Failed to serialize relay message");
+                }
+            }
+        });
+        
+        // Keep the relay from dropping us as idle
+        let heartbeat_tx = outgoing_tx.clone();
+        let heartbeat_interval = std::time::Duration::from_secs(self.config.heartbeat_interval_seconds.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(heartbeat_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let heartbeat = RelayMessage {
+                    message_type: RelayMessageType::Heartbeat,
+                    source_id: None,
+                    target_id: "relay".to_string(),
+                    data: serde_json::json!({}),
+                    timestamp: chrono::Utc::now(),
+                };
+                let text = match serde_json::to_string(&heartbeat) {
+                    Ok(text) => text,
+                    Err(_) => break,
+                };
+                if heartbeat_tx.send(Message::Text(text)).is_err() {
+                    break;
                 }
             }
         });
@@ -180,6 +344,15 @@ impl RelayClient {
         let event_tx_clone = event_tx.clone();
         let is_connected_clone = is_connected.clone();
         let is_registered_clone = is_registered.clone();
+        let session_context = SessionContext {
+            sessions: self.sessions.clone(),
+            identity: self.identity.clone(),
+            known_hosts: self.known_hosts.clone(),
+            security: self.security.clone(),
+            device_info: self.device_info.clone(),
+            outgoing: outgoing_tx,
+            require_encryption: self.config.require_encryption,
+        };
         tokio::spawn(async move {
             while let Some(msg) = ws_receiver.next().await {
                 match msg {
@@ -194,7 +367,64 @@ impl RelayClient {
                                         handle_register_response(&relay_message, &event_tx_clone, &is_registered_clone).await;
                                     }
                                     RelayMessageType::ConnectRequest => {
-                                        handle_connect_request(&relay_message, &event_tx_clone).await;
+                                        handle_connect_request(&relay_message);
+                                    }
+                                    RelayMessageType::KeyExchangeInit => {
+                                        if let Err(e) = session_context.respond_to_key_exchange(&relay_message).await {
+                                            warn!("Rejected key exchange from {:?}: {}", relay_message.source_id, e);
+                                        }
+                                    }
+                                    RelayMessageType::KeyExchangeReply => {
+                                        match session_context.complete_key_exchange(&relay_message).await {
+                                            Ok(event) => {
+                                                let _ = event_tx_clone.send(event);
+                                            }
+                                            Err(e) => {
+                                                error!("Key exchange with {:?} failed: {}", relay_message.source_id, e);
+                                                let _ = event_tx_clone.send(RelayClientEvent::Error(e.to_string()));
+                                            }
+                                        }
+                                    }
+                                    RelayMessageType::Sealed => {
+                                        let sealed = relay_message.data.get("payload")
+                                            .and_then(|v| v.as_str())
+                                            .and_then(|payload| general_purpose::STANDARD.decode(payload).ok());
+                                        
+                                        match sealed {
+                                            Some(sealed) => {
+                                                let source_id = relay_message.source_id.clone();
+                                                let target_id = relay_message.target_id.clone();
+                                                session_context.deliver_sealed(source_id, target_id, &sealed, &event_tx_clone).await;
+                                            }
+                                            None => warn!("Malformed sealed relay message"),
+                                        }
+                                    }
+                                    RelayMessageType::Heartbeat => {
+                                        debug!("Relay heartbeat acknowledged");
+                                    }
+                                    RelayMessageType::ScreenFrame | RelayMessageType::InputEvent | RelayMessageType::FileTransfer if session_context.require_encryption => {
+                                        warn!("Dropping unencrypted {:?} from {:?}", relay_message.message_type, relay_message.source_id);
+                                    }
+                                    RelayMessageType::FileTransfer => {
+                                        // Hand file transfer on as a packet so JSON and binary relays look the same
+                                        let payload = relay_message.data.get("payload")
+                                            .and_then(|v| v.as_str())
+                                            .and_then(|payload| general_purpose::STANDARD.decode(payload).ok());
+                                        
+                                        match payload {
+                                            Some(payload) => {
+                                                let packet = RelayPacket {
+                                                    message_type: RelayMessageType::FileTransfer,
+                                                    source_id: relay_message.source_id,
+                                                    target_id: relay_message.target_id,
+                                                    payload,
+                                                };
+                                                if let Err(e) = event_tx_clone.send(RelayClientEvent::PacketReceived(packet)) {
+                                                    error!("Failed to send packet received event: {}", e);
+                                                }
+                                            }
+                                            None => warn!("Malformed file transfer relay message"),
+                                        }
                                     }
                                     _ => {
                                         // Forward other messages as events
@@ -209,6 +439,30 @@ impl RelayClient {
                             }
                         }
                     }
+                    Ok(Message::Binary(data)) => {
+                        match RelayPacket::decode(&data) {
+                            Ok(packet) => {
+                                debug!("Received relay packet: {:?} ({} bytes)", packet.message_type, packet.payload.len());
+                                
+                                match packet.message_type {
+                                    RelayMessageType::Sealed => {
+                                        session_context.deliver_sealed(packet.source_id, packet.target_id, &packet.payload, &event_tx_clone).await;
+                                    }
+                                    _ if session_context.require_encryption => {
+                                        warn!("Dropping unencrypted {:?} packet from {:?}", packet.message_type, packet.source_id);
+                                    }
+                                    _ => {
+                                        if let Err(e) = event_tx_clone.send(RelayClientEvent::PacketReceived(packet)) {
+                                            error!("Failed to send packet received event: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                error!("Failed to parse relay packet: {}", e);
+                            }
+                        }
+                    }
                     Ok(Message::Close(_)) => {
                         info!("Relay server connection closed");
                         
@@ -279,14 +533,14 @@ impl RelayClient {
             timestamp: chrono::Utc::now(),
         };
         
-        // TODO: Send message through outgoing channel
-        // For now, we'll simulate registration success
-        debug!("Registration message prepared for ID: {}", connection_id);
+        self.send_message(message)?;
+        debug!("Registration message sent for ID: {}", connection_id);
         
         Ok(())
     }
     
-    pub async fn connect_to_peer(&self, target_connection_id: String) -> Result<()> {
+    /// Ask a host for a session. The credentials go sealed once the host's identity checks out.
+    pub async fn connect_to_peer(&self, target_connection_id: String, credentials: ClientCredentials) -> Result<()> {
         if !*self.is_registered.read().await {
             return Err(anyhow::anyhow!("Not registered with relay server"));
         }
@@ -296,6 +550,7 @@ impl RelayClient {
         let connect_request = ConnectRequest {
             target_connection_id: target_connection_id.clone(),
             client_info: self.device_info.clone(),
+            source_id: None,
         };
         
         let message = RelayMessage {
@@ -306,51 +561,172 @@ impl RelayClient {
             timestamp: chrono::Utc::now(),
         };
         
-        // TODO: Send message through outgoing channel
-        debug!("Connect request prepared for target: {}", target_connection_id);
+        self.send_message(message)?;
+        debug!("Connect request sent for target: {}", target_connection_id);
+        
+        // The end-to-end handshake rides along; the target answers once it sees it
+        let exchange = KeyExchange::new();
+        let init = exchange.init_message();
+        
+        let mut sessions = self.sessions.write().await;
+        let init_message = match sessions.get_mut(&target_connection_id) {
+            // The host only lets an established session be replaced from inside it
+            Some(PeerSession::Established { sealer, .. }) => {
+                let plaintext = bincode::serialize(&SealedPayload {
+                    message_type: RelayMessageType::KeyExchangeInit,
+                    payload: serde_json::to_vec(&init)?,
+                })?;
+                RelayMessage {
+                    message_type: RelayMessageType::Sealed,
+                    source_id: self.connection_id.clone(),
+                    target_id: target_connection_id.clone(),
+                    data: serde_json::json!({ "payload": general_purpose::STANDARD.encode(sealer.seal(&plaintext)?) }),
+                    timestamp: chrono::Utc::now(),
+                }
+            }
+            _ => RelayMessage {
+                message_type: RelayMessageType::KeyExchangeInit,
+                source_id: self.connection_id.clone(),
+                target_id: target_connection_id.clone(),
+                data: serde_json::to_value(init)?,
+                timestamp: chrono::Utc::now(),
+            },
+        };
+        
+        sessions.insert(target_connection_id, PeerSession::Pending { exchange, credentials: Some(credentials) });
+        self.send_message(init_message)?;
         
         Ok(())
     }
     
+    /// Whether an end-to-end session with the peer is ready
+    pub async fn is_peer_secured(&self, peer_id: &str) -> bool {
+        matches!(self.sessions.read().await.get(peer_id), Some(PeerSession::Established { .. }))
+    }
+    
     pub async fn send_screen_frame(&self, target_id: String, frame_data: Vec<u8>) -> Result<()> {
         if !*self.is_registered.read().await {
             return Err(anyhow::anyhow!("Not registered with relay server"));
         }
         
-        let message = RelayMessage {
+        if self.config.require_encryption {
+            return self.send_sealed(target_id, RelayMessageType::ScreenFrame, frame_data).await;
+        }
+        
+        if self.config.wire_format == WireFormat::Json {
+            let message = RelayMessage {
+                message_type: RelayMessageType::ScreenFrame,
+                source_id: self.connection_id.clone(),
+                target_id,
+                data: serde_json::json!({
+                    "frame_data": general_purpose::STANDARD.encode(frame_data),
+                    "timestamp": chrono::Utc::now(),
+                }),
+                timestamp: chrono::Utc::now(),
+            };
+            
+            return self.send_message(message);
+        }
+        
+        self.send_packet(RelayPacket {
             message_type: RelayMessageType::ScreenFrame,
             source_id: self.connection_id.clone(),
             target_id,
-            data: serde_json::json!({
-                "frame_data": general_purpose::STANDARD.encode(frame_data),
-                "timestamp": chrono::Utc::now(),
-            }),
-            timestamp: chrono::Utc::now(),
-        };
-        
-        // TODO: Send message through outgoing channel
-        debug!("Screen frame prepared for forwarding");
-        
-        Ok(())
+            payload: frame_data,
+        })
     }
     
-    pub async fn send_input_event(&self, target_id: String, input_data: serde_json::Value) -> Result<()> {
+    pub async fn send_input_event(&self, target_id: String, input_event: InputEvent) -> Result<()> {
         if !*self.is_registered.read().await {
             return Err(anyhow::anyhow!("Not registered with relay server"));
         }
         
-        let message = RelayMessage {
+        if self.config.require_encryption {
+            return self.send_sealed(target_id, RelayMessageType::InputEvent, bincode::serialize(&input_event)?).await;
+        }
+        
+        if self.config.wire_format == WireFormat::Json {
+            let message = RelayMessage {
+                message_type: RelayMessageType::InputEvent,
+                source_id: self.connection_id.clone(),
+                target_id,
+                data: serde_json::to_value(input_event)?,
+                timestamp: chrono::Utc::now(),
+            };
+            
+            return self.send_message(message);
+        }
+        
+        self.send_packet(RelayPacket {
             message_type: RelayMessageType::InputEvent,
             source_id: self.connection_id.clone(),
             target_id,
-            data: input_data,
-            timestamp: chrono::Utc::now(),
+            payload: bincode::serialize(&input_event)?,
+        })
+    }
+    
+    /// Queue an encoded file transfer `WireMessage` for the peer. They go in order, behind
+    /// everything else on the connection and not before `send_at`.
+    pub async fn send_file_transfer(&self, target_id: String, payload: Vec<u8>, send_at: tokio::time::Instant) -> Result<()> {
+        if !*self.is_registered.read().await {
+            return Err(anyhow::anyhow!("Not registered with relay server"));
+        }
+        
+        if self.config.require_encryption
+            && !matches!(self.sessions.read().await.get(&target_id), Some(PeerSession::Established { .. }))
+        {
+            return Err(anyhow::anyhow!("No end-to-end session with {}", target_id));
+        }
+        
+        let sender = self.transfer_sender.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to relay server"))?;
+        sender.send(QueuedTransfer { target_id, source_id: self.connection_id.clone(), payload, send_at })
+            .map_err(|_| anyhow::anyhow!("Relay connection closed"))
+    }
+    
+    /// Encrypt for the peer so the relay only sees the routing fields
+    async fn send_sealed(&self, target_id: String, message_type: RelayMessageType, payload: Vec<u8>) -> Result<()> {
+        let plaintext = bincode::serialize(&SealedPayload { message_type, payload })?;
+        
+        // Sealed and queued under the lock so counters reach the peer in order
+        let mut sessions = self.sessions.write().await;
+        let sealed = match sessions.get_mut(&target_id) {
+            Some(PeerSession::Established { sealer, .. }) => sealer.seal(&plaintext)?,
+            _ => return Err(anyhow::anyhow!("No end-to-end session with {}", target_id)),
         };
         
-        // TODO: Send message through outgoing channel
-        debug!("Input event prepared for forwarding");
+        if self.config.wire_format == WireFormat::Json {
+            return self.send_message(RelayMessage {
+                message_type: RelayMessageType::Sealed,
+                source_id: self.connection_id.clone(),
+                target_id,
+                data: serde_json::json!({ "payload": general_purpose::STANDARD.encode(sealed) }),
+                timestamp: chrono::Utc::now(),
+            });
+        }
         
-        Ok(())
+        self.send_packet(RelayPacket {
+            message_type: RelayMessageType::Sealed,
+            source_id: self.connection_id.clone(),
+            target_id,
+            payload: sealed,
+        })
+    }
+    
+    fn send_message(&self, message: RelayMessage) -> Result<()> {
+        let sender = self.outgoing_sender.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to relay server"))?;
+        
+        sender.send(Message::Text(serde_json::to_string(&message)?))
+            .map_err(|_| anyhow::anyhow!("Relay connection closed"))
+    }
+    
+    fn send_packet(&self, packet: RelayPacket) -> Result<()> {
+        let sender = self.outgoing_sender.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to relay server"))?;
+        
+        sender.send(Message::Binary(packet.encode()?))
+            .map_err(|_| anyhow::anyhow!("Relay connection closed"))
     }
     
     pub async fn disconnect(&mut self) -> Result<()> {
@@ -369,9 +745,17 @@ impl RelayClient {
                 timestamp: chrono::Utc::now(),
             };
             
-            // TODO: Send disconnect message
-            debug!("Disconnect message prepared");
+            if let Err(e) = self.send_message(message) {
+                debug!("Failed to send disconnect message: {}", e);
+            }
+        }
+        
+        if let Some(sender) = self.outgoing_sender.take() {
+            let _ = sender.send(Message::Close(None));
         }
+        self.transfer_sender = None;
+        
+        self.sessions.write().await.clear();
         
         // Update connection status
         {
@@ -402,6 +786,317 @@ impl RelayClient {
     }
 }
 
+/// Build a queued file transfer message as it goes out, sealed if the relay needs encryption.
+/// Messages already queued were sealed before it, so they are taken off the queue to go first
+/// and counters still reach the peer in order.
+async fn take_transfer(
+    transfer: QueuedTransfer,
+    sessions: &PeerSessions,
+    encrypted: bool,
+    wire_format: WireFormat,
+    queued: &mut mpsc::UnboundedReceiver<Message>,
+) -> Result<Vec<Message>> {
+    let mut sessions = sessions.write().await;
+    
+    let (message_type, payload) = if encrypted {
+        let plaintext = bincode::serialize(&SealedPayload {
+            message_type: RelayMessageType::FileTransfer,
+            payload: transfer.payload,
+        })?;
+        match sessions.get_mut(&transfer.target_id) {
+            Some(PeerSession::Established { sealer, .. }) => (RelayMessageType::Sealed, sealer.seal(&plaintext)?),
+            _ => return Err(anyhow::anyhow!("No end-to-end session with {}", transfer.target_id)),
+        }
+    } else {
+        (RelayMessageType::FileTransfer, transfer.payload)
+    };
+    
+    let message = if wire_format == WireFormat::Json {
+        Message::Text(serde_json::to_string(&RelayMessage {
+            message_type,
+            source_id: transfer.source_id,
+            target_id: transfer.target_id,
+            data: serde_json::json!({ "payload": general_purpose::STANDARD.encode(payload) }),
+            timestamp: chrono::Utc::now(),
+        })?)
+    } else {
+        Message::Binary(RelayPacket {
+            message_type,
+            source_id: transfer.source_id,
+            target_id: transfer.target_id,
+            payload,
+        }.encode()?)
+    };
+    
+    let mut messages = Vec::new();
+    while let Ok(message) = queued.try_recv() {
+        messages.push(message);
+    }
+    messages.push(message);
+    Ok(messages)
+}
+
+/// State the incoming task needs to run end-to-end handshakes
+struct SessionContext {
+    sessions: PeerSessions,
+    identity: Arc<HostIdentity>,
+    known_hosts: Arc<KnownHostsStore>,
+    security: Option<Arc<SecurityManager>>,
+    device_info: DeviceInfo,
+    outgoing: mpsc::UnboundedSender<Message>,
+    require_encryption: bool,
+}
+
+impl SessionContext {
+    /// Host side: answer a peer's first key exchange with our signed identity. An established
+    /// session is only replaced by a rekey sealed under it, so the relay can't take it over.
+    async fn respond_to_key_exchange(&self, message: &RelayMessage) -> Result<()> {
+        let peer_id = message.source_id.clone()
+            .ok_or_else(|| anyhow::anyhow!("Key exchange without a source ID"))?;
+        let init = serde_json::from_value::<KeyExchangeInit>(message.data.clone())?;
+        
+        let mut sessions = self.sessions.write().await;
+        if matches!(sessions.get(&peer_id), Some(PeerSession::Established { .. })) {
+            return Err(anyhow::anyhow!("{} already has a session, and a rekey has to be sealed under it", peer_id));
+        }
+        
+        self.answer_key_exchange(&mut sessions, peer_id, message.target_id.clone(), &init, false)
+    }
+    
+    /// Host side: a key exchange that arrived sealed under the current session keeps its authentication
+    async fn rekey(&self, peer_id: String, own_id: String, payload: &[u8]) -> Result<()> {
+        let init = serde_json::from_slice::<KeyExchangeInit>(payload)?;
+        
+        let mut sessions = self.sessions.write().await;
+        let authenticated = match sessions.get(&peer_id) {
+            Some(PeerSession::Established { authenticated, .. }) => *authenticated,
+            _ => return Err(anyhow::anyhow!("No session with {} to rekey", peer_id)),
+        };
+        
+        self.answer_key_exchange(&mut sessions, peer_id, own_id, &init, authenticated)
+    }
+    
+    fn answer_key_exchange(
+        &self,
+        sessions: &mut HashMap<String, PeerSession>,
+        peer_id: String,
+        own_id: String,
+        init: &KeyExchangeInit,
+        authenticated: bool,
+    ) -> Result<()> {
+        let (reply, channel) = KeyExchange::new().respond(init, &self.identity)?;
+        let (sealer, opener) = channel.into_split();
+        
+        let reply_message = RelayMessage {
+            message_type: RelayMessageType::KeyExchangeReply,
+            source_id: Some(own_id),
+            target_id: peer_id.clone(),
+            data: serde_json::to_value(reply)?,
+            timestamp: chrono::Utc::now(),
+        };
+        
+        sessions.insert(peer_id.clone(), PeerSession::Established { sealer, opener, authenticated });
+        self.outgoing.send(Message::Text(serde_json::to_string(&reply_message)?))
+            .map_err(|_| anyhow::anyhow!("Relay connection closed"))?;
+        
+        info!("End-to-end session established with relay peer {}", peer_id);
+        Ok(())
+    }
+    
+    /// Viewer side: check the host's signature and pinned identity, then send our credentials
+    async fn complete_key_exchange(&self, message: &RelayMessage) -> Result<RelayClientEvent> {
+        let peer_id = message.source_id.clone()
+            .ok_or_else(|| anyhow::anyhow!("Key exchange reply without a source ID"))?;
+        let reply = serde_json::from_value::<KeyExchangeReply>(message.data.clone())?;
+        
+        let mut sessions = self.sessions.write().await;
+        let (exchange, credentials) = match sessions.remove(&peer_id) {
+            Some(PeerSession::Pending { exchange, credentials }) => (exchange, credentials),
+            other => {
+                if let Some(session) = other {
+                    sessions.insert(peer_id.clone(), session);
+                }
+                return Err(anyhow::anyhow!("Unexpected key exchange reply from {}", peer_id));
+            }
+        };
+        
+        let (sealer, opener) = exchange.complete(&reply)?.into_split();
+        
+        let status = self.known_hosts.verify(&peer_id, &reply.identity_key)?;
+        
+        if let HostKeyStatus::Mismatch { expected, presented } = status {
+            error!("Relay peer {} presented a different identity, refusing session", peer_id);
+            return Ok(RelayClientEvent::HostKeyMismatch { peer_id, expected, presented });
+        }
+        
+        sessions.insert(peer_id.clone(), PeerSession::Established { sealer, opener, authenticated: true });
+        info!("End-to-end session established with relay peer {}", peer_id);
+        
+        if let Some(credentials) = credentials {
+            let authentication = PeerAuthentication { client_info: self.device_info.clone(), credentials };
+            self.send_sealed(&mut sessions, &peer_id, &message.target_id, RelayMessageType::Authenticate, serde_json::to_vec(&authentication)?)?;
+        }
+        
+        Ok(RelayClientEvent::PeerSecured {
+            peer_id,
+            fingerprint: Some(fingerprint(&reply.identity_key)),
+            first_use: status == HostKeyStatus::FirstUse,
+        })
+    }
+    
+    /// Host side: only a viewer whose credentials check out gets its connection request through
+    async fn authenticate_peer(
+        &self,
+        peer_id: String,
+        own_id: String,
+        payload: &[u8],
+        event_sender: &mpsc::UnboundedSender<RelayClientEvent>,
+    ) {
+        let verdict = self.check_credentials(&peer_id, payload).await;
+        
+        let mut sessions = self.sessions.write().await;
+        let response = match verdict {
+            Ok(_) => {
+                if let Some(PeerSession::Established { authenticated, .. }) = sessions.get_mut(&peer_id) {
+                    *authenticated = true;
+                }
+                serde_json::json!({ "success": true })
+            }
+            Err(ref e) => {
+                warn!("Relay peer {} failed authentication: {}", peer_id, e);
+                serde_json::json!({ "success": false, "error": e.to_string() })
+            }
+        };
+        
+        if let Err(e) = self.send_sealed(&mut sessions, &peer_id, &own_id, RelayMessageType::ConnectResponse, response.to_string().into_bytes()) {
+            warn!("Failed to answer authentication from {}: {}", peer_id, e);
+        }
+        drop(sessions);
+        
+        if let Ok(client_info) = verdict {
+            info!("Relay peer {} authenticated", peer_id);
+            let request = ConnectRequest {
+                target_connection_id: own_id,
+                client_info,
+                source_id: Some(peer_id),
+            };
+            if let Err(e) = event_sender.send(RelayClientEvent::ConnectionRequest(request)) {
+                error!("Failed to send connection request event: {}", e);
+            }
+        }
+    }
+    
+    async fn check_credentials(&self, peer_id: &str, payload: &[u8]) -> Result<DeviceInfo> {
+        let authentication = serde_json::from_slice::<PeerAuthentication>(payload)?;
+        let security = self.security.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Authentication is not available on this host"))?;
+        
+        if !security.authenticate_client(peer_id, &authentication.credentials).await? {
+            return Err(anyhow::anyhow!("Authentication failed"));
+        }
+        Ok(authentication.client_info)
+    }
+    
+    /// Seal and queue a message for the peer. Callers hold the sessions lock so counters go out in order.
+    fn send_sealed(
+        &self,
+        sessions: &mut HashMap<String, PeerSession>,
+        peer_id: &str,
+        own_id: &str,
+        message_type: RelayMessageType,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        let plaintext = bincode::serialize(&SealedPayload { message_type, payload })?;
+        let sealed = match sessions.get_mut(peer_id) {
+            Some(PeerSession::Established { sealer, .. }) => sealer.seal(&plaintext)?,
+            _ => return Err(anyhow::anyhow!("No end-to-end session with {}", peer_id)),
+        };
+        
+        let message = RelayMessage {
+            message_type: RelayMessageType::Sealed,
+            source_id: Some(own_id.to_string()),
+            target_id: peer_id.to_string(),
+            data: serde_json::json!({ "payload": general_purpose::STANDARD.encode(sealed) }),
+            timestamp: chrono::Utc::now(),
+        };
+        self.outgoing.send(Message::Text(serde_json::to_string(&message)?))
+            .map_err(|_| anyhow::anyhow!("Relay connection closed"))
+    }
+    
+    /// Decrypt peer data and hand it on as a plain packet
+    async fn deliver_sealed(
+        &self,
+        source_id: Option<String>,
+        target_id: String,
+        sealed: &[u8],
+        event_sender: &mpsc::UnboundedSender<RelayClientEvent>,
+    ) {
+        let peer_id = match source_id {
+            Some(peer_id) => peer_id,
+            None => {
+                warn!("Dropping sealed relay data without a source ID");
+                return;
+            }
+        };
+        
+        let (plaintext, authenticated) = match self.sessions.write().await.get_mut(&peer_id) {
+            Some(PeerSession::Established { opener, authenticated, .. }) => (opener.open(sealed), *authenticated),
+            _ => (Err(anyhow::anyhow!("no end-to-end session")), false),
+        };
+        
+        let payload = match plaintext.and_then(|plaintext| Ok(bincode::deserialize::<SealedPayload>(&plaintext)?)) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Dropping sealed relay data from {}: {}", peer_id, e);
+                return;
+            }
+        };
+        
+        match payload.message_type {
+            RelayMessageType::KeyExchangeInit => {
+                if let Err(e) = self.rekey(peer_id.clone(), target_id, &payload.payload).await {
+                    warn!("Rejected rekey from {}: {}", peer_id, e);
+                }
+                return;
+            }
+            RelayMessageType::Authenticate if !authenticated => {
+                self.authenticate_peer(peer_id, target_id, &payload.payload, event_sender).await;
+                return;
+            }
+            RelayMessageType::Authenticate => {
+                debug!("Ignoring repeated authentication from {}", peer_id);
+                return;
+            }
+            RelayMessageType::ConnectResponse => {
+                let response = serde_json::from_slice::<serde_json::Value>(&payload.payload).unwrap_or_default();
+                if response.get("success").and_then(|v| v.as_bool()) == Some(true) {
+                    info!("Authenticated with relay peer {}", peer_id);
+                } else {
+                    let error = response.get("error").and_then(|v| v.as_str()).unwrap_or("Unknown error");
+                    let _ = event_sender.send(RelayClientEvent::Error(format!("{} refused authentication: {}", peer_id, error)));
+                }
+                return;
+            }
+            ref message_type if !authenticated => {
+                warn!("Dropping {:?} from unauthenticated relay peer {}", message_type, peer_id);
+                return;
+            }
+            _ => {}
+        }
+        
+        let packet = RelayPacket {
+            message_type: payload.message_type,
+            source_id: Some(peer_id),
+            target_id,
+            payload: payload.payload,
+        };
+        
+        if let Err(e) = event_sender.send(RelayClientEvent::PacketReceived(packet)) {
+            error!("Failed to send packet received event: {}", e);
+        }
+    }
+}
+
 async fn handle_register_response(
     message: &RelayMessage,
     event_sender: &mpsc::UnboundedSender<RelayClientEvent>,
@@ -438,16 +1133,11 @@ async fn handle_register_response(
     }
 }
 
-async fn handle_connect_request(
-    message: &RelayMessage,
-    event_sender: &mpsc::UnboundedSender<RelayClientEvent>,
-) {
+fn handle_connect_request(message: &RelayMessage) {
+    // Anyone can claim a name here, so the request only goes on once the viewer authenticates
     if let Ok(connect_request) = serde_json::from_value::<ConnectRequest>(message.data.clone()) {
-        info!("Received connection request from: {}", connect_request.client_info.name);
-        
-        if let Err(e) = event_sender.send(RelayClientEvent::ConnectionRequest(connect_request)) {
-            error!("Failed to send connection request event: {}", e);
-        }
+        debug!("Connection request from {:?} ({}), waiting for it to authenticate",
+               message.source_id, connect_request.client_info.name);
     }
 }
 
@@ -467,4 +1157,224 @@ fn get_os_name() -> String {
     } else {
         "Unknown".to_string()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(security: Option<Arc<SecurityManager>>) -> (SessionContext, mpsc::UnboundedReceiver<Message>) {
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let context = SessionContext {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            identity: Arc::new(HostIdentity::generate()),
+            known_hosts: Arc::new(KnownHostsStore::load(
+                std::env::temp_dir().join(format!("anyviewer-test-{}.json", uuid::Uuid::new_v4())),
+            ).unwrap()),
+            security,
+            device_info: DeviceInfo { name: "test".to_string(), os: "Linux".to_string(), version: "0".to_string() },
+            outgoing,
+            require_encryption: true,
+        };
+        (context, outgoing_rx)
+    }
+
+    fn relay_message(message: Message) -> RelayMessage {
+        match message {
+            Message::Text(text) => serde_json::from_str::<RelayMessage>(&text).unwrap(),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    fn sealed_payload(message: &RelayMessage) -> Vec<u8> {
+        general_purpose::STANDARD.decode(message.data["payload"].as_str().unwrap()).unwrap()
+    }
+
+    async fn seal(context: &SessionContext, peer_id: &str, message_type: RelayMessageType, payload: &[u8]) -> Vec<u8> {
+        let plaintext = bincode::serialize(&SealedPayload { message_type, payload: payload.to_vec() }).unwrap();
+        match context.sessions.write().await.get_mut(peer_id) {
+            Some(PeerSession::Established { sealer, .. }) => sealer.seal(&plaintext).unwrap(),
+            _ => panic!("Session not established"),
+        }
+    }
+
+    /// A host and a viewer that have shaken hands, the viewer seeing the host as `11111111`
+    /// and sending `pin`. Returns what each side has queued for the relay.
+    async fn handshake(security: Arc<SecurityManager>, pin: &str) -> (SessionContext, SessionContext, mpsc::UnboundedReceiver<Message>, mpsc::UnboundedReceiver<Message>) {
+        let (host, mut host_outgoing) = context(Some(security));
+        let (viewer, viewer_outgoing) = context(None);
+
+        let exchange = KeyExchange::new();
+        let init = RelayMessage {
+            message_type: RelayMessageType::KeyExchangeInit,
+            source_id: Some("22222222".to_string()),
+            target_id: "11111111".to_string(),
+            data: serde_json::to_value(exchange.init_message()).unwrap(),
+            timestamp: chrono::Utc::now(),
+        };
+        let credentials = Some(ClientCredentials::Pin { pin: pin.to_string() });
+        viewer.sessions.write().await.insert("11111111".to_string(), PeerSession::Pending { exchange, credentials });
+
+        host.respond_to_key_exchange(&init).await.unwrap();
+        let reply = relay_message(host_outgoing.recv().await.unwrap());
+
+        match viewer.complete_key_exchange(&reply).await.unwrap() {
+            RelayClientEvent::PeerSecured { first_use, .. } => assert!(first_use),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        (host, viewer, host_outgoing, viewer_outgoing)
+    }
+
+    /// A host and an authenticated viewer with an end-to-end session
+    async fn secured() -> (SessionContext, SessionContext) {
+        let security = Arc::new(SecurityManager::new().unwrap());
+        let pin = security.generate_session_pin();
+        let (host, viewer, _, mut viewer_outgoing) = handshake(security, &pin).await;
+
+        let authentication = relay_message(viewer_outgoing.recv().await.unwrap());
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &sealed_payload(&authentication), &event_tx).await;
+
+        match event_rx.recv().await.unwrap() {
+            RelayClientEvent::ConnectionRequest(request) => assert_eq!(request.source_id.as_deref(), Some("22222222")),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        (host, viewer)
+    }
+
+    #[tokio::test]
+    async fn test_wrong_credentials_get_no_connection_request() {
+        let (host, viewer, _, mut viewer_outgoing) = handshake(Arc::new(SecurityManager::new().unwrap()), "000000").await;
+
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let authentication = relay_message(viewer_outgoing.recv().await.unwrap());
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &sealed_payload(&authentication), &event_tx).await;
+
+        // Nor does anything else it sends get through
+        let input = seal(&viewer, "11111111", RelayMessageType::InputEvent, b"keystrokes").await;
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &input, &event_tx).await;
+
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plain_key_exchange_cannot_replace_session() {
+        let (host, viewer) = secured().await;
+
+        let init = RelayMessage {
+            message_type: RelayMessageType::KeyExchangeInit,
+            source_id: Some("22222222".to_string()),
+            target_id: "11111111".to_string(),
+            data: serde_json::to_value(KeyExchange::new().init_message()).unwrap(),
+            timestamp: chrono::Utc::now(),
+        };
+        assert!(host.respond_to_key_exchange(&init).await.is_err());
+
+        // The viewer's session is untouched
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let input = seal(&viewer, "11111111", RelayMessageType::InputEvent, b"keystrokes").await;
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &input, &event_tx).await;
+        assert!(matches!(event_rx.try_recv(), Ok(RelayClientEvent::PacketReceived(_))));
+    }
+
+    #[tokio::test]
+    async fn test_sealed_rekey_keeps_authentication() {
+        let security = Arc::new(SecurityManager::new().unwrap());
+        let pin = security.generate_session_pin();
+        let (host, viewer, mut host_outgoing, mut viewer_outgoing) = handshake(security, &pin).await;
+
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let authentication = relay_message(viewer_outgoing.recv().await.unwrap());
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &sealed_payload(&authentication), &event_tx).await;
+        assert!(matches!(event_rx.recv().await, Some(RelayClientEvent::ConnectionRequest(_))));
+        host_outgoing.recv().await.unwrap();
+
+        let exchange = KeyExchange::new();
+        let init = serde_json::to_vec(&exchange.init_message()).unwrap();
+        let rekey = seal(&viewer, "11111111", RelayMessageType::KeyExchangeInit, &init).await;
+        viewer.sessions.write().await.insert("11111111".to_string(), PeerSession::Pending { exchange, credentials: None });
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &rekey, &event_tx).await;
+
+        let reply = relay_message(host_outgoing.recv().await.unwrap());
+        assert!(matches!(viewer.complete_key_exchange(&reply).await.unwrap(), RelayClientEvent::PeerSecured { .. }));
+
+        let input = seal(&viewer, "11111111", RelayMessageType::InputEvent, b"keystrokes").await;
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &input, &event_tx).await;
+        match event_rx.try_recv() {
+            Ok(RelayClientEvent::PacketReceived(packet)) => assert_eq!(packet.payload, b"keystrokes"),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_only_sees_ciphertext() {
+        let (host, viewer) = secured().await;
+
+        let plaintext = bincode::serialize(&SealedPayload {
+            message_type: RelayMessageType::InputEvent,
+            payload: b"secret keystrokes".to_vec(),
+        }).unwrap();
+        let sealed = match viewer.sessions.write().await.get_mut("11111111") {
+            Some(PeerSession::Established { sealer, .. }) => sealer.seal(&plaintext).unwrap(),
+            _ => panic!("Session not established"),
+        };
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &sealed, &event_tx).await;
+
+        match event_rx.recv().await.unwrap() {
+            RelayClientEvent::PacketReceived(packet) => {
+                assert!(matches!(packet.message_type, RelayMessageType::InputEvent));
+                assert_eq!(packet.payload, b"secret keystrokes");
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queued_transfer_goes_after_messages_sealed_before_it() {
+        let (host, viewer) = secured().await;
+
+        // A frame is sealed and queued while a file chunk waits its turn
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel();
+        let plaintext = bincode::serialize(&SealedPayload {
+            message_type: RelayMessageType::ScreenFrame,
+            payload: b"frame".to_vec(),
+        }).unwrap();
+        let frame = match viewer.sessions.write().await.get_mut("11111111") {
+            Some(PeerSession::Established { sealer, .. }) => sealer.seal(&plaintext).unwrap(),
+            _ => panic!("Session not established"),
+        };
+        outgoing.send(Message::Binary(RelayPacket {
+            message_type: RelayMessageType::Sealed,
+            source_id: None,
+            target_id: "11111111".to_string(),
+            payload: frame,
+        }.encode().unwrap())).unwrap();
+
+        let transfer = QueuedTransfer {
+            target_id: "11111111".to_string(),
+            source_id: None,
+            payload: b"chunk".to_vec(),
+            send_at: tokio::time::Instant::now(),
+        };
+        let messages = take_transfer(transfer, &viewer.sessions, true, WireFormat::Binary, &mut outgoing_rx).await.unwrap();
+        assert_eq!(messages.len(), 2);
+
+        // Both open at the host, which refuses anything out of order
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        for message in messages {
+            let packet = match message {
+                Message::Binary(data) => RelayPacket::decode(&data).unwrap(),
+                other => panic!("Unexpected message: {:?}", other),
+            };
+            host.deliver_sealed(Some("22222222".to_string()), "11111111".to_string(), &packet.payload, &event_tx).await;
+        }
+
+        let mut received = Vec::new();
+        while let Ok(RelayClientEvent::PacketReceived(packet)) = event_rx.try_recv() {
+            received.push(packet.payload);
+        }
+        assert_eq!(received, vec![b"frame".to_vec(), b"chunk".to_vec()]);
+    }
+}
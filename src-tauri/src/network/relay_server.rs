@@ -0,0 +1,621 @@
+//! Reference relay server for self-hosting and local testing.
+//!
+//! Clients register their connection ID, then everything they send is routed
+//! to the target ID with the source rewritten to the sender's registered ID.
+//! Payloads are forwarded untouched, so end-to-end sealed traffic stays opaque.
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+use uuid::Uuid;
+
+use super::relay_client::{
+    ConnectRequest, DeviceInfo, RegisterRequest, RelayMessage, RelayMessageType, RelayPacket,
+};
+
+/// Messages queued for a client's socket
+const OUTGOING_QUEUE_LENGTH: usize = 256;
+
+/// Queue slots bulk messages can't take, so replies and control messages still get through
+const CONTROL_HEADROOM: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayServerConfig {
+    pub bind_address: String,
+    pub max_clients: usize,
+    /// Bytes per second a client may push through the relay, 0 for no limit
+    pub max_bandwidth_per_client: u64,
+    /// Drop clients that send nothing (not even a heartbeat) for this long
+    pub heartbeat_timeout_seconds: u64,
+    /// How often to log stats, 0 to disable
+    pub stats_interval_seconds: u64,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0:8080".to_string(),
+            max_clients: 1000,
+            max_bandwidth_per_client: 0,
+            heartbeat_timeout_seconds: 90,
+            stats_interval_seconds: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RelayStats {
+    pub active_connections: usize,
+    pub registered_clients: usize,
+    pub total_connections: u64,
+    pub messages_forwarded: u64,
+    pub bytes_forwarded: u64,
+    /// Messages dropped for bandwidth caps, clients that don't keep up or unknown targets
+    pub messages_dropped: u64,
+}
+
+struct RegisteredClient {
+    /// Identifies the socket, so a stale socket can't unregister a newer one
+    socket_id: String,
+    sender: Outgoing,
+    device_info: DeviceInfo,
+    registered_at: chrono::DateTime<chrono::Utc>,
+}
+
+type Clients = Arc<RwLock<HashMap<String, RegisteredClient>>>;
+
+/// A client's queue of messages for its socket
+type Outgoing = mpsc::Sender<Message>;
+
+/// Per-client byte budget over a one second window
+struct BandwidthWindow {
+    limit: u64,
+    started: Instant,
+    used: u64,
+}
+
+impl BandwidthWindow {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            started: Instant::now(),
+            used: 0,
+        }
+    }
+
+    fn allow(&mut self, bytes: usize) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        if self.started.elapsed() >= Duration::from_secs(1) {
+            self.started = Instant::now();
+            self.used = 0;
+        }
+
+        if self.used + bytes as u64 > self.limit {
+            return false;
+        }
+
+        self.used += bytes as u64;
+        true
+    }
+}
+
+pub struct RelayServer {
+    config: RelayServerConfig,
+    clients: Clients,
+    stats: Arc<RwLock<RelayStats>>,
+}
+
+impl RelayServer {
+    pub fn new(config: RelayServerConfig) -> Self {
+        Self {
+            config,
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            stats: Arc::new(RwLock::new(RelayStats::default())),
+        }
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let listener = TcpListener::bind(&self.config.bind_address).await?;
+        info!("Relay server listening on {}", listener.local_addr()?);
+        self.serve(listener).await
+    }
+
+    /// Accept relay clients on an already bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        if self.config.stats_interval_seconds > 0 {
+            let stats = self.stats.clone();
+            let interval_seconds = self.config.stats_interval_seconds;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let stats = stats.read().await;
+                    info!(
+                        "Relay stats: {} connections, {} registered, {} messages / {} bytes forwarded, {} dropped",
+                        stats.active_connections,
+                        stats.registered_clients,
+                        stats.messages_forwarded,
+                        stats.bytes_forwarded,
+                        stats.messages_dropped
+                    );
+                }
+            });
+        }
+
+        // Taken as soon as a socket is accepted, so handshakes still in progress count too
+        let slots = Arc::new(Semaphore::new(self.config.max_clients.min(Semaphore::MAX_PERMITS)));
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+
+            let slot = match slots.clone().try_acquire_owned() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    warn!("Refusing relay client {}: {} clients connected", addr, self.config.max_clients);
+                    continue;
+                }
+            };
+
+            let connection = RelayConnection {
+                socket_id: Uuid::new_v4().to_string(),
+                address: addr,
+                registered_id: None,
+                clients: self.clients.clone(),
+                stats: self.stats.clone(),
+                bandwidth: BandwidthWindow::new(self.config.max_bandwidth_per_client),
+                heartbeat_timeout: Duration::from_secs(self.config.heartbeat_timeout_seconds),
+            };
+
+            tokio::spawn(async move {
+                if let Err(e) = connection.run(stream, slot).await {
+                    debug!("Relay connection {} ended: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    pub async fn get_stats(&self) -> RelayStats {
+        self.stats.read().await.clone()
+    }
+
+    pub async fn get_registered_ids(&self) -> Vec<String> {
+        self.clients.read().await.keys().cloned().collect()
+    }
+}
+
+/// One relay client's socket
+struct RelayConnection {
+    socket_id: String,
+    address: SocketAddr,
+    registered_id: Option<String>,
+    clients: Clients,
+    stats: Arc<RwLock<RelayStats>>,
+    bandwidth: BandwidthWindow,
+    heartbeat_timeout: Duration,
+}
+
+impl RelayConnection {
+    /// Serve the socket; its slot is given back when this returns, however it ends
+    async fn run(mut self, stream: TcpStream, _slot: OwnedSemaphorePermit) -> Result<()> {
+        // A client that stalls its handshake doesn't get to hold the slot for good
+        let ws_stream = tokio::time::timeout(self.heartbeat_timeout, accept_async(stream)).await
+            .map_err(|_| anyhow::anyhow!("WebSocket handshake timed out"))??;
+        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+        info!("Relay client connected from {}", self.address);
+        {
+            let mut stats = self.stats.write().await;
+            stats.active_connections += 1;
+            stats.total_connections += 1;
+        }
+
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel::<Message>(OUTGOING_QUEUE_LENGTH);
+        tokio::spawn(async move {
+            while let Some(message) = outgoing_rx.recv().await {
+                if ws_sender.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let message = match tokio::time::timeout(self.heartbeat_timeout, ws_receiver.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => {
+                    debug!("Relay client {} socket error: {}", self.address, e);
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    warn!("Relay client {} missed its heartbeat, dropping", self.address);
+                    break;
+                }
+            };
+
+            match message {
+                Message::Text(text) => match serde_json::from_str::<RelayMessage>(&text) {
+                    Ok(relay_message) => self.handle_message(relay_message, &outgoing_tx).await,
+                    Err(e) => {
+                        warn!("Malformed relay message from {}: {}", self.address, e);
+                        send_error(&outgoing_tx, "Malformed relay message");
+                    }
+                },
+                Message::Binary(data) => match RelayPacket::decode(&data) {
+                    Ok(packet) => self.handle_packet(packet, data.len(), &outgoing_tx).await,
+                    Err(e) => {
+                        warn!("Malformed relay packet from {}: {}", self.address, e);
+                        send_error(&outgoing_tx, "Malformed relay packet");
+                    }
+                },
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        self.unregister().await;
+        self.stats.write().await.active_connections -= 1;
+        info!("Relay client {} disconnected", self.address);
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, mut message: RelayMessage, outgoing: &Outgoing) {
+        match message.message_type {
+            RelayMessageType::Register => {
+                let response = match serde_json::from_value::<RegisterRequest>(message.data) {
+                    Ok(request) => self.register(request, outgoing).await,
+                    Err(e) => Err(anyhow::anyhow!("Invalid register request: {}", e)),
+                };
+
+                let data = match response {
+                    Ok(connection_id) => serde_json::json!({ "success": true, "connection_id": connection_id }),
+                    Err(e) => serde_json::json!({ "success": false, "error": e.to_string() }),
+                };
+                send_message(outgoing, RelayMessageType::RegisterResponse, data);
+            }
+            RelayMessageType::Heartbeat => {
+                send_message(outgoing, RelayMessageType::Heartbeat, serde_json::json!({}));
+            }
+            RelayMessageType::Disconnect => {
+                self.unregister().await;
+            }
+            _ => {
+                let source_id = match self.registered_id {
+                    Some(ref id) => id.clone(),
+                    None => {
+                        send_error(outgoing, "Register before sending to peers");
+                        return;
+                    }
+                };
+
+                if is_bulk(&message.message_type) && !self.bandwidth.allow(text_len(&message)) {
+                    debug!("Client {} is over its bandwidth cap, dropping message", source_id);
+                    self.stats.write().await.messages_dropped += 1;
+                    return;
+                }
+
+                let is_connect_request = matches!(message.message_type, RelayMessageType::ConnectRequest);
+                let target_id = message.target_id.clone();
+                message.source_id = Some(source_id);
+
+                let text = match serde_json::to_string(&message) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        error!("Failed to re-encode relay message: {}", e);
+                        return;
+                    }
+                };
+
+                let bulk = is_bulk(&message.message_type);
+                if let Err(e) = self.forward(&target_id, Message::Text(text), bulk).await {
+                    if is_connect_request {
+                        // Brokering: tell the requester straight away instead of leaving it waiting
+                        let data = serde_json::json!({ "success": false, "error": e.to_string() });
+                        send_message(outgoing, RelayMessageType::ConnectResponse, data);
+                    } else {
+                        send_error(outgoing, &e.to_string());
+                    }
+                } else if is_connect_request {
+                    if let Ok(request) = serde_json::from_value::<ConnectRequest>(message.data) {
+                        info!("Brokered connect request from {} to {}", request.client_info.name, target_id);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, mut packet: RelayPacket, size: usize, outgoing: &Outgoing) {
+        let source_id = match self.registered_id {
+            Some(ref id) => id.clone(),
+            None => {
+                send_error(outgoing, "Register before sending to peers");
+                return;
+            }
+        };
+
+        if !self.bandwidth.allow(size) {
+            debug!("Client {} is over its bandwidth cap, dropping packet", source_id);
+            self.stats.write().await.messages_dropped += 1;
+            return;
+        }
+
+        let target_id = packet.target_id.clone();
+        let bulk = is_bulk(&packet.message_type);
+        packet.source_id = Some(source_id);
+
+        let data = match packet.encode() {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to re-encode relay packet: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.forward(&target_id, Message::Binary(data), bulk).await {
+            send_error(outgoing, &e.to_string());
+        }
+    }
+
+    async fn register(&mut self, request: RegisterRequest, outgoing: &Outgoing) -> Result<String> {
+        let connection_id = request.connection_id;
+
+        {
+            let mut clients = self.clients.write().await;
+            if let Some(existing) = clients.get(&connection_id) {
+                if existing.socket_id != self.socket_id {
+                    return Err(anyhow::anyhow!("Connection ID {} is already registered", connection_id));
+                }
+            }
+
+            clients.insert(connection_id.clone(), RegisteredClient {
+                socket_id: self.socket_id.clone(),
+                sender: outgoing.clone(),
+                device_info: request.device_info,
+                registered_at: chrono::Utc::now(),
+            });
+        }
+
+        // Re-registering under a new ID releases the old one
+        if let Some(previous) = self.registered_id.replace(connection_id.clone()) {
+            if previous != connection_id {
+                self.clients.write().await.remove(&previous);
+            }
+        }
+
+        self.update_registered_count().await;
+        info!("Registered relay client {} as {}", self.address, connection_id);
+        Ok(connection_id)
+    }
+
+    async fn unregister(&mut self) {
+        if let Some(connection_id) = self.registered_id.take() {
+            let mut clients = self.clients.write().await;
+            if clients.get(&connection_id).map(|c| c.socket_id == self.socket_id).unwrap_or(false) {
+                if let Some(client) = clients.remove(&connection_id) {
+                    debug!(
+                        "Unregistered {} ({}), registered since {}",
+                        connection_id, client.device_info.name, client.registered_at
+                    );
+                }
+            }
+            drop(clients);
+            self.update_registered_count().await;
+        }
+    }
+
+    /// Route a message to the client registered under `target_id`; bulk messages for a
+    /// client that isn't keeping up are dropped rather than queued
+    async fn forward(&mut self, target_id: &str, message: Message, bulk: bool) -> Result<()> {
+        let size = message.len();
+        let target = self.clients.read().await.get(target_id).map(|c| c.sender.clone());
+
+        let queued = match target {
+            Some(sender) => enqueue(&sender, message, bulk),
+            None => Err(TrySendError::Closed(message)),
+        };
+
+        let mut stats = self.stats.write().await;
+        match queued {
+            Ok(()) => {
+                stats.messages_forwarded += 1;
+                stats.bytes_forwarded += size as u64;
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                debug!("Peer {} is not keeping up, dropping message", target_id);
+                stats.messages_dropped += 1;
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => {
+                stats.messages_dropped += 1;
+                Err(anyhow::anyhow!("Peer {} is not connected to the relay", target_id))
+            }
+        }
+    }
+
+    async fn update_registered_count(&self) {
+        let registered = self.clients.read().await.len();
+        self.stats.write().await.registered_clients = registered;
+    }
+}
+
+/// Peer data that counts against bandwidth caps; control messages always pass
+fn is_bulk(message_type: &RelayMessageType) -> bool {
+    matches!(
+        message_type,
+        RelayMessageType::ScreenFrame
+            | RelayMessageType::InputEvent
+            | RelayMessageType::FileTransfer
+            | RelayMessageType::Sealed
+    )
+}
+
+/// Queue a message for a client's socket, keeping the last of the queue for non-bulk messages
+fn enqueue(outgoing: &Outgoing, message: Message, bulk: bool) -> Result<(), TrySendError<Message>> {
+    if bulk && outgoing.capacity() <= CONTROL_HEADROOM {
+        return Err(TrySendError::Full(message));
+    }
+    outgoing.try_send(message)
+}
+
+fn text_len(message: &RelayMessage) -> usize {
+    message.data.to_string().len()
+}
+
+fn send_message(outgoing: &Outgoing, message_type: RelayMessageType, data: serde_json::Value) {
+    let message = RelayMessage {
+        message_type,
+        source_id: Some("relay".to_string()),
+        target_id: String::new(),
+        data,
+        timestamp: chrono::Utc::now(),
+    };
+
+    match serde_json::to_string(&message) {
+        Ok(text) => {
+            if enqueue(outgoing, Message::Text(text), false).is_err() {
+                debug!("Relay reply dropped, the client is not keeping up");
+            }
+        }
+        Err(e) => error!("Failed to encode relay reply: {}", e),
+    }
+}
+
+fn send_error(outgoing: &Outgoing, error: &str) {
+    send_message(outgoing, RelayMessageType::Error, serde_json::json!({ "error": error }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::connect_async;
+
+    type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>;
+
+    async fn start_relay(config: RelayServerConfig) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        tokio::spawn(async move { RelayServer::new(config).serve(listener).await });
+        url
+    }
+
+    fn message(message_type: RelayMessageType, target_id: &str, data: serde_json::Value) -> Message {
+        Message::Text(serde_json::to_string(&RelayMessage {
+            message_type,
+            source_id: None,
+            target_id: target_id.to_string(),
+            data,
+            timestamp: chrono::Utc::now(),
+        }).unwrap())
+    }
+
+    async fn register(url: &str, connection_id: &str) -> (Socket, serde_json::Value) {
+        let (mut socket, _) = connect_async(url).await.unwrap();
+        let request = RegisterRequest {
+            connection_id: connection_id.to_string(),
+            device_info: DeviceInfo {
+                name: "test".to_string(),
+                os: "Linux".to_string(),
+                version: "0.1.0".to_string(),
+            },
+            capabilities: vec![],
+        };
+        socket.send(message(RelayMessageType::Register, "relay", serde_json::to_value(request).unwrap())).await.unwrap();
+        let response = next_message(&mut socket).await;
+        (socket, response.data)
+    }
+
+    async fn next_message(socket: &mut Socket) -> RelayMessage {
+        match socket.next().await.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshakes_count_towards_max_clients() {
+        let url = start_relay(RelayServerConfig { max_clients: 1, ..Default::default() }).await;
+        let address = url.trim_start_matches("ws://").trim_end_matches("/ws").to_string();
+
+        // A socket that never sends its handshake holds the only slot
+        let stalled = TcpStream::connect(&address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(connect_async(url.as_str()).await.is_err());
+
+        // Closing it gives the slot back
+        drop(stalled);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (_, response) = register(&url, "11111111").await;
+        assert_eq!(response["success"], true);
+    }
+
+    #[tokio::test]
+    async fn test_routes_between_registered_clients() {
+        let url = start_relay(RelayServerConfig::default()).await;
+
+        let (mut host, response) = register(&url, "11111111").await;
+        assert_eq!(response["success"], true);
+        let (mut viewer, _) = register(&url, "22222222").await;
+
+        // IDs are unique per relay
+        let (_, duplicate) = register(&url, "11111111").await;
+        assert_eq!(duplicate["success"], false);
+
+        // The relay stamps the sender's ID, whatever the sender claims
+        let packet = RelayPacket {
+            message_type: RelayMessageType::Sealed,
+            source_id: Some("99999999".to_string()),
+            target_id: "11111111".to_string(),
+            payload: b"opaque".to_vec(),
+        };
+        viewer.send(Message::Binary(packet.encode().unwrap())).await.unwrap();
+
+        let forwarded = match host.next().await.unwrap().unwrap() {
+            Message::Binary(data) => RelayPacket::decode(&data).unwrap(),
+            other => panic!("Unexpected message: {:?}", other),
+        };
+        assert_eq!(forwarded.source_id.as_deref(), Some("22222222"));
+        assert_eq!(forwarded.payload, b"opaque");
+
+        viewer.send(message(RelayMessageType::ConnectRequest, "33333333", serde_json::json!({}))).await.unwrap();
+        let refused = next_message(&mut viewer).await;
+        assert!(matches!(refused.message_type, RelayMessageType::ConnectResponse));
+        assert_eq!(refused.data["success"], false);
+    }
+
+    #[test]
+    fn test_bandwidth_window() {
+        let mut window = BandwidthWindow::new(1000);
+        assert!(window.allow(600));
+        assert!(!window.allow(600));
+        assert!(window.allow(400));
+
+        let mut unlimited = BandwidthWindow::new(0);
+        assert!(unlimited.allow(usize::MAX));
+    }
+
+    #[test]
+    fn test_full_queue_keeps_room_for_control_messages() {
+        let (outgoing, _outgoing_rx) = mpsc::channel(OUTGOING_QUEUE_LENGTH);
+
+        let mut queued = 0;
+        while enqueue(&outgoing, Message::Binary(vec![0; 16]), true).is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, OUTGOING_QUEUE_LENGTH - CONTROL_HEADROOM);
+
+        assert!(enqueue(&outgoing, Message::Text("{}".to_string()), false).is_ok());
+    }
+}
@@ -2,15 +2,31 @@ use anyhow::Result;
 use futures_util::{SinkExt, StreamExt};
 use log::{info, error, debug, warn};
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, RwLock};
+use tokio::time::{interval, MissedTickBehavior};
 use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
 use uuid::Uuid;
 
-use super::protocol::{ProtocolMessage, MessageType, InputEvent};
+use super::negotiation::negotiate_hello;
+use super::protocol::{
+    AuthRequest, Capabilities, ClientInfo, ErrorMessage, FrameAck, Hello, InputEvent, KeyframeRequest, MessageType, ProtocolMessage, Region,
+    ScreenFrame, Viewport, ERROR_ENCRYPTION_REQUIRED, ERROR_HANDSHAKE_REQUIRED, ERROR_SCREEN_CAPTURE_FAILED, PROTOCOL_VERSION,
+};
+use crate::capture::cursor::{self, CursorSource, CursorTracker, CursorUpdate, CURSOR_POLL_INTERVAL};
+use crate::capture::{MonitorSelection, ScreenCaptureManager, ScreenLayout};
+use crate::codec::{self, EncoderSettings, FrameEncoder};
+use crate::permissions::{DeviceInfo, Permission, PermissionManager};
+use crate::security::secure_channel::{KeyExchange, KeyExchangeInit, SecureChannel};
+use crate::streaming::{AdaptiveController, FrameScaler, StreamGeometry, StreamingConfig};
+use crate::security::{ClientCredentials, SecurityManager};
+use crate::utils::file_transfer::{FileTransferManager, TransferMessage};
+use super::wire::{is_encrypted, WireFormat, WireMessage};
 
 type ClientId = String;
 type WebSocket = WebSocketStream<TcpStream>;
@@ -19,6 +35,14 @@ pub struct RemoteDesktopServer {
     port: u16,
     clients: Arc<RwLock<HashMap<ClientId, ClientConnection>>>,
     message_tx: Option<mpsc::UnboundedSender<ServerMessage>>,
+    capabilities: Capabilities,
+    security: Arc<SecurityManager>,
+    permissions: Option<Arc<PermissionManager>>,
+    file_transfers: Option<Arc<FileTransferManager>>,
+    /// Transfer messages started on the host, queued for each client's connection
+    transfer_senders: Arc<RwLock<HashMap<ClientId, mpsc::UnboundedSender<TransferMessage>>>>,
+    /// What new viewers' streams start at and whether they adapt to the link
+    streaming: Arc<RwLock<StreamingConfig>>,
 }
 
 #[derive(Debug, Clone)]
@@ -28,12 +52,15 @@ pub struct ClientConnection {
     pub connected_at: chrono::DateTime<chrono::Utc>,
     pub authenticated: bool,
     pub capabilities: Vec<String>,
+    pub session_capabilities: Option<Capabilities>,
 }
 
 #[derive(Debug, Clone)]
 pub enum ServerMessage {
     ClientConnected(ClientId, SocketAddr),
     ClientDisconnected(ClientId),
+    ClientNegotiated(ClientId, Capabilities),
+    ClientAuthenticated(ClientId),
     ScreenFrameRequest(ClientId),
     InputEvent(ClientId, InputEvent),
     BroadcastFrame(Vec<u8>),
@@ -41,11 +68,37 @@ pub enum ServerMessage {
 
 impl RemoteDesktopServer {
     pub async fn new(port: u16) -> Result<Self> {
-        Ok(Self {
+        Ok(Self::with_security(port, Arc::new(SecurityManager::new()?)))
+    }
+    
+    /// Create a server that verifies clients against a shared security manager
+    pub fn with_security(port: u16, security: Arc<SecurityManager>) -> Self {
+        Self {
             port,
             clients: Arc::new(RwLock::new(HashMap::new())),
             message_tx: None,
-        })
+            capabilities: Capabilities::local(),
+            security,
+            permissions: None,
+            file_transfers: None,
+            transfer_senders: Arc::new(RwLock::new(HashMap::new())),
+            streaming: Arc::new(RwLock::new(StreamingConfig::default())),
+        }
+    }
+    
+    /// Check clients' file transfers against the host's grants; without it their offers are declined
+    pub fn set_permission_manager(&mut self, permissions: Arc<PermissionManager>) {
+        self.permissions = Some(permissions);
+    }
+    
+    /// Enable file transfer with clients; progress is reported through the manager's events
+    pub fn set_file_transfer_manager(&mut self, file_transfers: Arc<FileTransferManager>) {
+        self.file_transfers = Some(file_transfers);
+    }
+    
+    /// Stream settings for viewers that connect from now on
+    pub async fn set_streaming_config(&self, config: StreamingConfig) {
+        *self.streaming.write().await = config;
     }
     
     pub async fn start(&self) -> Result<()> {
@@ -68,10 +121,42 @@ impl RemoteDesktopServer {
             info!("New connection from {}", addr);
             
             let clients = self.clients.clone();
+            let transfer_senders = self.transfer_senders.clone();
             let message_tx = message_tx.clone();
+            let streaming = self.streaming.read().await.clone();
+            let session = ClientSession {
+                address: addr,
+                capabilities: self.capabilities.clone(),
+                negotiated: None,
+                client_info: None,
+                authenticated: false,
+                format: WireFormat::Binary,
+                channel: None,
+                require_encryption: self.security.encryption_required().await,
+                security: self.security.clone(),
+                frame_sequence: 0,
+                capture: None,
+                encoder: None,
+                adaptive: AdaptiveController::new(&streaming),
+                adaptive_quality: streaming.adaptive_quality,
+                sent_frames: VecDeque::new(),
+                last_frame_at: None,
+                frame_requested: false,
+                viewport: None,
+                frame_size: None,
+                cursor_source: None,
+                cursor_unavailable: false,
+                cursor_tracker: CursorTracker::new(),
+                permissions: self.permissions.clone(),
+                file_transfers: self.file_transfers.clone(),
+                transfers: HashSet::new(),
+                file_transfer_requested: false,
+                chunks: VecDeque::new(),
+                next_chunk_at: None,
+            };
             
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(stream, addr, clients, message_tx).await {
+                if let Err(e) = Self::handle_connection(stream, addr, clients, transfer_senders, message_tx, session).await {
                     error!("Connection error for {}: {}", addr, e);
                 }
             });
@@ -84,7 +169,9 @@ impl RemoteDesktopServer {
         stream: TcpStream,
         addr: SocketAddr,
         clients: Arc<RwLock<HashMap<ClientId, ClientConnection>>>,
+        transfer_senders: Arc<RwLock<HashMap<ClientId, mpsc::UnboundedSender<TransferMessage>>>>,
         message_tx: mpsc::UnboundedSender<ServerMessage>,
+        mut session: ClientSession,
     ) -> Result<()> {
         let ws_stream = accept_async(stream).await?;
         let client_id = Uuid::new_v4().to_string();
@@ -98,17 +185,23 @@ impl RemoteDesktopServer {
             connected_at: chrono::Utc::now(),
             authenticated: false,
             capabilities: vec![],
+            session_capabilities: None,
         };
         
         clients.write().await.insert(client_id.clone(), client_connection);
         
+        let (transfer_tx, transfer_rx) = mpsc::unbounded_channel::<TransferMessage>();
+        transfer_senders.write().await.insert(client_id.clone(), transfer_tx);
+        
         // Notify about new connection
         let _ = message_tx.send(ServerMessage::ClientConnected(client_id.clone(), addr));
         
         // Handle WebSocket messages
-        let result = Self::handle_websocket(ws_stream, client_id.clone(), message_tx.clone()).await;
+        let result = Self::handle_websocket(ws_stream, client_id.clone(), message_tx.clone(), &mut session, transfer_rx).await;
         
-        // Cleanup on disconnect
+        // Cleanup on disconnect; transfers wait, paused, for the file to be offered again
+        session.pause_transfers().await;
+        transfer_senders.write().await.remove(&client_id);
         clients.write().await.remove(&client_id);
         let _ = message_tx.send(ServerMessage::ClientDisconnected(client_id));
         
@@ -119,21 +212,82 @@ impl RemoteDesktopServer {
         mut ws_stream: WebSocket,
         client_id: ClientId,
         message_tx: mpsc::UnboundedSender<ServerMessage>,
+        session: &mut ClientSession,
+        mut transfer_rx: mpsc::UnboundedReceiver<TransferMessage>,
     ) -> Result<()> {
-        while let Some(msg) = ws_stream.next().await {
-            match msg? {
-                Message::Text(text) => {
-                    debug!("Received text message from {}: {}", client_id, text);
+        let mut cursor_tick = interval(CURSOR_POLL_INTERVAL);
+        cursor_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        
+        loop {
+            let next_frame_at = session.next_frame_at();
+            let next_chunk_at = session.next_chunk_at();
+            
+            // Requests, input and frames go first and the pointer at its own rate;
+            // file chunks only when nothing else is waiting
+            let msg = tokio::select! {
+                biased;
+                
+                msg = ws_stream.next() => match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(next_frame_at.unwrap_or_else(tokio::time::Instant::now)), if next_frame_at.is_some() => {
+                    session.send_requested_frame(&mut ws_stream).await?;
+                    continue;
+                }
+                _ = cursor_tick.tick(), if session.streams_cursor() => {
+                    session.send_cursor(&mut ws_stream).await?;
+                    continue;
+                }
+                Some(transfer) = transfer_rx.recv() => {
+                    session.send_transfer(&mut ws_stream, transfer).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(next_chunk_at.unwrap_or_else(tokio::time::Instant::now)), if next_chunk_at.is_some() => {
+                    session.send_chunk(&mut ws_stream).await?;
+                    continue;
+                }
+            };
+            
+            match msg {
+                Message::Binary(ref data) if is_encrypted(&msg) => {
+                    let channel = match session.channel {
+                        Some(ref mut channel) => channel,
+                        None => {
+                            warn!("Dropping encrypted frame from {} sent before key exchange", client_id);
+                            continue;
+                        }
+                    };
                     
-                    if let Ok(protocol_msg) = serde_json::from_str::<ProtocolMessage>(&text) {
-                        Self::handle_protocol_message(protocol_msg, &client_id, &message_tx, &mut ws_stream).await?;
-                    } else {
-                        warn!("Invalid protocol message from {}: {}", client_id, text);
-                    }
+                    // A frame that fails to open is tampering or a desync; neither is recoverable
+                    let wire_msg = WireMessage::open(data, &mut channel.opener)
+                        .map_err(|e| anyhow::anyhow!("Could not open frame from {}: {}", client_id, e))?;
+                    
+                    Self::handle_wire_message(wire_msg, &client_id, &message_tx, &mut ws_stream, session).await?;
                 }
-                Message::Binary(data) => {
-                    debug!("Received binary message from {} ({} bytes)", client_id, data.len());
-                    // Handle binary data (e.g., compressed screen frames)
+                Message::Text(_) | Message::Binary(_) => {
+                    // Reply in whatever format the client last used
+                    if let Some(format) = WireFormat::of(&msg) {
+                        session.format = format;
+                    }
+                    
+                    match WireMessage::from_ws_message(&msg) {
+                        Ok(Some(wire_msg)) if session.accepts_plaintext(&wire_msg) => {
+                            Self::handle_wire_message(wire_msg, &client_id, &message_tx, &mut ws_stream, session).await?;
+                        }
+                        Ok(Some(_)) => {
+                            let refusal = ErrorMessage {
+                                code: ERROR_ENCRYPTION_REQUIRED,
+                                message: "This host only accepts encrypted sessions".to_string(),
+                                details: None,
+                            };
+                            return Self::refuse(&mut ws_stream, session, &client_id, refusal).await;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("Invalid message from {}: {}", client_id, e);
+                        }
+                    }
                 }
                 Message::Ping(payload) => {
                     debug!("Received ping from {}", client_id);
@@ -153,40 +307,206 @@ impl RemoteDesktopServer {
         Ok(())
     }
     
+    async fn handle_wire_message(
+        message: WireMessage,
+        client_id: &str,
+        message_tx: &mpsc::UnboundedSender<ServerMessage>,
+        ws_stream: &mut WebSocket,
+        session: &mut ClientSession,
+    ) -> Result<()> {
+        match message {
+            WireMessage::InputEvent(_) if !session.authenticated => {
+                warn!("Ignoring input from unauthenticated client {}", client_id);
+            }
+            WireMessage::InputEvent(mut input_event) => {
+                debug!("Input event from client {}: {:?}", client_id, input_event);
+                
+                // Viewers send positions within the streamed frame, which may be scaled down, not the host desktop
+                if let (Some(x), Some(y)) = (input_event.x, input_event.y) {
+                    if let Ok(geometry) = session.stream_geometry().await {
+                        let (x, y) = geometry.to_desktop(x, y);
+                        input_event.x = Some(x);
+                        input_event.y = Some(y);
+                    }
+                }
+                
+                let _ = message_tx.send(ServerMessage::InputEvent(client_id.to_string(), input_event));
+            }
+            WireMessage::ScreenFrame(_) => {
+                debug!("Ignoring screen frame sent by client {}", client_id);
+            }
+            WireMessage::FileChunk(_) if !session.authenticated => {
+                warn!("Ignoring file chunk from unauthenticated client {}", client_id);
+            }
+            WireMessage::FileChunk(chunk) => {
+                Self::handle_file_transfer(TransferMessage::Chunk(chunk), client_id, ws_stream, session).await?;
+            }
+            WireMessage::Control(protocol_msg) => {
+                Self::handle_protocol_message(protocol_msg, client_id, message_tx, ws_stream, session).await?;
+            }
+        }
+        
+        Ok(())
+    }
+    
     async fn handle_protocol_message(
         message: ProtocolMessage,
         client_id: &str,
         message_tx: &mpsc::UnboundedSender<ServerMessage>,
         ws_stream: &mut WebSocket,
+        session: &mut ClientSession,
     ) -> Result<()> {
         match message.message_type {
+            MessageType::Hello => {
+                let hello = serde_json::from_value::<Hello>(message.data)?;
+                debug!("Hello from client {} (protocol {})", client_id, hello.protocol_version);
+                session.client_info = Some(hello.client_info.clone());
+                
+                match negotiate_hello(&session.capabilities, &hello) {
+                    Ok(negotiated) => {
+                        info!("Negotiated capabilities with client {}: {:?}", client_id, negotiated);
+                        
+                        session.negotiated = Some(negotiated.clone());
+                        let _ = message_tx.send(ServerMessage::ClientNegotiated(client_id.to_string(), negotiated.clone()));
+                        
+                        let ack = ProtocolMessage::hello_ack(negotiated);
+                        session.send(ws_stream, WireMessage::Control(ack)).await?;
+                    }
+                    Err(refusal) => {
+                        return Self::refuse(ws_stream, session, client_id, refusal).await;
+                    }
+                }
+            }
+            MessageType::KeyExchangeInit => {
+                if session.negotiated.is_none() || session.channel.is_some() {
+                    let refusal = ErrorMessage {
+                        code: ERROR_HANDSHAKE_REQUIRED,
+                        message: "Key exchange must follow hello and happen once per session".to_string(),
+                        details: None,
+                    };
+                    return Self::refuse(ws_stream, session, client_id, refusal).await;
+                }
+                
+                let init = serde_json::from_value::<KeyExchangeInit>(message.data)?;
+                let (reply, channel) = KeyExchange::new().respond(&init, &session.security.identity())?;
+                
+                // The reply itself goes out in the clear; everything after it is sealed
+                session.send(ws_stream, WireMessage::Control(ProtocolMessage::key_exchange_reply(reply))).await?;
+                session.channel = Some(channel);
+                
+                info!("Encrypted channel established with client {}", client_id);
+            }
             MessageType::AuthRequest => {
                 debug!("Auth request from client {}", client_id);
                 
-                // Simple authentication (in real app, use proper auth)
-                let auth_response = ProtocolMessage {
-                    id: Uuid::new_v4().to_string(),
-                    message_type: MessageType::AuthResponse,
-                    data: serde_json::json!({
-                        "success": true,
-                        "capabilities": ["screen_capture", "input_forwarding"]
-                    }),
-                    timestamp: chrono::Utc::now(),
+                let negotiated = match session.negotiated {
+                    Some(ref negotiated) => negotiated,
+                    None => {
+                        let refusal = ErrorMessage {
+                            code: ERROR_HANDSHAKE_REQUIRED,
+                            message: format!("Client must send hello before authenticating (protocol {})", PROTOCOL_VERSION),
+                            details: None,
+                        };
+                        return Self::refuse(ws_stream, session, client_id, refusal).await;
+                    }
+                };
+                
+                let feature_names = negotiated.feature_names();
+                
+                let auth_request = serde_json::from_value::<AuthRequest>(message.data)?;
+                let credentials = match credentials_from_request(&auth_request) {
+                    Some(credentials) => credentials,
+                    None => {
+                        let auth_response = ProtocolMessage::auth_response(
+                            false,
+                            Some("No credentials supplied".to_string()),
+                            None,
+                            feature_names,
+                        );
+                        session.send(ws_stream, WireMessage::Control(auth_response)).await?;
+                        return Ok(());
+                    }
                 };
                 
-                let response_text = serde_json::to_string(&auth_response)?;
-                ws_stream.send(Message::Text(response_text)).await?;
+                // Rate limiting is keyed on the peer address so reconnecting doesn't reset it
+                let peer = session.address.ip().to_string();
+                let authenticated = session.security.authenticate_client(&peer, &credentials).await?;
+                
+                let auth_response = if authenticated {
+                    session.authenticated = true;
+                    let _ = message_tx.send(ServerMessage::ClientAuthenticated(client_id.to_string()));
+                    
+                    let subject = auth_request.username.clone().unwrap_or_else(|| auth_request.client_info.name.clone());
+                    let session_token = session.security.issue_session_token(&subject).await?;
+                    ProtocolMessage::auth_response(true, None, Some(session_token), feature_names)
+                } else {
+                    ProtocolMessage::auth_response(false, Some("Invalid credentials".to_string()), None, feature_names)
+                };
+                
+                session.send(ws_stream, WireMessage::Control(auth_response)).await?;
+            }
+            MessageType::ScreenFrameRequest if !session.authenticated => {
+                warn!("Ignoring screen frame request from unauthenticated client {}", client_id);
             }
             MessageType::ScreenFrameRequest => {
                 debug!("Screen frame request from client {}", client_id);
                 let _ = message_tx.send(ServerMessage::ScreenFrameRequest(client_id.to_string()));
+                
+                // Sent from the select loop once the frame rate allows; requests in between share it
+                session.frame_requested = true;
+            }
+            MessageType::KeyframeRequest if !session.authenticated => {
+                warn!("Ignoring keyframe request from unauthenticated client {}", client_id);
+            }
+            MessageType::KeyframeRequest => {
+                // Older viewers send no details
+                let request: KeyframeRequest = serde_json::from_value(message.data).unwrap_or_default();
+                debug!("Keyframe requested by client {} ({:?}, last frame {:?})", client_id, request.reason, request.last_sequence);
+                session.request_keyframe();
             }
-            MessageType::InputEvent => {
-                if let Ok(input_event) = serde_json::from_value::<InputEvent>(message.data) {
-                    debug!("Input event from client {}: {:?}", client_id, input_event);
-                    let _ = message_tx.send(ServerMessage::InputEvent(client_id.to_string(), input_event));
+            MessageType::Viewport if !session.authenticated => {
+                warn!("Ignoring viewport from unauthenticated client {}", client_id);
+            }
+            MessageType::Viewport => {
+                let viewport: Option<Viewport> = serde_json::from_value(message.data)?;
+                debug!("Client {} is viewing through {:?}", client_id, viewport);
+                session.viewport = viewport;
+            }
+            MessageType::FrameAck if !session.authenticated => {
+                warn!("Ignoring frame acknowledgement from unauthenticated client {}", client_id);
+            }
+            MessageType::FrameAck => {
+                let ack: FrameAck = serde_json::from_value(message.data)?;
+                session.frame_acked(ack.sequence_number);
+            }
+            MessageType::ScreenInfo | MessageType::SelectMonitor if !session.authenticated => {
+                warn!("Ignoring monitor request from unauthenticated client {}", client_id);
+            }
+            MessageType::ScreenInfo | MessageType::SelectMonitor => {
+                let selection = if message.message_type == MessageType::SelectMonitor {
+                    Some(serde_json::from_value::<MonitorSelection>(message.data)?)
+                } else {
+                    None
+                };
+                
+                match session.screen_layout(selection).await {
+                    Ok(layout) => {
+                        debug!("Client {} is viewing {:?}", client_id, layout.selected);
+                        session.send(ws_stream, WireMessage::Control(ProtocolMessage::screen_info(&layout))).await?;
+                    }
+                    Err(e) => {
+                        let error_msg = ProtocolMessage::error(ERROR_SCREEN_CAPTURE_FAILED, e.to_string(), None);
+                        session.send(ws_stream, WireMessage::Control(error_msg)).await?;
+                    }
                 }
             }
+            ref message_type if message_type.is_file_transfer() && !session.authenticated => {
+                warn!("Ignoring file transfer from unauthenticated client {}", client_id);
+            }
+            ref message_type if message_type.is_file_transfer() => {
+                let transfer = message.to_file_transfer()?;
+                Self::handle_file_transfer(transfer, client_id, ws_stream, session).await?;
+            }
             MessageType::Heartbeat => {
                 debug!("Heartbeat from client {}", client_id);
                 
@@ -197,8 +517,7 @@ impl RemoteDesktopServer {
                     timestamp: chrono::Utc::now(),
                 };
                 
-                let response_text = serde_json::to_string(&heartbeat_response)?;
-                ws_stream.send(Message::Text(response_text)).await?;
+                session.send(ws_stream, WireMessage::Control(heartbeat_response)).await?;
             }
             _ => {
                 debug!("Unhandled message type from client {}: {:?}", client_id, message.message_type);
@@ -208,6 +527,60 @@ impl RemoteDesktopServer {
         Ok(())
     }
     
+    /// Take a transfer step with the client; its offers need the host's file transfer permission
+    async fn handle_file_transfer(
+        transfer: TransferMessage,
+        client_id: &str,
+        ws_stream: &mut WebSocket,
+        session: &mut ClientSession,
+    ) -> Result<()> {
+        let transfer_id = transfer.transfer_id().to_string();
+        
+        if let Some(name) = transfer.offered_name() {
+            if let Err(reason) = session.allow_file_transfer(client_id).await {
+                info!("Declining {} from client {}: {}", name, client_id, reason);
+                return session.send(ws_stream, TransferMessage::decline(&transfer_id, reason).into()).await;
+            }
+            session.transfers.insert(transfer_id.clone());
+        } else if !session.transfers.contains(&transfer_id) {
+            warn!("Ignoring file transfer {} that client {} is not part of", transfer_id, client_id);
+            return Ok(());
+        }
+        
+        let file_transfers = match session.file_transfers {
+            Some(ref file_transfers) => file_transfers.clone(),
+            None => return Ok(()),
+        };
+        
+        // Replies may offer the next file of a directory, which this connection then takes part in
+        match file_transfers.handle_message(transfer).await {
+            Ok(replies) => {
+                for reply in replies {
+                    session.send_transfer(ws_stream, reply).await?;
+                }
+            }
+            Err(e) => warn!("File transfer {} with client {} failed: {}", transfer_id, client_id, e),
+        }
+        
+        Ok(())
+    }
+    
+    /// Send an error and end the connection
+    async fn refuse(
+        ws_stream: &mut WebSocket,
+        session: &mut ClientSession,
+        client_id: &str,
+        refusal: ErrorMessage,
+    ) -> Result<()> {
+        warn!("Refusing client {}: {} ({})", client_id, refusal.message, refusal.code);
+        
+        let error_msg = ProtocolMessage::error(refusal.code, refusal.message.clone(), refusal.details);
+        session.send(ws_stream, WireMessage::Control(error_msg)).await?;
+        ws_stream.close(None).await?;
+        
+        Err(anyhow::anyhow!("Session refused: {}", refusal.message))
+    }
+    
     async fn handle_server_message(
         message: ServerMessage,
         clients: &Arc<RwLock<HashMap<ClientId, ClientConnection>>>,
@@ -219,6 +592,18 @@ impl RemoteDesktopServer {
             ServerMessage::ClientDisconnected(client_id) => {
                 info!("Client {} disconnected", client_id);
             }
+            ServerMessage::ClientAuthenticated(client_id) => {
+                if let Some(client) = clients.write().await.get_mut(&client_id) {
+                    client.authenticated = true;
+                }
+                info!("Client {} authenticated", client_id);
+            }
+            ServerMessage::ClientNegotiated(client_id, capabilities) => {
+                if let Some(client) = clients.write().await.get_mut(&client_id) {
+                    client.capabilities = capabilities.feature_names();
+                    client.session_capabilities = Some(capabilities);
+                }
+            }
             ServerMessage::ScreenFrameRequest(client_id) => {
                 debug!("Processing screen frame request from {}", client_id);
                 // In a real implementation, this would trigger screen capture
@@ -250,4 +635,507 @@ impl RemoteDesktopServer {
     pub async fn get_connected_clients(&self) -> Vec<ClientConnection> {
         self.clients.read().await.values().cloned().collect()
     }
-}
\ No newline at end of file
+    
+    /// Offer a file to an authenticated client, returning the transfer ID
+    pub async fn send_file(&self, client_id: &str, path: &Path) -> Result<String> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        let sender = self.transfer_sender(client_id).await?;
+        
+        let request = file_transfers.send_file(path).await?;
+        let transfer_id = request.transfer_id.clone();
+        sender.send(TransferMessage::Offer(request))
+            .map_err(|_| anyhow::anyhow!("Client {} disconnected", client_id))?;
+        
+        Ok(transfer_id)
+    }
+    
+    /// Offer a directory to an authenticated client, returning the directory's transfer ID
+    pub async fn send_directory(&self, client_id: &str, path: &Path) -> Result<String> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        let sender = self.transfer_sender(client_id).await?;
+        
+        let request = file_transfers.send_directory(path).await?;
+        let directory_id = request.directory_id.clone();
+        sender.send(TransferMessage::DirectoryOffer(request))
+            .map_err(|_| anyhow::anyhow!("Client {} disconnected", client_id))?;
+        
+        Ok(directory_id)
+    }
+    
+    /// Where to queue transfer messages for a client that can take part in file transfer
+    async fn transfer_sender(&self, client_id: &str) -> Result<mpsc::UnboundedSender<TransferMessage>> {
+        let negotiated = match self.clients.read().await.get(client_id) {
+            Some(client) if client.authenticated => client.session_capabilities.as_ref()
+                .map(|capabilities| capabilities.file_transfer)
+                .unwrap_or(false),
+            Some(_) => return Err(anyhow::anyhow!("Client {} has not authenticated", client_id)),
+            None => return Err(anyhow::anyhow!("Unknown client: {}", client_id)),
+        };
+        if !negotiated {
+            return Err(anyhow::anyhow!("Client {} does not support file transfer", client_id));
+        }
+        
+        self.transfer_senders.read().await.get(client_id).cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown client: {}", client_id))
+    }
+    
+    /// Stop a transfer in either direction and tell the client taking part
+    pub async fn cancel_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        file_transfers.cancel_transfer(transfer_id).await?;
+        self.notify_transfer(TransferMessage::cancel(transfer_id, "Cancelled by the host")).await;
+        
+        Ok(())
+    }
+    
+    pub async fn pause_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let pause = file_transfers.pause_transfer(transfer_id).await?;
+        self.notify_transfer(pause).await;
+        
+        Ok(())
+    }
+    
+    /// Continue a paused transfer whose client is still connected; otherwise offer the file again
+    pub async fn resume_file_transfer(&self, transfer_id: &str) -> Result<()> {
+        let file_transfers = self.file_transfers.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("File transfer is not enabled"))?;
+        
+        let resume = file_transfers.resume_transfer(transfer_id).await?;
+        self.notify_transfer(resume).await;
+        
+        Ok(())
+    }
+    
+    /// Only the connection the transfer runs on passes this on
+    async fn notify_transfer(&self, message: TransferMessage) {
+        for sender in self.transfer_senders.read().await.values() {
+            let _ = sender.send(message.clone());
+        }
+    }
+}
+
+/// Per-connection handshake state
+struct ClientSession {
+    address: SocketAddr,
+    capabilities: Capabilities,
+    negotiated: Option<Capabilities>,
+    /// As introduced in the client's hello
+    client_info: Option<ClientInfo>,
+    authenticated: bool,
+    format: WireFormat,
+    channel: Option<SecureChannel>,
+    require_encryption: bool,
+    security: Arc<SecurityManager>,
+    frame_sequence: u64,
+    /// Opened on first use, holds this viewer's monitor selection
+    capture: Option<ScreenCaptureManager>,
+    /// This viewer's stream in the preferred negotiated format, opened on the first frame
+    encoder: Option<Box<dyn FrameEncoder>>,
+    /// Paces this viewer's stream by its acknowledgements
+    adaptive: AdaptiveController,
+    /// Without it the stream stays at the configured quality and frame rate
+    adaptive_quality: bool,
+    /// Frames sent in the last `SENT_FRAME_HISTORY`, oldest first
+    sent_frames: VecDeque<SentFrame>,
+    last_frame_at: Option<Instant>,
+    /// Set while a frame the viewer asked for waits for its turn
+    frame_requested: bool,
+    /// The viewer's window, which frames are shrunk to fit
+    viewport: Option<Viewport>,
+    /// Size of the last frame sent, which the viewer's input positions refer to
+    frame_size: Option<(u32, u32)>,
+    /// Opened on first use
+    cursor_source: Option<Box<dyn CursorSource>>,
+    /// Set once the pointer turned out to be unreadable on this host, so it isn't retried at every poll
+    cursor_unavailable: bool,
+    cursor_tracker: CursorTracker,
+    permissions: Option<Arc<PermissionManager>>,
+    file_transfers: Option<Arc<FileTransferManager>>,
+    /// Transfers running on this connection; messages for any other are ignored
+    transfers: HashSet<String>,
+    /// Set once the host has been asked to allow file transfer, so each offer doesn't ask again
+    file_transfer_requested: bool,
+    /// File chunks waiting for everything else on the connection, and for the transfer speed limit
+    chunks: VecDeque<TransferMessage>,
+    /// When the first waiting chunk may go, once its bandwidth is reserved
+    next_chunk_at: Option<tokio::time::Instant>,
+}
+
+/// How long sent frames are remembered for timing their acknowledgements
+const SENT_FRAME_HISTORY: Duration = Duration::from_secs(5);
+
+/// Window the send rate is measured over
+const SEND_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+struct SentFrame {
+    sequence_number: u64,
+    sent_at: Instant,
+    size_bytes: usize,
+}
+
+impl ClientSession {
+    /// Seal once the channel is up, otherwise use the client's wire format
+    async fn send(&mut self, ws_stream: &mut WebSocket, message: WireMessage) -> Result<()> {
+        let ws_message = match self.channel {
+            Some(ref mut channel) => message.seal(&mut channel.sealer)?,
+            None => message.to_ws_message(self.format)?,
+        };
+        
+        ws_stream.send(ws_message).await?;
+        Ok(())
+    }
+    
+    fn capture_manager(&mut self) -> Result<&ScreenCaptureManager> {
+        if self.capture.is_none() {
+            self.capture = Some(ScreenCaptureManager::new()?);
+        }
+        
+        Ok(self.capture.as_ref().unwrap())
+    }
+    
+    /// Capture the viewer's selected monitor(s) in the preferred negotiated format
+    async fn capture_frame(&mut self, sequence_number: u64) -> Result<ScreenFrame> {
+        let mut image = self.capture_manager()?.capture_selected().await?;
+        
+        // Viewers without the cursor channel see the pointer only if it is part of the picture
+        if !self.negotiated_cursor() && self.capture_manager()?.get_config().await.capture_cursor {
+            self.draw_cursor(&mut image).await;
+        }
+        
+        if self.encoder.is_none() {
+            let format = match self.negotiated {
+                Some(ref negotiated) => negotiated.image_formats.first().copied(),
+                None => self.capabilities.image_formats.first().copied(),
+            }.ok_or_else(|| anyhow::anyhow!("No frame format to encode with"))?;
+            
+            self.encoder = Some(codec::create_encoder(format, &EncoderSettings::default())?);
+        }
+        
+        let level = self.adaptive.current();
+        let encoder = self.encoder.as_mut().unwrap();
+        encoder.set_quality(level.quality);
+        
+        // A new output size makes the encoder start over with a keyframe
+        let scaler = FrameScaler {
+            scale: level.scale,
+            viewport: self.viewport,
+            ..FrameScaler::default()
+        };
+        let encoded = encoder.encode(&scaler.scale_frame(&image))?;
+        self.frame_size = Some((encoded.width, encoded.height));
+        
+        Ok(ScreenFrame {
+            width: encoded.width,
+            height: encoded.height,
+            format: encoded.format,
+            data: encoded.data,
+            timestamp: chrono::Utc::now(),
+            sequence_number,
+            is_keyframe: encoded.is_keyframe,
+            changed_regions: encoded.changed_regions
+                .map(|regions| regions.into_iter().map(Region::from).collect()),
+        })
+    }
+    
+    /// When the requested frame may go, held back to the frame rate the link can take
+    ///
+    /// The viewer pulls frames, so one asking faster than its link allows is answered late rather than queued.
+    fn next_frame_at(&self) -> Option<tokio::time::Instant> {
+        if !self.frame_requested {
+            return None;
+        }
+        
+        Some(match self.last_frame_at {
+            Some(last_frame_at) => (last_frame_at + self.adaptive.current().frame_interval()).into(),
+            None => tokio::time::Instant::now(),
+        })
+    }
+    
+    /// Capture and send the frame the viewer asked for
+    async fn send_requested_frame(&mut self, ws_stream: &mut WebSocket) -> Result<()> {
+        self.frame_requested = false;
+        self.pace();
+        
+        // Only frames actually sent are numbered, so the viewer can tell a lost one from a failed capture
+        let sequence_number = self.frame_sequence + 1;
+        match self.capture_frame(sequence_number).await {
+            Ok(frame) => {
+                let size_bytes = frame.data.len();
+                self.frame_sequence = sequence_number;
+                self.send(ws_stream, WireMessage::ScreenFrame(frame)).await?;
+                self.frame_sent(sequence_number, size_bytes);
+            }
+            Err(e) => warn!("Could not capture a frame for {}: {}", self.address, e),
+        }
+        
+        Ok(())
+    }
+    
+    /// Note the frame going out and re-judge the link
+    fn pace(&mut self) {
+        let now = Instant::now();
+        self.last_frame_at = Some(now);
+        
+        if !self.adaptive_quality {
+            return;
+        }
+        
+        let sent_bytes: usize = self.sent_frames.iter()
+            .filter(|frame| now.duration_since(frame.sent_at) <= SEND_RATE_WINDOW)
+            .map(|frame| frame.size_bytes)
+            .sum();
+        let send_mbps = (sent_bytes as f32 * 8.0) / (SEND_RATE_WINDOW.as_secs_f32() * 1_000_000.0);
+        
+        if let Some(level) = self.adaptive.evaluate(send_mbps, now) {
+            debug!("Streaming to {} at {:?}", self.address, level);
+        }
+    }
+    
+    fn frame_sent(&mut self, sequence_number: u64, size_bytes: usize) {
+        let now = Instant::now();
+        while self.sent_frames.front().is_some_and(|frame| now.duration_since(frame.sent_at) > SENT_FRAME_HISTORY) {
+            self.sent_frames.pop_front();
+        }
+        
+        self.sent_frames.push_back(SentFrame { sequence_number, sent_at: now, size_bytes });
+    }
+    
+    /// Time the round trip of an acknowledged frame; acknowledgements for forgotten frames are ignored
+    fn frame_acked(&mut self, sequence_number: u64) {
+        if let Some(frame) = self.sent_frames.iter().find(|frame| frame.sequence_number == sequence_number) {
+            self.adaptive.on_ack(frame.size_bytes, frame.sent_at.elapsed());
+        }
+    }
+    
+    /// Send the next frame whole, for a viewer that lost its reference
+    fn request_keyframe(&mut self) {
+        if let Some(ref mut encoder) = self.encoder {
+            encoder.request_keyframe();
+        }
+    }
+    
+    fn negotiated_cursor(&self) -> bool {
+        self.negotiated.as_ref().is_some_and(|negotiated| negotiated.cursor)
+    }
+    
+    /// Whether pointer updates are due to this viewer
+    fn streams_cursor(&self) -> bool {
+        self.authenticated && self.negotiated_cursor() && !self.cursor_unavailable
+    }
+    
+    fn sample_cursor(&mut self) -> Option<cursor::CursorSample> {
+        if self.cursor_unavailable {
+            return None;
+        }
+        
+        let sample = match self.cursor_source {
+            Some(ref mut source) => source.sample(),
+            None => cursor::open_cursor_source().and_then(|mut source| {
+                let sample = source.sample();
+                self.cursor_source = Some(source);
+                sample
+            }),
+        };
+        
+        match sample {
+            Ok(sample) => Some(sample),
+            Err(e) => {
+                warn!("Could not read the pointer for {}, leaving it out: {}", self.address, e);
+                self.cursor_source = None;
+                self.cursor_unavailable = true;
+                None
+            }
+        }
+    }
+    
+    /// Send whatever changed about the pointer since the last poll
+    async fn send_cursor(&mut self, ws_stream: &mut WebSocket) -> Result<()> {
+        let sample = match self.sample_cursor() {
+            Some(sample) => sample,
+            None => return Ok(()),
+        };
+        
+        let geometry = self.stream_geometry().await?;
+        for update in self.cursor_tracker.update(&sample, &geometry) {
+            let message = match update {
+                CursorUpdate::Shape(ref shape) => ProtocolMessage::cursor_shape(shape),
+                CursorUpdate::Position(position) => ProtocolMessage::cursor_position(position),
+            };
+            self.send(ws_stream, WireMessage::Control(message)).await?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Draw the pointer into a captured picture of the selected monitor(s)
+    async fn draw_cursor(&mut self, image: &mut image::RgbaImage) {
+        let sample = match self.sample_cursor() {
+            Some(sample) => sample,
+            None => return,
+        };
+        
+        let bounds = match self.capture_manager() {
+            Ok(capture) => capture.get_layout().await.bounds(),
+            Err(_) => return,
+        };
+        
+        if let Err(e) = cursor::composite(image, &sample.shape, sample.x - bounds.x, sample.y - bounds.y) {
+            debug!("Could not draw the pointer: {}", e);
+        }
+    }
+    
+    /// Where the frames sent to this viewer lie on the desktop
+    async fn stream_geometry(&mut self) -> Result<StreamGeometry> {
+        let frame_size = self.frame_size;
+        let region = self.capture_manager()?.get_layout().await.bounds();
+        
+        Ok(match frame_size {
+            Some((frame_width, frame_height)) => StreamGeometry { frame_width, frame_height, region },
+            None => StreamGeometry::unscaled(region),
+        })
+    }
+    
+    /// Current monitor layout, after switching to `selection` if given
+    async fn screen_layout(&mut self, selection: Option<MonitorSelection>) -> Result<ScreenLayout> {
+        if let Some(selection) = selection {
+            self.capture_manager()?.select_monitor(selection).await?;
+            // Frames of the new monitor will have their own size
+            self.frame_size = None;
+        }
+        
+        Ok(self.capture_manager()?.get_layout().await)
+    }
+    
+    /// Whether this client may send files; the first offer asks the host, whose approval policy may answer at once
+    async fn allow_file_transfer(&mut self, client_id: &str) -> Result<(), String> {
+        if self.file_transfers.is_none() {
+            return Err("File transfer is not enabled on this host".to_string());
+        }
+        
+        if !self.negotiated.as_ref().map(|negotiated| negotiated.file_transfer).unwrap_or(false) {
+            return Err("File transfer was not negotiated".to_string());
+        }
+        
+        let permissions = match self.permissions {
+            Some(ref permissions) => permissions.clone(),
+            None => return Err("File transfer needs the host's permission".to_string()),
+        };
+        
+        if !permissions.is_allowed(client_id, &Permission::FileTransfer).await && !self.file_transfer_requested {
+            self.file_transfer_requested = true;
+            
+            let device_info = match self.client_info {
+                Some(ref info) => DeviceInfo {
+                    name: info.name.clone(),
+                    os: info.platform.clone(),
+                    version: info.version.clone(),
+                    ip_address: Some(self.address.ip().to_string()),
+                },
+                None => DeviceInfo {
+                    name: "unknown".to_string(),
+                    os: "unknown".to_string(),
+                    version: "unknown".to_string(),
+                    ip_address: Some(self.address.ip().to_string()),
+                },
+            };
+            
+            permissions.request_permission(client_id.to_string(), device_info, vec![Permission::FileTransfer]).await
+                .map_err(|e| e.to_string())?;
+        }
+        
+        if permissions.is_allowed(client_id, &Permission::FileTransfer).await {
+            Ok(())
+        } else {
+            Err("The host has not allowed file transfer for this session".to_string())
+        }
+    }
+    
+    /// Send a transfer message started on the host; only offers start a transfer on this connection
+    async fn send_transfer(&mut self, ws_stream: &mut WebSocket, transfer: TransferMessage) -> Result<()> {
+        match transfer {
+            ref offer if offer.offered_name().is_some() => {
+                self.transfers.insert(offer.transfer_id().to_string());
+            }
+            ref other if !self.transfers.contains(other.transfer_id()) => return Ok(()),
+            TransferMessage::Chunk(_) => {
+                self.chunks.push_back(transfer);
+                return Ok(());
+            }
+            _ => {}
+        }
+        
+        self.send(ws_stream, transfer.into()).await
+    }
+    
+    /// When the first waiting chunk may go, reserving its share of the speed limit the first time
+    fn next_chunk_at(&mut self) -> Option<tokio::time::Instant> {
+        let chunk = self.chunks.front()?;
+        
+        if self.next_chunk_at.is_none() {
+            let delay = self.file_transfers.as_ref()
+                .map(|file_transfers| file_transfers.reserve_bandwidth(chunk))
+                .unwrap_or_default();
+            self.next_chunk_at = Some(tokio::time::Instant::now() + delay);
+        }
+        self.next_chunk_at
+    }
+    
+    async fn send_chunk(&mut self, ws_stream: &mut WebSocket) -> Result<()> {
+        self.next_chunk_at = None;
+        match self.chunks.pop_front() {
+            Some(chunk) => self.send(ws_stream, chunk.into()).await,
+            None => Ok(()),
+        }
+    }
+    
+    async fn pause_transfers(&mut self) {
+        if let Some(ref file_transfers) = self.file_transfers {
+            file_transfers.pause_transfers(self.transfers.drain()).await;
+        }
+    }
+    
+    /// Plaintext is never accepted once encrypted; before that only the handshake may be
+    fn accepts_plaintext(&self, message: &WireMessage) -> bool {
+        if self.channel.is_some() {
+            return false;
+        }
+        
+        if !self.require_encryption {
+            return true;
+        }
+        
+        match message {
+            WireMessage::Control(control) => matches!(
+                control.message_type,
+                MessageType::Hello | MessageType::KeyExchangeInit | MessageType::Heartbeat
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Pick the credentials to check; a PIN or token takes precedence over a password
+fn credentials_from_request(request: &AuthRequest) -> Option<ClientCredentials> {
+    if let Some(ref pin) = request.pin {
+        return Some(ClientCredentials::Pin { pin: pin.clone() });
+    }
+    
+    if let Some(ref token) = request.token {
+        return Some(ClientCredentials::Token { token: token.clone() });
+    }
+    
+    match (&request.username, &request.password) {
+        (Some(username), Some(password)) => Some(ClientCredentials::Password {
+            username: username.clone(),
+            password: password.clone(),
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,366 @@
+//! Binary framing for WebSocket traffic.
+//!
+//! Every binary message starts with an 8-byte header: the `AV` magic, the
+//! wire version, a frame kind and the big-endian payload length. Screen
+//! frames, input events and file chunks are bincode-encoded so pixel and
+//! file data travel as raw bytes. Control messages keep a JSON body because `ProtocolMessage::data`
+//! is a free-form value. JSON text frames are still accepted as a debug
+//! fallback. Once a session channel is established every message is sealed
+//! whole inside an `Encrypted` frame.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::security::secure_channel::{ChannelOpener, ChannelSealer};
+use crate::utils::file_transfer::{FileChunk, TransferMessage};
+
+use super::protocol::{InputEvent, MessageType, ProtocolMessage, ScreenFrame, MAX_MESSAGE_SIZE};
+
+pub const WIRE_MAGIC: [u8; 2] = *b"AV";
+pub const WIRE_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Control = 1,
+    ScreenFrame = 2,
+    InputEvent = 3,
+    Relay = 4,
+    Encrypted = 5,
+    FileChunk = 6,
+}
+
+impl FrameKind {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(FrameKind::Control),
+            2 => Ok(FrameKind::ScreenFrame),
+            3 => Ok(FrameKind::InputEvent),
+            4 => Ok(FrameKind::Relay),
+            5 => Ok(FrameKind::Encrypted),
+            6 => Ok(FrameKind::FileChunk),
+            other => Err(anyhow::anyhow!("Unknown frame kind: {}", other)),
+        }
+    }
+}
+
+/// Encoding used when sending to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    Binary,
+    Json,
+}
+
+impl Default for WireFormat {
+    fn default() -> Self {
+        WireFormat::Binary
+    }
+}
+
+impl WireFormat {
+    /// Format a received WebSocket message was sent in, if it carries data
+    pub fn of(message: &Message) -> Option<Self> {
+        match message {
+            Message::Binary(_) => Some(WireFormat::Binary),
+            Message::Text(_) => Some(WireFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum WireMessage {
+    Control(ProtocolMessage),
+    ScreenFrame(ScreenFrame),
+    InputEvent(InputEvent),
+    FileChunk(FileChunk),
+}
+
+/// Prefix a payload with the wire header
+pub fn encode_frame(kind: FrameKind, payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(anyhow::anyhow!(
+            "Payload too large: {} bytes (max {})",
+            payload.len(),
+            MAX_MESSAGE_SIZE
+        ));
+    }
+
+    let mut buffer = Vec::with_capacity(HEADER_SIZE + payload.len());
+    buffer.extend_from_slice(&WIRE_MAGIC);
+    buffer.push(WIRE_VERSION);
+    buffer.push(kind as u8);
+    buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(payload);
+
+    Ok(buffer)
+}
+
+/// Validate the wire header and return the frame kind and payload
+pub fn decode_frame(data: &[u8]) -> Result<(FrameKind, &[u8])> {
+    if data.len() < HEADER_SIZE {
+        return Err(anyhow::anyhow!("Frame too short: {} bytes", data.len()));
+    }
+
+    if data[0..2] != WIRE_MAGIC {
+        return Err(anyhow::anyhow!("Invalid frame magic"));
+    }
+
+    if data[2] != WIRE_VERSION {
+        return Err(anyhow::anyhow!("Unsupported wire version: {}", data[2]));
+    }
+
+    let kind = FrameKind::from_u8(data[3])?;
+    let length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+    if length > MAX_MESSAGE_SIZE {
+        return Err(anyhow::anyhow!("Frame length {} exceeds maximum", length));
+    }
+
+    let payload = &data[HEADER_SIZE..];
+    if payload.len() != length {
+        return Err(anyhow::anyhow!(
+            "Frame length mismatch: header says {}, got {}",
+            length,
+            payload.len()
+        ));
+    }
+
+    Ok((kind, payload))
+}
+
+impl WireMessage {
+    pub fn kind(&self) -> FrameKind {
+        match self {
+            WireMessage::Control(_) => FrameKind::Control,
+            WireMessage::ScreenFrame(_) => FrameKind::ScreenFrame,
+            WireMessage::InputEvent(_) => FrameKind::InputEvent,
+            WireMessage::FileChunk(_) => FrameKind::FileChunk,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let payload = match self {
+            WireMessage::Control(message) => serde_json::to_vec(message)?,
+            WireMessage::ScreenFrame(frame) => bincode::serialize(frame)?,
+            WireMessage::InputEvent(event) => bincode::serialize(event)?,
+            WireMessage::FileChunk(chunk) => bincode::serialize(chunk)?,
+        };
+
+        encode_frame(self.kind(), &payload)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let (kind, payload) = decode_frame(data)?;
+
+        match kind {
+            FrameKind::Control => Ok(WireMessage::Control(serde_json::from_slice(payload)?)),
+            FrameKind::ScreenFrame => Ok(WireMessage::ScreenFrame(bincode::deserialize(payload)?)),
+            FrameKind::InputEvent => Ok(WireMessage::InputEvent(bincode::deserialize(payload)?)),
+            FrameKind::FileChunk => Ok(WireMessage::FileChunk(bincode::deserialize(payload)?)),
+            FrameKind::Relay => Err(anyhow::anyhow!("Relay frames are not peer messages")),
+            FrameKind::Encrypted => Err(anyhow::anyhow!("Encrypted frame received without a session channel")),
+        }
+    }
+
+    /// Encrypt the encoded message into an `Encrypted` binary frame
+    pub fn seal(&self, sealer: &mut ChannelSealer) -> Result<Message> {
+        let sealed = sealer.seal(&self.encode()?)?;
+        Ok(Message::Binary(encode_frame(FrameKind::Encrypted, &sealed)?))
+    }
+
+    /// Decrypt and decode an `Encrypted` frame
+    pub fn open(data: &[u8], opener: &mut ChannelOpener) -> Result<Self> {
+        let (kind, payload) = decode_frame(data)?;
+        if kind != FrameKind::Encrypted {
+            return Err(anyhow::anyhow!("Expected an encrypted frame, got {:?}", kind));
+        }
+
+        Self::decode(&opener.open(payload)?)
+    }
+
+    /// Convert to the legacy JSON representation
+    pub fn to_protocol_message(&self) -> Result<ProtocolMessage> {
+        Ok(match self {
+            WireMessage::Control(message) => message.clone(),
+            WireMessage::ScreenFrame(frame) => ProtocolMessage::screen_frame(frame.clone()),
+            WireMessage::InputEvent(event) => ProtocolMessage::input_event(event.clone()),
+            WireMessage::FileChunk(chunk) => ProtocolMessage::file_transfer(&TransferMessage::Chunk(chunk.clone())),
+        })
+    }
+
+    /// The file transfer message carried, if this is one
+    pub fn file_transfer(&self) -> Option<Result<TransferMessage>> {
+        match self {
+            WireMessage::FileChunk(chunk) => Some(Ok(TransferMessage::Chunk(chunk.clone()))),
+            WireMessage::Control(message) if message.message_type.is_file_transfer() => Some(message.to_file_transfer()),
+            _ => None,
+        }
+    }
+
+    /// Parse a legacy JSON message, lifting frames, input and file chunks into typed variants
+    pub fn from_protocol_message(message: ProtocolMessage) -> Self {
+        match message.message_type {
+            MessageType::ScreenFrame => match serde_json::from_value::<ScreenFrame>(message.data.clone()) {
+                Ok(frame) => WireMessage::ScreenFrame(frame),
+                Err(_) => WireMessage::Control(message),
+            },
+            MessageType::InputEvent => match serde_json::from_value::<InputEvent>(message.data.clone()) {
+                Ok(event) => WireMessage::InputEvent(event),
+                Err(_) => WireMessage::Control(message),
+            },
+            MessageType::FileTransferData => match serde_json::from_value::<FileChunk>(message.data.clone()) {
+                Ok(chunk) => WireMessage::FileChunk(chunk),
+                Err(_) => WireMessage::Control(message),
+            },
+            _ => WireMessage::Control(message),
+        }
+    }
+
+    pub fn to_ws_message(&self, format: WireFormat) -> Result<Message> {
+        match format {
+            WireFormat::Binary => Ok(Message::Binary(self.encode()?)),
+            WireFormat::Json => Ok(Message::Text(serde_json::to_string(&self.to_protocol_message()?)?)),
+        }
+    }
+
+    /// Decode a WebSocket message; returns `None` for non-data frames
+    pub fn from_ws_message(message: &Message) -> Result<Option<Self>> {
+        match message {
+            Message::Binary(data) => Ok(Some(Self::decode(data)?)),
+            Message::Text(text) => {
+                let protocol_msg = serde_json::from_str::<ProtocolMessage>(text)?;
+                Ok(Some(Self::from_protocol_message(protocol_msg)))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Whether a WebSocket message is a sealed session frame
+pub fn is_encrypted(message: &Message) -> bool {
+    match message {
+        Message::Binary(data) => data.len() >= HEADER_SIZE && data[3] == FrameKind::Encrypted as u8,
+        _ => false,
+    }
+}
+
+impl From<ProtocolMessage> for WireMessage {
+    fn from(message: ProtocolMessage) -> Self {
+        WireMessage::Control(message)
+    }
+}
+
+impl From<TransferMessage> for WireMessage {
+    fn from(message: TransferMessage) -> Self {
+        match message {
+            TransferMessage::Chunk(chunk) => WireMessage::FileChunk(chunk),
+            other => WireMessage::Control(ProtocolMessage::file_transfer(&other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::protocol::{FrameFormat, MouseButton};
+
+    fn test_frame() -> ScreenFrame {
+        ScreenFrame {
+            width: 4,
+            height: 2,
+            format: FrameFormat::Jpeg,
+            data: vec![0xff, 0xd8, 0x00, 0x10, 0x20],
+            timestamp: chrono::Utc::now(),
+            sequence_number: 42,
+            is_keyframe: true,
+            changed_regions: None,
+        }
+    }
+
+    #[test]
+    fn test_screen_frame_roundtrip() {
+        let encoded = WireMessage::ScreenFrame(test_frame()).encode().unwrap();
+
+        assert_eq!(&encoded[0..2], &WIRE_MAGIC);
+        assert_eq!(encoded[3], FrameKind::ScreenFrame as u8);
+
+        match WireMessage::decode(&encoded).unwrap() {
+            WireMessage::ScreenFrame(frame) => {
+                assert_eq!(frame.sequence_number, 42);
+                assert_eq!(frame.data, vec![0xff, 0xd8, 0x00, 0x10, 0x20]);
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_input_event_roundtrip() {
+        let event = InputEvent::mouse_click(10, 20, MouseButton::Left);
+        let encoded = WireMessage::InputEvent(event).encode().unwrap();
+
+        match WireMessage::decode(&encoded).unwrap() {
+            WireMessage::InputEvent(event) => {
+                assert_eq!(event.x, Some(10));
+                assert_eq!(event.y, Some(20));
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_binary_is_smaller_than_json() {
+        let mut frame = test_frame();
+        frame.data = vec![200u8; 4096];
+
+        let binary = WireMessage::ScreenFrame(frame.clone()).encode().unwrap();
+        let json = serde_json::to_vec(&ProtocolMessage::screen_frame(frame)).unwrap();
+
+        assert!(binary.len() * 3 < json.len());
+    }
+
+    #[test]
+    fn test_json_fallback() {
+        let text = serde_json::to_string(&ProtocolMessage::input_event(InputEvent::mouse_move(1, 2))).unwrap();
+
+        match WireMessage::from_ws_message(&Message::Text(text)).unwrap() {
+            Some(WireMessage::InputEvent(event)) => assert_eq!(event.x, Some(1)),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_chunk_roundtrip() {
+        let chunk = FileChunk {
+            transfer_id: "transfer".to_string(),
+            chunk_index: 3,
+            data: vec![1, 2, 3, 4],
+            is_compressed: false,
+            checksum: "abc".to_string(),
+        };
+        let encoded = WireMessage::from(TransferMessage::Chunk(chunk)).encode().unwrap();
+
+        assert_eq!(encoded[3], FrameKind::FileChunk as u8);
+        match WireMessage::decode(&encoded).unwrap().file_transfer() {
+            Some(Ok(TransferMessage::Chunk(chunk))) => {
+                assert_eq!(chunk.chunk_index, 3);
+                assert_eq!(chunk.data, vec![1, 2, 3, 4]);
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_frames() {
+        let mut encoded = WireMessage::Control(ProtocolMessage::heartbeat()).encode().unwrap();
+
+        assert!(decode_frame(&encoded[..HEADER_SIZE - 1]).is_err());
+        assert!(decode_frame(&encoded[..encoded.len() - 1]).is_err());
+
+        encoded[0] = b'X';
+        assert!(decode_frame(&encoded).is_err());
+    }
+}
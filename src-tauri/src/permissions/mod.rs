@@ -6,6 +6,10 @@ use std::sync::Arc;
 use tokio::sync::{RwLock, mpsc};
 use chrono::{DateTime, Utc, Duration};
 
+pub mod policy;
+
+pub use policy::{ApprovalMode, ApprovalPolicy};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PermissionRequest {
     pub id: String,
@@ -35,6 +39,32 @@ pub enum Permission {
     SystemInfo,
 }
 
+impl Permission {
+    /// Parse the names used by the UI and by connection requests
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "screen_view" | "screen_capture" => Some(Permission::ScreenView),
+            "input_control" | "input_forwarding" => Some(Permission::InputControl),
+            "file_transfer" => Some(Permission::FileTransfer),
+            "clipboard" => Some(Permission::Clipboard),
+            "audio_access" => Some(Permission::AudioAccess),
+            "system_info" => Some(Permission::SystemInfo),
+            _ => None,
+        }
+    }
+    
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::ScreenView => "screen_view",
+            Permission::InputControl => "input_control",
+            Permission::FileTransfer => "file_transfer",
+            Permission::Clipboard => "clipboard",
+            Permission::AudioAccess => "audio_access",
+            Permission::SystemInfo => "system_info",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PermissionResponse {
     Granted {
@@ -75,6 +105,9 @@ pub struct PermissionConfig {
     pub enable_whitelist: bool,
     pub whitelisted_devices: Vec<String>, // connection IDs or device names
     pub default_session_duration_minutes: Option<u32>,
+    /// Answers requests on the host's behalf; manual by default
+    #[serde(default)]
+    pub auto_approval: ApprovalPolicy,
 }
 
 impl Default for PermissionConfig {
@@ -88,6 +121,7 @@ impl Default for PermissionConfig {
             enable_whitelist: false,
             whitelisted_devices: Vec::new(),
             default_session_duration_minutes: Some(60), // 1 hour
+            auto_approval: ApprovalPolicy::default(),
         }
     }
 }
@@ -181,6 +215,13 @@ impl PermissionManager {
             pending.insert(request_id.clone(), request.clone());
         }
         
+        let decision = self.config.read().await.auto_approval.decide(&request);
+        if let Some(response) = decision {
+            info!("Approval policy answered request {} from {}", request_id, request.device_name);
+            self.respond_to_request(request_id.clone(), response).await?;
+            return Ok(request_id);
+        }
+        
         // Send event
         if let Some(sender) = self.event_sender.read().await.as_ref() {
             let _ = sender.send(PermissionEvent::RequestReceived(request));
@@ -293,6 +334,21 @@ impl PermissionManager {
         }
     }
     
+    /// Like `check_permission`, but features the config doesn't guard are always allowed
+    pub async fn is_allowed(&self, connection_id: &str, permission: &Permission) -> bool {
+        let required = {
+            let config = self.config.read().await;
+            match permission {
+                Permission::ScreenView => config.require_permission_for_screen_view,
+                Permission::InputControl => config.require_permission_for_input_control,
+                Permission::FileTransfer => config.require_permission_for_file_transfer,
+                _ => true,
+            }
+        };
+        
+        !required || self.check_permission(connection_id, permission).await
+    }
+    
     pub async fn revoke_permissions(
         &self,
         connection_id: &str,
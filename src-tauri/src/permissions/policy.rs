@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use super::{Permission, PermissionRequest, PermissionResponse};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalMode {
+    /// Every request waits for someone to answer it
+    #[default]
+    Manual,
+    /// Approve devices on the allow list, deny everyone else
+    AllowListed,
+    AllowAll,
+}
+
+/// Rules for answering permission requests without a person at the host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApprovalPolicy {
+    pub mode: ApprovalMode,
+    /// IP addresses, CIDR blocks such as `10.20.0.0/16`, or relay connection IDs.
+    /// Device names are chosen by the viewer, so they are never trusted.
+    pub allowed: Vec<String>,
+    /// The most that is granted automatically; other requested permissions are dropped
+    pub permissions: Vec<Permission>,
+    pub session_duration_minutes: Option<u32>,
+}
+
+impl Default for ApprovalPolicy {
+    fn default() -> Self {
+        Self {
+            mode: ApprovalMode::Manual,
+            allowed: Vec::new(),
+            permissions: vec![Permission::ScreenView, Permission::InputControl],
+            session_duration_minutes: Some(60),
+        }
+    }
+}
+
+impl ApprovalPolicy {
+    /// Answer a request, or `None` if it needs a person
+    pub fn decide(&self, request: &PermissionRequest) -> Option<PermissionResponse> {
+        match self.mode {
+            ApprovalMode::Manual => return None,
+            ApprovalMode::AllowListed if !self.is_allowed(request) => {
+                return Some(PermissionResponse::Denied {
+                    reason: "Device is not on the approval list".to_string(),
+                });
+            }
+            _ => {}
+        }
+
+        let permissions: Vec<Permission> = request.requested_permissions.iter()
+            .filter(|p| self.permissions.contains(p))
+            .cloned()
+            .collect();
+
+        if permissions.is_empty() {
+            return Some(PermissionResponse::Denied {
+                reason: "None of the requested permissions can be granted automatically".to_string(),
+            });
+        }
+
+        Some(PermissionResponse::Granted {
+            permissions,
+            duration_minutes: self.session_duration_minutes,
+        })
+    }
+
+    /// Requests seen on the LAN match on the address they came from. Only requests through the
+    /// relay have no address, and their connection ID is the one the relay stamped on them.
+    fn is_allowed(&self, request: &PermissionRequest) -> bool {
+        let address = request.device_info.ip_address.as_deref()
+            .map(|ip| ip.parse::<IpAddr>().map(|ip| ip.to_canonical()).ok());
+
+        self.allowed.iter().filter(|entry| !entry.is_empty()).any(|entry| {
+            match (parse_network(entry), address) {
+                (Some((network, prefix)), Some(Some(ip))) => network_contains(network, prefix, ip),
+                (None, None) => entry == &request.connection_id,
+                _ => false,
+            }
+        })
+    }
+}
+
+/// An address and prefix length from `10.20.0.0/16`, or a lone address as a network of one
+fn parse_network(entry: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = match entry.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix.parse::<u32>().ok()?)),
+        None => (entry, None),
+    };
+    let address = address.parse::<IpAddr>().ok()?.to_canonical();
+    let bits = if address.is_ipv4() { 32 } else { 128 };
+
+    match prefix {
+        Some(prefix) if prefix > bits => None,
+        Some(prefix) => Some((address, prefix)),
+        None => Some((address, bits)),
+    }
+}
+
+fn network_contains(network: IpAddr, prefix: u32, ip: IpAddr) -> bool {
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+        _ => return false,
+    };
+    let host_bits = bits - prefix;
+    host_bits >= 128 || (network >> host_bits) == (ip >> host_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permissions::DeviceInfo;
+    use chrono::Utc;
+
+    fn request(device_name: &str, ip_address: Option<&str>, permissions: Vec<Permission>) -> PermissionRequest {
+        PermissionRequest {
+            id: "request".to_string(),
+            connection_id: "123 456 789".to_string(),
+            device_name: device_name.to_string(),
+            device_info: DeviceInfo {
+                name: device_name.to_string(),
+                os: "Linux".to_string(),
+                version: "0.1.0".to_string(),
+                ip_address: ip_address.map(str::to_string),
+            },
+            requested_permissions: permissions,
+            requested_at: Utc::now(),
+            expires_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_allow_listed_policy() {
+        let policy = ApprovalPolicy {
+            mode: ApprovalMode::AllowListed,
+            allowed: vec!["10.20.0.0/16".to_string(), "192.168.1.4".to_string(), "123 456 789".to_string()],
+            ..Default::default()
+        };
+
+        // Only the permissions the policy allows are granted
+        let lab = request("lab-07", Some("10.20.0.15"), vec![Permission::ScreenView, Permission::FileTransfer]);
+        match policy.decide(&lab) {
+            Some(PermissionResponse::Granted { permissions, .. }) => assert_eq!(permissions, vec![Permission::ScreenView]),
+            other => panic!("Unexpected decision: {:?}", other),
+        }
+
+        let admin = request("lab-admin", Some("192.168.1.4"), vec![Permission::InputControl]);
+        assert!(matches!(policy.decide(&admin), Some(PermissionResponse::Granted { .. })));
+        let mapped = request("lab-08", Some("::ffff:10.20.3.3"), vec![Permission::ScreenView]);
+        assert!(matches!(policy.decide(&mapped), Some(PermissionResponse::Granted { .. })));
+        let relayed = request("laptop", None, vec![Permission::ScreenView]);
+        assert!(matches!(policy.decide(&relayed), Some(PermissionResponse::Granted { .. })));
+
+        let stranger = request("laptop", Some("192.168.1.9"), vec![Permission::ScreenView]);
+        assert!(matches!(policy.decide(&stranger), Some(PermissionResponse::Denied { .. })));
+
+        // A name, a connection ID claimed on the LAN or a neighbouring block is not enough
+        let named = ApprovalPolicy { allowed: vec!["lab-admin".to_string(), "10.2.0.0/16".to_string()], ..policy.clone() };
+        let impostor = request("lab-admin", Some("10.200.0.1"), vec![Permission::ScreenView]);
+        assert!(matches!(named.decide(&impostor), Some(PermissionResponse::Denied { .. })));
+        let claimed = ApprovalPolicy { allowed: vec!["123 456 789".to_string()], ..policy.clone() };
+        assert!(matches!(claimed.decide(&stranger), Some(PermissionResponse::Denied { .. })));
+
+        assert!(parse_network("10.20.0.0/33").is_none());
+        assert!(parse_network("10.20.").is_none());
+
+        assert!(ApprovalPolicy::default().decide(&lab).is_none());
+    }
+}
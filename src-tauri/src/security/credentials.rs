@@ -0,0 +1,395 @@
+//! Credential verification backends.
+//!
+//! `SecurityManager` asks each registered `CredentialVerifier` in turn until
+//! one accepts. The built-in backends cover unattended-access passwords
+//! (argon2 hashes on disk), one-time session PINs shown on the host and
+//! HMAC-signed expiring tokens.
+
+use anyhow::Result;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use super::ClientCredentials;
+use crate::config::AppConfig;
+
+pub const PASSWORD_FILE: &str = "unattended_access.json";
+pub const TOKEN_SECRET_FILE: &str = "token_secret.key";
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const SESSION_PIN_LENGTH: usize = 6;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub trait CredentialVerifier: Send + Sync {
+    /// Short backend name used in logs
+    fn name(&self) -> &str;
+
+    /// `Ok(false)` means the credentials were not accepted by this backend
+    fn verify(&self, credentials: &ClientCredentials) -> Result<bool>;
+}
+
+/// Argon2-hashed passwords for unattended access, keyed by username
+pub struct UnattendedPasswordStore {
+    path: PathBuf,
+    users: RwLock<HashMap<String, String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PasswordFile {
+    users: HashMap<String, String>,
+}
+
+impl UnattendedPasswordStore {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let users = if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            serde_json::from_str::<PasswordFile>(&content)?.users
+        } else {
+            HashMap::new()
+        };
+
+        debug!("Loaded {} unattended access account(s)", users.len());
+
+        Ok(Self {
+            path,
+            users: RwLock::new(users),
+        })
+    }
+
+    pub fn load_default() -> Result<Self> {
+        Self::load(AppConfig::get_data_dir()?.join(PASSWORD_FILE))
+    }
+
+    pub fn set_password(&self, username: &str, password: &str) -> Result<()> {
+        if username.is_empty() {
+            return Err(anyhow::anyhow!("Username cannot be empty"));
+        }
+
+        if password.len() < MIN_PASSWORD_LENGTH {
+            return Err(anyhow::anyhow!(
+                "Password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ));
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?
+            .to_string();
+
+        self.users.write().unwrap().insert(username.to_string(), hash);
+        self.save()?;
+
+        info!("Unattended access password set for {}", username);
+        Ok(())
+    }
+
+    pub fn remove_user(&self, username: &str) -> Result<bool> {
+        let removed = self.users.write().unwrap().remove(username).is_some();
+        if removed {
+            self.save()?;
+            info!("Unattended access removed for {}", username);
+        }
+        Ok(removed)
+    }
+
+    pub fn has_users(&self) -> bool {
+        !self.users.read().unwrap().is_empty()
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = PasswordFile {
+            users: self.users.read().unwrap().clone(),
+        };
+        write_private_file(&self.path, serde_json::to_string_pretty(&file)?.as_bytes())
+    }
+}
+
+impl CredentialVerifier for UnattendedPasswordStore {
+    fn name(&self) -> &str {
+        "unattended_password"
+    }
+
+    fn verify(&self, credentials: &ClientCredentials) -> Result<bool> {
+        let (username, password) = match credentials {
+            ClientCredentials::Password { username, password } => (username, password),
+            _ => return Ok(false),
+        };
+
+        let users = self.users.read().unwrap();
+        let stored = match users.get(username) {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+
+        let hash = PasswordHash::new(stored)
+            .map_err(|e| anyhow::anyhow!("Corrupt password hash for {}: {}", username, e))?;
+
+        Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SessionPin {
+    pin: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// One-time PIN displayed on the host for attended sessions
+pub struct SessionPinVerifier {
+    current: Mutex<Option<SessionPin>>,
+    ttl: chrono::Duration,
+}
+
+impl SessionPinVerifier {
+    pub fn new(ttl: chrono::Duration) -> Self {
+        Self {
+            current: Mutex::new(None),
+            ttl,
+        }
+    }
+
+    /// Replace the current PIN with a fresh one
+    pub fn generate_pin(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let pin: String = (0..SESSION_PIN_LENGTH)
+            .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+            .collect();
+
+        *self.current.lock().unwrap() = Some(SessionPin {
+            pin: pin.clone(),
+            expires_at: Utc::now() + self.ttl,
+        });
+
+        pin
+    }
+
+    pub fn current_pin(&self) -> Option<String> {
+        self.current.lock().unwrap()
+            .as_ref()
+            .filter(|pin| pin.expires_at > Utc::now())
+            .map(|pin| pin.pin.clone())
+    }
+
+    pub fn clear(&self) {
+        *self.current.lock().unwrap() = None;
+    }
+}
+
+impl CredentialVerifier for SessionPinVerifier {
+    fn name(&self) -> &str {
+        "session_pin"
+    }
+
+    fn verify(&self, credentials: &ClientCredentials) -> Result<bool> {
+        let pin = match credentials {
+            ClientCredentials::Pin { pin } => pin,
+            _ => return Ok(false),
+        };
+
+        let mut current = self.current.lock().unwrap();
+        let matches = match current.as_ref() {
+            Some(session_pin) if session_pin.expires_at > Utc::now() => {
+                constant_time_eq(session_pin.pin.as_bytes(), pin.as_bytes())
+            }
+            _ => false,
+        };
+
+        // A PIN is only good for one session
+        if matches {
+            *current = None;
+        }
+
+        Ok(matches)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClaims {
+    pub subject: String,
+    pub issued_at: i64,
+    pub expires_at: i64,
+}
+
+/// Issues and checks `<claims>.<hmac>` tokens signed with a host-local secret
+pub struct TokenVerifier {
+    secret: Vec<u8>,
+}
+
+impl TokenVerifier {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self { secret }
+    }
+
+    /// Load the signing secret from disk, creating it on first use
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            let secret = std::fs::read(path)?;
+            if secret.len() >= 32 {
+                return Ok(Self::new(secret));
+            }
+            warn!("Token secret at {:?} is too short, regenerating", path);
+        }
+
+        let mut secret = vec![0u8; 32];
+        rand::thread_rng().fill(&mut secret[..]);
+        write_private_file(path, &secret)?;
+
+        info!("Generated new token signing secret");
+        Ok(Self::new(secret))
+    }
+
+    pub fn load_default() -> Result<Self> {
+        Self::load_or_create(&AppConfig::get_data_dir()?.join(TOKEN_SECRET_FILE))
+    }
+
+    pub fn issue(&self, subject: &str, ttl: chrono::Duration) -> Result<String> {
+        let now = Utc::now();
+        let claims = TokenClaims {
+            subject: subject.to_string(),
+            issued_at: now.timestamp(),
+            expires_at: (now + ttl).timestamp(),
+        };
+
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?);
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(payload.as_bytes())?);
+
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    pub fn validate(&self, token: &str) -> Result<TokenClaims> {
+        let (payload, signature) = token.split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("Malformed token"))?;
+
+        let signature = URL_SAFE_NO_PAD.decode(signature)?;
+        let mut mac = HmacSha256::new_from_slice(&self.secret)
+            .map_err(|e| anyhow::anyhow!("Invalid token secret: {}", e))?;
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("Invalid token signature"))?;
+
+        let claims: TokenClaims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+        if claims.expires_at <= Utc::now().timestamp() {
+            return Err(anyhow::anyhow!("Token expired"));
+        }
+
+        Ok(claims)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut mac = HmacSha256::new_from_slice(&self.secret)
+            .map_err(|e| anyhow::anyhow!("Invalid token secret: {}", e))?;
+        mac.update(data);
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+}
+
+impl CredentialVerifier for TokenVerifier {
+    fn name(&self) -> &str {
+        "signed_token"
+    }
+
+    fn verify(&self, credentials: &ClientCredentials) -> Result<bool> {
+        let token = match credentials {
+            ClientCredentials::Token { token } => token,
+            _ => return Ok(false),
+        };
+
+        match self.validate(token) {
+            Ok(claims) => {
+                debug!("Token accepted for {}", claims.subject);
+                Ok(true)
+            }
+            Err(e) => {
+                debug!("Token rejected: {}", e);
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Write a secret file readable only by the current user
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, contents)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unattended_password() {
+        let path = std::env::temp_dir().join(format!("anyviewer-test-{}.json", uuid::Uuid::new_v4()));
+        let store = UnattendedPasswordStore::load(path.clone()).unwrap();
+
+        assert!(store.set_password("admin", "short").is_err());
+        store.set_password("admin", "correct horse").unwrap();
+
+        let good = ClientCredentials::Password { username: "admin".into(), password: "correct horse".into() };
+        let bad = ClientCredentials::Password { username: "admin".into(), password: "wrong horse".into() };
+        assert!(store.verify(&good).unwrap());
+        assert!(!store.verify(&bad).unwrap());
+
+        // Hashes survive a reload
+        let reloaded = UnattendedPasswordStore::load(path.clone()).unwrap();
+        assert!(reloaded.verify(&good).unwrap());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_session_pin_is_single_use() {
+        let verifier = SessionPinVerifier::new(chrono::Duration::minutes(5));
+        let pin = verifier.generate_pin();
+        assert_eq!(pin.len(), SESSION_PIN_LENGTH);
+
+        let credentials = ClientCredentials::Pin { pin };
+        assert!(verifier.verify(&credentials).unwrap());
+        assert!(!verifier.verify(&credentials).unwrap());
+    }
+
+    #[test]
+    fn test_signed_tokens() {
+        let verifier = TokenVerifier::new(vec![7u8; 32]);
+        let token = verifier.issue("viewer", chrono::Duration::minutes(5)).unwrap();
+
+        assert_eq!(verifier.validate(&token).unwrap().subject, "viewer");
+
+        let other = TokenVerifier::new(vec![8u8; 32]);
+        assert!(other.validate(&token).is_err());
+
+        let expired = verifier.issue("viewer", chrono::Duration::seconds(-1)).unwrap();
+        assert!(verifier.validate(&expired).is_err());
+    }
+}
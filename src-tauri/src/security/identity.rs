@@ -0,0 +1,136 @@
+//! Long-term host identity used to authenticate key exchanges.
+//!
+//! The Ed25519 key is created once and kept in the data dir, so viewers can
+//! recognise the same machine across restarts by its fingerprint.
+
+use anyhow::Result;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::info;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::credentials::write_private_file;
+use crate::config::AppConfig;
+
+pub const IDENTITY_KEY_LENGTH: usize = 32;
+pub const IDENTITY_KEY_FILE: &str = "host_identity.key";
+
+/// Bytes of the key hash shown to users
+const FINGERPRINT_BYTES: usize = 10;
+
+/// The security manager and P2P manager both load the key; only one may create it
+static IDENTITY_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+pub struct HostIdentity {
+    signing_key: SigningKey,
+}
+
+impl HostIdentity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Load the identity key from disk, creating it on first use.
+    ///
+    /// A key that can't be read is an error rather than replaced: viewers that pinned it
+    /// would see a new one as a different machine.
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        let _guard = IDENTITY_FILE_LOCK.lock().unwrap();
+
+        if path.exists() {
+            let bytes = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Could not read host identity key at {}: {}", path.display(), e))?;
+            let secret = <[u8; IDENTITY_KEY_LENGTH]>::try_from(bytes.as_slice()).map_err(|_| anyhow::anyhow!(
+                "Host identity key at {} is corrupt; restore it from a backup, or remove it to create a new identity \
+                 that viewers will have to trust again",
+                path.display()
+            ))?;
+            return Ok(Self {
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+
+        let identity = Self::generate();
+        write_private_file(path, &identity.signing_key.to_bytes())?;
+
+        info!("Generated new host identity {}", identity.fingerprint());
+        Ok(identity)
+    }
+
+    pub fn load_default() -> Result<Self> {
+        Self::load_or_create(&AppConfig::get_data_dir()?.join(IDENTITY_KEY_FILE))
+    }
+
+    pub fn public_key(&self) -> [u8; IDENTITY_KEY_LENGTH] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(message).to_bytes().to_vec()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+}
+
+/// Short form of an identity key for reading aloud, e.g. `3F2A-91C0-7B44-E1D2-05AA`
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..FINGERPRINT_BYTES]
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Check a signature made by the host owning `public_key`
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key: [u8; IDENTITY_KEY_LENGTH] = public_key.try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid identity key length: {}", public_key.len()))?;
+    let verifying_key = VerifyingKey::from_bytes(&public_key)
+        .map_err(|e| anyhow::anyhow!("Invalid identity key: {}", e))?;
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| anyhow::anyhow!("Invalid signature: {}", e))?;
+
+    verifying_key.verify(message, &signature)
+        .map_err(|_| anyhow::anyhow!("Host identity signature does not match"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_persists() {
+        let path = std::env::temp_dir().join(format!("anyviewer-test-{}.key", uuid::Uuid::new_v4()));
+
+        let identity = HostIdentity::load_or_create(&path).unwrap();
+        let reloaded = HostIdentity::load_or_create(&path).unwrap();
+        assert_eq!(identity.public_key(), reloaded.public_key());
+
+        let fingerprint = identity.fingerprint();
+        assert_eq!(fingerprint.len(), 24);
+        assert_eq!(fingerprint, reloaded.fingerprint());
+
+        let signature = identity.sign(b"transcript");
+        assert!(verify_signature(&reloaded.public_key(), b"transcript", &signature).is_ok());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_corrupt_identity_is_not_replaced() {
+        let path = std::env::temp_dir().join(format!("anyviewer-test-{}.key", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"not a key").unwrap();
+
+        assert!(HostIdentity::load_or_create(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a key");
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,162 @@
+//! Viewer-side record of host identity keys.
+//!
+//! The first connection to a host pins its identity key (trust on first
+//! use). Later connections under the same host ID must present the same key;
+//! a different key is reported as a mismatch and the connection is aborted
+//! until the user forgets the old key.
+
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use super::credentials::write_private_file;
+use super::identity::fingerprint;
+use crate::config::AppConfig;
+
+pub const KNOWN_HOSTS_FILE: &str = "known_hosts.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownHost {
+    pub host_id: String,
+    /// Base64url-encoded Ed25519 public key
+    pub identity_key: String,
+    pub fingerprint: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostKeyStatus {
+    /// Never seen before; the key is now pinned
+    FirstUse,
+    Trusted,
+    /// The host ID is known but presented a different key
+    Mismatch { expected: String, presented: String },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KnownHostsFile {
+    hosts: HashMap<String, KnownHost>,
+}
+
+pub struct KnownHostsStore {
+    path: PathBuf,
+    hosts: RwLock<HashMap<String, KnownHost>>,
+}
+
+impl KnownHostsStore {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let hosts = if path.exists() {
+            let content = std::fs::read_to_string(&path)?;
+            serde_json::from_str::<KnownHostsFile>(&content)?.hosts
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            path,
+            hosts: RwLock::new(hosts),
+        })
+    }
+
+    pub fn load_default() -> Result<Self> {
+        Self::load(AppConfig::get_data_dir()?.join(KNOWN_HOSTS_FILE))
+    }
+
+    /// Check a host's key, pinning it if the host has not been seen before
+    pub fn verify(&self, host_id: &str, identity_key: &[u8]) -> Result<HostKeyStatus> {
+        let encoded = URL_SAFE_NO_PAD.encode(identity_key);
+        let now = Utc::now();
+
+        let status = {
+            let mut hosts = self.hosts.write().unwrap();
+            match hosts.get_mut(host_id) {
+                Some(known) if known.identity_key == encoded => {
+                    known.last_seen = now;
+                    HostKeyStatus::Trusted
+                }
+                Some(known) => {
+                    warn!(
+                        "Host {} presented key {} but {} was expected",
+                        host_id,
+                        fingerprint(identity_key),
+                        known.fingerprint
+                    );
+                    return Ok(HostKeyStatus::Mismatch {
+                        expected: known.fingerprint.clone(),
+                        presented: fingerprint(identity_key),
+                    });
+                }
+                None => {
+                    info!("Pinning identity {} for new host {}", fingerprint(identity_key), host_id);
+                    hosts.insert(host_id.to_string(), KnownHost {
+                        host_id: host_id.to_string(),
+                        identity_key: encoded,
+                        fingerprint: fingerprint(identity_key),
+                        first_seen: now,
+                        last_seen: now,
+                    });
+                    HostKeyStatus::FirstUse
+                }
+            }
+        };
+
+        self.save()?;
+        Ok(status)
+    }
+
+    /// Drop a pinned key so the next connection pins afresh; for legitimate key changes
+    pub fn forget(&self, host_id: &str) -> Result<bool> {
+        let removed = self.hosts.write().unwrap().remove(host_id).is_some();
+        if removed {
+            self.save()?;
+            info!("Forgot known host {}", host_id);
+        }
+        Ok(removed)
+    }
+
+    pub fn list(&self) -> Vec<KnownHost> {
+        self.hosts.read().unwrap().values().cloned().collect()
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = KnownHostsFile {
+            hosts: self.hosts.read().unwrap().clone(),
+        };
+        write_private_file(&self.path, serde_json::to_string_pretty(&file)?.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let path = std::env::temp_dir().join(format!("anyviewer-test-{}.json", uuid::Uuid::new_v4()));
+        let store = KnownHostsStore::load(path.clone()).unwrap();
+
+        let key = [7u8; 32];
+        let other_key = [9u8; 32];
+
+        assert_eq!(store.verify("12345678", &key).unwrap(), HostKeyStatus::FirstUse);
+        assert_eq!(store.verify("12345678", &key).unwrap(), HostKeyStatus::Trusted);
+
+        // The pin survives a reload and a different key is refused
+        let reloaded = KnownHostsStore::load(path.clone()).unwrap();
+        assert!(matches!(
+            reloaded.verify("12345678", &other_key).unwrap(),
+            HostKeyStatus::Mismatch { .. }
+        ));
+
+        assert!(reloaded.forget("12345678").unwrap());
+        assert_eq!(reloaded.verify("12345678", &other_key).unwrap(), HostKeyStatus::FirstUse);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod credentials;
+pub mod identity;
+pub mod known_hosts;
+pub mod secure_channel;
+
 use anyhow::Result;
 use aes_gcm::{Aes256Gcm, Key, Nonce, aead::{Aead, KeyInit}};
 use log::{info, error, debug, warn};
@@ -8,12 +13,16 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+use credentials::{CredentialVerifier, SessionPinVerifier, TokenVerifier, UnattendedPasswordStore};
+use identity::HostIdentity;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
     pub enable_encryption: bool,
     pub key_size: usize,
     pub session_timeout: u64, // seconds
     pub max_failed_attempts: u32,
+    pub session_pin_ttl: u64, // seconds
 }
 
 impl Default for SecurityConfig {
@@ -23,6 +32,7 @@ impl Default for SecurityConfig {
             key_size: 2048,
             session_timeout: 3600, // 1 hour
             max_failed_attempts: 5,
+            session_pin_ttl: 600, // 10 minutes
         }
     }
 }
@@ -48,6 +58,11 @@ pub struct SecurityManager {
     rsa_public_key: RsaPublicKey,
     session_keys: Arc<RwLock<HashMap<String, SessionKey>>>,
     auth_attempts: Arc<RwLock<Vec<AuthAttempt>>>,
+    verifiers: Arc<RwLock<Vec<Arc<dyn CredentialVerifier>>>>,
+    password_store: Option<Arc<UnattendedPasswordStore>>,
+    session_pins: Arc<SessionPinVerifier>,
+    token_verifier: Arc<TokenVerifier>,
+    identity: Arc<HostIdentity>,
 }
 
 impl SecurityManager {
@@ -63,15 +78,102 @@ impl SecurityManager {
         
         info!("Generated RSA key pair (size: {})", config.key_size);
         
+        // Built-in credential backends
+        let password_store = match UnattendedPasswordStore::load_default() {
+            Ok(store) => Some(Arc::new(store)),
+            Err(e) => {
+                warn!("Unattended access unavailable: {}", e);
+                None
+            }
+        };
+        
+        let token_verifier = match TokenVerifier::load_default() {
+            Ok(verifier) => Arc::new(verifier),
+            Err(e) => {
+                warn!("Could not load token secret, tokens will not survive a restart: {}", e);
+                let mut secret = vec![0u8; 32];
+                rng.fill_bytes(&mut secret);
+                Arc::new(TokenVerifier::new(secret))
+            }
+        };
+        
+        let session_pins = Arc::new(SessionPinVerifier::new(
+            chrono::Duration::seconds(config.session_pin_ttl as i64),
+        ));
+        
+        let identity = Arc::new(HostIdentity::load_default()?);
+        info!("Host identity fingerprint: {}", identity.fingerprint());
+        
+        let mut verifiers: Vec<Arc<dyn CredentialVerifier>> = vec![session_pins.clone(), token_verifier.clone()];
+        if let Some(ref store) = password_store {
+            verifiers.push(store.clone());
+        }
+        
         Ok(Self {
             config: Arc::new(RwLock::new(config)),
             rsa_private_key: private_key,
             rsa_public_key: public_key,
             session_keys: Arc::new(RwLock::new(HashMap::new())),
             auth_attempts: Arc::new(RwLock::new(Vec::new())),
+            verifiers: Arc::new(RwLock::new(verifiers)),
+            password_store,
+            session_pins,
+            token_verifier,
+            identity,
         })
     }
     
+    /// Register an additional credential backend
+    pub async fn add_verifier(&self, verifier: Arc<dyn CredentialVerifier>) {
+        info!("Registered credential verifier: {}", verifier.name());
+        self.verifiers.write().await.push(verifier);
+    }
+    
+    /// Generate a one-time PIN to display on the host
+    pub fn generate_session_pin(&self) -> String {
+        self.session_pins.generate_pin()
+    }
+    
+    pub fn current_session_pin(&self) -> Option<String> {
+        self.session_pins.current_pin()
+    }
+    
+    /// Issue a signed token that lets `subject` reconnect without a PIN
+    pub async fn issue_session_token(&self, subject: &str) -> Result<String> {
+        let lifetime = self.config.read().await.session_timeout as i64;
+        self.token_verifier.issue(subject, chrono::Duration::seconds(lifetime))
+    }
+    
+    pub fn set_unattended_password(&self, username: &str, password: &str) -> Result<()> {
+        self.password_store.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Unattended access store is not available"))?
+            .set_password(username, password)
+    }
+    
+    pub fn remove_unattended_password(&self, username: &str) -> Result<bool> {
+        self.password_store.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Unattended access store is not available"))?
+            .remove_user(username)
+    }
+    
+    pub fn unattended_access_enabled(&self) -> bool {
+        self.password_store.as_ref().map(|store| store.has_users()).unwrap_or(false)
+    }
+    
+    /// Long-term key that signs session key exchanges
+    pub fn identity(&self) -> Arc<HostIdentity> {
+        self.identity.clone()
+    }
+    
+    /// Fingerprint to show on the host so viewers can compare it
+    pub fn host_fingerprint(&self) -> String {
+        self.identity.fingerprint()
+    }
+    
+    pub async fn encryption_required(&self) -> bool {
+        self.config.read().await.enable_encryption
+    }
+    
     pub fn get_public_key(&self) -> Result<String> {
         let public_key_pem = self.rsa_public_key.to_pkcs1_pem(rsa::pkcs8::LineEnding::LF)?;
         Ok(public_key_pem)
@@ -166,17 +268,20 @@ impl SecurityManager {
             return Ok(false);
         }
         
-        // Simple authentication logic - in a real app, you'd validate against a database
-        let is_valid = match credentials {
-            ClientCredentials::Password { username, password } => {
-                // For demo purposes, accept any non-empty credentials
-                !username.is_empty() && !password.is_empty()
-            }
-            ClientCredentials::Token { token } => {
-                // For demo purposes, accept any non-empty token
-                !token.is_empty()
+        let mut is_valid = false;
+        for verifier in self.verifiers.read().await.iter() {
+            match verifier.verify(credentials) {
+                Ok(true) => {
+                    debug!("Client {} accepted by {}", client_id, verifier.name());
+                    is_valid = true;
+                    break;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Credential verifier {} failed: {}", verifier.name(), e);
+                }
             }
-        };
+        }
         
         self.record_auth_attempt(client_id, is_valid).await;
         
@@ -286,6 +391,7 @@ impl SecurityManager {
 pub enum ClientCredentials {
     Password { username: String, password: String },
     Token { token: String },
+    Pin { pin: String },
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -0,0 +1,400 @@
+//! Authenticated key exchange and per-session transport encryption.
+//!
+//! The viewer sends an ephemeral X25519 key and a nonce. The host answers
+//! with its own ephemeral key and nonce, signed with its long-term identity
+//! key, so a man in the middle cannot substitute its own ephemeral key.
+//! HKDF-SHA256 turns the shared secret into one AES-256-GCM key per
+//! direction. Nonces are a key generation plus a message counter, and each
+//! side ratchets its sending key forward after a byte or time budget. The
+//! generation travels in the clear so the receiver can follow.
+
+use anyhow::Result;
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::time::{Duration, Instant};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::identity::{verify_signature, HostIdentity};
+
+const TRANSCRIPT_LABEL: &[u8] = b"anyviewer-kex-v1";
+const VIEWER_TO_HOST_INFO: &[u8] = b"anyviewer viewer->host";
+const HOST_TO_VIEWER_INFO: &[u8] = b"anyviewer host->viewer";
+const REKEY_INFO: &[u8] = b"anyviewer rekey";
+
+/// Generation (u32) + counter (u64) prefix on every sealed payload
+pub const SEALED_HEADER_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyExchangeInit {
+    pub ephemeral_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyExchangeReply {
+    pub ephemeral_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub identity_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: 512 * 1024 * 1024, // 512MB
+            max_age: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// One side's in-progress key exchange
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+    nonce: [u8; 32],
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        let mut nonce = [0u8; 32];
+        OsRng.fill_bytes(&mut nonce);
+
+        Self { secret, public_key, nonce }
+    }
+
+    /// Viewer side: the message that opens the exchange
+    pub fn init_message(&self) -> KeyExchangeInit {
+        KeyExchangeInit {
+            ephemeral_key: self.public_key.as_bytes().to_vec(),
+            nonce: self.nonce.to_vec(),
+        }
+    }
+
+    /// Host side: answer a viewer's init and derive the channel
+    pub fn respond(self, init: &KeyExchangeInit, identity: &HostIdentity) -> Result<(KeyExchangeReply, SecureChannel)> {
+        let viewer_key = parse_public_key(&init.ephemeral_key)?;
+        let viewer_nonce = parse_nonce(&init.nonce)?;
+        let identity_key = identity.public_key();
+
+        let transcript = transcript(
+            viewer_key.as_bytes(),
+            &viewer_nonce,
+            self.public_key.as_bytes(),
+            &self.nonce,
+            &identity_key,
+        );
+
+        let reply = KeyExchangeReply {
+            ephemeral_key: self.public_key.as_bytes().to_vec(),
+            nonce: self.nonce.to_vec(),
+            identity_key: identity_key.to_vec(),
+            signature: identity.sign(&transcript),
+        };
+
+        let host_nonce = self.nonce;
+        let shared = self.secret.diffie_hellman(&viewer_key);
+        let (viewer_to_host, host_to_viewer) = derive_keys(shared.as_bytes(), &viewer_nonce, &host_nonce)?;
+
+        Ok((reply, SecureChannel::new(host_to_viewer, viewer_to_host)))
+    }
+
+    /// Viewer side: check the host's signature and derive the channel
+    pub fn complete(self, reply: &KeyExchangeReply) -> Result<SecureChannel> {
+        let host_key = parse_public_key(&reply.ephemeral_key)?;
+        let host_nonce = parse_nonce(&reply.nonce)?;
+
+        let transcript = transcript(
+            self.public_key.as_bytes(),
+            &self.nonce,
+            host_key.as_bytes(),
+            &host_nonce,
+            &reply.identity_key,
+        );
+        verify_signature(&reply.identity_key, &transcript, &reply.signature)?;
+
+        let viewer_nonce = self.nonce;
+        let shared = self.secret.diffie_hellman(&host_key);
+        let (viewer_to_host, host_to_viewer) = derive_keys(shared.as_bytes(), &viewer_nonce, &host_nonce)?;
+
+        Ok(SecureChannel::new(viewer_to_host, host_to_viewer))
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Both directions of an established session
+pub struct SecureChannel {
+    pub sealer: ChannelSealer,
+    pub opener: ChannelOpener,
+}
+
+impl SecureChannel {
+    fn new(send_key: [u8; 32], receive_key: [u8; 32]) -> Self {
+        Self {
+            sealer: ChannelSealer {
+                state: CipherState::new(send_key),
+                policy: RekeyPolicy::default(),
+            },
+            opener: ChannelOpener {
+                state: CipherState::new(receive_key),
+                last_counter: None,
+            },
+        }
+    }
+
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.sealer.policy = policy;
+        self
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.sealer.seal(plaintext)
+    }
+
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.opener.open(sealed)
+    }
+
+    /// Split so sending and receiving can live in different tasks
+    pub fn into_split(self) -> (ChannelSealer, ChannelOpener) {
+        (self.sealer, self.opener)
+    }
+}
+
+struct CipherState {
+    key: [u8; 32],
+    cipher: Aes256Gcm,
+    generation: u32,
+    counter: u64,
+    bytes: u64,
+    started_at: Instant,
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            key,
+            generation: 0,
+            counter: 0,
+            bytes: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Move to the next key; the old key cannot be recovered from the new one
+    fn ratchet(&mut self) -> Result<()> {
+        let hkdf = Hkdf::<Sha256>::from_prk(&self.key)
+            .map_err(|_| anyhow::anyhow!("Invalid key length for rekey"))?;
+        let mut next_key = [0u8; 32];
+        hkdf.expand(REKEY_INFO, &mut next_key)
+            .map_err(|_| anyhow::anyhow!("Rekey derivation failed"))?;
+
+        let generation = self.generation.checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Key generation exhausted"))?;
+
+        *self = CipherState::new(next_key);
+        self.generation = generation;
+        Ok(())
+    }
+}
+
+pub struct ChannelSealer {
+    state: CipherState,
+    policy: RekeyPolicy,
+}
+
+impl ChannelSealer {
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        if self.state.bytes >= self.policy.max_bytes
+            || self.state.started_at.elapsed() >= self.policy.max_age
+            || self.state.counter == u64::MAX
+        {
+            self.state.ratchet()?;
+            log::debug!("Rekeyed sending channel (generation {})", self.state.generation);
+        }
+
+        let header = sealed_header(self.state.generation, self.state.counter);
+        let ciphertext = self.state.cipher
+            .encrypt(Nonce::from_slice(&header), Payload { msg: plaintext, aad: &header })
+            .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+
+        self.state.counter += 1;
+        self.state.bytes += plaintext.len() as u64;
+
+        let mut sealed = header.to_vec();
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.state.generation
+    }
+}
+
+pub struct ChannelOpener {
+    state: CipherState,
+    last_counter: Option<u64>,
+}
+
+impl ChannelOpener {
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEALED_HEADER_SIZE {
+            return Err(anyhow::anyhow!("Sealed payload too short"));
+        }
+
+        let header = &sealed[..SEALED_HEADER_SIZE];
+        let generation = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let counter = u64::from_be_bytes(header[4..12].try_into().unwrap());
+
+        // Follow the sender's ratchet; generations only ever move forward by one
+        if generation == self.state.generation + 1 {
+            self.state.ratchet()?;
+            self.last_counter = None;
+        } else if generation != self.state.generation {
+            return Err(anyhow::anyhow!(
+                "Unexpected key generation {} (expected {})",
+                generation,
+                self.state.generation
+            ));
+        }
+
+        if let Some(last) = self.last_counter {
+            if counter <= last {
+                return Err(anyhow::anyhow!("Replayed or reordered message (counter {})", counter));
+            }
+        }
+
+        let plaintext = self.state.cipher
+            .decrypt(Nonce::from_slice(header), Payload { msg: &sealed[SEALED_HEADER_SIZE..], aad: header })
+            .map_err(|_| anyhow::anyhow!("Decryption failed"))?;
+
+        self.last_counter = Some(counter);
+        Ok(plaintext)
+    }
+}
+
+fn sealed_header(generation: u32, counter: u64) -> [u8; SEALED_HEADER_SIZE] {
+    let mut header = [0u8; SEALED_HEADER_SIZE];
+    header[..4].copy_from_slice(&generation.to_be_bytes());
+    header[4..].copy_from_slice(&counter.to_be_bytes());
+    header
+}
+
+fn transcript(
+    viewer_key: &[u8],
+    viewer_nonce: &[u8],
+    host_key: &[u8],
+    host_nonce: &[u8],
+    identity_key: &[u8],
+) -> Vec<u8> {
+    [TRANSCRIPT_LABEL, viewer_key, viewer_nonce, host_key, host_nonce, identity_key].concat()
+}
+
+fn derive_keys(shared: &[u8], viewer_nonce: &[u8], host_nonce: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+    let salt = [viewer_nonce, host_nonce].concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared);
+
+    let mut viewer_to_host = [0u8; 32];
+    let mut host_to_viewer = [0u8; 32];
+    hkdf.expand(VIEWER_TO_HOST_INFO, &mut viewer_to_host)
+        .map_err(|_| anyhow::anyhow!("Key derivation failed"))?;
+    hkdf.expand(HOST_TO_VIEWER_INFO, &mut host_to_viewer)
+        .map_err(|_| anyhow::anyhow!("Key derivation failed"))?;
+
+    Ok((viewer_to_host, host_to_viewer))
+}
+
+fn parse_public_key(bytes: &[u8]) -> Result<PublicKey> {
+    let bytes: [u8; 32] = bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid ephemeral key length: {}", bytes.len()))?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn parse_nonce(bytes: &[u8]) -> Result<[u8; 32]> {
+    bytes.try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid handshake nonce length: {}", bytes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> (SecureChannel, SecureChannel) {
+        let identity = HostIdentity::generate();
+        let viewer = KeyExchange::new();
+        let init = viewer.init_message();
+
+        let (reply, host_channel) = KeyExchange::new().respond(&init, &identity).unwrap();
+        let viewer_channel = viewer.complete(&reply).unwrap();
+
+        (viewer_channel, host_channel)
+    }
+
+    #[test]
+    fn test_both_directions() {
+        let (mut viewer, mut host) = handshake();
+
+        let sealed = viewer.seal(b"input event").unwrap();
+        assert_eq!(host.open(&sealed).unwrap(), b"input event");
+
+        let sealed = host.seal(b"screen frame").unwrap();
+        assert_eq!(viewer.open(&sealed).unwrap(), b"screen frame");
+    }
+
+    #[test]
+    fn test_rejects_replay_and_tampering() {
+        let (mut viewer, mut host) = handshake();
+
+        let sealed = viewer.seal(b"hello").unwrap();
+        host.open(&sealed).unwrap();
+        assert!(host.open(&sealed).is_err());
+
+        let mut tampered = viewer.seal(b"hello").unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(host.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_rejects_forged_identity() {
+        let viewer = KeyExchange::new();
+        let init = viewer.init_message();
+        let (mut reply, _) = KeyExchange::new().respond(&init, &HostIdentity::generate()).unwrap();
+
+        // An attacker swapping in their own identity key cannot reuse the signature
+        reply.identity_key = HostIdentity::generate().public_key().to_vec();
+        assert!(viewer.complete(&reply).is_err());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let (viewer, mut host) = handshake();
+        let mut viewer = viewer.with_rekey_policy(RekeyPolicy {
+            max_bytes: 8,
+            max_age: Duration::from_secs(600),
+        });
+
+        for _ in 0..4 {
+            let sealed = viewer.seal(b"0123456789").unwrap();
+            assert_eq!(host.open(&sealed).unwrap(), b"0123456789");
+        }
+
+        assert!(viewer.sealer.generation() > 0);
+    }
+}
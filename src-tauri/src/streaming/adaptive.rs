@@ -0,0 +1,251 @@
+//! Congestion-aware quality control for an outgoing stream.
+//!
+//! The controller walks a ladder of levels, each trading picture quality,
+//! frame rate and resolution for bandwidth. It steps down as soon as the link
+//! shows congestion: sending over the configured budget, round trips growing
+//! past the best seen (frames queueing somewhere), or the viewer acknowledging
+//! less than is sent. It only climbs back after the link has stayed clear for a
+//! while, so a recovering connection doesn't flap between levels.
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use super::StreamingConfig;
+
+/// How often the link is judged; samples in between are pooled
+pub const EVALUATION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Minimum time between two steps down, so the last one can take effect
+const DOWNGRADE_COOLDOWN: Duration = Duration::from_secs(1);
+
+/// How long the link must stay clear before stepping back up
+const UPGRADE_HOLD: Duration = Duration::from_secs(5);
+
+/// Round-trip time on top of the best seen that means frames are queueing
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(150);
+
+/// Share of the sent bytes the viewer must keep acknowledging
+const MIN_ACK_RATIO: f32 = 0.7;
+
+/// Below this send rate acknowledgements are too sparse to compare, in Mbps
+const MIN_COMPARED_RATE: f32 = 0.2;
+
+/// Weight of the newest sample in the smoothed rates and round-trip time
+const SMOOTHING: f32 = 0.3;
+
+/// Quality, frame rate and resolution factors of each level, best first
+const LADDER: [(f32, f32, f32); 6] = [
+    (1.0, 1.0, 1.0),
+    (0.8, 1.0, 1.0),
+    (0.65, 0.66, 1.0),
+    (0.6, 0.5, 0.75),
+    (0.5, 0.33, 0.5),
+    (0.4, 0.2, 0.5),
+];
+
+/// What the stream is encoded at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QualityLevel {
+    pub quality: u8,
+    pub fps: u32,
+    /// Share of the captured resolution that is encoded
+    pub scale: f32,
+}
+
+impl QualityLevel {
+    /// Time between two frames at this level
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_millis(1000 / self.fps.max(1) as u64)
+    }
+}
+
+pub struct AdaptiveController {
+    levels: Vec<QualityLevel>,
+    level: usize,
+    max_bandwidth_mbps: f32,
+    /// Best round trip seen, taken as the link's delay without queueing
+    min_rtt: Option<Duration>,
+    smoothed_rtt: Option<Duration>,
+    send_mbps: Option<f32>,
+    acked_mbps: Option<f32>,
+    /// Acknowledged since the last evaluation
+    acked_bytes: u64,
+    last_evaluation: Instant,
+    last_change: Instant,
+    clear_since: Option<Instant>,
+}
+
+impl AdaptiveController {
+    /// Start at the configured quality and frame rate, at full resolution
+    pub fn new(config: &StreamingConfig) -> Self {
+        let levels = LADDER.iter()
+            .map(|&(quality, fps, scale)| QualityLevel {
+                quality: ((config.quality as f32 * quality) as u8).clamp(10, 100),
+                fps: ((config.target_fps as f32 * fps).round() as u32).max(1),
+                scale,
+            })
+            .collect();
+
+        let now = Instant::now();
+        Self {
+            levels,
+            level: 0,
+            max_bandwidth_mbps: config.max_bandwidth_mbps,
+            min_rtt: None,
+            smoothed_rtt: None,
+            send_mbps: None,
+            acked_mbps: None,
+            acked_bytes: 0,
+            last_evaluation: now,
+            last_change: now,
+            clear_since: None,
+        }
+    }
+
+    pub fn current(&self) -> QualityLevel {
+        self.levels[self.level]
+    }
+
+    /// The viewer acknowledged a frame of `size_bytes`, `rtt` after it was sent
+    pub fn on_ack(&mut self, size_bytes: usize, rtt: Duration) {
+        self.acked_bytes += size_bytes as u64;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed) => smoothed.mul_f32(1.0 - SMOOTHING) + rtt.mul_f32(SMOOTHING),
+            None => rtt,
+        });
+    }
+
+    /// Judge the link given the current send rate, returning the new level if it changed
+    pub fn evaluate(&mut self, send_mbps: f32, now: Instant) -> Option<QualityLevel> {
+        let elapsed = now.saturating_duration_since(self.last_evaluation);
+        if elapsed < EVALUATION_INTERVAL {
+            return None;
+        }
+
+        let acked_mbps = (self.acked_bytes as f32 * 8.0) / (elapsed.as_secs_f32() * 1_000_000.0);
+        self.acked_bytes = 0;
+        self.last_evaluation = now;
+        self.send_mbps = Some(smooth(self.send_mbps, send_mbps));
+        self.acked_mbps = Some(smooth(self.acked_mbps, acked_mbps));
+
+        if let Some(reason) = self.congestion() {
+            self.clear_since = None;
+
+            if self.level + 1 < self.levels.len() && now.saturating_duration_since(self.last_change) >= DOWNGRADE_COOLDOWN {
+                self.level += 1;
+                self.last_change = now;
+                info!("Stepping stream down to {:?}: {}", self.current(), reason);
+                return Some(self.current());
+            }
+
+            return None;
+        }
+
+        let clear_since = *self.clear_since.get_or_insert(now);
+        if self.level > 0
+            && now.saturating_duration_since(clear_since) >= UPGRADE_HOLD
+            && now.saturating_duration_since(self.last_change) >= UPGRADE_HOLD
+        {
+            self.level -= 1;
+            self.last_change = now;
+            // The next step up has to earn its own clear stretch
+            self.clear_since = Some(now);
+            info!("Stepping stream up to {:?}", self.current());
+            return Some(self.current());
+        }
+
+        None
+    }
+
+    fn congestion(&self) -> Option<&'static str> {
+        let send_mbps = self.send_mbps.unwrap_or(0.0);
+
+        if self.max_bandwidth_mbps > 0.0 && send_mbps > self.max_bandwidth_mbps {
+            return Some("sending over the bandwidth limit");
+        }
+
+        // Without acknowledgements only the budget can be checked
+        let (min_rtt, smoothed_rtt) = match (self.min_rtt, self.smoothed_rtt) {
+            (Some(min_rtt), Some(smoothed_rtt)) => (min_rtt, smoothed_rtt),
+            _ => return None,
+        };
+
+        if smoothed_rtt > min_rtt + MAX_QUEUE_DELAY {
+            return Some("round trips are queueing");
+        }
+
+        if send_mbps > MIN_COMPARED_RATE && self.acked_mbps.unwrap_or(0.0) < send_mbps * MIN_ACK_RATIO {
+            return Some("the viewer is falling behind");
+        }
+
+        None
+    }
+}
+
+fn smooth(previous: Option<f32>, sample: f32) -> f32 {
+    match previous {
+        Some(previous) => previous * (1.0 - SMOOTHING) + sample * SMOOTHING,
+        None => sample,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a steady stream of 25 KB frames at 10 fps for `seconds`, acknowledged after `rtt`
+    fn run(controller: &mut AdaptiveController, start: Instant, seconds: u64, rtt: Duration, acked: bool) -> Instant {
+        let mut now = start;
+        for _ in 0..seconds * 10 {
+            now += Duration::from_millis(100);
+            if acked {
+                controller.on_ack(25_000, rtt);
+            }
+            controller.evaluate(2.0, now);
+        }
+        now
+    }
+
+    #[test]
+    fn test_steps_down_under_congestion_and_recovers() {
+        let config = StreamingConfig { target_fps: 30, quality: 80, max_bandwidth_mbps: 10.0, ..Default::default() };
+        let mut controller = AdaptiveController::new(&config);
+        assert_eq!(controller.current(), QualityLevel { quality: 80, fps: 30, scale: 1.0 });
+
+        let now = run(&mut controller, Instant::now(), 5, Duration::from_millis(20), true);
+        assert_eq!(controller.level, 0);
+
+        // Frames start queueing: one step per cooldown, not one per evaluation
+        let now = run(&mut controller, now, 2, Duration::from_millis(900), true);
+        assert!((1..=2).contains(&controller.level));
+        let now = run(&mut controller, now, 10, Duration::from_millis(900), true);
+        let lowest = controller.current();
+        assert!(lowest.fps < 30 && lowest.scale < 1.0 && lowest.quality < 80);
+
+        // Recovery waits for the smoothed round trip to settle and then a clear stretch per step
+        let now = run(&mut controller, now, 4, Duration::from_millis(20), true);
+        assert_eq!(controller.current(), lowest);
+        run(&mut controller, now, 60, Duration::from_millis(20), true);
+        assert_eq!(controller.level, 0);
+    }
+
+    #[test]
+    fn test_stalled_viewer_and_budget() {
+        let mut controller = AdaptiveController::new(&StreamingConfig::default());
+        let now = run(&mut controller, Instant::now(), 2, Duration::from_millis(20), true);
+        assert_eq!(controller.level, 0);
+
+        // Acknowledgements stop arriving while frames keep going out
+        run(&mut controller, now, 3, Duration::ZERO, false);
+        assert!(controller.level > 0);
+
+        // A viewer that never acknowledges is only held to the budget
+        let config = StreamingConfig { max_bandwidth_mbps: 1.0, ..Default::default() };
+        let mut controller = AdaptiveController::new(&config);
+        let now = Instant::now() + EVALUATION_INTERVAL;
+        assert_eq!(controller.evaluate(0.5, now), None);
+        assert!(controller.evaluate(5.0, now + Duration::from_secs(2)).is_some());
+    }
+}
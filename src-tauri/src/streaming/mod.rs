@@ -1,6 +1,8 @@
 pub mod screen_streamer;
 pub mod compression;
 pub mod frame_buffer;
+pub mod adaptive;
+pub mod scaling;
 
 use anyhow::Result;
 use log::{info, error};
@@ -11,16 +13,41 @@ use tokio::time::{interval, Duration, Instant};
 
 pub use screen_streamer::*;
 pub use frame_buffer::*;
+pub use adaptive::{AdaptiveController, QualityLevel};
+pub use scaling::{FrameScaler, StreamGeometry, Viewport};
+
+use crate::capture::{MonitorSelection, ScreenLayout};
+use crate::codec::FrameFormat;
+
+/// Two seconds at the default frame rate
+pub const DEFAULT_KEYFRAME_INTERVAL: u32 = 60;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamingConfig {
     pub target_fps: u32,
     pub quality: u8, // 1-100, higher = better quality
-    pub compression_type: CompressionType,
+    pub compression_type: FrameFormat,
     pub adaptive_quality: bool,
     pub max_bandwidth_mbps: f32,
     pub enable_delta_compression: bool,
     pub buffer_size: usize,
+    /// Frames between keyframes for inter-frame codecs such as H.264
+    #[serde(default = "default_keyframe_interval")]
+    pub keyframe_interval: u32,
+    /// Share of the captured resolution to stream at, up to 1
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    /// Longest side of the streamed frames in pixels, if limited
+    #[serde(default)]
+    pub max_dimension: Option<u32>,
+}
+
+fn default_keyframe_interval() -> u32 {
+    DEFAULT_KEYFRAME_INTERVAL
+}
+
+fn default_scale() -> f32 {
+    1.0
 }
 
 impl Default for StreamingConfig {
@@ -28,24 +55,18 @@ impl Default for StreamingConfig {
         Self {
             target_fps: 30,
             quality: 75,
-            compression_type: CompressionType::JPEG,
+            compression_type: FrameFormat::Jpeg,
             adaptive_quality: true,
             max_bandwidth_mbps: 10.0,
             enable_delta_compression: true,
             buffer_size: 3,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            scale: 1.0,
+            max_dimension: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum CompressionType {
-    JPEG,
-    WebP,
-    H264,
-    VP8,
-    AV1,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamingStats {
     pub fps: f32,
@@ -61,7 +82,8 @@ pub struct StreamingStats {
 #[derive(Debug, Clone)]
 pub enum StreamingEvent {
     FrameReady(Vec<u8>),
-    QualityAdjusted(u8),
+    /// The stream's quality, frame rate or resolution changed, by hand or to follow the link
+    QualityAdjusted(QualityLevel),
     FrameDropped(String), // reason
     Error(String),
     StatUpdate(StreamingStats),
@@ -75,12 +97,17 @@ pub struct StreamingManager {
     event_sender: Arc<RwLock<Option<mpsc::UnboundedSender<StreamingEvent>>>>,
     is_streaming: Arc<RwLock<bool>>,
     start_time: Arc<RwLock<Option<Instant>>>,
+    /// Keeps the stream within `max_bandwidth_mbps` when `adaptive_quality` is on
+    controller: Arc<RwLock<AdaptiveController>>,
 }
 
 impl StreamingManager {
     pub fn new() -> Self {
+        let config = StreamingConfig::default();
+        
         Self {
-            config: Arc::new(RwLock::new(StreamingConfig::default())),
+            controller: Arc::new(RwLock::new(AdaptiveController::new(&config))),
+            config: Arc::new(RwLock::new(config)),
             screen_streamer: Arc::new(RwLock::new(None)),
             frame_buffer: Arc::new(FrameBuffer::new(3)),
             stats: Arc::new(RwLock::new(StreamingStats {
@@ -145,12 +172,10 @@ impl StreamingManager {
         let event_sender = self.event_sender.clone();
         let stats = self.stats.clone();
         let start_time = self.start_time.clone();
+        let controller = self.controller.clone();
         
         tokio::spawn(async move {
-            let mut frame_interval = {
-                let config = config.read().await;
-                interval(Duration::from_millis(1000 / config.target_fps as u64))
-            };
+            let mut frame_interval = interval(controller.read().await.current().frame_interval());
             
             let mut frame_count = 0u64;
             let mut total_frame_size = 0u64;
@@ -161,6 +186,30 @@ impl StreamingManager {
                 
                 let frame_start = Instant::now();
                 
+                if config.read().await.adaptive_quality {
+                    // The buffer only holds the last few frames, so the send rate is measured over their span
+                    let level = controller.read().await.current();
+                    let window = level.frame_interval() * frame_buffer.get_buffer_size().await.max(1) as u32;
+                    let send_mbps = frame_buffer.get_bandwidth_usage(window).await;
+                    
+                    let adjusted = controller.write().await.evaluate(send_mbps, frame_start.into_std());
+                    if let Some(level) = adjusted {
+                        if frame_interval.period() != level.frame_interval() {
+                            frame_interval = interval(level.frame_interval());
+                        }
+                        
+                        if let Some(streamer) = screen_streamer.write().await.as_mut() {
+                            if let Err(e) = streamer.set_level(level).await {
+                                error!("Could not apply stream level: {}", e);
+                            }
+                        }
+                        
+                        if let Some(sender) = event_sender.read().await.as_ref() {
+                            let _ = sender.send(StreamingEvent::QualityAdjusted(level));
+                        }
+                    }
+                }
+                
                 // Capture and compress frame
                 if let Some(streamer) = screen_streamer.read().await.as_ref() {
                     match streamer.capture_and_compress().await {
@@ -269,6 +318,9 @@ impl StreamingManager {
             *config = new_config.clone();
         }
         
+        // The ladder is built around the configured quality and frame rate
+        *self.controller.write().await = AdaptiveController::new(&new_config);
+        
         // Update screen streamer if it exists
         if let Some(streamer) = self.screen_streamer.write().await.as_mut() {
             streamer.update_config(new_config).await?;
@@ -287,19 +339,70 @@ impl StreamingManager {
             config.quality = new_quality;
         }
         
+        let level = QualityLevel {
+            quality: new_quality,
+            ..self.controller.read().await.current()
+        };
+        
         if let Some(streamer) = self.screen_streamer.write().await.as_mut() {
             streamer.set_quality(new_quality).await?;
         }
         
         // Send quality adjustment event
         if let Some(sender) = self.event_sender.read().await.as_ref() {
-            let _ = sender.send(StreamingEvent::QualityAdjusted(new_quality));
+            let _ = sender.send(StreamingEvent::QualityAdjusted(level));
         }
         
         info!("Adjusted streaming quality to {}", new_quality);
         Ok(())
     }
     
+    /// Quality, frame rate and resolution currently streamed at
+    pub async fn current_level(&self) -> QualityLevel {
+        self.controller.read().await.current()
+    }
+    
+    /// Fit the stream into the viewer's window, or stop doing so with `None`
+    pub async fn set_viewport(&self, viewport: Option<Viewport>) -> Result<()> {
+        match self.screen_streamer.write().await.as_mut() {
+            Some(streamer) => {
+                streamer.set_viewport(viewport);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("Streaming is not initialized")),
+        }
+    }
+    
+    /// Where the last streamed frame lies on the desktop, for mapping a viewer's input
+    pub async fn get_stream_geometry(&self) -> Option<StreamGeometry> {
+        match self.screen_streamer.read().await.as_ref() {
+            Some(streamer) => streamer.get_stream_geometry().await,
+            None => None,
+        }
+    }
+    
+    /// Switch the streamed monitor; takes effect from the next frame
+    pub async fn select_monitor(&self, selection: MonitorSelection) -> Result<()> {
+        match self.screen_streamer.read().await.as_ref() {
+            Some(streamer) => streamer.select_monitor(selection).await,
+            None => Err(anyhow::anyhow!("Streaming is not initialized")),
+        }
+    }
+    
+    /// Start the next frame of an inter-coded stream with a keyframe
+    pub async fn request_keyframe(&self) {
+        if let Some(streamer) = self.screen_streamer.read().await.as_ref() {
+            streamer.request_keyframe().await;
+        }
+    }
+    
+    pub async fn get_screen_layout(&self) -> Option<ScreenLayout> {
+        match self.screen_streamer.read().await.as_ref() {
+            Some(streamer) => Some(streamer.get_screen_layout().await),
+            None => None,
+        }
+    }
+    
     pub async fn get_latest_frame(&self) -> Option<Vec<u8>> {
         self.frame_buffer.get_latest_frame().await
     }
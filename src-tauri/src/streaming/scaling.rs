@@ -0,0 +1,222 @@
+//! Streaming below the captured resolution.
+//!
+//! Frames are shrunk to the configured scale, the configured maximum dimension
+//! and the viewer's window, whichever is smallest, keeping their aspect ratio
+//! and never enlarging. Viewers send input in the pixels of the frames they
+//! receive, which `StreamGeometry` maps back onto the host desktop.
+
+use image::{imageops, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use super::StreamingConfig;
+use crate::capture::screen_capture::CaptureRegion;
+
+/// Bicubic keeps text legible at a fraction of the cost of Lanczos on every frame
+const RESAMPLING_FILTER: imageops::FilterType = imageops::FilterType::CatmullRom;
+
+/// Size of the viewer's window onto the stream, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Viewport {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// `1280x720`
+impl FromStr for Viewport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s.split_once(['x', 'X'])
+            .ok_or_else(|| anyhow::anyhow!("Expected WIDTHxHEIGHT, got {:?}", s))?;
+
+        let viewport = Viewport {
+            width: width.trim().parse()?,
+            height: height.trim().parse()?,
+        };
+        if viewport.width == 0 || viewport.height == 0 {
+            return Err(anyhow::anyhow!("Viewport must not be empty"));
+        }
+
+        Ok(viewport)
+    }
+}
+
+/// Limits a captured picture is shrunk to before encoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameScaler {
+    /// Share of the captured resolution, up to 1
+    pub scale: f32,
+    /// Longest side in pixels
+    pub max_dimension: Option<u32>,
+    /// Fit inside the viewer's window
+    pub viewport: Option<Viewport>,
+}
+
+impl FrameScaler {
+    /// Size a `width` x `height` capture is encoded at
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        if width == 0 || height == 0 {
+            return (width, height);
+        }
+
+        let mut factor = self.scale.clamp(0.0, 1.0) as f64;
+
+        if let Some(max_dimension) = self.max_dimension {
+            factor = factor.min(max_dimension as f64 / width.max(height) as f64);
+        }
+
+        if let Some(viewport) = self.viewport {
+            factor = factor
+                .min(viewport.width as f64 / width as f64)
+                .min(viewport.height as f64 / height as f64);
+        }
+
+        if factor >= 1.0 {
+            return (width, height);
+        }
+
+        (
+            ((width as f64 * factor).round() as u32).max(1),
+            ((height as f64 * factor).round() as u32).max(1),
+        )
+    }
+
+    pub fn scale_frame<'a>(&self, image: &'a RgbaImage) -> Cow<'a, RgbaImage> {
+        let (width, height) = self.output_size(image.width(), image.height());
+        if (width, height) == image.dimensions() {
+            return Cow::Borrowed(image);
+        }
+
+        Cow::Owned(imageops::resize(image, width, height, RESAMPLING_FILTER))
+    }
+}
+
+impl Default for FrameScaler {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            max_dimension: None,
+            viewport: None,
+        }
+    }
+}
+
+impl From<&StreamingConfig> for FrameScaler {
+    fn from(config: &StreamingConfig) -> Self {
+        Self {
+            scale: config.scale,
+            max_dimension: config.max_dimension,
+            viewport: None,
+        }
+    }
+}
+
+/// Where the frames a viewer receives lie on the host desktop
+#[derive(Debug, Clone)]
+pub struct StreamGeometry {
+    /// Size of the frames sent
+    pub frame_width: u32,
+    pub frame_height: u32,
+    /// Part of the desktop they show, at full resolution
+    pub region: CaptureRegion,
+}
+
+impl StreamGeometry {
+    /// Frames showing `region` unscaled
+    pub fn unscaled(region: CaptureRegion) -> Self {
+        Self {
+            frame_width: region.width,
+            frame_height: region.height,
+            region,
+        }
+    }
+
+    /// Map a pixel of a received frame to desktop coordinates for input injection
+    pub fn to_desktop(&self, x: i32, y: i32) -> (i32, i32) {
+        let axis = |position: i32, frame: u32, desktop: u32| -> i32 {
+            if frame == 0 || desktop == 0 {
+                return position;
+            }
+
+            // Aim for the middle of the desktop pixels a frame pixel covers
+            let scaled = ((position as f64 + 0.5) * desktop as f64 / frame as f64) as i32;
+            scaled.clamp(0, desktop as i32 - 1)
+        };
+
+        (
+            self.region.x + axis(x, self.frame_width, self.region.width),
+            self.region.y + axis(y, self.frame_height, self.region.height),
+        )
+    }
+
+    /// Map a desktop position into the frames, e.g. to place the pointer; may lie outside them
+    pub fn to_frame(&self, x: i32, y: i32) -> (i32, i32) {
+        let axis = |position: i32, origin: i32, desktop: u32, frame: u32| -> i32 {
+            if desktop == 0 {
+                return position - origin;
+            }
+
+            ((position - origin) as f64 * frame as f64 / desktop as f64).floor() as i32
+        };
+
+        (
+            axis(x, self.region.x, self.region.width, self.frame_width),
+            axis(y, self.region.y, self.region.height, self.frame_height),
+        )
+    }
+
+    /// Whether a desktop position is within the streamed region
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.region.x
+            && y >= self.region.y
+            && ((x - self.region.x) as i64) < self.region.width as i64
+            && ((y - self.region.y) as i64) < self.region.height as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_size() {
+        let scaler = FrameScaler::default();
+        assert_eq!(scaler.output_size(3840, 2160), (3840, 2160));
+
+        // The tightest limit wins and the aspect ratio is kept
+        let scaler = FrameScaler { scale: 0.75, max_dimension: Some(1920), viewport: None };
+        assert_eq!(scaler.output_size(3840, 2160), (1920, 1080));
+        assert_eq!(scaler.output_size(1280, 720), (960, 540));
+
+        let viewport = "1280x1000".parse::<Viewport>().unwrap();
+        let scaler = FrameScaler { viewport: Some(viewport), ..Default::default() };
+        assert_eq!(scaler.output_size(3840, 2160), (1280, 720));
+
+        // A window larger than the desktop doesn't enlarge it
+        let scaler = FrameScaler { viewport: Some(Viewport { width: 5000, height: 5000 }), ..Default::default() };
+        assert_eq!(scaler.output_size(1920, 1080), (1920, 1080));
+
+        assert!("1280".parse::<Viewport>().is_err());
+        assert!("0x720".parse::<Viewport>().is_err());
+    }
+
+    #[test]
+    fn test_input_follows_scaled_frame() {
+        // A 4K monitor right of a 1080p one, streamed at half size
+        let geometry = StreamGeometry {
+            frame_width: 1920,
+            frame_height: 1080,
+            region: CaptureRegion { x: 1920, y: 0, width: 3840, height: 2160 },
+        };
+
+        assert_eq!(geometry.to_desktop(0, 0), (1921, 1));
+        assert_eq!(geometry.to_desktop(960, 540), (1920 + 1921, 1081));
+        assert_eq!(geometry.to_desktop(1919, 1079), (1920 + 3839, 2159));
+        assert_eq!(geometry.to_desktop(5000, -10), (1920 + 3839, 0));
+
+        let unscaled = StreamGeometry::unscaled(CaptureRegion { x: -1280, y: 0, width: 1280, height: 1024 });
+        assert_eq!(unscaled.to_desktop(100, 200), (-1180, 200));
+    }
+}
@@ -1,21 +1,24 @@
 use anyhow::Result;
-use image::{ImageBuffer, RgbaImage};
+use image::ImageBuffer;
 use log::{info, debug};
-use screenshots::Screen;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::Instant;
 
-use super::StreamingConfig;
+use super::{FrameScaler, QualityLevel, StreamGeometry, StreamingConfig, Viewport};
 use super::compression::Compressor;
-use crate::capture::ScreenCaptureManager;
+use crate::capture::{MonitorSelection, ScreenCaptureManager, ScreenLayout};
 
 pub struct ScreenStreamer {
     config: StreamingConfig,
     capture_manager: ScreenCaptureManager,
     compressor: Arc<RwLock<Compressor>>,
-    last_frame: Arc<RwLock<Option<RgbaImage>>>,
     frame_counter: Arc<RwLock<u64>>,
+    /// Share of the resolution the adaptive controller allows, on top of the configured limits
+    scale: f32,
+    viewport: Option<Viewport>,
+    /// Where the last frame lies on the desktop
+    geometry: Arc<RwLock<Option<StreamGeometry>>>,
 }
 
 impl ScreenStreamer {
@@ -29,42 +32,30 @@ impl ScreenStreamer {
             config,
             capture_manager,
             compressor,
-            last_frame: Arc::new(RwLock::new(None)),
             frame_counter: Arc::new(RwLock::new(0)),
+            scale: 1.0,
+            viewport: None,
+            geometry: Arc::new(RwLock::new(None)),
         })
     }
     
     pub async fn capture_and_compress(&self) -> Result<Vec<u8>> {
         let start_time = Instant::now();
         
-        // Capture screen
-        let screen_data = self.capture_manager.capture_primary_screen().await?;
+        // Capture the selected monitor(s), already decoded
+        let image = self.capture_manager.capture_selected().await?;
         let capture_time = start_time.elapsed();
         
-        // Convert to image
-        let image = self.bytes_to_image(screen_data)?;
-        let conversion_time = start_time.elapsed() - capture_time;
-        
-        // Check if we should use delta compression
-        let should_use_delta = self.config.enable_delta_compression;
-        let compressed_data = if should_use_delta {
-            if let Some(last_frame) = self.last_frame.read().await.as_ref() {
-                // Compress only the differences
-                self.compress_frame_delta(&image, last_frame).await?
-            } else {
-                // First frame, compress fully
-                self.compress_frame_full(&image).await?
-            }
-        } else {
-            // Always compress full frame
-            self.compress_frame_full(&image).await?
-        };
-        
-        // Update last frame for delta compression
-        if should_use_delta {
-            let mut last_frame = self.last_frame.write().await;
-            *last_frame = Some(image);
-        }
+        // A new output size makes the encoder start over with a keyframe
+        let image = self.scaler().scale_frame(&image);
+        *self.geometry.write().await = Some(StreamGeometry {
+            frame_width: image.width(),
+            frame_height: image.height(),
+            region: self.capture_manager.get_layout().await.bounds(),
+        });
+        
+        // The encoder decides between keyframes and deltas
+        let compressed_data = self.compressor.read().await.compress_frame(&image)?.data;
         
         // Update frame counter
         {
@@ -74,10 +65,9 @@ impl ScreenStreamer {
         
         let total_time = start_time.elapsed();
         debug!(
-            "Frame processed: capture={}ms, convert={}ms, compress={}ms, total={}ms, size={}KB",
+            "Frame processed: capture={}ms, compress={}ms, total={}ms, size={}KB",
             capture_time.as_millis(),
-            conversion_time.as_millis(),
-            (total_time - conversion_time - capture_time).as_millis(),
+            (total_time - capture_time).as_millis(),
             total_time.as_millis(),
             compressed_data.len() / 1024
         );
@@ -85,28 +75,17 @@ impl ScreenStreamer {
         Ok(compressed_data)
     }
     
-    async fn compress_frame_full(&self, image: &RgbaImage) -> Result<Vec<u8>> {
-        let compressor = self.compressor.read().await;
-        compressor.compress_frame(image).await
+    pub async fn select_monitor(&self, selection: MonitorSelection) -> Result<()> {
+        self.capture_manager.select_monitor(selection).await?;
+        Ok(())
     }
     
-    async fn compress_frame_delta(&self, current: &RgbaImage, previous: &RgbaImage) -> Result<Vec<u8>> {
-        let compressor = self.compressor.read().await;
-        compressor.compress_frame_delta(current, previous).await
+    pub async fn request_keyframe(&self) {
+        self.compressor.read().await.request_keyframe();
     }
     
-    fn bytes_to_image(&self, bytes: Vec<u8>) -> Result<RgbaImage> {
-        // Get primary screen dimensions
-        let screens = Screen::all()?;
-        let primary_screen = screens.first()
-            .ok_or_else(|| anyhow::anyhow!("No screens available"))?;
-        
-        let width = primary_screen.display_info.width as u32;
-        let height = primary_screen.display_info.height as u32;
-        
-        // Convert RGBA bytes to image
-        ImageBuffer::from_raw(width, height, bytes)
-            .ok_or_else(|| anyhow::anyhow!("Failed to create image from bytes"))
+    pub async fn get_screen_layout(&self) -> ScreenLayout {
+        self.capture_manager.get_layout().await
     }
     
     pub async fn update_config(&mut self, new_config: StreamingConfig) -> Result<()> {
@@ -125,11 +104,37 @@ impl ScreenStreamer {
         self.config.quality = quality;
         
         let mut compressor = self.compressor.write().await;
-        compressor.set_quality(quality).await?;
+        compressor.set_quality(quality)?;
         
         Ok(())
     }
     
+    /// Follow the adaptive controller: encode at `level`'s quality and resolution
+    pub async fn set_level(&mut self, level: QualityLevel) -> Result<()> {
+        self.scale = level.scale;
+        self.set_quality(level.quality).await
+    }
+    
+    /// Fit frames into the viewer's window, or stop doing so with `None`
+    pub fn set_viewport(&mut self, viewport: Option<Viewport>) {
+        info!("Streaming into viewport {:?}", viewport);
+        self.viewport = viewport;
+    }
+    
+    pub async fn get_stream_geometry(&self) -> Option<StreamGeometry> {
+        self.geometry.read().await.clone()
+    }
+    
+    fn scaler(&self) -> FrameScaler {
+        let configured = FrameScaler::from(&self.config);
+        
+        FrameScaler {
+            scale: configured.scale * self.scale,
+            viewport: self.viewport,
+            ..configured
+        }
+    }
+    
     pub async fn get_frame_count(&self) -> u64 {
         *self.frame_counter.read().await
     }
@@ -141,8 +146,7 @@ impl ScreenStreamer {
     
     pub async fn capture_region(&self, x: u32, y: u32, width: u32, height: u32) -> Result<Vec<u8>> {
         // Capture full screen first
-        let full_screen = self.capture_manager.capture_primary_screen().await?;
-        let full_image = self.bytes_to_image(full_screen)?;
+        let full_image = self.capture_manager.capture_selected().await?;
         
         // Get screen dimensions
         let screen_width = full_image.width();
@@ -168,9 +172,9 @@ impl ScreenStreamer {
             }
         }
         
-        // Compress region
+        // Compress region on its own, outside the stream
         let compressor = self.compressor.read().await;
-        compressor.compress_frame(&region_image).await
+        Ok(compressor.compress_still(&region_image)?.data)
     }
     
     pub fn get_config(&self) -> &StreamingConfig {
@@ -2,19 +2,22 @@
 // pub mod benchmarks;
 
 use anyhow::Result;
-use log::info;
+use image::RgbaImage;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::time::{Duration, Instant};
 
+use crate::capture::ScreenCaptureManager;
 use crate::network::connection_manager::{ConnectionManager, ConnectionType};
-use crate::streaming::{StreamingManager, StreamingConfig, CompressionType};
+use crate::codec::{self, EncoderSettings, FrameFormat};
+use crate::streaming::{StreamingManager, StreamingConfig, DEFAULT_KEYFRAME_INTERVAL};
 use crate::metrics::MetricsCollector;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PerformanceTestConfig {
     pub test_duration_seconds: u64,
     pub target_fps: u32,
-    pub test_compression_types: Vec<CompressionType>,
+    pub test_compression_types: Vec<FrameFormat>,
     pub test_quality_levels: Vec<u8>,
     pub connection_timeout_seconds: u64,
     pub benchmark_iterations: u32,
@@ -26,8 +29,8 @@ impl Default for PerformanceTestConfig {
             test_duration_seconds: 30,
             target_fps: 30,
             test_compression_types: vec![
-                CompressionType::JPEG,
-                CompressionType::WebP,
+                FrameFormat::Jpeg,
+                FrameFormat::WebP,
             ],
             test_quality_levels: vec![50, 75, 90],
             connection_timeout_seconds: 10,
@@ -62,7 +65,7 @@ pub enum TestType {
 pub struct TestConfiguration {
     pub fps: u32,
     pub quality: u8,
-    pub compression_type: CompressionType,
+    pub compression_type: FrameFormat,
     pub bandwidth_limit_mbps: Option<f32>,
     pub artificial_latency_ms: Option<u32>,
 }
@@ -189,11 +192,13 @@ impl PerformanceTester {
         let streaming_config = StreamingConfig {
             target_fps: self.config.target_fps,
             quality: 75,
-            compression_type: CompressionType::JPEG,
+            compression_type: FrameFormat::Jpeg,
             adaptive_quality: false,
             max_bandwidth_mbps: 50.0,
             enable_delta_compression: true,
             buffer_size: 3,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            ..Default::default()
         };
         
         self.streaming_manager.update_config(streaming_config.clone()).await?;
@@ -269,7 +274,7 @@ impl PerformanceTester {
             config_used: TestConfiguration {
                 fps: self.config.target_fps,
                 quality: 75,
-                compression_type: CompressionType::JPEG,
+                compression_type: FrameFormat::Jpeg,
                 bandwidth_limit_mbps: None,
                 artificial_latency_ms: None,
             },
@@ -284,10 +289,17 @@ impl PerformanceTester {
         let mut results = Vec::new();
         let compression_types = self.config.test_compression_types.clone();
         
+        let available = codec::available_formats();
+        
         for compression_type in compression_types {
+            if !available.contains(&compression_type) {
+                warn!("No {} codec in this build, skipping it", compression_type);
+                continue;
+            }
+            
             info!("Testing compression algorithm: {:?}", compression_type);
             
-            let result = self.run_compression_test(compression_type.clone()).await?;
+            let result = self.run_compression_test(compression_type).await?;
             results.push(result);
         }
         
@@ -297,60 +309,81 @@ impl PerformanceTester {
         Ok(results)
     }
     
-    async fn run_compression_test(&mut self, compression_type: CompressionType) -> Result<PerformanceTestResult> {
+    /// Encode and decode the same frame repeatedly and measure size, speed and fidelity
+    async fn run_compression_test(&mut self, compression_type: FrameFormat) -> Result<PerformanceTestResult> {
         let test_id = uuid::Uuid::new_v4().to_string();
         let started_at = chrono::Utc::now();
         
-        // Configure streaming with specific compression
-        let streaming_config = StreamingConfig {
-            target_fps: self.config.target_fps,
+        let mut encoder = codec::create_encoder(compression_type, &EncoderSettings {
             quality: 75,
-            compression_type: compression_type.clone(),
-            adaptive_quality: false,
-            max_bandwidth_mbps: 50.0,
-            enable_delta_compression: true,
-            buffer_size: 3,
-        };
-        
-        self.streaming_manager.update_config(streaming_config.clone()).await?;
-        self.streaming_manager.start_streaming().await?;
-        
-        // Run test
-        let test_duration = Duration::from_secs(15); // Shorter test for compression comparison
-        tokio::time::sleep(test_duration).await;
-        
-        self.streaming_manager.stop_streaming().await?;
+            fps: self.config.target_fps.max(1),
+            bitrate_bps: 50_000_000,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            delta: false,
+        })?;
+        // Inter-frame codecs decode against the previous frame, so keep one decoder for the run
+        let mut decoder = codec::create_decoder(compression_type)?;
+        
+        let frame = benchmark_frame().await;
+        let raw_size = frame.as_raw().len() as f64;
+        let iterations = self.config.benchmark_iterations.max(1);
+        
+        let mut encode_time = Duration::ZERO;
+        let mut decode_time = Duration::ZERO;
+        let mut total_size = 0usize;
+        let mut psnr_total = 0.0;
+        
+        for _ in 0..iterations {
+            let start = Instant::now();
+            let encoded = encoder.encode(&frame)?;
+            encode_time += start.elapsed();
+            
+            let start = Instant::now();
+            let decoded = decoder.decode(&encoded.data, encoded.is_keyframe)?;
+            decode_time += start.elapsed();
+            
+            total_size += encoded.data.len();
+            psnr_total += psnr(&frame, &decoded);
+        }
         
         let completed_at = chrono::Utc::now();
         let duration_seconds = (completed_at - started_at).num_milliseconds() as f64 / 1000.0;
         
-        // Get streaming stats
-        let streaming_stats = self.streaming_manager.get_stats().await;
-        
-        // Simulate compression-specific results
-        let (efficiency, cpu_usage, quality_score) = match compression_type {
-            CompressionType::JPEG => (0.70, 20.0, 80.0),
-            CompressionType::WebP => (0.80, 35.0, 85.0),
-            CompressionType::H264 => (0.85, 45.0, 90.0),
-            CompressionType::VP8 => (0.82, 40.0, 87.0),
-            CompressionType::AV1 => (0.90, 60.0, 92.0),
-        };
+        let average_size = total_size as f64 / iterations as f64;
+        let encode_ms = encode_time.as_secs_f64() * 1000.0 / iterations as f64;
+        let decode_ms = decode_time.as_secs_f64() * 1000.0 / iterations as f64;
+        let frame_budget_ms = 1000.0 / self.config.target_fps.max(1) as f64;
+        let average_psnr = psnr_total / iterations as f64;
         
         let results = TestResults {
-            average_fps: streaming_stats.fps,
-            average_latency_ms: streaming_stats.latency_ms,
-            average_bandwidth_mbps: streaming_stats.bandwidth_mbps,
-            packet_loss_percent: 0.2,
-            frame_drops: (streaming_stats.total_frames as f32 * 0.01) as u32,
-            total_frames: streaming_stats.total_frames as u32,
-            quality_score,
-            stability_score: 88.0,
-            connection_reliability: 92.0,
-            cpu_usage_percent: cpu_usage,
-            memory_usage_mb: 180.0,
-            compression_efficiency: efficiency,
+            // How many frames per second the encoder could keep up with
+            average_fps: (1000.0 / encode_ms.max(0.001)) as f32,
+            average_latency_ms: (encode_ms + decode_ms) as f32,
+            average_bandwidth_mbps: (average_size * 8.0 * self.config.target_fps as f64 / 1_000_000.0) as f32,
+            // No network is involved in this test
+            packet_loss_percent: 0.0,
+            frame_drops: 0,
+            total_frames: iterations,
+            // PSNR of 50 dB or more (and lossless) is treated as perfect
+            quality_score: (average_psnr / 50.0 * 100.0).min(100.0) as f32,
+            stability_score: 100.0,
+            connection_reliability: 100.0,
+            // Share of one core's frame budget spent encoding at the target frame rate
+            cpu_usage_percent: (encode_ms / frame_budget_ms * 100.0).min(100.0) as f32,
+            memory_usage_mb: 0.0,
+            compression_efficiency: (1.0 - average_size / raw_size) as f32,
         };
         
+        info!(
+            "{:?}: {:.0} KB per frame ({:.1}x), encode {:.1}ms, decode {:.1}ms, PSNR {:.1} dB",
+            compression_type,
+            average_size / 1024.0,
+            raw_size / average_size,
+            encode_ms,
+            decode_ms,
+            average_psnr
+        );
+        
         Ok(PerformanceTestResult {
             test_id,
             test_type: TestType::CompressionComparison,
@@ -391,11 +424,13 @@ impl PerformanceTester {
         let streaming_config = StreamingConfig {
             target_fps: self.config.target_fps,
             quality,
-            compression_type: CompressionType::JPEG,
+            compression_type: FrameFormat::Jpeg,
             adaptive_quality: false,
             max_bandwidth_mbps: 50.0,
             enable_delta_compression: true,
             buffer_size: 3,
+            keyframe_interval: DEFAULT_KEYFRAME_INTERVAL,
+            ..Default::default()
         };
         
         self.streaming_manager.update_config(streaming_config.clone()).await?;
@@ -436,7 +471,7 @@ impl PerformanceTester {
             config_used: TestConfiguration {
                 fps: self.config.target_fps,
                 quality,
-                compression_type: CompressionType::JPEG,
+                compression_type: FrameFormat::Jpeg,
                 bandwidth_limit_mbps: None,
                 artificial_latency_ms: None,
             },
@@ -483,7 +518,7 @@ impl PerformanceTester {
             config_used: TestConfiguration {
                 fps: self.config.target_fps,
                 quality: 75,
-                compression_type: CompressionType::JPEG,
+                compression_type: FrameFormat::Jpeg,
                 bandwidth_limit_mbps: None,
                 artificial_latency_ms: None,
             },
@@ -576,4 +611,66 @@ impl PerformanceTester {
         
         Ok(report)
     }
+}
+
+/// A capture of the host screen when there is one, else a synthetic text-heavy desktop
+async fn benchmark_frame() -> RgbaImage {
+    match ScreenCaptureManager::new() {
+        Ok(capture) => match capture.capture_selected().await {
+            Ok(frame) => return frame,
+            Err(e) => warn!("Screen capture failed, benchmarking a synthetic frame: {}", e),
+        },
+        Err(e) => warn!("No screen to capture, benchmarking a synthetic frame: {}", e),
+    }
+    
+    synthetic_desktop(1280, 720)
+}
+
+/// Window chrome and rows of glyph-like strokes, the content WebP and JPEG differ most on
+fn synthetic_desktop(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        if y < 28 {
+            return image::Rgba([45, 45, 48, 255]);
+        }
+        if x < 220 {
+            return image::Rgba([243, 243, 243, 255]);
+        }
+        
+        // 8x16 character cells with a deterministic pseudo-random glyph in each
+        let (column, row) = (x / 8, (y - 28) / 16);
+        let (cell_x, cell_y) = (x % 8, (y - 28) % 16);
+        let glyph = column.wrapping_mul(2_654_435_761).wrapping_add(row.wrapping_mul(40_503)) >> 7;
+        let inked = (3..13).contains(&cell_y)
+            && cell_x < 6
+            && glyph % 5 != 0
+            && (glyph >> (cell_x + (cell_y % 4) * 6)) & 1 == 1;
+        
+        if inked {
+            image::Rgba([30, 30, 30, 255])
+        } else {
+            image::Rgba([255, 255, 255, 255])
+        }
+    })
+}
+
+/// Peak signal-to-noise ratio over RGB in dB; infinite for identical images
+fn psnr(original: &RgbaImage, decoded: &RgbaImage) -> f64 {
+    if original.dimensions() != decoded.dimensions() {
+        return 0.0;
+    }
+    
+    let mut squared_error = 0.0;
+    for (a, b) in original.pixels().zip(decoded.pixels()) {
+        for channel in 0..3 {
+            let diff = a[channel] as f64 - b[channel] as f64;
+            squared_error += diff * diff;
+        }
+    }
+    
+    let mse = squared_error / (original.width() as f64 * original.height() as f64 * 3.0);
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
 }
\ No newline at end of file
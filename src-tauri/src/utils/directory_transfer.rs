@@ -0,0 +1,275 @@
+//! Manifests for sending a directory: what it holds, with the metadata to restore,
+//! under paths a receiver can check before anything is written.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use super::sanitize_filename;
+
+/// Entries a receiver accepts in one manifest
+pub const MAX_MANIFEST_ENTRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// What a sender does with the symbolic links in a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymlinkPolicy {
+    /// Leave them out
+    Skip,
+    /// Send what they point to
+    Follow,
+    /// Send the links themselves; a receiver only makes ones that stay inside the directory
+    Preserve,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Relative to the directory, `/` separated
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Unix permission bits, where the sender has them
+    pub mode: Option<u32>,
+    /// Seconds since the Unix epoch
+    pub modified: Option<u64>,
+    /// Links only: where the link points, as the sender read it
+    pub target: Option<String>,
+}
+
+/// Everything in a directory, each directory listed before what it holds
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectoryManifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl DirectoryManifest {
+    pub fn build(root: &Path, symlinks: SymlinkPolicy) -> Result<Self> {
+        let mut manifest = Self::default();
+        let mut ancestors = HashSet::from([root.canonicalize()?]);
+        manifest.walk(root, "", symlinks, &mut ancestors)?;
+        Ok(manifest)
+    }
+
+    fn walk(&mut self, directory: &Path, prefix: &str, symlinks: SymlinkPolicy, ancestors: &mut HashSet<PathBuf>) -> Result<()> {
+        let mut entries = std::fs::read_dir(directory)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            let file_path = entry.path();
+
+            let mut metadata = std::fs::symlink_metadata(&file_path)?;
+            if metadata.file_type().is_symlink() {
+                match symlinks {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Preserve => {
+                        let target = std::fs::read_link(&file_path)?;
+                        self.entries.push(ManifestEntry {
+                            path,
+                            kind: EntryKind::Symlink,
+                            size: 0,
+                            mode: None,
+                            modified: None,
+                            target: Some(target.to_string_lossy().to_string()),
+                        });
+                        continue;
+                    }
+                    SymlinkPolicy::Follow => match std::fs::metadata(&file_path) {
+                        Ok(target) => metadata = target,
+                        // A dangling link has nothing to send
+                        Err(_) => continue,
+                    },
+                }
+            }
+
+            if metadata.is_dir() {
+                // A followed link back up the tree would never end
+                let canonical = file_path.canonicalize()?;
+                if ancestors.contains(&canonical) {
+                    continue;
+                }
+                self.entries.push(entry_for(path.clone(), EntryKind::Directory, &metadata));
+
+                ancestors.insert(canonical.clone());
+                self.walk(&file_path, &path, symlinks, ancestors)?;
+                ancestors.remove(&canonical);
+            } else if metadata.is_file() {
+                self.entries.push(entry_for(path, EntryKind::File, &metadata));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.iter().filter(|entry| entry.kind == EntryKind::File)
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files().count()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.files().map(|entry| entry.size).sum()
+    }
+}
+
+fn entry_for(path: String, kind: EntryKind, metadata: &std::fs::Metadata) -> ManifestEntry {
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode())
+    };
+    #[cfg(not(unix))]
+    let mode = metadata.permissions().readonly().then_some(0o444);
+
+    ManifestEntry {
+        path,
+        kind,
+        size: if kind == EntryKind::File { metadata.len() } else { 0 },
+        mode,
+        modified: metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs()),
+        target: None,
+    }
+}
+
+/// Where a manifest path goes under the receiving directory, each part through `sanitize_filename`;
+/// `None` for anything but a plain relative path
+pub fn sanitize_relative_path(path: &str) -> Option<PathBuf> {
+    let mut sanitized = PathBuf::new();
+    for part in path.split('/') {
+        let part = sanitize_filename(part);
+        if part.is_empty() || part == "." || part == ".." {
+            return None;
+        }
+        sanitized.push(part);
+    }
+    Some(sanitized)
+}
+
+/// Whether a link target only leads down from the link's directory, so it can't point outside the transfer
+pub fn is_contained_target(target: &str) -> bool {
+    let target = Path::new(target);
+    !target.as_os_str().is_empty() && target.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Make a link from a manifest at `path`, if its target is contained and the platform has links
+pub fn create_symlink(path: &Path, entry: &ManifestEntry) -> Result<()> {
+    let target = entry.target.as_deref()
+        .filter(|target| is_contained_target(target))
+        .ok_or_else(|| anyhow::anyhow!("Link {} points outside the directory", entry.path))?;
+
+    make_link(target, path)
+}
+
+#[cfg(unix)]
+fn make_link(target: &str, path: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(target, path)?)
+}
+
+#[cfg(not(unix))]
+fn make_link(target: &str, path: &Path) -> Result<()> {
+    Err(anyhow::anyhow!("Not making link {} to {}: links are only made on Unix", path.display(), target))
+}
+
+/// Give a received file or directory the sender's modification time, then its permissions
+pub fn apply_metadata(path: &Path, entry: &ManifestEntry) -> Result<()> {
+    if let Some(modified) = entry.modified {
+        let file = match entry.kind {
+            EntryKind::Directory => File::open(path)?,
+            _ => File::options().write(true).open(path)?,
+        };
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+    }
+
+    match entry.mode {
+        Some(mode) => set_mode(path, mode),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Only the permission bits; a peer doesn't get to set setuid and the like
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+    Ok(())
+}
+
+/// Elsewhere only read-only carries over
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    if mode & 0o200 == 0 {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_manifest_paths_are_sanitised() {
+        assert_eq!(sanitize_relative_path("photos/2024/beach day.jpg"), Some(PathBuf::from("photos/2024/beach_day.jpg")));
+        assert_eq!(sanitize_relative_path("..."), Some(PathBuf::from("...")));
+        assert_eq!(sanitize_relative_path("../outside"), None);
+        assert_eq!(sanitize_relative_path("photos/../../outside"), None);
+        assert_eq!(sanitize_relative_path("/etc/passwd"), None);
+        assert_eq!(sanitize_relative_path("photos//x"), None);
+        assert_eq!(sanitize_relative_path(""), None);
+        assert_eq!(sanitize_relative_path("..\\..\\windows"), Some(PathBuf::from(".._.._windows")));
+
+        assert!(is_contained_target("lib/current"));
+        assert!(!is_contained_target("../lib"));
+        assert!(!is_contained_target("/etc"));
+        assert!(!is_contained_target("./lib"));
+    }
+
+    #[test]
+    fn test_manifest_lists_directories_before_their_files() {
+        let root = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("docs/empty")).unwrap();
+        std::fs::write(root.join("docs/readme.txt"), b"hello").unwrap();
+        std::fs::write(root.join("a.bin"), [0u8; 10]).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("docs", root.join("link")).unwrap();
+
+        let manifest = DirectoryManifest::build(&root, SymlinkPolicy::Skip).unwrap();
+        let paths: Vec<&str> = manifest.entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.bin", "docs", "docs/empty", "docs/readme.txt"]);
+        assert_eq!(manifest.file_count(), 2);
+        assert_eq!(manifest.total_bytes(), 15);
+
+        #[cfg(unix)]
+        {
+            let preserved = DirectoryManifest::build(&root, SymlinkPolicy::Preserve).unwrap();
+            assert_eq!(preserved.get("link").and_then(|entry| entry.target.as_deref()), Some("docs"));
+
+            let followed = DirectoryManifest::build(&root, SymlinkPolicy::Follow).unwrap();
+            assert_eq!(followed.get("link/readme.txt").map(|entry| entry.size), Some(5));
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
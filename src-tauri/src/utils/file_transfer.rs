@@ -1,16 +1,25 @@
 use anyhow::Result;
 use log::{info, debug, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::fs;
+use tokio::io::AsyncReadExt;
 use tokio::sync::{mpsc, RwLock, Semaphore};
 use uuid::Uuid;
 
+use super::directory_transfer::{
+    apply_metadata, create_symlink, sanitize_relative_path, DirectoryManifest, EntryKind, SymlinkPolicy, MAX_MANIFEST_ENTRIES,
+};
+use super::sanitize_filename;
+use super::throttle::TokenBucket;
+use super::transfer_state::{chunk_count, range_bytes, ChunkBitmap, ChunkRange, PartialDownload};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileTransferRequest {
     pub transfer_id: String,
@@ -20,6 +29,19 @@ pub struct FileTransferRequest {
     pub compression: CompressionType,
     pub encryption_enabled: bool,
     pub checksum: Option<String>,
+    /// Set for a file of a directory transfer, with where the manifest puts it
+    #[serde(default)]
+    pub directory_id: Option<String>,
+    #[serde(default)]
+    pub relative_path: Option<String>,
+}
+
+/// Offer of a directory; once it is accepted each of its files is offered in turn
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryTransferRequest {
+    pub directory_id: String,
+    pub name: String,
+    pub manifest: DirectoryManifest,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +50,9 @@ pub struct FileTransferResponse {
     pub accepted: bool,
     pub reason: Option<String>,
     pub suggested_path: Option<String>,
+    /// Chunks still needed when an earlier partial download is picked up; every chunk when `None`
+    #[serde(default)]
+    pub missing_chunks: Option<Vec<ChunkRange>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,10 +60,135 @@ pub struct FileChunk {
     pub transfer_id: String,
     pub chunk_index: u64,
     pub data: Vec<u8>,
+    /// Whether `data` is packed with the offer's compression
     pub is_compressed: bool,
+    /// SHA-256 of the chunk as it is in the file, before compression
     pub checksum: String,
 }
 
+/// Receiver's acknowledgement of a written chunk; the sender keeps `CHUNK_WINDOW` unacknowledged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkAck {
+    pub transfer_id: String,
+    pub chunk_index: u64,
+    /// The chunk failed its checksum and nothing was written; the sender sends it again
+    #[serde(default)]
+    pub corrupt: bool,
+}
+
+/// Receiver's answer once every chunk is written and the file checked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransferComplete {
+    pub transfer_id: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransferCancel {
+    pub transfer_id: String,
+    pub reason: Option<String>,
+}
+
+/// Either side stopping a transfer for now; the sender stops once its unacknowledged chunks are out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransferPause {
+    pub transfer_id: String,
+}
+
+/// Picking a paused transfer up again. The receiver always names the chunks it still needs;
+/// a sender resuming asks for that list by leaving it out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransferResume {
+    pub transfer_id: String,
+    pub missing_chunks: Option<Vec<ChunkRange>>,
+}
+
+/// What peers exchange for a transfer, whatever session carries it
+#[derive(Debug, Clone)]
+pub enum TransferMessage {
+    Offer(FileTransferRequest),
+    DirectoryOffer(DirectoryTransferRequest),
+    Response(FileTransferResponse),
+    Chunk(FileChunk),
+    Ack(ChunkAck),
+    Complete(FileTransferComplete),
+    Cancel(FileTransferCancel),
+    Pause(FileTransferPause),
+    Resume(FileTransferResume),
+}
+
+impl TransferMessage {
+    pub fn transfer_id(&self) -> &str {
+        match self {
+            TransferMessage::Offer(request) => &request.transfer_id,
+            TransferMessage::DirectoryOffer(request) => &request.directory_id,
+            TransferMessage::Response(response) => &response.transfer_id,
+            TransferMessage::Chunk(chunk) => &chunk.transfer_id,
+            TransferMessage::Ack(ack) => &ack.transfer_id,
+            TransferMessage::Complete(complete) => &complete.transfer_id,
+            TransferMessage::Cancel(cancel) => &cancel.transfer_id,
+            TransferMessage::Pause(pause) => &pause.transfer_id,
+            TransferMessage::Resume(resume) => &resume.transfer_id,
+        }
+    }
+    
+    /// Name of the file or directory, for offers, the messages that start a transfer
+    pub fn offered_name(&self) -> Option<&str> {
+        match self {
+            TransferMessage::Offer(request) => Some(&request.file_name),
+            TransferMessage::DirectoryOffer(request) => Some(&request.name),
+            _ => None,
+        }
+    }
+    
+    pub fn cancel(transfer_id: &str, reason: impl Into<String>) -> Self {
+        TransferMessage::Cancel(FileTransferCancel {
+            transfer_id: transfer_id.to_string(),
+            reason: Some(reason.into()),
+        })
+    }
+    
+    /// Refusal of an offer
+    pub fn decline(transfer_id: &str, reason: impl Into<String>) -> Self {
+        TransferMessage::Response(FileTransferResponse {
+            transfer_id: transfer_id.to_string(),
+            accepted: false,
+            reason: Some(reason.into()),
+            suggested_path: None,
+            missing_chunks: None,
+        })
+    }
+}
+
+/// Chunks sent ahead of the receiver's acknowledgements, so a transfer can't flood the session
+pub const CHUNK_WINDOW: usize = 8;
+
+/// Chunks written between saves of a download's state; a crash costs at most this many again
+const STATE_SAVE_INTERVAL: u64 = 64;
+
+/// Times one chunk may arrive corrupt before the download gives up
+const MAX_CHUNK_RETRIES: u32 = 3;
+
+/// Chunk sizes an offer may use; the receiver keeps a bit per chunk, so tiny chunks of a large file cost real memory
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Formats that are compressed already, where packing chunks again only costs time
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "7z", "rar", "zst", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "m4a", "ogg", "opus", "flac",
+    "mp4", "m4v", "mkv", "mov", "avi", "webm",
+    "docx", "xlsx", "pptx", "odt", "jar", "apk", "pdf",
+];
+
+/// Text formats, where gzip's better ratio is worth its speed
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "log", "csv", "tsv", "json", "xml", "html", "htm", "css", "js", "ts",
+    "md", "yaml", "yml", "toml", "ini", "sql", "rs", "py", "c", "h", "cpp", "java", "go", "sh",
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileTransferProgress {
     pub transfer_id: String,
@@ -49,14 +199,52 @@ pub struct FileTransferProgress {
     pub status: TransferStatus,
 }
 
+/// A directory's progress over all its files
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryProgress {
+    pub directory_id: String,
+    pub files_done: usize,
+    pub file_count: usize,
+    pub bytes_transferred: u64,
+    pub total_bytes: u64,
+    pub status: TransferStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompressionType {
     None,
     Gzip,
     Lz4,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl CompressionType {
+    /// Gzip for text, nothing for formats that are compressed already, LZ4 for the rest
+    pub fn for_file(file_path: &Path) -> Self {
+        let extension = file_path.extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        
+        if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+            CompressionType::None
+        } else if TEXT_EXTENSIONS.contains(&extension.as_str()) {
+            CompressionType::Gzip
+        } else {
+            CompressionType::Lz4
+        }
+    }
+}
+
+/// What became of a chunk from the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkReceipt {
+    Written,
+    /// Written, and it was the last one missing
+    Complete,
+    /// Failed its checksum or didn't unpack; nothing was written
+    Corrupt,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TransferStatus {
     Pending,
     Transferring,
@@ -70,12 +258,14 @@ pub enum TransferStatus {
 pub struct TransferConfig {
     pub max_concurrent_transfers: usize,
     pub chunk_size: usize,
-    pub max_speed_bps: Option<u64>, // Rate limiting
+    pub max_speed_bps: Option<u64>, // Rate limiting, shared by all uploads
     pub enable_compression: bool,
     pub compression_level: u32,
     pub max_file_size: u64,
     pub allowed_extensions: Option<Vec<String>>,
     pub download_directory: PathBuf,
+    /// How links in a directory being sent are handled
+    pub symlink_policy: SymlinkPolicy,
 }
 
 impl Default for TransferConfig {
@@ -89,6 +279,7 @@ impl Default for TransferConfig {
             max_file_size: 10 * 1024 * 1024 * 1024, // 10GB
             allowed_extensions: None, // Allow all extensions
             download_directory: dirs::download_dir().unwrap_or_else(|| PathBuf::from(".")),
+            symlink_policy: SymlinkPolicy::Skip,
         }
     }
 }
@@ -96,7 +287,10 @@ impl Default for TransferConfig {
 pub struct FileTransferManager {
     config: Arc<RwLock<TransferConfig>>,
     active_transfers: Arc<RwLock<HashMap<String, TransferSession>>>,
+    directories: Arc<RwLock<HashMap<String, DirectoryTransfer>>>,
     transfer_semaphore: Arc<Semaphore>,
+    /// Paces the chunks sessions send, at `max_speed_bps`
+    throttle: TokenBucket,
     event_sender: mpsc::UnboundedSender<TransferEvent>,
 }
 
@@ -105,21 +299,73 @@ struct TransferSession {
     pub id: String,
     pub file_path: PathBuf,
     pub file_size: u64,
+    /// As offered; chunk offsets depend on it, not on the size of each chunk's data
+    pub chunk_size: usize,
+    pub checksum: Option<String>,
+    pub compression: CompressionType,
+    /// Uploads only: chunks still to send and how many are unacknowledged
+    pub pending: VecDeque<ChunkRange>,
+    pub in_flight: usize,
+    /// Downloads only: chunks in the `.part` file, and how many arrived since its state was saved
+    pub received: Option<ChunkBitmap>,
+    pub unsaved_chunks: u64,
+    /// Downloads only: chunks that arrived corrupt, by how many times
+    pub retries: HashMap<u64, u32>,
     pub bytes_transferred: u64,
     pub start_time: Instant,
     pub last_chunk_time: Instant,
     pub status: TransferStatus,
     pub is_upload: bool,
     pub speed_samples: Vec<(Instant, u64)>,
+    /// Files of a directory transfer only: the directory and the file's path in its manifest
+    pub directory_id: Option<String>,
+    pub relative_path: Option<String>,
 }
 
+/// A directory being sent or received, one file at a time
 #[derive(Debug, Clone)]
+struct DirectoryTransfer {
+    pub id: String,
+    /// The directory being sent, or the one made for it on receipt
+    pub root: PathBuf,
+    pub manifest: DirectoryManifest,
+    pub is_upload: bool,
+    /// Uploads only: where in the manifest to look for the next file to offer
+    pub next_entry: usize,
+    /// The file transfer under way
+    pub current: Option<String>,
+    pub files_done: usize,
+    pub bytes_done: u64,
+    pub status: TransferStatus,
+}
+
+impl DirectoryTransfer {
+    fn is_running(&self) -> bool {
+        matches!(self.status, TransferStatus::Pending | TransferStatus::Transferring)
+    }
+    
+    fn progress(&self, current_bytes: u64) -> DirectoryProgress {
+        DirectoryProgress {
+            directory_id: self.id.clone(),
+            files_done: self.files_done,
+            file_count: self.manifest.file_count(),
+            bytes_transferred: self.bytes_done + current_bytes,
+            total_bytes: self.manifest.total_bytes(),
+            status: self.status.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub enum TransferEvent {
     TransferStarted(String, String), // transfer_id, file_name
     ProgressUpdate(FileTransferProgress),
     TransferCompleted(String),
     TransferFailed(String, String), // transfer_id, error
     TransferCancelled(String),
+    TransferPaused(String),
+    TransferResumed(String),
+    DirectoryProgress(DirectoryProgress),
 }
 
 impl FileTransferManager {
@@ -130,15 +376,72 @@ impl FileTransferManager {
         let manager = Self {
             config: Arc::new(RwLock::new(config.clone())),
             active_transfers: Arc::new(RwLock::new(HashMap::new())),
+            directories: Arc::new(RwLock::new(HashMap::new())),
             transfer_semaphore: Arc::new(Semaphore::new(config.max_concurrent_transfers)),
+            throttle: TokenBucket::new(config.max_speed_bps),
             event_sender,
         };
         
         (manager, event_receiver)
     }
     
-    /// Start sending a file
-    pub async fn send_file(&self, file_path: &Path) -> Result<String> {
+    /// Start sending a file, returning the offer for the peer
+    pub async fn send_file(&self, file_path: &Path) -> Result<FileTransferRequest> {
+        self.offer_file(file_path, None).await
+    }
+    
+    /// Start sending a directory and everything in it, returning the offer for the peer
+    pub async fn send_directory(&self, directory_path: &Path) -> Result<DirectoryTransferRequest> {
+        let metadata = fs::metadata(directory_path).await
+            .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", directory_path.display(), e))?;
+        if !metadata.is_dir() {
+            return Err(anyhow::anyhow!("Path is not a directory: {}", directory_path.display()));
+        }
+        
+        let name = directory_path.file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid directory name"))?
+            .to_string_lossy()
+            .to_string();
+        
+        let config = self.config.read().await.clone();
+        let root = directory_path.to_path_buf();
+        let symlink_policy = config.symlink_policy;
+        let manifest = tokio::task::spawn_blocking(move || DirectoryManifest::build(&root, symlink_policy)).await??;
+        
+        // Checked up front, so the directory doesn't stop half way
+        for entry in manifest.files() {
+            Self::check_file(&config, Path::new(&entry.path), entry.size)?;
+        }
+        
+        let directory_id = Uuid::new_v4().to_string();
+        let request = DirectoryTransferRequest {
+            directory_id: directory_id.clone(),
+            name: name.clone(),
+            manifest: manifest.clone(),
+        };
+        
+        self.directories.write().await.insert(directory_id.clone(), DirectoryTransfer {
+            id: directory_id.clone(),
+            root: directory_path.to_path_buf(),
+            manifest,
+            is_upload: true,
+            next_entry: 0,
+            current: None,
+            files_done: 0,
+            bytes_done: 0,
+            status: TransferStatus::Pending,
+        });
+        
+        info!("Directory transfer initiated: {} ({} files, {} bytes)",
+              name, request.manifest.file_count(), request.manifest.total_bytes());
+        
+        let _ = self.event_sender.send(TransferEvent::TransferStarted(directory_id, name));
+        
+        Ok(request)
+    }
+    
+    /// Offer a file on its own, or as the file at `relative_path` of a directory being sent
+    async fn offer_file(&self, file_path: &Path, directory: Option<(&str, &str)>) -> Result<FileTransferRequest> {
         // Validate file
         if !file_path.exists() {
             return Err(anyhow::anyhow!("File does not exist: {}", file_path.display()));
@@ -150,24 +453,13 @@ impl FileTransferManager {
         }
         
         let config = self.config.read().await;
+        Self::check_file(&config, file_path, metadata.len())?;
         
-        // Check file size limit
-        if metadata.len() > config.max_file_size {
-            return Err(anyhow::anyhow!("File too large: {} bytes (max: {} bytes)", 
-                                     metadata.len(), config.max_file_size));
-        }
-        
-        // Check file extension if restricted
-        if let Some(ref allowed_exts) = config.allowed_extensions {
-            if let Some(ext) = file_path.extension() {
-                let ext_str = ext.to_string_lossy().to_lowercase();
-                if !allowed_exts.contains(&ext_str) {
-                    return Err(anyhow::anyhow!("File extension not allowed: {}", ext_str));
-                }
-            }
-        }
-        
-        let transfer_id = Uuid::new_v4().to_string();
+        // Sending a paused upload's file again takes its place, under the same ID
+        let transfer_id = self.active_transfers.read().await.values()
+            .find(|session| session.is_upload && session.status == TransferStatus::Paused && session.file_path == file_path)
+            .map(|session| session.id.clone())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
         let file_name = file_path.file_name()
             .ok_or_else(|| anyhow::anyhow!("Invalid file name"))?
             .to_string_lossy()
@@ -181,27 +473,42 @@ impl FileTransferManager {
             transfer_id: transfer_id.clone(),
             file_name: file_name.clone(),
             file_size: metadata.len(),
-            chunk_size: config.chunk_size,
+            chunk_size: config.chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
             compression: if config.enable_compression { 
-                CompressionType::Lz4 
+                CompressionType::for_file(file_path) 
             } else { 
                 CompressionType::None 
             },
             encryption_enabled: true,
             checksum: Some(checksum),
+            directory_id: directory.map(|(directory_id, _)| directory_id.to_string()),
+            relative_path: directory.map(|(_, relative_path)| relative_path.to_string()),
         };
         
+        // An empty file has no chunks to send
+        let chunks = chunk_count(metadata.len(), request.chunk_size);
+        
         // Create transfer session
         let session = TransferSession {
             id: transfer_id.clone(),
             file_path: file_path.to_path_buf(),
             file_size: metadata.len(),
+            chunk_size: request.chunk_size,
+            checksum: request.checksum.clone(),
+            compression: request.compression,
+            pending: (chunks > 0).then_some(ChunkRange { start: 0, end: chunks }).into_iter().collect(),
+            in_flight: 0,
+            received: None,
+            unsaved_chunks: 0,
+            retries: HashMap::new(),
             bytes_transferred: 0,
             start_time: Instant::now(),
             last_chunk_time: Instant::now(),
             status: TransferStatus::Pending,
             is_upload: true,
             speed_samples: Vec::new(),
+            directory_id: request.directory_id.clone(),
+            relative_path: request.relative_path.clone(),
         };
         
         self.active_transfers.write().await.insert(transfer_id.clone(), session);
@@ -210,11 +517,11 @@ impl FileTransferManager {
         
         // Send transfer started event
         let _ = self.event_sender.send(TransferEvent::TransferStarted(
-            transfer_id.clone(), 
+            transfer_id, 
             file_name
         ));
         
-        Ok(transfer_id)
+        Ok(request)
     }
     
     /// Accept an incoming file transfer
@@ -235,86 +542,169 @@ impl FileTransferManager {
                 accepted: false,
                 reason: Some("File already exists".to_string()),
                 suggested_path: Some(self.generate_unique_path(&file_path).await?),
+                missing_chunks: None,
             });
         }
         
         // Check available space (simplified)
         // In a real implementation, you'd check disk space
         
+        // Data goes to a .part file beside its saved state until the whole file is checked
+        let partial = PartialDownload {
+            transfer_id: request.transfer_id.clone(),
+            file_size: request.file_size,
+            chunk_size: request.chunk_size,
+            checksum: request.checksum.clone(),
+            received: ChunkBitmap::new(chunk_count(request.file_size, request.chunk_size)),
+        };
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        File::create(PartialDownload::part_path(&file_path))?;
+        partial.save(&file_path)?;
+        
         // Create transfer session
         let session = TransferSession {
             id: request.transfer_id.clone(),
             file_path: file_path.clone(),
             file_size: request.file_size,
+            chunk_size: request.chunk_size,
+            checksum: request.checksum.clone(),
+            compression: request.compression,
+            pending: VecDeque::new(),
+            in_flight: 0,
+            received: Some(partial.received),
+            unsaved_chunks: 0,
+            retries: HashMap::new(),
             bytes_transferred: 0,
             start_time: Instant::now(),
             last_chunk_time: Instant::now(),
             status: TransferStatus::Pending,
             is_upload: false,
             speed_samples: Vec::new(),
+            directory_id: request.directory_id.clone(),
+            relative_path: request.relative_path.clone(),
         };
         
         self.active_transfers.write().await.insert(request.transfer_id.clone(), session);
         
         info!("Accepting file transfer: {} -> {}", request.file_name, file_path.display());
         
+        let _ = self.event_sender.send(TransferEvent::TransferStarted(
+            request.transfer_id.clone(),
+            request.file_name.clone(),
+        ));
+        
         Ok(FileTransferResponse {
             transfer_id: request.transfer_id,
             accepted: true,
             reason: None,
             suggested_path: Some(file_path.to_string_lossy().to_string()),
+            missing_chunks: None,
         })
     }
     
-    /// Send file chunk (for upload)
-    pub async fn send_chunk(&self, transfer_id: &str, chunk_index: u64) -> Result<FileChunk> {
-        let permit = self.transfer_semaphore.acquire().await?;
+    /// Pick up an unfinished download of the offered file, asking only for what it lacks
+    async fn resume_download(&self, request: FileTransferRequest, file_path: PathBuf, mut partial: PartialDownload) -> Result<FileTransferResponse> {
+        partial.transfer_id = request.transfer_id.clone();
+        partial.save(&file_path)?;
         
-        let mut transfers = self.active_transfers.write().await;
-        let session = transfers.get_mut(transfer_id)
-            .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", transfer_id))?;
+        let missing = partial.received.missing();
+        let session = TransferSession {
+            id: request.transfer_id.clone(),
+            file_path: file_path.clone(),
+            file_size: request.file_size,
+            chunk_size: request.chunk_size,
+            checksum: request.checksum.clone(),
+            compression: request.compression,
+            pending: VecDeque::new(),
+            in_flight: 0,
+            received: Some(partial.received),
+            unsaved_chunks: 0,
+            retries: HashMap::new(),
+            bytes_transferred: request.file_size - range_bytes(&missing, request.file_size, request.chunk_size),
+            start_time: Instant::now(),
+            last_chunk_time: Instant::now(),
+            status: TransferStatus::Pending,
+            is_upload: false,
+            speed_samples: Vec::new(),
+            directory_id: request.directory_id.clone(),
+            relative_path: request.relative_path.clone(),
+        };
         
-        if !session.is_upload {
-            return Err(anyhow::anyhow!("Not an upload transfer"));
+        {
+            let mut transfers = self.active_transfers.write().await;
+            // An earlier session for the file, from a connection that dropped, is replaced
+            transfers.retain(|_, other| other.is_upload || other.file_path != file_path);
+            transfers.insert(request.transfer_id.clone(), session);
         }
         
-        let config = self.config.read().await;
-        let chunk_size = config.chunk_size;
-        let enable_compression = config.enable_compression;
-        drop(config);
+        info!("Resuming {} into {}: {} chunk ranges missing", request.file_name, file_path.display(), missing.len());
+        
+        let _ = self.event_sender.send(TransferEvent::TransferStarted(
+            request.transfer_id.clone(),
+            request.file_name.clone(),
+        ));
+        
+        Ok(FileTransferResponse {
+            transfer_id: request.transfer_id,
+            accepted: true,
+            reason: None,
+            suggested_path: Some(file_path.to_string_lossy().to_string()),
+            missing_chunks: Some(missing),
+        })
+    }
+    
+    /// Send file chunk (for upload)
+    pub async fn send_chunk(&self, transfer_id: &str, chunk_index: u64) -> Result<FileChunk> {
+        let permit = self.transfer_semaphore.acquire().await?;
+        
+        let (file_path, file_size, chunk_size, compression) = {
+            let transfers = self.active_transfers.read().await;
+            let session = transfers.get(transfer_id)
+                .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", transfer_id))?;
+            
+            if !session.is_upload {
+                return Err(anyhow::anyhow!("Not an upload transfer"));
+            }
+            
+            (session.file_path.clone(), session.file_size, session.chunk_size, session.compression)
+        };
+        let compression_level = self.config.read().await.compression_level;
         
         // Calculate chunk offset
         let offset = chunk_index * chunk_size as u64;
-        if offset >= session.file_size {
+        if offset >= file_size {
             return Err(anyhow::anyhow!("Chunk index out of bounds"));
         }
+        let actual_chunk_size = ((file_size - offset) as usize).min(chunk_size);
         
-        // Read chunk from file
-        let mut file = File::open(&session.file_path)?;
-        file.seek(SeekFrom::Start(offset))?;
-        
-        let actual_chunk_size = ((session.file_size - offset) as usize).min(chunk_size);
-        let mut buffer = vec![0u8; actual_chunk_size];
-        file.read_exact(&mut buffer)?;
-        
-        // Compress if enabled
-        let (data, is_compressed) = if enable_compression {
-            match self.compress_data(&buffer) {
-                Ok(compressed) => {
-                    if compressed.len() < buffer.len() {
-                        (compressed, true)
-                    } else {
-                        (buffer, false)
-                    }
-                },
-                Err(_) => (buffer, false),
-            }
-        } else {
-            (buffer, false)
-        };
+        // Read, hashed and packed off the async threads, without holding the other transfers up
+        let (data, is_compressed, checksum) = tokio::task::spawn_blocking(move || -> Result<(Vec<u8>, bool, String)> {
+            let mut file = File::open(&file_path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            
+            let mut buffer = vec![0u8; actual_chunk_size];
+            file.read_exact(&mut buffer)?;
+            
+            // Checked by the receiver once the chunk is unpacked
+            let checksum = Self::calculate_data_checksum(&buffer);
+            
+            // Compressed with the offered codec, unless that doesn't make this chunk smaller
+            let (data, is_compressed) = match Self::compress_data(&buffer, compression, compression_level) {
+                Ok(Some(compressed)) if compressed.len() < buffer.len() => (compressed, true),
+                Ok(_) => (buffer, false),
+                Err(e) => {
+                    debug!("Sending chunk {} uncompressed: {}", chunk_index, e);
+                    (buffer, false)
+                }
+            };
+            Ok((data, is_compressed, checksum))
+        }).await??;
         
-        // Calculate checksum
-        let checksum = self.calculate_data_checksum(&data);
+        let mut transfers = self.active_transfers.write().await;
+        let session = transfers.get_mut(transfer_id)
+            .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", transfer_id))?;
         
         // Update session
         session.bytes_transferred += actual_chunk_size as u64;
@@ -327,6 +717,7 @@ impl FileTransferManager {
         // Send progress update
         let progress = self.calculate_progress(session);
         let _ = self.event_sender.send(TransferEvent::ProgressUpdate(progress));
+        self.send_directory_progress(session).await;
         
         drop(transfers);
         drop(permit);
@@ -344,144 +735,964 @@ impl FileTransferManager {
     }
     
     /// Receive file chunk (for download)
-    pub async fn receive_chunk(&self, chunk: FileChunk) -> Result<()> {
+    pub async fn receive_chunk(&self, chunk: FileChunk) -> Result<ChunkReceipt> {
         let permit = self.transfer_semaphore.acquire().await?;
         
-        let mut transfers = self.active_transfers.write().await;
-        let session = transfers.get_mut(&chunk.transfer_id)
-            .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", chunk.transfer_id))?;
-        
-        if session.is_upload {
-            return Err(anyhow::anyhow!("Not a download transfer"));
-        }
+        let (file_path, file_size, chunk_size, compression) = {
+            let transfers = self.active_transfers.read().await;
+            let session = transfers.get(&chunk.transfer_id)
+                .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", chunk.transfer_id))?;
+            
+            if session.is_upload {
+                return Err(anyhow::anyhow!("Not a download transfer"));
+            }
+            
+            (session.file_path.clone(), session.file_size, session.chunk_size, session.compression)
+        };
         
-        // Verify checksum
-        let calculated_checksum = self.calculate_data_checksum(&chunk.data);
-        if calculated_checksum != chunk.checksum {
-            return Err(anyhow::anyhow!("Chunk checksum mismatch"));
+        // The index is the peer's, so it is checked before it is used for anything
+        if chunk.chunk_index >= chunk_count(file_size, chunk_size) {
+            return Err(anyhow::anyhow!("Chunk {} lies outside the file", chunk.chunk_index));
         }
         
-        // Decompress if needed
-        let data = if chunk.is_compressed {
-            self.decompress_data(&chunk.data)?
-        } else {
-            chunk.data
+        // Unpacked no further than a chunk, so a bad one can't take up much memory;
+        // that and the hashing happen off the async threads, without holding the other transfers up
+        let is_compressed = chunk.is_compressed;
+        let packed = chunk.data;
+        let unpacked = tokio::task::spawn_blocking(move || {
+            let data = if is_compressed {
+                Self::decompress_data(&packed, compression, chunk_size)
+            } else {
+                Ok(packed)
+            };
+            data.map(|data| {
+                let checksum = Self::calculate_data_checksum(&data);
+                (data, checksum)
+            })
+        }).await?;
+        let data = match unpacked {
+            Ok((data, checksum)) if checksum == chunk.checksum => data,
+            result => {
+                let mut transfers = self.active_transfers.write().await;
+                let session = transfers.get_mut(&chunk.transfer_id)
+                    .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", chunk.transfer_id))?;
+                let retries = session.retries.entry(chunk.chunk_index).or_insert(0);
+                *retries += 1;
+                if *retries > MAX_CHUNK_RETRIES {
+                    return Err(anyhow::anyhow!("Chunk {} arrived corrupt {} times", chunk.chunk_index, retries));
+                }
+                
+                match result {
+                    Ok(_) => warn!("Chunk {} of transfer {} failed its checksum", chunk.chunk_index, chunk.transfer_id),
+                    Err(e) => warn!("Chunk {} of transfer {} didn't unpack: {}", chunk.chunk_index, chunk.transfer_id, e),
+                }
+                return Ok(ChunkReceipt::Corrupt);
+            }
         };
         
-        // Write chunk to file
-        let chunk_size = data.len() as u64;
-        let offset = chunk.chunk_index * chunk_size;
-        
-        // Ensure parent directory exists
-        if let Some(parent) = session.file_path.parent() {
-            fs::create_dir_all(parent).await?;
+        // Only the last chunk may be short, so the offset comes from the offered size
+        let offset = chunk.chunk_index * chunk_size as u64;
+        let data_len = data.len();
+        if data_len > chunk_size || offset + data_len as u64 > file_size {
+            return Err(anyhow::anyhow!("Chunk {} lies outside the file", chunk.chunk_index));
         }
         
-        // Open file for writing (create if not exists)
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&session.file_path)?;
+        // Written into the .part file, which the session's acceptance created
+        let part_path = PartialDownload::part_path(&file_path);
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(part_path)?;
+            
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data)?;
+            file.flush()?;
+            Ok(())
+        }).await??;
+        
+        let mut transfers = self.active_transfers.write().await;
+        let session = transfers.get_mut(&chunk.transfer_id)
+            .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", chunk.transfer_id))?;
         
-        file.seek(SeekFrom::Start(offset))?;
-        file.write_all(&data)?;
-        file.flush()?;
+        // A chunk sent again after a resume is only counted once
+        let received = session.received.as_mut()
+            .ok_or_else(|| anyhow::anyhow!("Not a download transfer"))?;
+        if received.insert(chunk.chunk_index) {
+            session.bytes_transferred += data_len as u64;
+        }
+        let receipt = if received.is_complete() {
+            ChunkReceipt::Complete
+        } else {
+            ChunkReceipt::Written
+        };
         
         // Update session
-        session.bytes_transferred += data.len() as u64;
         session.last_chunk_time = Instant::now();
-        session.status = TransferStatus::Transferring;
+        if session.status == TransferStatus::Pending {
+            session.status = TransferStatus::Transferring;
+        }
+        
+        // The state is saved once the lock is let go; failing that only costs chunks sent again
+        session.unsaved_chunks += 1;
+        let partial = if session.unsaved_chunks >= STATE_SAVE_INTERVAL {
+            session.unsaved_chunks = 0;
+            Self::partial_download(session)
+        } else {
+            None
+        };
         
         // Update speed tracking
         self.update_speed_tracking(session).await;
         
-        // Check if transfer is complete
-        if session.bytes_transferred >= session.file_size {
-            session.status = TransferStatus::Completed;
-            let _ = self.event_sender.send(TransferEvent::TransferCompleted(chunk.transfer_id.clone()));
-            info!("File transfer completed: {}", session.file_path.display());
-        } else {
-            let progress = self.calculate_progress(session);
-            let _ = self.event_sender.send(TransferEvent::ProgressUpdate(progress));
-        }
+        let progress = self.calculate_progress(session);
+        let _ = self.event_sender.send(TransferEvent::ProgressUpdate(progress));
+        self.send_directory_progress(session).await;
         
         drop(transfers);
+        
+        if let Some(partial) = partial {
+            let saved = tokio::task::spawn_blocking(move || partial.save(&file_path)).await?;
+            if let Err(e) = saved {
+                warn!("Failed to save state of transfer {}: {}", chunk.transfer_id, e);
+            }
+        }
         drop(permit);
         
         debug!("Received chunk {} for transfer {} ({} bytes)", 
-               chunk.chunk_index, chunk.transfer_id, data.len());
+               chunk.chunk_index, chunk.transfer_id, data_len);
         
-        Ok(())
+        Ok(receipt)
     }
     
-    /// Cancel a transfer
-    pub async fn cancel_transfer(&self, transfer_id: &str) -> Result<()> {
-        let mut transfers = self.active_transfers.write().await;
+    /// Accept an offer from a peer into the download directory, under a name that doesn't clash
+    pub async fn receive_offer(&self, request: FileTransferRequest) -> Result<FileTransferResponse> {
+        let (download_directory, max_file_size) = {
+            let config = self.config.read().await;
+            (config.download_directory.clone(), config.max_file_size)
+        };
         
-        if let Some(mut session) = transfers.remove(transfer_id) {
-            session.status = TransferStatus::Cancelled;
-            
-            // If it's a download, optionally remove partial file
-            if !session.is_upload && session.bytes_transferred < session.file_size {
-                if let Err(e) = fs::remove_file(&session.file_path).await {
-                    warn!("Failed to remove partial file: {}", e);
-                }
+        let refusal = |reason: &str| FileTransferResponse {
+            transfer_id: request.transfer_id.clone(),
+            accepted: false,
+            reason: Some(reason.to_string()),
+            suggested_path: None,
+            missing_chunks: None,
+        };
+        
+        if request.file_size > max_file_size {
+            return Ok(refusal("File too large"));
+        }
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&request.chunk_size) {
+            return Ok(refusal("Invalid chunk size"));
+        }
+        
+        // Only the name is kept, so a peer can't choose where the file is written;
+        // a file of a directory goes where the directory's checked manifest puts it
+        let file_path = match request.directory_id {
+            Some(ref directory_id) => match self.start_directory_file(directory_id, &request).await {
+                Some(file_path) => file_path,
+                None => return Ok(refusal("Not part of a directory being received")),
+            },
+            None => match Path::new(&request.file_name).file_name() {
+                Some(file_name) => download_directory.join(file_name),
+                None => return Ok(refusal("Invalid file name")),
+            },
+        };
+        
+        // The same file offered again, after a dropped session or by a restarted peer
+        if let (Some(checksum), Some(directory), Some(file_name)) = (&request.checksum, file_path.parent(), file_path.file_name()) {
+            let partial = PartialDownload::find(
+                directory,
+                &file_name.to_string_lossy(),
+                request.file_size,
+                request.chunk_size,
+                checksum,
+            );
+            if let Some((file_path, partial)) = partial {
+                return self.resume_download(request, file_path, partial).await;
             }
-            
-            let _ = self.event_sender.send(TransferEvent::TransferCancelled(transfer_id.to_string()));
-            info!("Transfer cancelled: {}", transfer_id);
         }
         
-        Ok(())
-    }
-    
-    /// Get transfer progress
-    pub async fn get_transfer_progress(&self, transfer_id: &str) -> Option<FileTransferProgress> {
-        let transfers = self.active_transfers.read().await;
-        transfers.get(transfer_id).map(|session| self.calculate_progress(session))
-    }
-    
-    /// Get all active transfers
-    pub async fn get_active_transfers(&self) -> Vec<FileTransferProgress> {
-        let transfers = self.active_transfers.read().await;
-        transfers.values().map(|session| self.calculate_progress(session)).collect()
-    }
-    
-    // Helper methods
-    
-    async fn calculate_file_checksum(&self, file_path: &Path) -> Result<String> {
-        let data = fs::read(file_path).await?;
-        Ok(self.calculate_data_checksum(&data))
-    }
-    
-    fn calculate_data_checksum(&self, data: &[u8]) -> String {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
+        // A directory's files have their place; anything already there is refused
+        let mut file_path = file_path;
+        let taken = file_path.exists() || PartialDownload::part_path(&file_path).exists();
+        if taken && request.directory_id.is_none() {
+            file_path = PathBuf::from(self.generate_unique_path(&file_path).await?);
+        }
         
-        let mut hasher = DefaultHasher::new();
-        data.hash(&mut hasher);
-        format!("{:x}", hasher.finish())
+        self.accept_file_transfer(request, Some(file_path)).await
     }
     
-    fn compress_data(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Simple compression using flate2 (would be better with lz4)
-        use std::io::Write;
-        use flate2::Compression;
-        use flate2::write::GzEncoder;
+    /// Accept a directory offer: its directories and links are made now, its files as they are offered
+    pub async fn receive_directory_offer(&self, request: DirectoryTransferRequest) -> Result<FileTransferResponse> {
+        let (download_directory, max_file_size) = {
+            let config = self.config.read().await;
+            (config.download_directory.clone(), config.max_file_size)
+        };
         
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(data)?;
-        Ok(encoder.finish()?)
-    }
-    
-    fn decompress_data(&self, data: &[u8]) -> Result<Vec<u8>> {
-        use std::io::Read;
+        let refusal = |reason: &str| FileTransferResponse {
+            transfer_id: request.directory_id.clone(),
+            accepted: false,
+            reason: Some(reason.to_string()),
+            suggested_path: None,
+            missing_chunks: None,
+        };
+        
+        let name = sanitize_filename(&request.name);
+        if name.is_empty() || name == "." || name == ".." {
+            return Ok(refusal("Invalid directory name"));
+        }
+        if request.manifest.entries.len() > MAX_MANIFEST_ENTRIES {
+            return Ok(refusal("Too many entries"));
+        }
+        
+        // Every path is checked before anything is written
+        let mut paths = Vec::with_capacity(request.manifest.entries.len());
+        let mut seen = HashSet::new();
+        for entry in &request.manifest.entries {
+            let path = match sanitize_relative_path(&entry.path) {
+                Some(path) => path,
+                None => return Ok(refusal("Invalid path in manifest")),
+            };
+            // Sanitising can give two entries one name
+            if !seen.insert(path.clone()) {
+                return Ok(refusal("Manifest names a path twice"));
+            }
+            if entry.kind == EntryKind::File && entry.size > max_file_size {
+                return Ok(refusal("File too large"));
+            }
+            paths.push(path);
+        }
+        
+        let mut root = download_directory.join(&name);
+        if root.exists() {
+            root = PathBuf::from(self.generate_unique_path(&root).await?);
+        }
+        fs::create_dir_all(&root).await?;
+        
+        for (entry, path) in request.manifest.entries.iter().zip(&paths) {
+            match entry.kind {
+                EntryKind::Directory => fs::create_dir_all(root.join(path)).await?,
+                EntryKind::Symlink => {
+                    if let Err(e) = create_symlink(&root.join(path), entry) {
+                        warn!("Skipping link in {}: {}", name, e);
+                    }
+                }
+                EntryKind::File => {}
+            }
+        }
+        
+        let directory = DirectoryTransfer {
+            id: request.directory_id.clone(),
+            root: root.clone(),
+            manifest: request.manifest,
+            is_upload: false,
+            next_entry: 0,
+            current: None,
+            files_done: 0,
+            bytes_done: 0,
+            status: TransferStatus::Pending,
+        };
+        let empty = directory.manifest.file_count() == 0;
+        self.directories.write().await.insert(request.directory_id.clone(), directory);
+        
+        info!("Accepting directory transfer: {} -> {}", request.name, root.display());
+        let _ = self.event_sender.send(TransferEvent::TransferStarted(request.directory_id.clone(), request.name));
+        
+        // Nothing will be offered for a directory without files
+        if empty {
+            self.finish_directory(&request.directory_id).await;
+        }
+        
+        Ok(FileTransferResponse {
+            transfer_id: request.directory_id,
+            accepted: true,
+            reason: None,
+            suggested_path: Some(root.to_string_lossy().to_string()),
+            missing_chunks: None,
+        })
+    }
+    
+    /// Where an offered file of a directory being received goes, if the directory's manifest lists it
+    async fn start_directory_file(&self, directory_id: &str, request: &FileTransferRequest) -> Option<PathBuf> {
+        let mut directories = self.directories.write().await;
+        let directory = directories.get_mut(directory_id)
+            .filter(|directory| !directory.is_upload && directory.is_running())?;
+        
+        let relative_path = request.relative_path.as_deref()?;
+        let entry = directory.manifest.get(relative_path)
+            .filter(|entry| entry.kind == EntryKind::File && entry.size == request.file_size)?;
+        let file_path = directory.root.join(sanitize_relative_path(&entry.path)?);
+        
+        directory.current = Some(request.transfer_id.clone());
+        directory.status = TransferStatus::Transferring;
+        Some(file_path)
+    }
+    
+    /// Offer the next file of a directory being sent, or finish the directory once none are left
+    async fn next_directory_file(&self, directory_id: &str) -> Vec<TransferMessage> {
+        let (file_path, relative_path) = {
+            let mut directories = self.directories.write().await;
+            let directory = match directories.get_mut(directory_id) {
+                Some(directory) if directory.is_upload && directory.is_running() => directory,
+                _ => return Vec::new(),
+            };
+            
+            let next = directory.manifest.entries.iter()
+                .enumerate()
+                .skip(directory.next_entry)
+                .find(|(_, entry)| entry.kind == EntryKind::File)
+                .map(|(index, entry)| (index, entry.path.clone()));
+            
+            match next {
+                Some((index, relative_path)) => {
+                    directory.next_entry = index + 1;
+                    directory.status = TransferStatus::Transferring;
+                    (directory.root.join(&relative_path), relative_path)
+                }
+                None => {
+                    directory.status = TransferStatus::Completed;
+                    directory.current = None;
+                    info!("Directory transfer completed: {}", directory.root.display());
+                    let _ = self.event_sender.send(TransferEvent::DirectoryProgress(directory.progress(0)));
+                    let _ = self.event_sender.send(TransferEvent::TransferCompleted(directory_id.to_string()));
+                    return Vec::new();
+                }
+            }
+        };
+        
+        match self.offer_file(&file_path, Some((directory_id, &relative_path))).await {
+            Ok(request) => {
+                if let Some(directory) = self.directories.write().await.get_mut(directory_id) {
+                    directory.current = Some(request.transfer_id.clone());
+                }
+                vec![TransferMessage::Offer(request)]
+            }
+            // Changed since the manifest was made
+            Err(e) => {
+                let error = format!("Cannot send {}: {}", relative_path, e);
+                self.fail_transfer(directory_id, error.clone()).await;
+                vec![TransferMessage::cancel(directory_id, error)]
+            }
+        }
+    }
+    
+    /// Count a finished file towards its directory, restoring its metadata on the receiving side
+    async fn finish_directory_file(&self, directory_id: &str, relative_path: Option<&str>, file_path: &Path, file_size: u64) {
+        let complete = {
+            let mut directories = self.directories.write().await;
+            let directory = match directories.get_mut(directory_id) {
+                Some(directory) => directory,
+                None => return,
+            };
+            
+            if !directory.is_upload {
+                if let Some(entry) = relative_path.and_then(|relative_path| directory.manifest.get(relative_path)) {
+                    if let Err(e) = apply_metadata(file_path, entry) {
+                        warn!("Could not restore metadata of {}: {}", file_path.display(), e);
+                    }
+                }
+            }
+            
+            directory.files_done += 1;
+            directory.bytes_done += file_size;
+            directory.current = None;
+            let _ = self.event_sender.send(TransferEvent::DirectoryProgress(directory.progress(0)));
+            
+            !directory.is_upload && directory.files_done >= directory.manifest.file_count()
+        };
+        
+        if complete {
+            self.finish_directory(directory_id).await;
+        }
+    }
+    
+    /// A received directory has all its files: its directories get their metadata, innermost first
+    async fn finish_directory(&self, directory_id: &str) {
+        let mut directories = self.directories.write().await;
+        let directory = match directories.get_mut(directory_id) {
+            Some(directory) if directory.is_running() => directory,
+            _ => return,
+        };
+        
+        for entry in directory.manifest.entries.iter().rev().filter(|entry| entry.kind == EntryKind::Directory) {
+            if let Some(path) = sanitize_relative_path(&entry.path) {
+                if let Err(e) = apply_metadata(&directory.root.join(path), entry) {
+                    warn!("Could not restore metadata of {}: {}", entry.path, e);
+                }
+            }
+        }
+        
+        directory.status = TransferStatus::Completed;
+        info!("Directory transfer completed: {}", directory.root.display());
+        let _ = self.event_sender.send(TransferEvent::DirectoryProgress(directory.progress(0)));
+        let _ = self.event_sender.send(TransferEvent::TransferCompleted(directory_id.to_string()));
+    }
+    
+    /// Send a directory's progress along with a progress update of one of its files
+    async fn send_directory_progress(&self, session: &TransferSession) {
+        let directory_id = match session.directory_id {
+            Some(ref directory_id) => directory_id,
+            None => return,
+        };
+        
+        if let Some(directory) = self.directories.read().await.get(directory_id) {
+            let _ = self.event_sender.send(TransferEvent::DirectoryProgress(directory.progress(session.bytes_transferred)));
+        }
+    }
+    
+    /// Act on a message from the peer, returning the replies to send back
+    pub async fn handle_message(&self, message: TransferMessage) -> Result<Vec<TransferMessage>> {
+        match message {
+            TransferMessage::Offer(request) => {
+                let transfer_id = request.transfer_id.clone();
+                let response = self.receive_offer(request).await?;
+                let accepted = response.accepted;
+                
+                let mut replies = vec![TransferMessage::Response(response)];
+                // Nothing will arrive for an empty file, or one received in full before the session dropped
+                if accepted && self.is_fully_received(&transfer_id).await {
+                    replies.push(TransferMessage::Complete(self.finish_download(&transfer_id).await));
+                }
+                Ok(replies)
+            }
+            TransferMessage::DirectoryOffer(request) => {
+                Ok(vec![TransferMessage::Response(self.receive_directory_offer(request).await?)])
+            }
+            TransferMessage::Response(response) if response.accepted => {
+                debug!("Peer accepted transfer {}", response.transfer_id);
+                
+                if self.directories.read().await.contains_key(&response.transfer_id) {
+                    return Ok(self.next_directory_file(&response.transfer_id).await);
+                }
+                if let Some(missing) = response.missing_chunks {
+                    self.set_pending(&response.transfer_id, missing).await;
+                }
+                self.fill_window(&response.transfer_id).await
+            }
+            TransferMessage::Response(response) => {
+                let reason = response.reason.unwrap_or_else(|| "no reason given".to_string());
+                self.fail_transfer(&response.transfer_id, format!("Declined by peer: {}", reason)).await;
+                Ok(Vec::new())
+            }
+            TransferMessage::Chunk(chunk) => {
+                let transfer_id = chunk.transfer_id.clone();
+                let chunk_index = chunk.chunk_index;
+                
+                match self.receive_chunk(chunk).await {
+                    Ok(ChunkReceipt::Complete) => Ok(vec![TransferMessage::Complete(self.finish_download(&transfer_id).await)]),
+                    Ok(ChunkReceipt::Written) => Ok(vec![TransferMessage::Ack(ChunkAck { transfer_id, chunk_index, corrupt: false })]),
+                    Ok(ChunkReceipt::Corrupt) => Ok(vec![TransferMessage::Ack(ChunkAck { transfer_id, chunk_index, corrupt: true })]),
+                    Err(e) => {
+                        self.fail_transfer(&transfer_id, e.to_string()).await;
+                        Ok(vec![TransferMessage::cancel(&transfer_id, e.to_string())])
+                    }
+                }
+            }
+            TransferMessage::Ack(ack) => {
+                let mut outside = false;
+                if let Some(session) = self.active_transfers.write().await.get_mut(&ack.transfer_id) {
+                    outside = ack.chunk_index >= chunk_count(session.file_size, session.chunk_size);
+                }
+                if outside {
+                    let error = format!("Acknowledged chunk {} lies outside the file", ack.chunk_index);
+                    self.fail_transfer(&ack.transfer_id, error.clone()).await;
+                    return Ok(vec![TransferMessage::cancel(&ack.transfer_id, error)]);
+                }
+                
+                if let Some(session) = self.active_transfers.write().await.get_mut(&ack.transfer_id) {
+                    session.in_flight = session.in_flight.saturating_sub(1);
+                    
+                    // A corrupt chunk goes out again ahead of the rest
+                    let offset = ack.chunk_index * session.chunk_size as u64;
+                    if ack.corrupt && session.is_upload {
+                        debug!("Peer asked again for chunk {} of transfer {}", ack.chunk_index, ack.transfer_id);
+                        session.pending.push_front(ChunkRange { start: ack.chunk_index, end: ack.chunk_index + 1 });
+                        session.bytes_transferred = session.bytes_transferred
+                            .saturating_sub((session.file_size - offset).min(session.chunk_size as u64));
+                        session.speed_samples.clear();
+                    }
+                }
+                
+                Ok(self.next_chunk(&ack.transfer_id).await?.map(TransferMessage::Chunk).into_iter().collect())
+            }
+            TransferMessage::Complete(complete) if complete.success => {
+                let finished = match self.active_transfers.write().await.get_mut(&complete.transfer_id) {
+                    Some(session) => {
+                        session.status = TransferStatus::Completed;
+                        info!("File transfer completed: {}", session.file_path.display());
+                        let _ = self.event_sender.send(TransferEvent::TransferCompleted(complete.transfer_id));
+                        session.directory_id.clone().map(|directory_id| (directory_id, session.file_path.clone(), session.file_size))
+                    }
+                    None => None,
+                };
+                
+                // The peer has this file of a directory, so the next one goes
+                match finished {
+                    Some((directory_id, file_path, file_size)) => {
+                        self.finish_directory_file(&directory_id, None, &file_path, file_size).await;
+                        Ok(self.next_directory_file(&directory_id).await)
+                    }
+                    None => Ok(Vec::new()),
+                }
+            }
+            TransferMessage::Complete(complete) => {
+                let error = complete.error.unwrap_or_else(|| "Peer could not save the file".to_string());
+                self.fail_transfer(&complete.transfer_id, error).await;
+                Ok(Vec::new())
+            }
+            TransferMessage::Cancel(cancel) => {
+                info!("Peer cancelled transfer {}: {}", cancel.transfer_id, cancel.reason.unwrap_or_default());
+                self.cancel_transfer(&cancel.transfer_id).await?;
+                Ok(Vec::new())
+            }
+            TransferMessage::Pause(pause) => {
+                info!("Peer paused transfer {}", pause.transfer_id);
+                self.pause_transfers([pause.transfer_id]).await;
+                Ok(Vec::new())
+            }
+            TransferMessage::Resume(resume) => {
+                let is_upload = match self.active_transfers.read().await.get(&resume.transfer_id) {
+                    Some(session) if matches!(session.status, TransferStatus::Completed | TransferStatus::Failed | TransferStatus::Cancelled) => {
+                        return Ok(vec![TransferMessage::cancel(&resume.transfer_id, "Transfer is no longer running")]);
+                    }
+                    Some(session) => session.is_upload,
+                    None => return Ok(vec![TransferMessage::cancel(&resume.transfer_id, "Transfer not found")]),
+                };
+                
+                info!("Peer resumed transfer {}", resume.transfer_id);
+                self.set_running(&resume.transfer_id).await;
+                
+                match (is_upload, resume.missing_chunks) {
+                    (true, Some(missing)) => {
+                        self.set_pending(&resume.transfer_id, missing).await;
+                        self.fill_window(&resume.transfer_id).await
+                    }
+                    (true, None) => {
+                        warn!("Peer resumed upload {} without naming the chunks it needs", resume.transfer_id);
+                        Ok(Vec::new())
+                    }
+                    (false, _) => Ok(vec![self.resume_message(&resume.transfer_id).await]),
+                }
+            }
+        }
+    }
+    
+    /// Stop a running transfer for now, returning the message that tells the peer
+    pub async fn pause_transfer(&self, transfer_id: &str) -> Result<TransferMessage> {
+        match self.active_transfers.read().await.get(transfer_id) {
+            Some(session) if matches!(session.status, TransferStatus::Pending | TransferStatus::Transferring) => {}
+            Some(_) => return Err(anyhow::anyhow!("Transfer {} is not running", transfer_id)),
+            None => return Err(anyhow::anyhow!("Transfer not found: {}", transfer_id)),
+        }
+        
+        self.pause_transfers([transfer_id.to_string()]).await;
+        Ok(TransferMessage::Pause(FileTransferPause { transfer_id: transfer_id.to_string() }))
+    }
+    
+    /// Pause whichever of these transfers are running, saving downloads' state; used when their session drops
+    pub async fn pause_transfers(&self, transfer_ids: impl IntoIterator<Item = String>) {
+        let mut transfers = self.active_transfers.write().await;
+        
+        for transfer_id in transfer_ids {
+            if let Some(session) = transfers.get_mut(&transfer_id) {
+                if !matches!(session.status, TransferStatus::Pending | TransferStatus::Transferring) {
+                    continue;
+                }
+                
+                session.status = TransferStatus::Paused;
+                if !session.is_upload {
+                    self.save_partial(session);
+                }
+                
+                info!("Transfer paused: {}", transfer_id);
+                let _ = self.event_sender.send(TransferEvent::TransferPaused(transfer_id));
+            }
+        }
+    }
+    
+    /// Continue a paused transfer, returning the message that tells the peer
+    pub async fn resume_transfer(&self, transfer_id: &str) -> Result<TransferMessage> {
+        match self.active_transfers.read().await.get(transfer_id) {
+            Some(session) if session.status == TransferStatus::Paused => {}
+            Some(_) => return Err(anyhow::anyhow!("Transfer {} is not paused", transfer_id)),
+            None => return Err(anyhow::anyhow!("Transfer not found: {}", transfer_id)),
+        }
+        
+        self.set_running(transfer_id).await;
+        Ok(self.resume_message(transfer_id).await)
+    }
+    
+    async fn set_running(&self, transfer_id: &str) {
+        if let Some(session) = self.active_transfers.write().await.get_mut(transfer_id) {
+            if session.status == TransferStatus::Paused {
+                session.status = TransferStatus::Transferring;
+                let _ = self.event_sender.send(TransferEvent::TransferResumed(transfer_id.to_string()));
+            }
+        }
+    }
+    
+    /// A receiver names the chunks it still needs; a sender asks the receiver for them
+    async fn resume_message(&self, transfer_id: &str) -> TransferMessage {
+        let missing_chunks = self.active_transfers.read().await.get(transfer_id)
+            .and_then(|session| session.received.as_ref())
+            .map(|received| received.missing());
+        
+        TransferMessage::Resume(FileTransferResume { transfer_id: transfer_id.to_string(), missing_chunks })
+    }
+    
+    /// Send only these chunks of an upload, whatever was sent before
+    async fn set_pending(&self, transfer_id: &str, missing: Vec<ChunkRange>) {
+        if let Some(session) = self.active_transfers.write().await.get_mut(transfer_id) {
+            let chunk_count = chunk_count(session.file_size, session.chunk_size);
+            let missing: Vec<ChunkRange> = missing.into_iter()
+                .map(|range| ChunkRange { start: range.start, end: range.end.min(chunk_count) })
+                .filter(|range| range.start < range.end)
+                .collect();
+            
+            session.bytes_transferred = session.file_size - range_bytes(&missing, session.file_size, session.chunk_size);
+            // Samples from before the resume could be ahead of the new count
+            session.speed_samples.clear();
+            session.pending = missing.into();
+            // Whatever was unacknowledged is either received or in the list
+            session.in_flight = 0;
+        }
+    }
+    
+    /// Send chunks until the window is full
+    async fn fill_window(&self, transfer_id: &str) -> Result<Vec<TransferMessage>> {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = self.next_chunk(transfer_id).await? {
+            chunks.push(TransferMessage::Chunk(chunk));
+        }
+        Ok(chunks)
+    }
+    
+    /// Read the next chunk of an upload, unless the window is full or everything was sent
+    async fn next_chunk(&self, transfer_id: &str) -> Result<Option<FileChunk>> {
+        let chunk_index = {
+            let mut transfers = self.active_transfers.write().await;
+            let session = transfers.get_mut(transfer_id)
+                .ok_or_else(|| anyhow::anyhow!("Transfer not found: {}", transfer_id))?;
+            
+            let running = matches!(session.status, TransferStatus::Pending | TransferStatus::Transferring);
+            if !running || session.in_flight >= CHUNK_WINDOW {
+                return Ok(None);
+            }
+            
+            let range = match session.pending.front_mut() {
+                Some(range) => range,
+                None => return Ok(None),
+            };
+            let chunk_index = range.start;
+            range.start += 1;
+            if range.start >= range.end {
+                session.pending.pop_front();
+            }
+            
+            session.in_flight += 1;
+            chunk_index
+        };
+        
+        self.send_chunk(transfer_id, chunk_index).await.map(Some)
+    }
+    
+    async fn is_fully_received(&self, transfer_id: &str) -> bool {
+        self.active_transfers.read().await.get(transfer_id)
+            .and_then(|session| session.received.as_ref())
+            .map(|received| received.is_complete())
+            .unwrap_or(false)
+    }
+    
+    /// Save a download's state so it can be resumed; failing that only costs chunks sent again
+    fn save_partial(&self, session: &mut TransferSession) {
+        let partial = match Self::partial_download(session) {
+            Some(partial) => partial,
+            None => return,
+        };
+        
+        match partial.save(&session.file_path) {
+            Ok(()) => session.unsaved_chunks = 0,
+            Err(e) => warn!("Failed to save state of transfer {}: {}", session.id, e),
+        }
+    }
+    
+    /// The state a download would be resumed from; `None` for an upload
+    fn partial_download(session: &TransferSession) -> Option<PartialDownload> {
+        let received = session.received.as_ref()?.clone();
+        
+        Some(PartialDownload {
+            transfer_id: session.id.clone(),
+            file_size: session.file_size,
+            chunk_size: session.chunk_size,
+            checksum: session.checksum.clone(),
+            received,
+        })
+    }
+    
+    /// Check a fully received file against the offered checksum, then move it into place
+    async fn finish_download(&self, transfer_id: &str) -> FileTransferComplete {
+        let (file_path, expected) = match self.active_transfers.read().await.get(transfer_id) {
+            Some(session) => (session.file_path.clone(), session.checksum.clone()),
+            None => return FileTransferComplete {
+                transfer_id: transfer_id.to_string(),
+                success: false,
+                error: Some("Transfer not found".to_string()),
+            },
+        };
+        let part_path = PartialDownload::part_path(&file_path);
+        
+        let verified = match expected {
+            Some(expected) => match self.calculate_file_checksum(&part_path).await {
+                Ok(checksum) if checksum == expected => Ok(()),
+                Ok(_) => Err("File checksum mismatch".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            None => Ok(()),
+        };
+        
+        // The name may have been taken while the download ran
+        let verified = match verified {
+            Ok(()) if file_path.exists() => self.generate_unique_path(&file_path).await
+                .map(PathBuf::from)
+                .map_err(|e| e.to_string()),
+            Ok(()) => Ok(file_path.clone()),
+            Err(e) => Err(e),
+        };
+        let verified = match verified {
+            Ok(final_path) => fs::rename(&part_path, &final_path).await
+                .map(|_| final_path)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        
+        match verified {
+            Ok(final_path) => {
+                let _ = fs::remove_file(PartialDownload::state_path(&file_path)).await;
+                let directory = match self.active_transfers.write().await.get_mut(transfer_id) {
+                    Some(session) => {
+                        session.status = TransferStatus::Completed;
+                        session.file_path = final_path.clone();
+                        session.directory_id.clone().map(|directory_id| (directory_id, session.relative_path.clone(), session.file_size))
+                    }
+                    None => None,
+                };
+                let _ = self.event_sender.send(TransferEvent::TransferCompleted(transfer_id.to_string()));
+                info!("File transfer completed: {}", final_path.display());
+                
+                if let Some((directory_id, relative_path, file_size)) = directory {
+                    self.finish_directory_file(&directory_id, relative_path.as_deref(), &final_path, file_size).await;
+                }
+                
+                FileTransferComplete { transfer_id: transfer_id.to_string(), success: true, error: None }
+            }
+            Err(error) => {
+                // Data that doesn't check out isn't worth resuming
+                PartialDownload::discard(&file_path);
+                self.fail_transfer(transfer_id, error.clone()).await;
+                FileTransferComplete { transfer_id: transfer_id.to_string(), success: false, error: Some(error) }
+            }
+        }
+    }
+    
+    /// Fail a file transfer, and the directory it is part of; or a directory itself
+    async fn fail_transfer(&self, transfer_id: &str, error: String) {
+        let directory_id = match self.active_transfers.write().await.get_mut(transfer_id) {
+            Some(session) => {
+                session.status = TransferStatus::Failed;
+                warn!("File transfer {} failed: {}", transfer_id, error);
+                let _ = self.event_sender.send(TransferEvent::TransferFailed(transfer_id.to_string(), error.clone()));
+                session.directory_id.clone()
+            }
+            None => Some(transfer_id.to_string()),
+        };
+        
+        if let Some(directory_id) = directory_id {
+            if let Some(directory) = self.directories.write().await.get_mut(&directory_id).filter(|directory| directory.is_running()) {
+                directory.status = TransferStatus::Failed;
+                warn!("Directory transfer {} failed: {}", directory_id, error);
+                let _ = self.event_sender.send(TransferEvent::TransferFailed(directory_id, error));
+            }
+        }
+    }
+    
+    /// Cancel a transfer that is running or paused; a directory with the file under way,
+    /// a file with the directory it is part of
+    pub async fn cancel_transfer(&self, transfer_id: &str) -> Result<()> {
+        let current = self.cancel_directory(transfer_id).await;
+        let transfer_id = current.as_deref().unwrap_or(transfer_id);
+        
+        let mut transfers = self.active_transfers.write().await;
+        
+        let running = transfers.get(transfer_id)
+            .map(|session| matches!(session.status, TransferStatus::Pending | TransferStatus::Transferring | TransferStatus::Paused))
+            .unwrap_or(false);
+        if !running {
+            return Ok(());
+        }
+        
+        if let Some(mut session) = transfers.remove(transfer_id) {
+            session.status = TransferStatus::Cancelled;
+            
+            // A cancelled download won't be resumed, so its partial file goes
+            if !session.is_upload {
+                PartialDownload::discard(&session.file_path);
+            }
+            
+            let _ = self.event_sender.send(TransferEvent::TransferCancelled(transfer_id.to_string()));
+            info!("Transfer cancelled: {}", transfer_id);
+            
+            drop(transfers);
+            if let Some(ref directory_id) = session.directory_id {
+                self.cancel_directory(directory_id).await;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Cancel a running directory, returning the file transfer it had under way
+    async fn cancel_directory(&self, directory_id: &str) -> Option<String> {
+        let mut directories = self.directories.write().await;
+        if !directories.get(directory_id).map(|directory| directory.is_running()).unwrap_or(false) {
+            return None;
+        }
+        
+        let directory = directories.remove(directory_id)?;
+        let _ = self.event_sender.send(TransferEvent::TransferCancelled(directory_id.to_string()));
+        info!("Directory transfer cancelled: {}", directory_id);
+        directory.current
+    }
+    
+    pub async fn update_config(&self, new_config: TransferConfig) {
+        self.throttle.set_rate(new_config.max_speed_bps);
+        *self.config.write().await = new_config;
+    }
+    
+    /// Limit all uploads together to this many bytes a second, or lift the limit
+    pub async fn set_speed_limit(&self, max_speed_bps: Option<u64>) {
+        self.throttle.set_rate(max_speed_bps);
+        self.config.write().await.max_speed_bps = max_speed_bps;
+    }
+    
+    /// Reserve room for a chunk about to be sent, returning how long the session should hold it back
+    pub fn reserve_bandwidth(&self, message: &TransferMessage) -> Duration {
+        match message {
+            TransferMessage::Chunk(chunk) => self.throttle.reserve(chunk.data.len()),
+            _ => Duration::ZERO,
+        }
+    }
+    
+    /// Get transfer progress
+    pub async fn get_transfer_progress(&self, transfer_id: &str) -> Option<FileTransferProgress> {
+        let transfers = self.active_transfers.read().await;
+        transfers.get(transfer_id).map(|session| self.calculate_progress(session))
+    }
+    
+    /// Get all active transfers
+    pub async fn get_active_transfers(&self) -> Vec<FileTransferProgress> {
+        let transfers = self.active_transfers.read().await;
+        transfers.values().map(|session| self.calculate_progress(session)).collect()
+    }
+    
+    /// Progress of a directory transfer over all its files
+    pub async fn get_directory_progress(&self, directory_id: &str) -> Option<DirectoryProgress> {
+        let transfers = self.active_transfers.read().await;
+        let directories = self.directories.read().await;
+        
+        let directory = directories.get(directory_id)?;
+        let current_bytes = directory.current.as_ref()
+            .and_then(|transfer_id| transfers.get(transfer_id))
+            .filter(|session| session.status != TransferStatus::Completed)
+            .map(|session| session.bytes_transferred)
+            .unwrap_or(0);
+        Some(directory.progress(current_bytes))
+    }
+    
+    // Helper methods
+    
+    /// Whether the config lets a file of this size and type be sent
+    fn check_file(config: &TransferConfig, file_path: &Path, file_size: u64) -> Result<()> {
+        // Check file size limit
+        if file_size > config.max_file_size {
+            return Err(anyhow::anyhow!("File too large: {} bytes (max: {} bytes)", 
+                                     file_size, config.max_file_size));
+        }
+        
+        // Check file extension if restricted
+        if let Some(ref allowed_exts) = config.allowed_extensions {
+            if let Some(ext) = file_path.extension() {
+                let ext_str = ext.to_string_lossy().to_lowercase();
+                if !allowed_exts.contains(&ext_str) {
+                    return Err(anyhow::anyhow!("File extension not allowed: {}", ext_str));
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// SHA-256 of the file, read a block at a time
+    async fn calculate_file_checksum(&self, file_path: &Path) -> Result<String> {
+        let mut file = fs::File::open(file_path).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+    
+    fn calculate_data_checksum(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+    
+    /// Pack a chunk with the transfer's codec; `None` when it has none
+    fn compress_data(data: &[u8], compression: CompressionType, level: u32) -> Result<Option<Vec<u8>>> {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        
+        match compression {
+            CompressionType::None => Ok(None),
+            CompressionType::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level.min(9)));
+                encoder.write_all(data)?;
+                Ok(Some(encoder.finish()?))
+            }
+            CompressionType::Lz4 => Ok(Some(lz4_flex::block::compress_prepend_size(data))),
+        }
+    }
+    
+    /// Unpack a chunk, refusing one that would come to more than `max_len` bytes
+    fn decompress_data(data: &[u8], compression: CompressionType, max_len: usize) -> Result<Vec<u8>> {
         use flate2::read::GzDecoder;
         
-        let mut decoder = GzDecoder::new(data);
-        let mut decompressed = Vec::new();
-        decoder.read_to_end(&mut decompressed)?;
-        Ok(decompressed)
+        match compression {
+            CompressionType::None => Err(anyhow::anyhow!("Compressed chunk in a transfer without compression")),
+            CompressionType::Gzip => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(data).take(max_len as u64 + 1).read_to_end(&mut decompressed)?;
+                if decompressed.len() > max_len {
+                    return Err(anyhow::anyhow!("Chunk unpacks to more than {} bytes", max_len));
+                }
+                Ok(decompressed)
+            }
+            CompressionType::Lz4 => {
+                let (size, compressed) = lz4_flex::block::uncompressed_size(data)
+                    .map_err(|e| anyhow::anyhow!("Invalid LZ4 chunk: {}", e))?;
+                if size > max_len {
+                    return Err(anyhow::anyhow!("Chunk unpacks to more than {} bytes", max_len));
+                }
+                lz4_flex::block::decompress(compressed, size)
+                    .map_err(|e| anyhow::anyhow!("Invalid LZ4 chunk: {}", e))
+            }
+        }
     }
     
     async fn generate_unique_path(&self, base_path: &Path) -> Result<String> {
@@ -498,7 +1709,7 @@ impl FileTransferManager {
             };
             
             let new_path = parent.join(new_name);
-            if !new_path.exists() {
+            if !new_path.exists() && !PartialDownload::part_path(&new_path).exists() {
                 return Ok(new_path.to_string_lossy().to_string());
             }
             
@@ -525,7 +1736,7 @@ impl FileTransferManager {
             
             let time_diff = newest_time.duration_since(oldest_time).as_secs_f64();
             if time_diff > 0.0 {
-                ((newest_bytes - oldest_bytes) as f64 / time_diff) as u64
+                (newest_bytes.saturating_sub(oldest_bytes) as f64 / time_diff) as u64
             } else {
                 0
             }
@@ -551,5 +1762,347 @@ impl FileTransferManager {
     }
 }
 
-// Add flate2 dependency to Cargo.toml for compression
-// flate2 = "1.0"
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    async fn manager(download_directory: &Path) -> (FileTransferManager, mpsc::UnboundedReceiver<TransferEvent>) {
+        let (manager, events) = FileTransferManager::new();
+        manager.update_config(TransferConfig {
+            chunk_size: 4096,
+            download_directory: download_directory.to_path_buf(),
+            ..TransferConfig::default()
+        }).await;
+        (manager, events)
+    }
+    
+    /// Pass messages both ways until neither side has anything left to say, returning the chunks sent
+    async fn exchange(sender: &FileTransferManager, receiver: &FileTransferManager, offer: FileTransferRequest) -> usize {
+        relay(sender, receiver, vec![TransferMessage::Offer(offer)]).await
+    }
+    
+    async fn relay(sender: &FileTransferManager, receiver: &FileTransferManager, mut to_receiver: Vec<TransferMessage>) -> usize {
+        let mut chunks = 0;
+        while !to_receiver.is_empty() {
+            let mut to_sender = Vec::new();
+            for message in to_receiver.drain(..) {
+                chunks += matches!(message, TransferMessage::Chunk(_)) as usize;
+                to_sender.extend(receiver.handle_message(message).await.unwrap());
+            }
+            for message in to_sender {
+                to_receiver.extend(sender.handle_message(message).await.unwrap());
+            }
+        }
+        chunks
+    }
+    
+    #[tokio::test]
+    async fn test_file_arrives_intact() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(directory.join("out")).unwrap();
+        
+        // More chunks than the window, and a short last one
+        let data: Vec<u8> = (0..84_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let source = directory.join("report.log");
+        std::fs::write(&source, &data).unwrap();
+        
+        let (sender, mut sender_events) = manager(&directory).await;
+        let (receiver, _receiver_events) = manager(&directory.join("out")).await;
+        
+        let offer = sender.send_file(&source).await.unwrap();
+        let transfer_id = offer.transfer_id.clone();
+        assert_eq!(offer.compression, CompressionType::Gzip);
+        exchange(&sender, &receiver, offer).await;
+        
+        assert_eq!(std::fs::read(directory.join("out").join("report.log")).unwrap(), data);
+        assert_eq!(receiver.get_transfer_progress(&transfer_id).await.unwrap().status, TransferStatus::Completed);
+        assert_eq!(sender.get_transfer_progress(&transfer_id).await.unwrap().status, TransferStatus::Completed);
+        
+        let mut completed = false;
+        while let Ok(event) = sender_events.try_recv() {
+            completed |= matches!(event, TransferEvent::TransferCompleted(ref id) if *id == transfer_id);
+        }
+        assert!(completed);
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_dropped_download_resumes_with_missing_chunks() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let downloads = directory.join("out");
+        std::fs::create_dir_all(&downloads).unwrap();
+        
+        let data: Vec<u8> = (0..84_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        let source = directory.join("disk.img");
+        std::fs::write(&source, &data).unwrap();
+        
+        let (sender, _sender_events) = manager(&directory).await;
+        let (receiver, _receiver_events) = manager(&downloads).await;
+        
+        // The session drops after five of the 21 chunks arrive
+        let offer = sender.send_file(&source).await.unwrap();
+        let transfer_id = offer.transfer_id.clone();
+        let response = receiver.handle_message(TransferMessage::Offer(offer)).await.unwrap().remove(0);
+        let chunks = sender.handle_message(response).await.unwrap();
+        for chunk in chunks.into_iter().take(5) {
+            receiver.handle_message(chunk).await.unwrap();
+        }
+        sender.pause_transfers([transfer_id.clone()]).await;
+        receiver.pause_transfers([transfer_id.clone()]).await;
+        assert!(PartialDownload::part_path(&downloads.join("disk.img")).exists());
+        
+        // Offered again to a restarted receiver, only the rest is sent
+        drop(receiver);
+        let (receiver, _receiver_events) = manager(&downloads).await;
+        let offer = sender.send_file(&source).await.unwrap();
+        assert_eq!(offer.transfer_id, transfer_id);
+        assert_eq!(exchange(&sender, &receiver, offer).await, 16);
+        
+        assert_eq!(std::fs::read(downloads.join("disk.img")).unwrap(), data);
+        assert!(!PartialDownload::part_path(&downloads.join("disk.img")).exists());
+        assert!(!PartialDownload::state_path(&downloads.join("disk.img")).exists());
+        assert_eq!(sender.get_transfer_progress(&transfer_id).await.unwrap().status, TransferStatus::Completed);
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_corrupt_chunk_is_sent_again() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let downloads = directory.join("out");
+        std::fs::create_dir_all(&downloads).unwrap();
+        
+        let data: Vec<u8> = (0..84_000u32).map(|i| (i * 11 % 239) as u8).collect();
+        let source = directory.join("disk.img");
+        std::fs::write(&source, &data).unwrap();
+        
+        let (sender, _sender_events) = manager(&directory).await;
+        let (receiver, _receiver_events) = manager(&downloads).await;
+        
+        let offer = sender.send_file(&source).await.unwrap();
+        assert_eq!(offer.compression, CompressionType::Lz4);
+        let response = receiver.handle_message(TransferMessage::Offer(offer)).await.unwrap().remove(0);
+        let mut chunks = sender.handle_message(response).await.unwrap();
+        
+        // The first chunk is damaged on the way
+        match chunks[0] {
+            TransferMessage::Chunk(ref mut chunk) => {
+                assert!(chunk.is_compressed);
+                chunk.data[10] ^= 0xff;
+            }
+            _ => panic!("Expected a chunk"),
+        }
+        let mut to_sender = receiver.handle_message(chunks.remove(0)).await.unwrap();
+        assert!(matches!(to_sender[0], TransferMessage::Ack(ChunkAck { chunk_index: 0, corrupt: true, .. })));
+        
+        let mut to_receiver = chunks;
+        to_receiver.extend(sender.handle_message(to_sender.remove(0)).await.unwrap());
+        relay(&sender, &receiver, to_receiver).await;
+        
+        assert_eq!(std::fs::read(downloads.join("disk.img")).unwrap(), data);
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_chunk_index_outside_the_file_fails_the_transfer() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let downloads = directory.join("out");
+        std::fs::create_dir_all(&downloads).unwrap();
+        
+        let source = directory.join("disk.img");
+        std::fs::write(&source, vec![7u8; 84_000]).unwrap();
+        
+        let (sender, _sender_events) = manager(&directory).await;
+        let (receiver, _receiver_events) = manager(&downloads).await;
+        
+        let offer = sender.send_file(&source).await.unwrap();
+        let transfer_id = offer.transfer_id.clone();
+        let response = receiver.handle_message(TransferMessage::Offer(offer)).await.unwrap().remove(0);
+        let mut chunks = sender.handle_message(response).await.unwrap();
+        
+        if let TransferMessage::Chunk(ref mut chunk) = chunks[0] {
+            chunk.chunk_index = u64::MAX;
+        }
+        let replies = receiver.handle_message(chunks.remove(0)).await.unwrap();
+        assert!(matches!(replies[0], TransferMessage::Cancel(_)));
+        
+        let ack = ChunkAck { transfer_id: transfer_id.clone(), chunk_index: u64::MAX / 2, corrupt: true };
+        let replies = sender.handle_message(TransferMessage::Ack(ack)).await.unwrap();
+        assert!(matches!(replies[0], TransferMessage::Cancel(_)));
+        assert_eq!(sender.get_transfer_progress(&transfer_id).await.unwrap().status, TransferStatus::Failed);
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_directory_arrives_with_its_structure_and_metadata() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let source = directory.join("project");
+        let downloads = directory.join("out");
+        std::fs::create_dir_all(source.join("src")).unwrap();
+        std::fs::create_dir_all(source.join("empty")).unwrap();
+        std::fs::create_dir_all(&downloads).unwrap();
+        
+        let blob: Vec<u8> = (0..84_000u32).map(|i| (i * 17 % 233) as u8).collect();
+        std::fs::write(source.join("src").join("main.rs"), b"fn main() {}\n").unwrap();
+        std::fs::write(source.join("blob.bin"), &blob).unwrap();
+        std::fs::write(source.join("notes one.txt"), b"").unwrap();
+        
+        let modified = std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options().write(true).open(source.join("blob.bin")).unwrap().set_modified(modified).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(source.join("blob.bin"), std::fs::Permissions::from_mode(0o640)).unwrap();
+            std::os::unix::fs::symlink("src", source.join("current")).unwrap();
+            std::os::unix::fs::symlink("../../etc", source.join("escape")).unwrap();
+        }
+        
+        let (sender, _sender_events) = manager(&directory).await;
+        let (receiver, mut receiver_events) = manager(&downloads).await;
+        sender.update_config(TransferConfig {
+            chunk_size: 4096,
+            download_directory: directory.clone(),
+            symlink_policy: SymlinkPolicy::Preserve,
+            ..TransferConfig::default()
+        }).await;
+        
+        let offer = sender.send_directory(&source).await.unwrap();
+        let directory_id = offer.directory_id.clone();
+        relay(&sender, &receiver, vec![TransferMessage::DirectoryOffer(offer)]).await;
+        
+        let received = downloads.join("project");
+        assert_eq!(std::fs::read(received.join("blob.bin")).unwrap(), blob);
+        assert_eq!(std::fs::read(received.join("src").join("main.rs")).unwrap(), b"fn main() {}\n");
+        assert!(received.join("notes_one.txt").exists());
+        assert!(received.join("empty").is_dir());
+        
+        let metadata = std::fs::metadata(received.join("blob.bin")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+            assert_eq!(std::fs::read_link(received.join("current")).unwrap(), PathBuf::from("src"));
+            assert!(std::fs::symlink_metadata(received.join("escape")).is_err());
+        }
+        
+        let progress = sender.get_directory_progress(&directory_id).await.unwrap();
+        assert_eq!((progress.files_done, progress.file_count), (3, 3));
+        assert_eq!(progress.bytes_transferred, progress.total_bytes);
+        assert_eq!(progress.status, TransferStatus::Completed);
+        
+        let mut completed = false;
+        while let Ok(event) = receiver_events.try_recv() {
+            completed |= matches!(event, TransferEvent::TransferCompleted(ref id) if *id == directory_id);
+        }
+        assert!(completed);
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_directory_offer_cannot_escape_download_directory() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let downloads = directory.join("out");
+        std::fs::create_dir_all(&downloads).unwrap();
+        let (receiver, _events) = manager(&downloads).await;
+        
+        let entry = |path: &str| crate::utils::directory_transfer::ManifestEntry {
+            path: path.to_string(),
+            kind: EntryKind::File,
+            size: 4,
+            mode: None,
+            modified: None,
+            target: None,
+        };
+        let offer = |entries| DirectoryTransferRequest {
+            directory_id: Uuid::new_v4().to_string(),
+            name: "photos".to_string(),
+            manifest: DirectoryManifest { entries },
+        };
+        
+        for entries in [vec![entry("../../evil")], vec![entry("/etc/passwd")], vec![entry("a b"), entry("a_b")]] {
+            let response = receiver.receive_directory_offer(offer(entries)).await.unwrap();
+            assert!(!response.accepted);
+        }
+        assert_eq!(std::fs::read_dir(&downloads).unwrap().count(), 0);
+        
+        // A file offered for a directory it isn't listed in is refused too
+        let accepted = offer(vec![entry("one.txt")]);
+        let directory_id = accepted.directory_id.clone();
+        assert!(receiver.receive_directory_offer(accepted).await.unwrap().accepted);
+        let response = receiver.receive_offer(FileTransferRequest {
+            transfer_id: Uuid::new_v4().to_string(),
+            file_name: "two.txt".to_string(),
+            file_size: 4,
+            chunk_size: 4096,
+            compression: CompressionType::None,
+            encryption_enabled: true,
+            checksum: None,
+            directory_id: Some(directory_id),
+            relative_path: Some("../two.txt".to_string()),
+        }).await.unwrap();
+        assert!(!response.accepted);
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[test]
+    fn test_compression_is_chosen_per_file() {
+        assert_eq!(CompressionType::for_file(Path::new("photos.zip")), CompressionType::None);
+        assert_eq!(CompressionType::for_file(Path::new("IMG_0001.JPG")), CompressionType::None);
+        assert_eq!(CompressionType::for_file(Path::new("server.log")), CompressionType::Gzip);
+        assert_eq!(CompressionType::for_file(Path::new("disk.img")), CompressionType::Lz4);
+        assert_eq!(CompressionType::for_file(Path::new("Makefile")), CompressionType::Lz4);
+    }
+    
+    #[tokio::test]
+    async fn test_offer_cannot_escape_download_directory() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let (receiver, _events) = manager(&directory).await;
+        
+        let response = receiver.receive_offer(FileTransferRequest {
+            transfer_id: Uuid::new_v4().to_string(),
+            file_name: "../../etc/passwd".to_string(),
+            file_size: 10,
+            chunk_size: 4096,
+            compression: CompressionType::None,
+            encryption_enabled: true,
+            checksum: None,
+            directory_id: None,
+            relative_path: None,
+        }).await.unwrap();
+        
+        assert!(response.accepted);
+        assert_eq!(response.suggested_path.map(PathBuf::from), Some(directory.join("passwd")));
+        
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_offer_with_tiny_chunks_is_refused() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        let (receiver, _events) = manager(&directory).await;
+        
+        // A bit per byte of a 1 GB file would be 128 MB of bitmap
+        let response = receiver.receive_offer(FileTransferRequest {
+            transfer_id: Uuid::new_v4().to_string(),
+            file_name: "disk.img".to_string(),
+            file_size: 1 << 30,
+            chunk_size: 1,
+            compression: CompressionType::None,
+            encryption_enabled: true,
+            checksum: None,
+            directory_id: None,
+            relative_path: None,
+        }).await.unwrap();
+        
+        assert!(!response.accepted);
+        assert!(receiver.get_active_transfers().await.is_empty());
+        
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
\ No newline at end of file
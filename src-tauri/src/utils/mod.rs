@@ -3,6 +3,9 @@ pub mod logging;
 pub mod performance;
 pub mod id_generator;
 pub mod file_transfer;
+pub mod transfer_state;
+pub mod directory_transfer;
+pub mod throttle;
 
 use serde_json::{json, Value};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -0,0 +1,92 @@
+//! Token bucket holding file transfer to a byte rate, shared by every transfer it paces.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How much of the rate may go out at once after an idle spell
+const BURST: Duration = Duration::from_millis(100);
+
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /// Bytes per second; unlimited when `None`
+    rate: Option<u64>,
+    /// Bytes that may go now; negative once sends are reserved ahead of the rate
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate: rate.filter(|rate| *rate > 0),
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Change the rate; what was reserved at the old rate stays reserved
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+        state.rate = rate.filter(|rate| *rate > 0);
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Take `bytes` from the bucket, returning how long to hold them back so the rate holds
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.refill(Instant::now());
+
+        let rate = match state.rate {
+            Some(rate) => rate as f64,
+            None => return Duration::ZERO,
+        };
+
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let rate = rate as f64;
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(rate * BURST.as_secs_f64());
+        }
+        self.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations_are_spaced_by_the_rate() {
+        let unlimited = TokenBucket::new(None);
+        assert_eq!(unlimited.reserve(10_000_000), Duration::ZERO);
+
+        // Starting empty, each 500 bytes at 1000 B/s waits half a second longer than the last
+        let bucket = TokenBucket::new(Some(1000));
+        let first = bucket.reserve(500);
+        let second = bucket.reserve(500);
+        assert!((first.as_secs_f64() - 0.5).abs() < 0.05, "{:?}", first);
+        assert!((second.as_secs_f64() - 1.0).abs() < 0.05, "{:?}", second);
+
+        bucket.set_rate(None);
+        assert_eq!(bucket.reserve(500), Duration::ZERO);
+    }
+}
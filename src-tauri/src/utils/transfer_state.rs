@@ -0,0 +1,202 @@
+//! What a download has received so far, kept beside its `.part` file so a
+//! dropped session or a restart doesn't lose the transfer.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Chunks `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// One bit per chunk of a file, set once the chunk is written
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkBitmap {
+    chunk_count: u64,
+    bits: Vec<u8>,
+}
+
+impl ChunkBitmap {
+    pub fn new(chunk_count: u64) -> Self {
+        Self {
+            chunk_count,
+            bits: vec![0; chunk_count.div_ceil(8) as usize],
+        }
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.chunk_count
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        index < self.chunk_count && self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    /// Mark a chunk received, returning false if it already was
+    pub fn insert(&mut self, index: u64) -> bool {
+        if index >= self.chunk_count || self.contains(index) {
+            return false;
+        }
+        self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        true
+    }
+
+    pub fn received(&self) -> u64 {
+        self.bits.iter().map(|byte| byte.count_ones() as u64).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received() == self.chunk_count
+    }
+
+    /// Runs of chunks not yet received, in order
+    pub fn missing(&self) -> Vec<ChunkRange> {
+        let mut ranges: Vec<ChunkRange> = Vec::new();
+        for index in (0..self.chunk_count).filter(|index| !self.contains(*index)) {
+            match ranges.last_mut() {
+                Some(range) if range.end == index => range.end += 1,
+                _ => ranges.push(ChunkRange { start: index, end: index + 1 }),
+            }
+        }
+        ranges
+    }
+}
+
+pub fn chunk_count(file_size: u64, chunk_size: usize) -> u64 {
+    file_size.div_ceil(chunk_size.max(1) as u64)
+}
+
+/// Bytes the chunks in `ranges` hold; only the file's last chunk may be short
+pub fn range_bytes(ranges: &[ChunkRange], file_size: u64, chunk_size: usize) -> u64 {
+    let chunk_size = chunk_size as u64;
+    ranges.iter()
+        .map(|range| (range.end * chunk_size).min(file_size).saturating_sub(range.start * chunk_size))
+        .sum()
+}
+
+/// Saved state of an unfinished download, found by the file it will become
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialDownload {
+    pub transfer_id: String,
+    pub file_size: u64,
+    pub chunk_size: usize,
+    /// Whole-file checksum from the offer; a download without one can't be matched to a later offer
+    pub checksum: Option<String>,
+    pub received: ChunkBitmap,
+}
+
+impl PartialDownload {
+    /// Where the data is written until the file is complete and checked
+    pub fn part_path(file_path: &Path) -> PathBuf {
+        let mut name = file_path.as_os_str().to_os_string();
+        name.push(".part");
+        PathBuf::from(name)
+    }
+
+    pub fn state_path(file_path: &Path) -> PathBuf {
+        let mut name = file_path.as_os_str().to_os_string();
+        name.push(".part.json");
+        PathBuf::from(name)
+    }
+
+    pub fn load(file_path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(Self::state_path(file_path))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Written through a temporary file, so a crash leaves the old state rather than half of the new one
+    pub fn save(&self, file_path: &Path) -> Result<()> {
+        let state_path = Self::state_path(file_path);
+        let mut temporary = state_path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        std::fs::write(&temporary, serde_json::to_vec(self)?)?;
+        std::fs::rename(&temporary, &state_path)?;
+        Ok(())
+    }
+
+    /// Drop the state and the data received so far
+    pub fn discard(file_path: &Path) {
+        let _ = std::fs::remove_file(Self::part_path(file_path));
+        let _ = std::fs::remove_file(Self::state_path(file_path));
+    }
+
+    /// The unfinished download in `directory` of the file with this name, size, chunking and checksum
+    pub fn find(directory: &Path, file_name: &str, file_size: u64, chunk_size: usize, checksum: &str) -> Option<(PathBuf, Self)> {
+        let entries = std::fs::read_dir(directory).ok()?;
+
+        let stem = Path::new(file_name).file_stem()?.to_string_lossy().to_string();
+        let extension = Path::new(file_name).extension().map(|extension| extension.to_string_lossy().to_string());
+
+        entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().strip_suffix(".part.json")?.to_string();
+                // The download may have been given a "name (n).ext" to avoid a clash
+                let same_file = name == file_name || match extension {
+                    Some(ref extension) => name.starts_with(&format!("{} (", stem)) && name.ends_with(&format!(").{}", extension)),
+                    None => name.starts_with(&format!("{} (", stem)) && name.ends_with(')'),
+                };
+                same_file.then(|| directory.join(name))
+            })
+            .filter(|file_path| Self::part_path(file_path).exists())
+            .find_map(|file_path| {
+                let state = Self::load(&file_path).ok()?;
+                let matches = state.file_size == file_size
+                    && state.chunk_size == chunk_size
+                    && state.checksum.as_deref() == Some(checksum)
+                    && state.received.chunk_count() == chunk_count(file_size, chunk_size);
+                matches.then_some((file_path, state))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_missing_chunks_are_listed_as_ranges() {
+        let mut received = ChunkBitmap::new(10);
+        for index in [0, 1, 4, 5, 6, 9] {
+            assert!(received.insert(index));
+        }
+        assert!(!received.insert(4));
+        assert!(!received.insert(10));
+
+        assert_eq!(received.received(), 6);
+        assert_eq!(received.missing(), vec![ChunkRange { start: 2, end: 4 }, ChunkRange { start: 7, end: 9 }]);
+        assert_eq!(range_bytes(&received.missing(), 9_500, 1000), 4000);
+        assert_eq!(range_bytes(&[ChunkRange { start: 9, end: 10 }], 9_500, 1000), 500);
+    }
+
+    #[test]
+    fn test_partial_download_is_found_again() {
+        let directory = std::env::temp_dir().join(format!("anyviewer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let file_path = directory.join("disk (1).img");
+        let mut state = PartialDownload {
+            transfer_id: Uuid::new_v4().to_string(),
+            file_size: 5000,
+            chunk_size: 1000,
+            checksum: Some("abc".to_string()),
+            received: ChunkBitmap::new(5),
+        };
+        state.received.insert(3);
+        state.save(&file_path).unwrap();
+        std::fs::write(PartialDownload::part_path(&file_path), b"").unwrap();
+
+        let (found_path, found) = PartialDownload::find(&directory, "disk.img", 5000, 1000, "abc").unwrap();
+        assert_eq!(found_path, file_path);
+        assert!(found.received.contains(3));
+
+        // A different file of the same name isn't resumed
+        assert!(PartialDownload::find(&directory, "disk.img", 5000, 1000, "def").is_none());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}